@@ -13,17 +13,53 @@ pub trait FlowController {
         &self,
     ) -> impl Future<Output = Result<Vec<Device>, ()>> + Send;
 
-    fn get_hybrid_credential(&mut self) -> impl Future<Output = Result<(), ()>> + Send;
-    fn get_usb_credential(&mut self) -> impl Future<Output = Result<(), ()>> + Send;
+    /// Starts the given device, cancelling the previously selected one.
+    fn select_device(&mut self, device_id: String) -> impl Future<Output = Result<(), ()>> + Send;
+    /// Starts several devices together, cancelling the previously selected
+    /// ones. The first device to return a credential wins.
+    fn select_devices(
+        &mut self,
+        device_ids: Vec<String>,
+    ) -> impl Future<Output = Result<(), ()>> + Send;
     fn subscribe(
         &mut self,
     ) -> impl Future<
         Output = Result<Pin<Box<dyn Stream<Item = BackgroundEvent> + Send + 'static>>, ()>,
     > + Send;
-    fn enter_client_pin(&mut self, pin: String) -> impl Future<Output = Result<(), ()>> + Send;
+    /// Sends a PIN to the device with the given ID, which asked for it.
+    fn enter_client_pin(
+        &mut self,
+        device_id: String,
+        pin: String,
+    ) -> impl Future<Output = Result<(), ()>> + Send;
+    /// Sends the user's login password to the platform authenticator.
+    fn enter_password(&mut self, password: String) -> impl Future<Output = Result<(), ()>> + Send;
+    /// Sends the credential chosen by the user to the device with the given
+    /// ID, which listed it.
     fn select_credential(
         &self,
+        device_id: String,
         credential_id: String,
     ) -> impl Future<Output = Result<(), ()>> + Send;
+    /// Sets the name shown for a linked device. An empty name restores the
+    /// one the device sent.
+    fn rename_device(
+        &self,
+        device_id: String,
+        name: String,
+    ) -> impl Future<Output = Result<(), ()>> + Send;
+    /// Removes a linked device, so that it is no longer offered.
+    fn forget_device(&self, device_id: String) -> impl Future<Output = Result<(), ()>> + Send;
+    /// Lists the relying parties whose passkeys the current transfer exports,
+    /// or imports from a plain archive.
+    fn get_transfer_summary(&self) -> impl Future<Output = Result<Vec<String>, ()>> + Send;
+    /// Completes the current transfer with the passphrase of the archive,
+    /// returning the number of passkeys transferred.
+    fn confirm_transfer(
+        &mut self,
+        passphrase: String,
+    ) -> impl Future<Output = Result<u32, ()>> + Send;
+    /// Confirms the change to the security key that the UI asked about.
+    fn confirm_change(&mut self) -> impl Future<Output = Result<(), ()>> + Send;
     fn cancel_request(&self, request_id: RequestId) -> impl Future<Output = Result<(), ()>> + Send;
 }
@@ -1,3 +1,4 @@
 pub mod client;
 pub mod model;
 pub mod server;
+pub mod webauthn;
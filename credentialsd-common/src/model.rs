@@ -1,7 +1,7 @@
-use std::fmt::Display;
+use std::{collections::HashMap, fmt::Display};
 
 use serde::{Deserialize, Serialize};
-use zvariant::{SerializeDict, Type};
+use zvariant::{DeserializeDict, SerializeDict, Type};
 
 pub use libwebauthn::ops::webauthn::{
     Assertion, GetAssertionRequest, MakeCredentialRequest, MakeCredentialResponse,
@@ -16,10 +16,26 @@ pub struct Credential {
 
 #[derive(Clone, Debug)]
 pub enum CredentialRequest {
-    CreatePublicKeyCredentialRequest(MakeCredentialRequest),
+    CreatePublicKeyCredentialRequest(MakeCredentialRequestInternal),
     GetPublicKeyCredentialRequest(GetAssertionRequest),
 }
 
+#[derive(Clone, Debug)]
+pub struct MakeCredentialRequestInternal {
+    /// The request, except for `ctap.algorithms`, which is left empty:
+    /// libwebauthn only models the algorithms it knows about.
+    pub ctap: MakeCredentialRequest,
+    /// The COSE identifiers of the algorithms in `pubKeyCredParams`, in the
+    /// order that the RP prefers them.
+    pub algorithms: Vec<i64>,
+}
+
+impl MakeCredentialRequestInternal {
+    pub fn new(ctap: MakeCredentialRequest, algorithms: Vec<i64>) -> Self {
+        Self { ctap, algorithms }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum CredentialResponse {
     CreatePublicKeyCredentialResponse(Box<MakeCredentialResponseInternal>),
@@ -96,6 +112,129 @@ pub struct GetClientCapabilitiesResponse {
     pub signal_all_accepted_credentials: bool,
     pub signal_current_user_details: bool,
     pub signal_unknown_credential: bool,
+    /// Not a WebAuthn capability: whether the platform authenticator binds
+    /// the keys of new credentials to a TPM.
+    pub tpm_bound_platform_authenticator: bool,
+}
+
+/// The state of the PIN of a security key.
+#[derive(Clone, Debug, SerializeDict, DeserializeDict, Type)]
+#[zvariant(signature = "dict", rename_all = "camelCase")]
+pub struct PinInfo {
+    pub pin_set: bool,
+    /// How many wrong PINs the key accepts before it blocks, if it has a PIN
+    /// and tells.
+    pub pin_retries: Option<u32>,
+    /// The minimum length of new PINs, in Unicode code points.
+    pub min_pin_length: u32,
+    /// Whether the key requires its PIN to be changed before it can be used.
+    pub force_pin_change: bool,
+}
+
+/// What a security key reports about itself in `authenticatorGetInfo`.
+#[derive(Clone, Debug, SerializeDict, DeserializeDict, Type)]
+#[zvariant(signature = "dict", rename_all = "camelCase")]
+pub struct AuthenticatorInfo {
+    /// The CTAP and U2F versions supported, e.g. `FIDO_2_1`.
+    pub versions: Vec<String>,
+    pub aaguid: Vec<u8>,
+    pub options: HashMap<String, bool>,
+    /// The COSE identifiers of the supported algorithms, in order of
+    /// preference.
+    pub algorithms: Vec<i32>,
+    pub extensions: Vec<String>,
+    pub pin_retries: Option<u32>,
+    /// How many failed on-device verifications the key accepts before it
+    /// falls back to its PIN, if it verifies users itself.
+    pub uv_retries: Option<u32>,
+    pub firmware_version: Option<u32>,
+    pub transports: Vec<String>,
+}
+
+/// A discoverable credential stored on a security key.
+#[derive(Clone, Debug, SerializeDict, DeserializeDict, Type)]
+#[zvariant(signature = "dict", rename_all = "camelCase")]
+pub struct SecurityKeyCredential {
+    pub rp_id: String,
+    pub rp_name: Option<String>,
+    pub credential_id: Vec<u8>,
+    pub user_id: Vec<u8>,
+    pub user_name: Option<String>,
+    pub user_display_name: Option<String>,
+}
+
+/// A fingerprint enrolled on a security key.
+#[derive(Clone, Debug, SerializeDict, DeserializeDict, Type)]
+#[zvariant(signature = "dict", rename_all = "camelCase")]
+pub struct Fingerprint {
+    pub template_id: Vec<u8>,
+    pub name: Option<String>,
+}
+
+/// Feedback on the last fingerprint sample captured during an enrollment,
+/// with the values of CTAP `lastEnrollSampleStatus`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FingerprintSampleStatus {
+    Good = 0x00,
+    TooHigh = 0x01,
+    TooLow = 0x02,
+    TooLeft = 0x03,
+    TooRight = 0x04,
+    TooFast = 0x05,
+    TooSlow = 0x06,
+    PoorQuality = 0x07,
+    TooSkewed = 0x08,
+    TooShort = 0x09,
+    MergeFailure = 0x0A,
+    /// The fingerprint is already enrolled.
+    Exists = 0x0B,
+    /// The user did not touch the sensor in time.
+    NoUserActivity = 0x0D,
+    /// The user did not lift their finger off the sensor.
+    NoUserPresenceTransition = 0x0E,
+}
+
+impl TryFrom<u8> for FingerprintSampleStatus {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Good),
+            0x01 => Ok(Self::TooHigh),
+            0x02 => Ok(Self::TooLow),
+            0x03 => Ok(Self::TooLeft),
+            0x04 => Ok(Self::TooRight),
+            0x05 => Ok(Self::TooFast),
+            0x06 => Ok(Self::TooSlow),
+            0x07 => Ok(Self::PoorQuality),
+            0x08 => Ok(Self::TooSkewed),
+            0x09 => Ok(Self::TooShort),
+            0x0A => Ok(Self::MergeFailure),
+            0x0B => Ok(Self::Exists),
+            0x0D => Ok(Self::NoUserActivity),
+            0x0E => Ok(Self::NoUserPresenceTransition),
+            _ => Err(value),
+        }
+    }
+}
+
+/// A change to the settings of a security key, which the user confirms
+/// before it is made.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SecurityKeyChange {
+    /// Turns `alwaysUv` on or off.
+    SetAlwaysUv(bool),
+    /// Raises the minimum PIN length, and lets the given relying parties read
+    /// it.
+    SetMinPinLength {
+        min_length: u32,
+        rp_ids: Vec<String>,
+    },
+    /// Enables enterprise attestation, which cannot be disabled again without
+    /// a reset.
+    EnableEnterpriseAttestation,
+    /// Deletes all passkeys, fingerprints and the PIN of the security key.
+    Reset,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -108,12 +247,34 @@ pub enum CredentialType {
 pub struct Device {
     pub id: String,
     pub transport: Transport,
+    /// Name to show for devices that the user can tell apart, like linked phones.
+    pub name: Option<String>,
+    /// The HID manufacturer and product names of the security key that is
+    /// plugged in, for USB devices when there is just one.
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Type)]
 pub enum Operation {
     Create,
     Get,
+    /// Exports platform passkeys to an archive.
+    Export,
+    /// Imports platform passkeys from an archive.
+    Import,
+    /// Reads or changes the PIN of a security key.
+    ManagePin,
+    /// Lists, deletes or updates the passkeys stored on a security key.
+    ManageCredentials,
+    /// Enrolls, lists, renames or removes the fingerprints of a security key.
+    ManageFingerprints,
+    /// Changes the settings of a security key.
+    ConfigureSecurityKey,
+    /// Reads what a security key reports about itself.
+    InspectSecurityKey,
+    /// Resets a security key to its factory state.
+    ResetSecurityKey,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -123,6 +284,8 @@ pub enum Transport {
     HybridQr,
     Internal,
     Nfc,
+    /// A third-party credential provider, e.g. a password manager.
+    PasskeyProvider,
     Usb,
 }
 
@@ -145,6 +308,7 @@ impl TryInto<Transport> for &str {
             "HybridQr" => Ok(Transport::HybridQr),
             "Internal" => Ok(Transport::Internal),
             "NFC" => Ok(Transport::Nfc),
+            "PasskeyProvider" => Ok(Transport::PasskeyProvider),
             "USB" => Ok(Transport::Usb),
             _ => Err(format!("Unrecognized transport: {}", self.to_owned())),
         }
@@ -165,6 +329,7 @@ impl Transport {
             Transport::HybridQr => "HybridQr",
             Transport::Internal => "Internal",
             Transport::Nfc => "NFC",
+            Transport::PasskeyProvider => "PasskeyProvider",
             Transport::Usb => "USB",
         }
     }
@@ -177,61 +342,80 @@ pub enum ViewUpdate {
     SetCredentials(Vec<Credential>),
 
     WaitingForDevice(Device),
+    /// Several devices were started together.
+    WaitingForDevices(Vec<Device>),
     SelectingDevice,
 
-    UsbNeedsPin { attempts_left: Option<u32> },
-    UsbNeedsUserVerification { attempts_left: Option<u32> },
+    UsbNeedsPin {
+        attempts_left: Option<u32>,
+    },
+    UsbNeedsUserVerification {
+        attempts_left: Option<u32>,
+    },
     UsbNeedsUserPresence,
+    UsbDisconnected,
+    /// The user chooses a new PIN for the security key.
+    UsbNeedsNewPin {
+        min_length: u32,
+        must_change: bool,
+    },
+    /// The security key waits for a fingerprint sample to enroll.
+    UsbNeedsFingerprintSample {
+        remaining_samples: Option<u32>,
+        last_sample: Option<FingerprintSampleStatus>,
+    },
+    /// The user confirms a change to the security key.
+    UsbNeedsConfirmation(SecurityKeyChange),
+    /// The user has to unplug the security key and plug it back in.
+    UsbNeedsReplug,
+
+    /// The platform authenticator waits for the user's fingerprint.
+    NeedsFingerprint {
+        attempts_left: Option<u32>,
+        password_fallback: bool,
+    },
+    /// The platform authenticator needs the user's login password.
+    NeedsPassword {
+        attempts_left: Option<u32>,
+    },
 
     HybridNeedsQrCode(String),
     HybridConnecting,
     HybridConnected,
 
+    /// The user confirms a transfer of platform passkeys by entering the
+    /// passphrase of the archive. Exports list the relying parties whose
+    /// passkeys are exported.
+    ConfirmTransfer(Vec<String>),
+    /// The user confirms the import of a plain archive of another credential
+    /// manager, which has no passphrase. Lists the relying parties whose
+    /// passkeys are imported.
+    ConfirmImport(Vec<String>),
+
     Completed,
     Cancelled,
     Failed(String),
 }
 
-#[derive(Clone, Debug, Default)]
-pub enum HybridState {
-    /// Default state, not listening for hybrid transport.
-    #[default]
-    Idle,
-
-    /// QR code flow is starting, awaiting QR code scan and BLE advert from phone.
-    Started(String),
-
-    /// BLE advert received, connecting to caBLE tunnel with shared secret.
-    Connecting,
-
-    /// Connected to device via caBLE tunnel.
-    Connected,
-
-    /// Credential received over tunnel.
-    Completed,
-
-    // This isn't actually sent from the server.
-    UserCancelled,
-
-    /// Failed to receive a credential
-    Failed,
-}
-
 /// Used to share public state between credential service and UI.
+///
+/// Devices of all transports go through these states, each using the ones
+/// that apply to it.
 #[derive(Clone, Debug, Default)]
-pub enum UsbState {
-    /// Not polling for FIDO USB device.
+pub enum AuthenticatorState {
+    /// Not looking for an authenticator.
     #[default]
     Idle,
 
-    /// Awaiting FIDO USB device to be plugged in.
+    /// Awaiting an authenticator, e.g. a USB device to be plugged in.
     Waiting,
 
     // When we encounter multiple devices, we let all of them blink and continue
     // with the one that was tapped.
     SelectingDevice,
 
-    /// USB device connected, prompt user to tap
+    /// Authenticator connected, prompt user to tap it or release the
+    /// credential on it.
     Connected,
 
     /// The device needs the PIN to be entered.
@@ -255,17 +439,75 @@ pub enum UsbState {
         creds: Vec<Credential>,
     },
 
-    /// USB tapped, received credential
+    /// Received credential
     Completed,
 
     /// Interaction with the authenticator failed.
     Failed(Error),
+
+    /// The authenticator was unplugged before the ceremony completed. The
+    /// service goes back to waiting for an authenticator.
+    Disconnected,
+
+    /// The platform authenticator waits for the user to touch the fingerprint
+    /// reader.
+    NeedsFingerprint {
+        attempts_left: Option<u32>,
+        /// Whether the user may enter their login password instead.
+        password_fallback: bool,
+    },
+
+    /// The platform authenticator needs the user's login password to verify
+    /// them.
+    NeedsPassword {
+        attempts_left: Option<u32>,
+    },
+
+    /// The user has to choose a new PIN for the security key, of at least
+    /// `min_length` code points. If `must_change` is set, the key requires its
+    /// PIN to be changed, and the new one must differ from the current one.
+    NeedsNewPin {
+        min_length: u32,
+        must_change: bool,
+    },
+
+    /// The security key waits for the user to touch its fingerprint sensor,
+    /// to capture a sample of a new fingerprint. `last_sample` is the
+    /// feedback on the previous sample, if there was one.
+    NeedsFingerprintSample {
+        remaining_samples: Option<u32>,
+        last_sample: Option<FingerprintSampleStatus>,
+    },
+
+    /// The user has to confirm a change to the security key before it is
+    /// made.
+    NeedsConfirmation(SecurityKeyChange),
+
+    /// Security keys only accept a reset shortly after they are plugged in,
+    /// so the user has to unplug the key and plug it back in.
+    NeedsReplug,
+
+    /// The user has to scan this hybrid QR code with their phone.
+    NeedsQrCodeScan(String),
+
+    /// The phone was found, and a tunnel to it is being established.
+    Connecting,
 }
 
 #[derive(Clone, Debug)]
 pub enum BackgroundEvent {
-    UsbStateChanged(UsbState),
-    HybridQrStateChanged(HybridState),
+    /// The state of the device selected by the UI changed.
+    DeviceStateChanged {
+        device_id: String,
+        state: DeviceState,
+    },
+}
+
+/// State of a device, along with its transport.
+#[derive(Clone, Debug)]
+pub struct DeviceState {
+    pub transport: Transport,
+    pub state: AuthenticatorState,
 }
 
 #[derive(Debug, Clone)]
@@ -282,6 +524,11 @@ pub enum Error {
     /// Note that this is different than exhausting the PIN count that fully
     /// locks out the device.
     PinAttemptsExhausted,
+    /// The platform authenticator could not verify the user with any of its
+    /// methods.
+    UserVerificationFailed,
+    /// The security key requires its PIN to be changed before it can be used.
+    PinChangeRequired,
     // TODO: We may want to hide the details on this variant from the public API.
     /// Something went wrong with the credential service itself, not the authenticator.
     Internal(String),
@@ -296,6 +543,8 @@ impl Display for Error {
             Self::NoCredentials => f.write_str("NoCredentials"),
             Self::CredentialExcluded => f.write_str("CredentialExcluded"),
             Self::PinAttemptsExhausted => f.write_str("PinAttemptsExhausted"),
+            Self::UserVerificationFailed => f.write_str("UserVerificationFailed"),
+            Self::PinChangeRequired => f.write_str("PinChangeRequired"),
             Self::Internal(s) => write!(f, "InternalError: {s}"),
         }
     }
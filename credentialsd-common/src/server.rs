@@ -9,7 +9,7 @@ use zvariant::{
     Signature, Structure, StructureBuilder, Type, Value, signature::Fields,
 };
 
-use crate::model::{BackgroundEvent, Operation};
+use crate::model::{BackgroundEvent, DeviceState, FingerprintSampleStatus, Operation};
 
 const TAG_VALUE_SIGNATURE: &Signature = &Signature::Structure(Fields::Static {
     fields: &[&Signature::U8, &Signature::Variant],
@@ -22,11 +22,13 @@ impl Type for BackgroundEvent {
 impl From<&BackgroundEvent> for Structure<'_> {
     fn from(value: &BackgroundEvent) -> Self {
         match value {
-            BackgroundEvent::UsbStateChanged(state) => {
-                tag_value_to_struct(0x01, Some(Value::Structure(state.into())))
-            }
-            BackgroundEvent::HybridQrStateChanged(state) => {
-                tag_value_to_struct(0x02, Some(Value::Structure(state.into())))
+            BackgroundEvent::DeviceStateChanged { device_id, state } => {
+                let value = StructureBuilder::new()
+                    .add_field(device_id.as_str())
+                    .add_field(Structure::from(state))
+                    .build()
+                    .expect("create a struct");
+                tag_value_to_struct(0x04, Some(Value::Structure(value)))
             }
         }
     }
@@ -39,15 +41,22 @@ impl TryFrom<&Structure<'_>> for BackgroundEvent {
         let (tag, value) = parse_tag_value_struct(value)?;
 
         match tag {
-            0x01 => {
-                let structure: Structure = value.downcast_ref()?;
-                Ok(BackgroundEvent::UsbStateChanged((&structure).try_into()?))
-            }
-            0x02 => {
+            // 0x01 and 0x02 were used for the USB and hybrid QR state before
+            // devices were selected by ID, and 0x03 before all devices shared
+            // the same states.
+            0x04 => {
                 let structure: Structure = value.downcast_ref()?;
-                Ok(BackgroundEvent::HybridQrStateChanged(
-                    (&structure).try_into()?,
-                ))
+                let [device_id, state] = structure.fields() else {
+                    return Err(zvariant::Error::Message(
+                        "Expected device ID and state in DeviceStateChanged".to_string(),
+                    ));
+                };
+                let device_id: &str = device_id.downcast_ref()?;
+                let state: Structure = state.downcast_ref()?;
+                Ok(BackgroundEvent::DeviceStateChanged {
+                    device_id: device_id.to_string(),
+                    state: (&state).try_into()?,
+                })
             }
             _ => Err(zvariant::Error::Message(format!(
                 "Unknown BackgroundEvent tag : {tag}"
@@ -171,6 +180,9 @@ impl From<crate::model::Credential> for Credential {
 pub struct Device {
     pub id: String,
     pub transport: String,
+    pub name: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
 }
 
 impl TryFrom<Value<'_>> for Device {
@@ -188,6 +200,9 @@ impl From<crate::model::Device> for Device {
         Device {
             id: value.id,
             transport: value.transport.as_str().to_owned(),
+            name: value.name,
+            manufacturer: value.manufacturer,
+            product: value.product,
         }
     }
 }
@@ -199,6 +214,9 @@ impl TryFrom<Device> for crate::model::Device {
         Ok(Self {
             id: value.id,
             transport,
+            name: value.name,
+            manufacturer: value.manufacturer,
+            product: value.product,
         })
     }
 }
@@ -213,6 +231,8 @@ impl TryFrom<&Value<'_>> for crate::model::Error {
             "NoCredentials" => crate::model::Error::NoCredentials,
             "CredentialExcluded" => crate::model::Error::CredentialExcluded,
             "PinAttemptsExhausted" => crate::model::Error::PinAttemptsExhausted,
+            "UserVerificationFailed" => crate::model::Error::UserVerificationFailed,
+            "PinChangeRequired" => crate::model::Error::PinChangeRequired,
             s => crate::model::Error::Internal(String::from(s)),
         };
         Ok(err)
@@ -260,121 +280,124 @@ impl From<GetPublicKeyCredentialResponse> for GetCredentialResponse {
     }
 }
 
-impl Serialize for crate::model::HybridState {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        let structure: Structure = self.into();
-        structure.serialize(serializer)
-    }
-}
-
-impl From<&crate::model::HybridState> for Structure<'_> {
-    fn from(value: &crate::model::HybridState) -> Self {
-        let (tag, value): (u8, Option<Value>) = match value {
-            crate::model::HybridState::Idle => (0x01, None),
-            crate::model::HybridState::Started(value) => (0x02, Some(Value::Str(value.into()))),
-            crate::model::HybridState::Connecting => (0x03, None),
-            crate::model::HybridState::Connected => (0x04, None),
-            crate::model::HybridState::Completed => (0x05, None),
-            crate::model::HybridState::UserCancelled => (0x06, None),
-            crate::model::HybridState::Failed => (0x07, None),
-        };
-        tag_value_to_struct(tag, value)
-    }
-}
-
-impl TryFrom<&Structure<'_>> for crate::model::HybridState {
-    type Error = zvariant::Error;
-
-    fn try_from(structure: &Structure<'_>) -> Result<Self, Self::Error> {
-        let (tag, value) = parse_tag_value_struct(structure)?;
-        match tag {
-            0x01 => Ok(Self::Idle),
-            0x02 => {
-                let qr_code: &str = value.downcast_ref()?;
-                Ok(Self::Started(qr_code.to_string()))
-            }
-            0x03 => Ok(Self::Connecting),
-            0x04 => Ok(Self::Connected),
-            0x05 => Ok(Self::Completed),
-            0x06 => Ok(Self::UserCancelled),
-            0x07 => Ok(Self::Failed),
-            _ => Err(zvariant::Error::Message(format!(
-                "Invalid HybridState type passed: {tag}"
-            ))),
-        }
+impl From<&DeviceState> for Structure<'_> {
+    fn from(value: &DeviceState) -> Self {
+        StructureBuilder::new()
+            .add_field(value.transport.as_str())
+            .add_field(Structure::from(&value.state))
+            .build()
+            .expect("create a struct")
     }
 }
 
-impl TryFrom<Structure<'_>> for crate::model::HybridState {
+impl TryFrom<&Structure<'_>> for DeviceState {
     type Error = zvariant::Error;
 
-    fn try_from(structure: Structure<'_>) -> Result<Self, Self::Error> {
-        Self::try_from(&structure)
-    }
-}
-
-impl<'de> Deserialize<'de> for crate::model::HybridState {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        deserialize_tag_value(deserializer)
+    fn try_from(value: &Structure<'_>) -> Result<Self, Self::Error> {
+        let [transport, state] = value.fields() else {
+            return Err(zvariant::Error::Message(
+                "Expected transport and state in DeviceState".to_string(),
+            ));
+        };
+        let transport: &str = transport.downcast_ref()?;
+        let state: Structure = state.downcast_ref()?;
+        Ok(DeviceState {
+            transport: transport.try_into().map_err(zvariant::Error::Message)?,
+            state: (&state).try_into()?,
+        })
     }
 }
 
-impl Type for crate::model::HybridState {
-    const SIGNATURE: &'static Signature = TAG_VALUE_SIGNATURE;
-}
-
 /// Identifier for a request to be used for cancellation.
 pub type RequestId = u32;
 
-impl Type for crate::model::UsbState {
+impl Type for crate::model::AuthenticatorState {
     const SIGNATURE: &'static Signature = TAG_VALUE_SIGNATURE;
 }
 
-impl From<&crate::model::UsbState> for Structure<'_> {
-    fn from(value: &crate::model::UsbState) -> Self {
+impl From<&crate::model::AuthenticatorState> for Structure<'_> {
+    fn from(value: &crate::model::AuthenticatorState) -> Self {
         let (tag, value): (u8, Option<Value>) = match value {
-            crate::model::UsbState::Idle => (0x01, None),
-            crate::model::UsbState::Waiting => (0x02, None),
-            crate::model::UsbState::SelectingDevice => (0x03, None),
-            crate::model::UsbState::Connected => (0x04, None),
+            crate::model::AuthenticatorState::Idle => (0x01, None),
+            crate::model::AuthenticatorState::Waiting => (0x02, None),
+            crate::model::AuthenticatorState::SelectingDevice => (0x03, None),
+            crate::model::AuthenticatorState::Connected => (0x04, None),
             // TODO: Add pin request reason to this struct
-            crate::model::UsbState::NeedsPin { attempts_left } => {
+            crate::model::AuthenticatorState::NeedsPin { attempts_left } => {
                 let num = match attempts_left {
                     Some(num) => *num as i32,
                     None => -1,
                 };
                 (0x05, Some(Value::I32(num)))
             }
-            crate::model::UsbState::NeedsUserVerification { attempts_left } => {
+            crate::model::AuthenticatorState::NeedsUserVerification { attempts_left } => {
                 let num = match attempts_left {
                     Some(num) => *num as i32,
                     None => -1,
                 };
                 (0x06, Some(Value::I32(num)))
             }
-            crate::model::UsbState::NeedsUserPresence => (0x07, None),
-            crate::model::UsbState::SelectCredential { creds } => {
+            crate::model::AuthenticatorState::NeedsUserPresence => (0x07, None),
+            crate::model::AuthenticatorState::SelectCredential { creds } => {
                 let creds: Vec<Credential> = creds.iter().map(Credential::from).collect();
                 let value = Value::new(creds);
                 (0x08, Some(value))
             }
-            crate::model::UsbState::Completed => (0x09, None),
-            crate::model::UsbState::Failed(error) => {
+            crate::model::AuthenticatorState::Completed => (0x09, None),
+            crate::model::AuthenticatorState::Failed(error) => {
                 let value = Value::<'_>::from(error.to_string());
                 (0x0A, Some(value))
             }
+            crate::model::AuthenticatorState::Disconnected => (0x0B, None),
+            crate::model::AuthenticatorState::NeedsFingerprint {
+                attempts_left,
+                password_fallback,
+            } => {
+                let num = match attempts_left {
+                    Some(num) => *num as i32,
+                    None => -1,
+                };
+                (0x0C, Some(Value::from((num, *password_fallback))))
+            }
+            crate::model::AuthenticatorState::NeedsPassword { attempts_left } => {
+                let num = match attempts_left {
+                    Some(num) => *num as i32,
+                    None => -1,
+                };
+                (0x0D, Some(Value::I32(num)))
+            }
+            crate::model::AuthenticatorState::NeedsNewPin {
+                min_length,
+                must_change,
+            } => (0x0E, Some(Value::from((*min_length, *must_change)))),
+            crate::model::AuthenticatorState::NeedsFingerprintSample {
+                remaining_samples,
+                last_sample,
+            } => {
+                let num = match remaining_samples {
+                    Some(num) => *num as i32,
+                    None => -1,
+                };
+                let status = match last_sample {
+                    Some(status) => *status as i32,
+                    None => -1,
+                };
+                (0x0F, Some(Value::from((num, status))))
+            }
+            crate::model::AuthenticatorState::NeedsConfirmation(change) => {
+                (0x10, Some(Value::from(Structure::from(change))))
+            }
+            crate::model::AuthenticatorState::NeedsReplug => (0x11, None),
+            crate::model::AuthenticatorState::NeedsQrCodeScan(qr_code) => {
+                (0x12, Some(Value::Str(qr_code.into())))
+            }
+            crate::model::AuthenticatorState::Connecting => (0x13, None),
         };
         tag_value_to_struct(tag, value)
     }
 }
 
-impl TryFrom<&Structure<'_>> for crate::model::UsbState {
+impl TryFrom<&Structure<'_>> for crate::model::AuthenticatorState {
     type Error = zvariant::Error;
 
     fn try_from(structure: &Structure<'_>) -> Result<Self, Self::Error> {
@@ -426,16 +449,79 @@ impl TryFrom<&Structure<'_>> for crate::model::UsbState {
                     "NoCredentials" => crate::model::Error::NoCredentials,
                     "CredentialExcluded" => crate::model::Error::CredentialExcluded,
                     "PinAttemptsExhausted" => crate::model::Error::PinAttemptsExhausted,
+                    "UserVerificationFailed" => crate::model::Error::UserVerificationFailed,
+                    "PinChangeRequired" => crate::model::Error::PinChangeRequired,
                     s => crate::model::Error::Internal(String::from(s)),
                 };
                 Ok(Self::Failed(err))
             }
+            0x0B => Ok(Self::Disconnected),
+            0x0C => {
+                let (attempts_left, password_fallback): (i32, bool) =
+                    value.try_clone()?.downcast()?;
+                let attempts_left = if attempts_left == -1 {
+                    None
+                } else {
+                    Some(attempts_left as u32)
+                };
+                Ok(Self::NeedsFingerprint {
+                    attempts_left,
+                    password_fallback,
+                })
+            }
+            0x0D => {
+                let attempts_left: i32 = value.downcast_ref()?;
+                let attempts_left = if attempts_left == -1 {
+                    None
+                } else {
+                    Some(attempts_left as u32)
+                };
+                Ok(Self::NeedsPassword { attempts_left })
+            }
+            0x0E => {
+                let (min_length, must_change): (u32, bool) = value.try_clone()?.downcast()?;
+                Ok(Self::NeedsNewPin {
+                    min_length,
+                    must_change,
+                })
+            }
+            0x0F => {
+                let (remaining_samples, last_sample): (i32, i32) = value.try_clone()?.downcast()?;
+                let remaining_samples = if remaining_samples == -1 {
+                    None
+                } else {
+                    Some(remaining_samples as u32)
+                };
+                let last_sample = if last_sample == -1 {
+                    None
+                } else {
+                    let status = u8::try_from(last_sample)
+                        .ok()
+                        .and_then(|status| FingerprintSampleStatus::try_from(status).ok())
+                        .ok_or(zvariant::Error::IncorrectType)?;
+                    Some(status)
+                };
+                Ok(Self::NeedsFingerprintSample {
+                    remaining_samples,
+                    last_sample,
+                })
+            }
+            0x10 => {
+                let change: Structure = value.try_clone()?.downcast()?;
+                Ok(Self::NeedsConfirmation((&change).try_into()?))
+            }
+            0x11 => Ok(Self::NeedsReplug),
+            0x12 => {
+                let qr_code: &str = value.downcast_ref()?;
+                Ok(Self::NeedsQrCodeScan(qr_code.to_string()))
+            }
+            0x13 => Ok(Self::Connecting),
             _ => Err(zvariant::Error::IncorrectType),
         }
     }
 }
 
-impl TryFrom<Structure<'_>> for crate::model::UsbState {
+impl TryFrom<Structure<'_>> for crate::model::AuthenticatorState {
     type Error = zvariant::Error;
 
     fn try_from(structure: Structure<'_>) -> Result<Self, Self::Error> {
@@ -443,7 +529,7 @@ impl TryFrom<Structure<'_>> for crate::model::UsbState {
     }
 }
 
-impl Serialize for crate::model::UsbState {
+impl Serialize for crate::model::AuthenticatorState {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
@@ -453,7 +539,7 @@ impl Serialize for crate::model::UsbState {
     }
 }
 
-impl<'de> Deserialize<'de> for crate::model::UsbState {
+impl<'de> Deserialize<'de> for crate::model::AuthenticatorState {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
@@ -462,6 +548,40 @@ impl<'de> Deserialize<'de> for crate::model::UsbState {
     }
 }
 
+impl From<&crate::model::SecurityKeyChange> for Structure<'_> {
+    fn from(value: &crate::model::SecurityKeyChange) -> Self {
+        let (tag, value): (u8, Option<Value>) = match value {
+            crate::model::SecurityKeyChange::SetAlwaysUv(enable) => {
+                (0x01, Some(Value::Bool(*enable)))
+            }
+            crate::model::SecurityKeyChange::SetMinPinLength { min_length, rp_ids } => {
+                (0x02, Some(Value::from((*min_length, rp_ids.clone()))))
+            }
+            crate::model::SecurityKeyChange::EnableEnterpriseAttestation => (0x03, None),
+            crate::model::SecurityKeyChange::Reset => (0x04, None),
+        };
+        tag_value_to_struct(tag, value)
+    }
+}
+
+impl TryFrom<&Structure<'_>> for crate::model::SecurityKeyChange {
+    type Error = zvariant::Error;
+
+    fn try_from(structure: &Structure<'_>) -> Result<Self, Self::Error> {
+        let (tag, value) = parse_tag_value_struct(structure)?;
+        match tag {
+            0x01 => Ok(Self::SetAlwaysUv(value.downcast_ref()?)),
+            0x02 => {
+                let (min_length, rp_ids): (u32, Vec<String>) = value.try_clone()?.downcast()?;
+                Ok(Self::SetMinPinLength { min_length, rp_ids })
+            }
+            0x03 => Ok(Self::EnableEnterpriseAttestation),
+            0x04 => Ok(Self::Reset),
+            _ => Err(zvariant::Error::IncorrectType),
+        }
+    }
+}
+
 fn deserialize_tag_value<'a, 'de, T, D>(deserializer: D) -> Result<T, D::Error>
 where
     T: TryFrom<Structure<'a>>,
@@ -539,45 +659,147 @@ mod test {
         serialized::{Context, Data, Format},
     };
 
-    use crate::model::{BackgroundEvent, HybridState, UsbState};
+    use crate::model::{
+        AuthenticatorState, BackgroundEvent, DeviceState, FingerprintSampleStatus,
+        SecurityKeyChange, Transport,
+    };
 
     #[test]
-    fn test_serialize_hybrid_state() {
-        let state = HybridState::Completed;
+    fn test_serialize_completed_state() {
+        let state = AuthenticatorState::Completed;
         let ctx = zvariant::serialized::Context::new_dbus(zvariant::BE, 0);
         let data = zvariant::to_bytes(ctx, &state).unwrap();
-        assert_eq!("(yv)", HybridState::SIGNATURE.to_string());
-        assert_eq!(&[5, 1, b'y', 0, 0], data.bytes());
+        assert_eq!("(yv)", AuthenticatorState::SIGNATURE.to_string());
+        assert_eq!(&[9, 1, b'y', 0, 0], data.bytes());
     }
 
     #[test]
     fn test_serialize_background_hybrid_event() {
-        let state = HybridState::Started("FIDO:/1234".to_string());
-        let event = BackgroundEvent::HybridQrStateChanged(state);
+        let state = AuthenticatorState::NeedsQrCodeScan("FIDO:/1234".to_string());
+        let event = BackgroundEvent::DeviceStateChanged {
+            device_id: "1".to_string(),
+            state: DeviceState {
+                transport: Transport::HybridQr,
+                state,
+            },
+        };
         let ctx = zvariant::serialized::Context::new_dbus(zvariant::BE, 0);
         assert_eq!("(yv)", BackgroundEvent::SIGNATURE.to_string());
         let data = zvariant::to_bytes(ctx, &event).unwrap();
-        let expected = b"\x02\x04(yv)\0\0\x02\x01s\0\0\0\0\x0aFIDO:/1234\0";
+        let expected = b"\x04\x0a(s(s(yv)))\0\0\0\0\0\0\0\x011\0\0\0\0\0\0\x08HybridQr\0\0\0\0\x12\x01s\0\0\0\0\x0aFIDO:/1234\0";
         assert_eq!(expected, data.bytes());
     }
 
     #[test]
     fn test_deserialize_background_hybrid_event() {
         let data = Data::new(
-            b"\x02\x04(yv)\0\0\x05\x01y\0\0",
+            b"\x04\x0a(s(s(yv)))\0\0\0\0\0\0\0\x011\0\0\0\0\0\0\x08HybridQr\0\0\0\0\x09\x01y\0\0",
             Context::new(Format::DBus, zvariant::BE, 0),
         );
         let event: BackgroundEvent = data.deserialize().unwrap().0;
         assert!(matches!(
             event,
-            BackgroundEvent::HybridQrStateChanged(crate::model::HybridState::Completed)
+            BackgroundEvent::DeviceStateChanged {
+                ref device_id,
+                state: DeviceState {
+                    transport: Transport::HybridQr,
+                    state: AuthenticatorState::Completed,
+                },
+            } if device_id == "1"
         ));
     }
 
     #[test]
     fn test_round_trip_background_hybrid_event() {
-        let event =
-            BackgroundEvent::HybridQrStateChanged(HybridState::Started(String::from("FIDO:/1234")));
+        let event = BackgroundEvent::DeviceStateChanged {
+            device_id: "1".to_string(),
+            state: DeviceState {
+                transport: Transport::HybridQr,
+                state: AuthenticatorState::NeedsQrCodeScan(String::from("FIDO:/1234")),
+            },
+        };
+        let ctx = zvariant::serialized::Context::new_dbus(zvariant::BE, 0);
+        let data = zvariant::to_bytes(ctx, &event).unwrap();
+        let bytes = data.bytes();
+        let data2 = Data::new(bytes, Context::new(Format::DBus, zvariant::BE, 0));
+        let event_2: BackgroundEvent = data2.deserialize().unwrap().0;
+        assert!(matches!(
+            event_2,
+            BackgroundEvent::DeviceStateChanged {
+                ref device_id,
+                state: DeviceState {
+                    transport: Transport::HybridQr,
+                    state: AuthenticatorState::NeedsQrCodeScan(ref f),
+                },
+            } if device_id == "1" && f == "FIDO:/1234"
+        ));
+    }
+
+    #[test]
+    fn test_round_trip_background_nfc_event() {
+        let event = BackgroundEvent::DeviceStateChanged {
+            device_id: "2".to_string(),
+            state: DeviceState {
+                transport: Transport::Nfc,
+                state: AuthenticatorState::NeedsPin {
+                    attempts_left: Some(3),
+                },
+            },
+        };
+        let ctx = zvariant::serialized::Context::new_dbus(zvariant::BE, 0);
+        let data = zvariant::to_bytes(ctx, &event).unwrap();
+        let bytes = data.bytes();
+        let data2 = Data::new(bytes, Context::new(Format::DBus, zvariant::BE, 0));
+        let event_2: BackgroundEvent = data2.deserialize().unwrap().0;
+        assert!(matches!(
+            event_2,
+            BackgroundEvent::DeviceStateChanged {
+                ref device_id,
+                state: DeviceState {
+                    transport: Transport::Nfc,
+                    state: AuthenticatorState::NeedsPin {
+                        attempts_left: Some(3)
+                    },
+                },
+            } if device_id == "2"
+        ));
+    }
+
+    #[test]
+    fn test_round_trip_background_hybrid_linked_event() {
+        let event = BackgroundEvent::DeviceStateChanged {
+            device_id: "1-04ab".to_string(),
+            state: DeviceState {
+                transport: Transport::HybridLinked,
+                state: AuthenticatorState::Connecting,
+            },
+        };
+        let ctx = zvariant::serialized::Context::new_dbus(zvariant::BE, 0);
+        let data = zvariant::to_bytes(ctx, &event).unwrap();
+        let bytes = data.bytes();
+        let data2 = Data::new(bytes, Context::new(Format::DBus, zvariant::BE, 0));
+        let event_2: BackgroundEvent = data2.deserialize().unwrap().0;
+        assert!(matches!(
+            event_2,
+            BackgroundEvent::DeviceStateChanged {
+                ref device_id,
+                state: DeviceState {
+                    transport: Transport::HybridLinked,
+                    state: AuthenticatorState::Connecting,
+                },
+            } if device_id == "1-04ab"
+        ));
+    }
+
+    #[test]
+    fn test_round_trip_background_passkey_provider_event() {
+        let event = BackgroundEvent::DeviceStateChanged {
+            device_id: "3-org.keepassxc.KeePassXC".to_string(),
+            state: DeviceState {
+                transport: Transport::PasskeyProvider,
+                state: AuthenticatorState::Connected,
+            },
+        };
         let ctx = zvariant::serialized::Context::new_dbus(zvariant::BE, 0);
         let data = zvariant::to_bytes(ctx, &event).unwrap();
         let bytes = data.bytes();
@@ -585,10 +807,48 @@ mod test {
         let event_2: BackgroundEvent = data2.deserialize().unwrap().0;
         assert!(matches!(
             event_2,
-            BackgroundEvent::HybridQrStateChanged(HybridState::Started(ref f)) if f == "FIDO:/1234"
+            BackgroundEvent::DeviceStateChanged {
+                ref device_id,
+                state: DeviceState {
+                    transport: Transport::PasskeyProvider,
+                    state: AuthenticatorState::Connected,
+                },
+            } if device_id == "3-org.keepassxc.KeePassXC"
         ));
     }
 
+    #[test]
+    fn test_round_trip_device_name() {
+        let device = crate::model::Device {
+            id: "1-04ab".to_string(),
+            transport: crate::model::Transport::HybridLinked,
+            name: Some("Pixel 9".to_string()),
+            manufacturer: None,
+            product: None,
+        };
+        let ctx = zvariant::serialized::Context::new_dbus(zvariant::BE, 0);
+        let data = zvariant::to_bytes(ctx, &super::Device::from(device.clone())).unwrap();
+        let data2 = Data::new(data.bytes(), Context::new(Format::DBus, zvariant::BE, 0));
+        let device_2: super::Device = data2.deserialize().unwrap().0;
+        assert_eq!(Ok(device), device_2.try_into());
+    }
+
+    #[test]
+    fn test_round_trip_usb_device_names() {
+        let device = crate::model::Device {
+            id: "1".to_string(),
+            transport: crate::model::Transport::Usb,
+            name: None,
+            manufacturer: Some("Yubico".to_string()),
+            product: Some("YubiKey OTP+FIDO+CCID".to_string()),
+        };
+        let ctx = zvariant::serialized::Context::new_dbus(zvariant::BE, 0);
+        let data = zvariant::to_bytes(ctx, &super::Device::from(device.clone())).unwrap();
+        let data2 = Data::new(data.bytes(), Context::new(Format::DBus, zvariant::BE, 0));
+        let device_2: super::Device = data2.deserialize().unwrap().0;
+        assert_eq!(Ok(device), device_2.try_into());
+    }
+
     #[test]
     fn test_serialize_usb_state() {
         let creds = vec![
@@ -603,14 +863,14 @@ mod test {
                 username: None,
             },
         ];
-        let state = UsbState::SelectCredential { creds };
+        let state = AuthenticatorState::SelectCredential { creds };
         let ctx = zvariant::serialized::Context::new_dbus(zvariant::BE, 0);
         let data = zvariant::to_bytes(ctx, &state).unwrap();
-        assert_eq!("(yv)", UsbState::SIGNATURE.to_string());
+        assert_eq!("(yv)", AuthenticatorState::SIGNATURE.to_string());
 
         #[rustfmt::skip]
         let expected = [
-            8, // UsbState::SelectCredential
+            8, // AuthenticatorState::SelectCredential
             6, 97, 97, 123, 115, 118, 125, 0, 0, 0, 0, // Signature aa{sv} + padding
             0, 0, 0, 165, // array(struct) data length
                 0, 0, 0, 83, 0, 0, 0, 0, // element 1(struct) length, + padding(4)
@@ -642,7 +902,7 @@ mod test {
     fn test_deserialize_usb_state() {
         #[rustfmt::skip]
         let input = [
-            8, // UsbState::SelectCredential
+            8, // AuthenticatorState::SelectCredential
             6, 97, 97, 123, 115, 118, 125, 0, 0, 0, 0, // Signature aa{sv} + padding
             0, 0, 0, 165, // array(struct) data length
                 0, 0, 0, 83, 0, 0, 0, 0, // element 1(struct) length, + padding(4)
@@ -669,9 +929,9 @@ mod test {
         ];
         let ctx = Context::new(Format::DBus, zvariant::BE, 0);
         let data = Data::new(&input, ctx);
-        let state: UsbState = data.deserialize().unwrap().0;
+        let state: AuthenticatorState = data.deserialize().unwrap().0;
         match state {
-            UsbState::SelectCredential { creds } => {
+            AuthenticatorState::SelectCredential { creds } => {
                 assert_eq!(2, creds.len());
                 assert_eq!("a1b2c3", creds[0].id,);
                 assert_eq!("user 1", creds[0].name,);
@@ -684,24 +944,183 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_round_trip_platform_user_verification_states() {
+        for state in [
+            AuthenticatorState::NeedsFingerprint {
+                attempts_left: Some(3),
+                password_fallback: true,
+            },
+            AuthenticatorState::NeedsPassword {
+                attempts_left: None,
+            },
+        ] {
+            let expected = format!("{state:?}");
+            let event = BackgroundEvent::DeviceStateChanged {
+                device_id: "platform".to_string(),
+                state: DeviceState {
+                    transport: Transport::Internal,
+                    state,
+                },
+            };
+            let ctx = zvariant::serialized::Context::new_dbus(zvariant::BE, 0);
+            let data = zvariant::to_bytes(ctx, &event).unwrap();
+            let data2 = Data::new(data.bytes(), Context::new(Format::DBus, zvariant::BE, 0));
+            let event_2: BackgroundEvent = data2.deserialize().unwrap().0;
+            let BackgroundEvent::DeviceStateChanged {
+                state:
+                    DeviceState {
+                        transport: Transport::Internal,
+                        state: state_2,
+                    },
+                ..
+            } = event_2
+            else {
+                panic!("unexpected event: {event_2:?}");
+            };
+            assert_eq!(expected, format!("{state_2:?}"));
+        }
+    }
+
+    #[test]
+    fn test_round_trip_new_pin_state() {
+        let event = BackgroundEvent::DeviceStateChanged {
+            device_id: "usb".to_string(),
+            state: DeviceState {
+                transport: Transport::Usb,
+                state: AuthenticatorState::NeedsNewPin {
+                    min_length: 6,
+                    must_change: true,
+                },
+            },
+        };
+        let ctx = zvariant::serialized::Context::new_dbus(zvariant::BE, 0);
+        let data = zvariant::to_bytes(ctx, &event).unwrap();
+        let data2 = Data::new(data.bytes(), Context::new(Format::DBus, zvariant::BE, 0));
+        let event_2: BackgroundEvent = data2.deserialize().unwrap().0;
+        assert!(matches!(
+            event_2,
+            BackgroundEvent::DeviceStateChanged {
+                state: DeviceState {
+                    transport: Transport::Usb,
+                    state: AuthenticatorState::NeedsNewPin {
+                        min_length: 6,
+                        must_change: true
+                    },
+                },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_round_trip_fingerprint_sample_states() {
+        for state in [
+            AuthenticatorState::NeedsFingerprintSample {
+                remaining_samples: Some(4),
+                last_sample: None,
+            },
+            AuthenticatorState::NeedsFingerprintSample {
+                remaining_samples: None,
+                last_sample: Some(FingerprintSampleStatus::NoUserPresenceTransition),
+            },
+        ] {
+            let expected = format!("{state:?}");
+            let event = BackgroundEvent::DeviceStateChanged {
+                device_id: "usb".to_string(),
+                state: DeviceState {
+                    transport: Transport::Usb,
+                    state,
+                },
+            };
+            let ctx = zvariant::serialized::Context::new_dbus(zvariant::BE, 0);
+            let data = zvariant::to_bytes(ctx, &event).unwrap();
+            let data2 = Data::new(data.bytes(), Context::new(Format::DBus, zvariant::BE, 0));
+            let event_2: BackgroundEvent = data2.deserialize().unwrap().0;
+            let BackgroundEvent::DeviceStateChanged {
+                state:
+                    DeviceState {
+                        transport: Transport::Usb,
+                        state: state_2,
+                    },
+                ..
+            } = event_2
+            else {
+                panic!("unexpected event: {event_2:?}");
+            };
+            assert_eq!(expected, format!("{state_2:?}"));
+        }
+    }
+
+    #[test]
+    fn test_round_trip_confirmation_states() {
+        for state in [
+            AuthenticatorState::NeedsConfirmation(SecurityKeyChange::SetAlwaysUv(true)),
+            AuthenticatorState::NeedsConfirmation(SecurityKeyChange::SetMinPinLength {
+                min_length: 8,
+                rp_ids: vec!["example.com".to_string()],
+            }),
+            AuthenticatorState::NeedsConfirmation(SecurityKeyChange::Reset),
+            AuthenticatorState::NeedsReplug,
+        ] {
+            let expected = format!("{state:?}");
+            let event = BackgroundEvent::DeviceStateChanged {
+                device_id: "usb".to_string(),
+                state: DeviceState {
+                    transport: Transport::Usb,
+                    state,
+                },
+            };
+            let ctx = zvariant::serialized::Context::new_dbus(zvariant::BE, 0);
+            let data = zvariant::to_bytes(ctx, &event).unwrap();
+            let data2 = Data::new(data.bytes(), Context::new(Format::DBus, zvariant::BE, 0));
+            let event_2: BackgroundEvent = data2.deserialize().unwrap().0;
+            let BackgroundEvent::DeviceStateChanged {
+                state:
+                    DeviceState {
+                        transport: Transport::Usb,
+                        state: state_2,
+                    },
+                ..
+            } = event_2
+            else {
+                panic!("unexpected event: {event_2:?}");
+            };
+            assert_eq!(expected, format!("{state_2:?}"));
+        }
+    }
+
     #[test]
     fn test_serialize_background_usb_event() {
-        let state = UsbState::NeedsPin {
+        let state = AuthenticatorState::NeedsPin {
             attempts_left: Some(254),
         };
-        let event = BackgroundEvent::UsbStateChanged(state);
+        let event = BackgroundEvent::DeviceStateChanged {
+            device_id: "0".to_string(),
+            state: DeviceState {
+                transport: Transport::Usb,
+                state,
+            },
+        };
         let ctx = zvariant::serialized::Context::new_dbus(zvariant::BE, 0);
         assert_eq!("(yv)", BackgroundEvent::SIGNATURE.to_string());
         let data = zvariant::to_bytes(ctx, &event).unwrap();
-        let expected = b"\x01\x04(yv)\0\0\x05\x01i\0\0\0\0\xfe";
+        let expected =
+            b"\x04\x0a(s(s(yv)))\0\0\0\0\0\0\0\x010\0\0\0\0\0\0\x03USB\0\x05\x01i\0\0\0\0\xfe";
         assert_eq!(expected, data.bytes());
     }
 
     #[test]
     fn test_round_trip_background_usb_event() {
-        let event = BackgroundEvent::UsbStateChanged(UsbState::NeedsUserVerification {
-            attempts_left: None,
-        });
+        let event = BackgroundEvent::DeviceStateChanged {
+            device_id: "0".to_string(),
+            state: DeviceState {
+                transport: Transport::Usb,
+                state: AuthenticatorState::NeedsUserVerification {
+                    attempts_left: None,
+                },
+            },
+        };
         let ctx = zvariant::serialized::Context::new_dbus(zvariant::BE, 0);
         let data = zvariant::to_bytes(ctx, &event).unwrap();
         let bytes = data.bytes();
@@ -709,7 +1128,13 @@ mod test {
         let event_2: BackgroundEvent = data2.deserialize().unwrap().0;
         assert!(matches!(
             event_2,
-            BackgroundEvent::UsbStateChanged(UsbState::NeedsUserVerification{ ref attempts_left }) if attempts_left.is_none()
+            BackgroundEvent::DeviceStateChanged {
+                state: DeviceState {
+                    transport: Transport::Usb,
+                    state: AuthenticatorState::NeedsUserVerification{ ref attempts_left },
+                },
+                ..
+            } if attempts_left.is_none()
         ));
     }
 
@@ -0,0 +1,739 @@
+//! Typed models of the WebAuthn request option dictionaries.
+//!
+//! These correspond to the JSON forms defined in WebAuthn Level 3, i.e. the
+//! input to `PublicKeyCredential.parseCreationOptionsFromJSON()` and
+//! `PublicKeyCredential.parseRequestOptionsFromJSON()`, and are what clients
+//! send in `request_json`. Parsing follows the same rules as those methods:
+//! missing required members are an error (which the service reports as
+//! `TypeError`), while enum values that aren't recognized are ignored as if the
+//! member was not present.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use libwebauthn::{
+    ops::webauthn::{
+        CredentialProtectionPolicy as Ctap2CredentialProtectionPolicy,
+        MakeCredentialLargeBlobExtension, ResidentKeyRequirement as Ctap2ResidentKeyRequirement,
+        UserVerificationRequirement as Ctap2UserVerificationRequirement,
+    },
+    proto::ctap2::Ctap2Transport,
+};
+
+/// https://www.w3.org/TR/webauthn-3/#dictdef-publickeycredentialcreationoptionsjson
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicKeyCredentialCreationOptionsJSON {
+    pub rp: PublicKeyCredentialRpEntity,
+
+    pub user: PublicKeyCredentialUserEntityJSON,
+
+    #[serde(with = "base64url")]
+    pub challenge: Vec<u8>,
+
+    /// Requested key types and algorithms, in descending order of RP
+    /// preference. If empty, the client picks a default set.
+    pub pub_key_cred_params: Vec<PublicKeyCredentialParameters>,
+
+    /// Timeout in milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_credentials: Vec<PublicKeyCredentialDescriptorJSON>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authenticator_selection: Option<AuthenticatorSelectionCriteria>,
+
+    /// Contextual information from the RP to help the client guide the user
+    /// through the ceremony, in descending order of RP preference.
+    #[serde(
+        default,
+        deserialize_with = "known::seq",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub hints: Vec<PublicKeyCredentialHint>,
+
+    /// Defaults to `none`.
+    #[serde(
+        default,
+        deserialize_with = "known::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub attestation: Option<AttestationConveyancePreference>,
+
+    /// Attestation statement format identifiers, in descending order of RP
+    /// preference. These are registered in the IANA "WebAuthn Attestation
+    /// Statement Format Identifiers" registry, so unknown values are kept.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attestation_formats: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<AuthenticationExtensionsClientInputsJSON>,
+}
+
+/// https://www.w3.org/TR/webauthn-3/#dictdef-publickeycredentialrequestoptionsjson
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicKeyCredentialRequestOptionsJSON {
+    #[serde(with = "base64url")]
+    pub challenge: Vec<u8>,
+
+    /// Timeout in milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u32>,
+
+    /// Relying Party ID.
+    /// If not set, the request origin's effective domain will be used instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rp_id: Option<String>,
+
+    /// A list of allowed credentials, in descending order of RP preference.
+    /// If empty, then any credential that can fulfill the request is allowed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow_credentials: Vec<PublicKeyCredentialDescriptorJSON>,
+
+    /// Defaults to `preferred`.
+    #[serde(
+        default,
+        deserialize_with = "known::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub user_verification: Option<UserVerificationRequirement>,
+
+    /// Contextual information from the RP to help the client guide the user
+    /// through the ceremony, in descending order of RP preference.
+    #[serde(
+        default,
+        deserialize_with = "known::seq",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub hints: Vec<PublicKeyCredentialHint>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<AuthenticationExtensionsClientInputsJSON>,
+}
+
+/// https://www.w3.org/TR/webauthn-3/#dictdef-publickeycredentialrpentity
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PublicKeyCredentialRpEntity {
+    /// Relying Party ID.
+    /// If not set, the request origin's effective domain will be used instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+
+    pub name: String,
+}
+
+/// https://www.w3.org/TR/webauthn-3/#dictdef-publickeycredentialuserentityjson
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicKeyCredentialUserEntityJSON {
+    /// User handle.
+    #[serde(with = "base64url")]
+    pub id: Vec<u8>,
+
+    pub name: String,
+
+    pub display_name: String,
+}
+
+/// https://www.w3.org/TR/webauthn-3/#dictdef-publickeycredentialparameters
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PublicKeyCredentialParameters {
+    /// `None` if the RP specified a credential type we don't know about.
+    /// Clients must ignore such entries.
+    #[serde(rename = "type", deserialize_with = "known::option")]
+    pub cred_type: Option<PublicKeyCredentialType>,
+
+    /// COSE algorithm identifier.
+    pub alg: i64,
+}
+
+/// https://www.w3.org/TR/webauthn-3/#dictdef-publickeycredentialdescriptorjson
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PublicKeyCredentialDescriptorJSON {
+    /// `None` if the RP specified a credential type we don't know about.
+    /// Clients must ignore such entries.
+    #[serde(rename = "type", deserialize_with = "known::option")]
+    pub cred_type: Option<PublicKeyCredentialType>,
+
+    /// Credential ID.
+    #[serde(with = "base64url")]
+    pub id: Vec<u8>,
+
+    /// Transports the RP believes the authenticator supports. This is only a
+    /// hint, so unrecognized transports are dropped.
+    #[serde(
+        default,
+        deserialize_with = "known::seq",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub transports: Vec<AuthenticatorTransport>,
+}
+
+/// https://www.w3.org/TR/webauthn-3/#dictdef-authenticatorselectioncriteria
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelectionCriteria {
+    #[serde(
+        default,
+        deserialize_with = "known::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub authenticator_attachment: Option<AuthenticatorAttachment>,
+
+    #[serde(
+        default,
+        deserialize_with = "known::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub resident_key: Option<ResidentKeyRequirement>,
+
+    /// Deprecated WebAuthn Level 1 member, only consulted if `residentKey` is
+    /// not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub require_resident_key: Option<bool>,
+
+    /// Defaults to `preferred`.
+    #[serde(
+        default,
+        deserialize_with = "known::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub user_verification: Option<UserVerificationRequirement>,
+}
+
+impl AuthenticatorSelectionCriteria {
+    /// The effective resident key requirement, taking the legacy
+    /// `requireResidentKey` member into account.
+    pub fn resident_key_requirement(&self) -> Option<ResidentKeyRequirement> {
+        match (self.resident_key, self.require_resident_key) {
+            (Some(resident_key), _) => Some(resident_key),
+            (None, Some(true)) => Some(ResidentKeyRequirement::Required),
+            (None, _) => None,
+        }
+    }
+}
+
+/// Client extension inputs for both registration and authentication.
+///
+/// https://www.w3.org/TR/webauthn-3/#dictdef-authenticationextensionsclientinputsjson
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticationExtensionsClientInputsJSON {
+    /// FIDO AppID extension (authentication only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub appid: Option<String>,
+
+    /// FIDO AppID exclusion extension (registration only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub appid_exclude: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cred_props: Option<bool>,
+
+    #[serde(
+        default,
+        deserialize_with = "known::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub credential_protection_policy: Option<CredentialProtectionPolicy>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enforce_credential_protection_policy: Option<bool>,
+
+    #[serde(
+        default,
+        with = "base64url::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub cred_blob: Option<Vec<u8>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub get_cred_blob: Option<bool>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub large_blob: Option<AuthenticationExtensionsLargeBlobInputsJSON>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_pin_length: Option<bool>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prf: Option<AuthenticationExtensionsPRFInputsJSON>,
+}
+
+/// https://www.w3.org/TR/webauthn-3/#dictdef-authenticationextensionslargeblobinputsjson
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AuthenticationExtensionsLargeBlobInputsJSON {
+    /// Registration only.
+    #[serde(
+        default,
+        deserialize_with = "known::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub support: Option<LargeBlobSupport>,
+
+    /// Authentication only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read: Option<bool>,
+
+    /// Authentication only.
+    #[serde(
+        default,
+        with = "base64url::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub write: Option<Vec<u8>>,
+}
+
+/// https://www.w3.org/TR/webauthn-3/#dictdef-authenticationextensionsprfinputsjson
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticationExtensionsPRFInputsJSON {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eval: Option<AuthenticationExtensionsPRFValuesJSON>,
+
+    /// PRF inputs keyed by base64url-encoded credential ID. Authentication only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eval_by_credential: Option<HashMap<String, AuthenticationExtensionsPRFValuesJSON>>,
+}
+
+/// https://www.w3.org/TR/webauthn-3/#dictdef-authenticationextensionsprfvaluesjson
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AuthenticationExtensionsPRFValuesJSON {
+    #[serde(with = "base64url")]
+    pub first: Vec<u8>,
+
+    #[serde(
+        default,
+        with = "base64url::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub second: Option<Vec<u8>>,
+}
+
+/// https://www.w3.org/TR/webauthn-3/#enum-credentialType
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PublicKeyCredentialType {
+    #[serde(rename = "public-key")]
+    PublicKey,
+}
+
+/// https://www.w3.org/TR/webauthn-3/#enum-attachment
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthenticatorAttachment {
+    Platform,
+    CrossPlatform,
+}
+
+/// https://www.w3.org/TR/webauthn-3/#enum-residentKeyRequirement
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResidentKeyRequirement {
+    Discouraged,
+    Preferred,
+    Required,
+}
+
+impl From<ResidentKeyRequirement> for Ctap2ResidentKeyRequirement {
+    fn from(value: ResidentKeyRequirement) -> Self {
+        match value {
+            ResidentKeyRequirement::Discouraged => Self::Discouraged,
+            ResidentKeyRequirement::Preferred => Self::Preferred,
+            ResidentKeyRequirement::Required => Self::Required,
+        }
+    }
+}
+
+/// https://www.w3.org/TR/webauthn-3/#enumdef-userverificationrequirement
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserVerificationRequirement {
+    Required,
+    #[default]
+    Preferred,
+    Discouraged,
+}
+
+impl From<UserVerificationRequirement> for Ctap2UserVerificationRequirement {
+    fn from(value: UserVerificationRequirement) -> Self {
+        match value {
+            UserVerificationRequirement::Required => Self::Required,
+            UserVerificationRequirement::Preferred => Self::Preferred,
+            UserVerificationRequirement::Discouraged => Self::Discouraged,
+        }
+    }
+}
+
+/// https://www.w3.org/TR/webauthn-3/#enum-attestation-convey
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttestationConveyancePreference {
+    #[default]
+    None,
+    Indirect,
+    Direct,
+    Enterprise,
+}
+
+/// https://www.w3.org/TR/webauthn-3/#enum-hints
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PublicKeyCredentialHint {
+    SecurityKey,
+    ClientDevice,
+    Hybrid,
+}
+
+/// https://www.w3.org/TR/webauthn-3/#enum-transport
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthenticatorTransport {
+    Usb,
+    Nfc,
+    Ble,
+    SmartCard,
+    Hybrid,
+    Internal,
+}
+
+impl TryFrom<AuthenticatorTransport> for Ctap2Transport {
+    type Error = ();
+
+    fn try_from(value: AuthenticatorTransport) -> Result<Self, Self::Error> {
+        match value {
+            AuthenticatorTransport::Usb => Ok(Self::Usb),
+            AuthenticatorTransport::Nfc => Ok(Self::Nfc),
+            AuthenticatorTransport::Ble => Ok(Self::Ble),
+            AuthenticatorTransport::Hybrid => Ok(Self::Hybrid),
+            AuthenticatorTransport::Internal => Ok(Self::Internal),
+            AuthenticatorTransport::SmartCard => Err(()),
+        }
+    }
+}
+
+/// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#sctn-credProtect-extension
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CredentialProtectionPolicy {
+    UserVerificationOptional,
+    #[serde(rename = "userVerificationOptionalWithCredentialIDList")]
+    UserVerificationOptionalWithCredentialIdList,
+    UserVerificationRequired,
+}
+
+impl From<CredentialProtectionPolicy> for Ctap2CredentialProtectionPolicy {
+    fn from(value: CredentialProtectionPolicy) -> Self {
+        match value {
+            CredentialProtectionPolicy::UserVerificationOptional => Self::UserVerificationOptional,
+            CredentialProtectionPolicy::UserVerificationOptionalWithCredentialIdList => {
+                Self::UserVerificationOptionalWithCredentialIDList
+            }
+            CredentialProtectionPolicy::UserVerificationRequired => Self::UserVerificationRequired,
+        }
+    }
+}
+
+/// https://www.w3.org/TR/webauthn-3/#enumdef-largeblobsupport
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LargeBlobSupport {
+    Required,
+    Preferred,
+}
+
+impl From<LargeBlobSupport> for MakeCredentialLargeBlobExtension {
+    fn from(value: LargeBlobSupport) -> Self {
+        match value {
+            LargeBlobSupport::Required => Self::Required,
+            LargeBlobSupport::Preferred => Self::Preferred,
+        }
+    }
+}
+
+/// Helpers for DOMString members that "SHOULD be a member of" an enum: values
+/// we don't recognize are dropped instead of failing the whole request.
+mod known {
+    use serde::{
+        Deserialize, Deserializer,
+        de::{DeserializeOwned, IntoDeserializer, value},
+    };
+
+    pub(super) fn option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: DeserializeOwned,
+    {
+        let value = Option::<String>::deserialize(deserializer)?;
+        Ok(value.and_then(parse))
+    }
+
+    pub(super) fn seq<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: DeserializeOwned,
+    {
+        let values = Vec::<String>::deserialize(deserializer)?;
+        Ok(values.into_iter().filter_map(parse).collect())
+    }
+
+    fn parse<T: DeserializeOwned>(value: String) -> Option<T> {
+        let deserializer: value::StringDeserializer<value::Error> = value.into_deserializer();
+        T::deserialize(deserializer).ok()
+    }
+}
+
+/// Base64url (de)serialization for `Base64URLString` members.
+///
+/// Output is unpadded, but padded input is accepted, since some RPs send it.
+mod base64url {
+    use base64::{
+        Engine as _,
+        alphabet::URL_SAFE,
+        engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    };
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    const ENGINE: GeneralPurpose = GeneralPurpose::new(
+        &URL_SAFE,
+        GeneralPurposeConfig::new()
+            .with_encode_padding(false)
+            .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+    );
+
+    pub(super) fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&ENGINE.encode(bytes))
+    }
+
+    pub(super) fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        ENGINE.decode(s).map_err(de::Error::custom)
+    }
+
+    pub(super) mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub(crate) fn serialize<S>(
+            bytes: &Option<Vec<u8>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            match bytes {
+                Some(bytes) => super::serialize(bytes, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            #[derive(Deserialize)]
+            struct Wrapper(#[serde(deserialize_with = "super::deserialize")] Vec<u8>);
+
+            let value = Option::<Wrapper>::deserialize(deserializer)?;
+            Ok(value.map(|Wrapper(bytes)| bytes))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        AttestationConveyancePreference, AuthenticatorAttachment, AuthenticatorTransport,
+        PublicKeyCredentialCreationOptionsJSON, PublicKeyCredentialHint,
+        PublicKeyCredentialRequestOptionsJSON, PublicKeyCredentialType, ResidentKeyRequirement,
+        UserVerificationRequirement,
+    };
+
+    const CREATE_JSON: &str = r#"{
+        "rp": { "id": "example.com", "name": "Example" },
+        "user": { "id": "dXNlcg", "name": "alice", "displayName": "Alice" },
+        "challenge": "YSBjaGFsbGVuZ2U",
+        "pubKeyCredParams": [
+            { "type": "public-key", "alg": -7 },
+            { "type": "future-key", "alg": -8 },
+            { "type": "public-key", "alg": -257 }
+        ],
+        "timeout": 60000,
+        "excludeCredentials": [
+            { "type": "public-key", "id": "AQID", "transports": ["usb", "carrier-pigeon", "nfc"] }
+        ],
+        "authenticatorSelection": {
+            "authenticatorAttachment": "cross-platform",
+            "residentKey": "required",
+            "userVerification": "required"
+        },
+        "hints": ["security-key", "telepathy", "hybrid"],
+        "attestation": "direct",
+        "attestationFormats": ["packed", "tpm"],
+        "extensions": { "credProps": true, "somethingNew": {} }
+    }"#;
+
+    #[test]
+    fn test_parse_creation_options() {
+        let options: PublicKeyCredentialCreationOptionsJSON =
+            serde_json::from_str(CREATE_JSON).unwrap();
+        assert_eq!(Some("example.com"), options.rp.id.as_deref());
+        assert_eq!(b"user", options.user.id.as_slice());
+        assert_eq!(b"a challenge", options.challenge.as_slice());
+        assert_eq!(3, options.pub_key_cred_params.len());
+        assert_eq!(None, options.pub_key_cred_params[1].cred_type);
+        assert_eq!(
+            vec![AuthenticatorTransport::Usb, AuthenticatorTransport::Nfc],
+            options.exclude_credentials[0].transports
+        );
+        let selection = options.authenticator_selection.unwrap();
+        assert_eq!(
+            Some(AuthenticatorAttachment::CrossPlatform),
+            selection.authenticator_attachment
+        );
+        assert_eq!(
+            Some(ResidentKeyRequirement::Required),
+            selection.resident_key_requirement()
+        );
+        assert_eq!(
+            vec![
+                PublicKeyCredentialHint::SecurityKey,
+                PublicKeyCredentialHint::Hybrid
+            ],
+            options.hints
+        );
+        assert_eq!(
+            Some(AttestationConveyancePreference::Direct),
+            options.attestation
+        );
+        assert_eq!(vec!["packed", "tpm"], options.attestation_formats);
+        assert_eq!(Some(true), options.extensions.unwrap().cred_props);
+    }
+
+    #[test]
+    fn test_unknown_enum_values_are_ignored() {
+        let json = r#"{
+            "rp": { "name": "Example" },
+            "user": { "id": "dXNlcg", "name": "alice", "displayName": "Alice" },
+            "challenge": "YSBjaGFsbGVuZ2U",
+            "pubKeyCredParams": [],
+            "authenticatorSelection": {
+                "authenticatorAttachment": "implant",
+                "residentKey": "mandatory",
+                "requireResidentKey": true,
+                "userVerification": "retina"
+            },
+            "attestation": "notarized"
+        }"#;
+        let options: PublicKeyCredentialCreationOptionsJSON = serde_json::from_str(json).unwrap();
+        let selection = options.authenticator_selection.unwrap();
+        assert_eq!(None, selection.authenticator_attachment);
+        assert_eq!(None, selection.resident_key);
+        // falls back to the legacy member
+        assert_eq!(
+            Some(ResidentKeyRequirement::Required),
+            selection.resident_key_requirement()
+        );
+        assert_eq!(None, selection.user_verification);
+        assert_eq!(None, options.attestation);
+    }
+
+    #[test]
+    fn test_missing_required_members_are_rejected() {
+        let mut json: serde_json::Value = serde_json::from_str(CREATE_JSON).unwrap();
+        for path in [
+            "/rp/name",
+            "/user/id",
+            "/user/displayName",
+            "/challenge",
+            "/pubKeyCredParams",
+            "/pubKeyCredParams/0/type",
+            "/excludeCredentials/0/id",
+        ] {
+            let mut value = json.clone();
+            let (parent, key) = path.rsplit_once('/').unwrap();
+            let parent = value.pointer_mut(parent).unwrap();
+            match parent {
+                serde_json::Value::Object(map) => map.remove(key),
+                _ => panic!("{path} does not point to an object member"),
+            };
+            assert!(
+                serde_json::from_value::<PublicKeyCredentialCreationOptionsJSON>(value).is_err(),
+                "request without {path} should be rejected"
+            );
+        }
+        // Sanity check that the unmodified request is accepted.
+        json["unknownMember"] = serde_json::Value::Bool(true);
+        assert!(serde_json::from_value::<PublicKeyCredentialCreationOptionsJSON>(json).is_ok());
+    }
+
+    #[test]
+    fn test_parse_request_options() {
+        let json = r#"{
+            "challenge": "YSBjaGFsbGVuZ2U=",
+            "rpId": "example.com",
+            "allowCredentials": [
+                { "type": "public-key", "id": "AQID" },
+                { "type": "other-key", "id": "BAUG" }
+            ],
+            "userVerification": "discouraged",
+            "extensions": {
+                "prf": {
+                    "eval": { "first": "AQID" },
+                    "evalByCredential": { "AQID": { "first": "AQID", "second": "BAUG" } }
+                },
+                "largeBlob": { "read": true }
+            }
+        }"#;
+        let options: PublicKeyCredentialRequestOptionsJSON = serde_json::from_str(json).unwrap();
+        // padded base64url is tolerated
+        assert_eq!(b"a challenge", options.challenge.as_slice());
+        assert_eq!(
+            Some(PublicKeyCredentialType::PublicKey),
+            options.allow_credentials[0].cred_type
+        );
+        assert_eq!(None, options.allow_credentials[1].cred_type);
+        assert_eq!(
+            Some(UserVerificationRequirement::Discouraged),
+            options.user_verification
+        );
+        let extensions = options.extensions.unwrap();
+        let prf = extensions.prf.unwrap();
+        assert_eq!(vec![1, 2, 3], prf.eval.unwrap().first);
+        assert_eq!(
+            Some(vec![4, 5, 6]),
+            prf.eval_by_credential.unwrap()["AQID"].second
+        );
+        assert_eq!(Some(true), extensions.large_blob.unwrap().read);
+    }
+
+    #[test]
+    fn test_request_options_require_challenge() {
+        let json = r#"{ "rpId": "example.com" }"#;
+        assert!(serde_json::from_str::<PublicKeyCredentialRequestOptionsJSON>(json).is_err());
+    }
+
+    #[test]
+    fn test_round_trip_creation_options() {
+        let options: PublicKeyCredentialCreationOptionsJSON =
+            serde_json::from_str(CREATE_JSON).unwrap();
+        let serialized = serde_json::to_string(&options).unwrap();
+        let reparsed: PublicKeyCredentialCreationOptionsJSON =
+            serde_json::from_str(&serialized).unwrap();
+        assert_eq!(options.challenge, reparsed.challenge);
+        assert_eq!(options.user.id, reparsed.user.id);
+        assert_eq!(options.hints, reparsed.hints);
+        assert_eq!(
+            options.exclude_credentials[0].transports,
+            reparsed.exclude_credentials[0].transports
+        );
+    }
+}
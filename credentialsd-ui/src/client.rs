@@ -35,22 +35,20 @@ impl FlowController for DbusCredentialClient {
         dbus_devices.into_iter().map(|d| d.try_into()).collect()
     }
 
-    async fn get_hybrid_credential(&mut self) -> std::result::Result<(), ()> {
+    async fn select_device(&mut self, device_id: String) -> std::result::Result<(), ()> {
         self.proxy()
             .await?
-            .get_hybrid_credential()
+            .select_device(device_id)
             .await
-            .inspect_err(|err| tracing::error!("Failed to start hybrid credential flow: {err}"))
-            .map_err(|_| ())
+            .map_err(|err| tracing::error!("Failed to start device: {err}"))
     }
 
-    async fn get_usb_credential(&mut self) -> std::result::Result<(), ()> {
+    async fn select_devices(&mut self, device_ids: Vec<String>) -> std::result::Result<(), ()> {
         self.proxy()
             .await?
-            .get_usb_credential()
+            .select_devices(device_ids)
             .await
-            .inspect_err(|err| tracing::error!("Failed to start USB credential flow: {err}"))
-            .map_err(|_| ())
+            .map_err(|err| tracing::error!("Failed to start devices: {err}"))
     }
 
     async fn subscribe(
@@ -82,22 +80,78 @@ impl FlowController for DbusCredentialClient {
             .map(|_| stream)
     }
 
-    async fn enter_client_pin(&mut self, pin: String) -> std::result::Result<(), ()> {
+    async fn enter_client_pin(
+        &mut self,
+        device_id: String,
+        pin: String,
+    ) -> std::result::Result<(), ()> {
         self.proxy()
             .await?
-            .enter_client_pin(pin)
+            .enter_client_pin(device_id, pin)
             .await
             .map_err(|err| tracing::error!("Failed to send PIN to authenticator: {err}"))
     }
 
-    async fn select_credential(&self, credential_id: String) -> std::result::Result<(), ()> {
+    async fn enter_password(&mut self, password: String) -> std::result::Result<(), ()> {
+        self.proxy()
+            .await?
+            .enter_password(password)
+            .await
+            .map_err(|err| tracing::error!("Failed to send password to authenticator: {err}"))
+    }
+
+    async fn select_credential(
+        &self,
+        device_id: String,
+        credential_id: String,
+    ) -> std::result::Result<(), ()> {
         self.proxy()
             .await?
-            .select_credential(credential_id)
+            .select_credential(device_id, credential_id)
             .await
             .map_err(|err| tracing::error!("Failed to select credential: {err}"))
     }
 
+    async fn rename_device(&self, device_id: String, name: String) -> std::result::Result<(), ()> {
+        self.proxy()
+            .await?
+            .rename_device(device_id, name)
+            .await
+            .map_err(|err| tracing::error!("Failed to rename device: {err}"))
+    }
+
+    async fn forget_device(&self, device_id: String) -> std::result::Result<(), ()> {
+        self.proxy()
+            .await?
+            .forget_device(device_id)
+            .await
+            .map_err(|err| tracing::error!("Failed to forget device: {err}"))
+    }
+
+    async fn get_transfer_summary(&self) -> std::result::Result<Vec<String>, ()> {
+        self.proxy()
+            .await?
+            .get_transfer_summary()
+            .await
+            .map_err(|err| tracing::error!("Failed to retrieve transfer summary: {err}"))
+    }
+
+    async fn confirm_transfer(&mut self, passphrase: String) -> std::result::Result<u32, ()> {
+        self.proxy()
+            .await?
+            .confirm_transfer(passphrase)
+            .await
+            .map_err(|err| tracing::error!("Failed to complete transfer: {err}"))
+    }
+
+    async fn confirm_change(&mut self) -> std::result::Result<(), ()> {
+        self.proxy()
+            .await?
+            .confirm_change()
+            .await
+            .map_err(|err| tracing::error!("Failed to confirm change: {err}"))
+    }
+
     async fn cancel_request(&self, request_id: RequestId) -> Result<(), ()> {
         if self
             .proxy()
@@ -16,13 +16,16 @@ pub trait FlowControlService {
 
     async fn get_available_public_key_devices(&self) -> fdo::Result<Vec<Device>>;
 
-    async fn get_hybrid_credential(&self) -> fdo::Result<()>;
-
-    async fn get_usb_credential(&self) -> fdo::Result<()>;
-
     async fn select_device(&self, device_id: String) -> fdo::Result<()>;
-    async fn enter_client_pin(&self, pin: String) -> fdo::Result<()>;
-    async fn select_credential(&self, credential_id: String) -> fdo::Result<()>;
+    async fn select_devices(&self, device_ids: Vec<String>) -> fdo::Result<()>;
+    async fn enter_client_pin(&self, device_id: String, pin: String) -> fdo::Result<()>;
+    async fn enter_password(&self, password: String) -> fdo::Result<()>;
+    async fn select_credential(&self, device_id: String, credential_id: String) -> fdo::Result<()>;
+    async fn rename_device(&self, device_id: String, name: String) -> fdo::Result<()>;
+    async fn forget_device(&self, device_id: String) -> fdo::Result<()>;
+    async fn get_transfer_summary(&self) -> fdo::Result<Vec<String>>;
+    async fn confirm_transfer(&self, passphrase: String) -> fdo::Result<u32>;
+    async fn confirm_change(&self) -> fdo::Result<()>;
     async fn cancel_request(&self, request_id: RequestId) -> fdo::Result<()>;
 
     #[zbus(signal)]
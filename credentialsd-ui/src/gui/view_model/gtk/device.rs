@@ -56,22 +56,25 @@ fn transport_name(transport: &Transport) -> &'static str {
         Transport::Ble => "A Bluetooth device",
         Transport::Internal => "This device",
         Transport::HybridQr => "A mobile device",
-        Transport::HybridLinked => "TODO: Linked Device",
+        Transport::HybridLinked => "A linked mobile device",
         Transport::Nfc => "An NFC device",
         Transport::Usb => "A security key",
-        // Transport::PasskeyProvider => ("symbolic-link-symbolic", "ACME Password Manager"),
+        Transport::PasskeyProvider => "A password manager",
     }
 }
 impl From<crate::gui::view_model::Device> for DeviceObject {
     fn from(value: crate::gui::view_model::Device) -> Self {
-        let name = transport_name(&value.transport);
-        Self::new(&value.id, &value.transport, name)
+        Self::from(&value)
     }
 }
 
 impl From<&crate::gui::view_model::Device> for DeviceObject {
     fn from(value: &crate::gui::view_model::Device) -> Self {
-        let name = transport_name(&value.transport);
+        let name = value
+            .name
+            .as_deref()
+            .or(value.product.as_deref())
+            .unwrap_or_else(|| transport_name(&value.transport));
         Self::new(&value.id, &value.transport, name)
     }
 }
@@ -81,9 +84,19 @@ impl TryFrom<DeviceObject> for crate::gui::view_model::Device {
 
     fn try_from(value: DeviceObject) -> Result<Self, Self::Error> {
         let transport: Transport = value.transport().try_into()?;
+        // Only linked devices and credential providers have names of their
+        // own. The names of security keys are only shown.
+        let name = matches!(
+            transport,
+            Transport::HybridLinked | Transport::PasskeyProvider
+        )
+        .then(|| value.name());
         Ok(Self {
             id: value.id(),
             transport,
+            name,
+            manufacturer: None,
+            product: None,
         })
     }
 }
@@ -18,6 +18,8 @@ use tracing::debug;
 use crate::config::{GETTEXT_PACKAGE, LOCALEDIR, RESOURCES_FILE};
 use application::CredentialsUi;
 
+use super::FingerprintSampleStatus;
+use super::SecurityKeyChange;
 use super::Transport;
 use super::{Credential, Device};
 use super::{ViewEvent, ViewUpdate};
@@ -48,6 +50,34 @@ mod imp {
         #[property(get, set)]
         pub usb_pin_entry_visible: RefCell<bool>,
 
+        /// Whether the PIN has to be entered twice, when a new one is chosen.
+        #[property(get, set)]
+        pub usb_pin_confirm_entry_visible: RefCell<bool>,
+
+        #[property(get, set)]
+        pub password_entry_visible: RefCell<bool>,
+
+        /// Whether the user is asked to confirm a change to the security key.
+        #[property(get, set)]
+        pub confirming_change: RefCell<bool>,
+
+        /// Whether the user is asked for the passphrase of a transfer.
+        #[property(get, set)]
+        pub confirming_transfer: RefCell<bool>,
+
+        /// Whether the archive of the transfer has a passphrase.
+        #[property(get, set)]
+        pub passphrase_entry_visible: RefCell<bool>,
+
+        /// Whether the user is asked to confirm the import of a plain archive.
+        #[property(get, set)]
+        pub confirming_import: RefCell<bool>,
+
+        /// Whether the passphrase has to be entered twice, when it is chosen
+        /// for an export.
+        #[property(get, set)]
+        pub passphrase_confirm_entry_visible: RefCell<bool>,
+
         #[property(get, set)]
         pub prompt: RefCell<String>,
 
@@ -63,7 +93,9 @@ mod imp {
         // pub(super) vm: RefCell<Option<crate::gui::view_model::ViewModel>>,
         pub(super) rx: RefCell<Option<Receiver<ViewUpdate>>>,
         pub(super) tx: RefCell<Option<Sender<ViewEvent>>>,
-        // hybrid_qr_state: HybridState,
+        /// Whether several devices were started together.
+        pub(super) racing: RefCell<bool>,
+        // hybrid_qr_state: AuthenticatorState,
         // hybrid_qr_code_data: Option<Vec<u8>>,
         #[property(get, set)]
         pub qr_code_paintable: RefCell<Option<Texture>>,
@@ -121,6 +153,9 @@ impl ViewModel {
                         Ok(update) => {
                             // TODO: hack so I don't have to unset this in every event manually.
                             view_model.set_usb_pin_entry_visible(false);
+                            view_model.set_usb_pin_confirm_entry_visible(false);
+                            view_model.set_password_entry_visible(false);
+                            view_model.set_confirming_change(false);
                             match update {
                                 ViewUpdate::SetTitle(title) => view_model.set_title(title),
                                 ViewUpdate::SetDevices(devices) => {
@@ -133,6 +168,9 @@ impl ViewModel {
                                 ViewUpdate::WaitingForDevice(device) => {
                                     view_model.waiting_for_device(&device)
                                 }
+                                ViewUpdate::WaitingForDevices(devices) => {
+                                    view_model.waiting_for_devices(&devices)
+                                }
                                 ViewUpdate::UsbNeedsPin { attempts_left } => {
                                     let prompt = match attempts_left {
                                         Some(1) => {
@@ -146,6 +184,98 @@ impl ViewModel {
                                     view_model.set_prompt(prompt);
                                     view_model.set_usb_pin_entry_visible(true);
                                 }
+                                ViewUpdate::UsbNeedsNewPin {
+                                    min_length,
+                                    must_change,
+                                } => {
+                                    let prompt = if must_change {
+                                        format!(
+                                            "Your security key requires a new PIN. Choose one of at least {min_length} characters, different from the current one."
+                                        )
+                                    } else {
+                                        format!(
+                                            "Choose a new PIN of at least {min_length} characters."
+                                        )
+                                    };
+                                    view_model.set_prompt(prompt);
+                                    view_model.set_usb_pin_entry_visible(true);
+                                    view_model.set_usb_pin_confirm_entry_visible(true);
+                                }
+                                ViewUpdate::UsbNeedsFingerprintSample {
+                                    remaining_samples,
+                                    last_sample,
+                                } => {
+                                    let feedback = match last_sample {
+                                        None | Some(FingerprintSampleStatus::Good) => "",
+                                        Some(FingerprintSampleStatus::Exists) => {
+                                            "This fingerprint is already enrolled. "
+                                        }
+                                        Some(FingerprintSampleStatus::NoUserActivity) => {
+                                            "The sensor was not touched. "
+                                        }
+                                        Some(FingerprintSampleStatus::NoUserPresenceTransition) => {
+                                            "Lift your finger between touches. "
+                                        }
+                                        Some(
+                                            FingerprintSampleStatus::TooHigh
+                                            | FingerprintSampleStatus::TooLow
+                                            | FingerprintSampleStatus::TooLeft
+                                            | FingerprintSampleStatus::TooRight
+                                            | FingerprintSampleStatus::TooSkewed,
+                                        ) => "Center your finger on the sensor. ",
+                                        Some(_) => "That touch could not be used. ",
+                                    };
+                                    let prompt = match remaining_samples {
+                                        Some(1) => format!(
+                                            "{feedback}Touch the fingerprint sensor of your security key one more time."
+                                        ),
+                                        Some(remaining) => format!(
+                                            "{feedback}Touch the fingerprint sensor of your security key {remaining} more times, lifting your finger in between."
+                                        ),
+                                        None => format!(
+                                            "{feedback}Touch the fingerprint sensor of your security key."
+                                        ),
+                                    };
+                                    view_model.set_prompt(prompt);
+                                }
+                                ViewUpdate::UsbNeedsConfirmation(change) => {
+                                    let prompt = match change {
+                                        SecurityKeyChange::SetAlwaysUv(true) => {
+                                            "Require your PIN or fingerprint every time your security key is used?".to_string()
+                                        }
+                                        SecurityKeyChange::SetAlwaysUv(false) => {
+                                            "Stop requiring your PIN or fingerprint every time your security key is used?".to_string()
+                                        }
+                                        SecurityKeyChange::SetMinPinLength {
+                                            min_length,
+                                            rp_ids,
+                                        } => {
+                                            let mut prompt = format!(
+                                                "Require PINs of at least {min_length} characters on your security key? A shorter PIN will have to be changed."
+                                            );
+                                            if !rp_ids.is_empty() {
+                                                prompt.push_str(&format!(
+                                                    " {} will be able to see the minimum length.",
+                                                    rp_ids.join(", ")
+                                                ));
+                                            }
+                                            prompt
+                                        }
+                                        SecurityKeyChange::EnableEnterpriseAttestation => {
+                                            "Let your security key identify itself to the websites of your organization? This can only be undone by resetting the key.".to_string()
+                                        }
+                                        SecurityKeyChange::Reset => {
+                                            "Reset your security key? All of its passkeys, fingerprints and its PIN will be deleted. This cannot be undone.".to_string()
+                                        }
+                                    };
+                                    view_model.set_prompt(prompt);
+                                    view_model.set_confirming_change(true);
+                                }
+                                ViewUpdate::UsbNeedsReplug => {
+                                    view_model.set_prompt(
+                                        "Unplug your security key and plug it back in. Then touch it within 10 seconds.",
+                                    );
+                                }
                                 ViewUpdate::UsbNeedsUserVerification { attempts_left } => {
                                     let prompt = match attempts_left {
                                         Some(1) => "Touch your device again. 1 attempt remaining."
@@ -160,8 +290,55 @@ impl ViewModel {
                                 ViewUpdate::UsbNeedsUserPresence => {
                                     view_model.set_prompt("Touch your device");
                                 }
+                                ViewUpdate::NeedsFingerprint {
+                                    attempts_left,
+                                    password_fallback,
+                                } => {
+                                    let prompt = match attempts_left {
+                                        Some(attempts_left @ 1..3) => format!(
+                                            "Fingerprint not recognized. Touch the fingerprint reader again ({attempts_left} left)."
+                                        ),
+                                        _ => "Touch the fingerprint reader.".to_string(),
+                                    };
+                                    if password_fallback {
+                                        view_model.set_prompt(format!(
+                                            "{prompt} You can also enter your password."
+                                        ));
+                                    } else {
+                                        view_model.set_prompt(prompt);
+                                    }
+                                    view_model.set_password_entry_visible(password_fallback);
+                                }
+                                ViewUpdate::NeedsPassword { attempts_left } => {
+                                    let prompt = match attempts_left {
+                                        Some(1) => {
+                                            "Enter your password. 1 attempt remaining.".to_string()
+                                        }
+                                        Some(attempts_left) => format!(
+                                            "Enter your password. {attempts_left} attempts remaining."
+                                        ),
+                                        None => "Enter your password.".to_string(),
+                                    };
+                                    view_model.set_prompt(prompt);
+                                    view_model.set_password_entry_visible(true);
+                                }
+                                ViewUpdate::UsbDisconnected => {
+                                    // Unplugging a security key that failed lets the user try again.
+                                    view_model.set_failed(false);
+                                    view_model.set_prompt(
+                                        "Your security key was removed. Insert it again to continue.",
+                                    );
+                                }
                                 ViewUpdate::HybridNeedsQrCode(qr_code) => {
-                                    view_model.set_prompt("Scan the QR code with your device to begin authentication.");
+                                    // A new QR code is offered after a failed attempt.
+                                    if view_model.failed() {
+                                        view_model.set_failed(false);
+                                        view_model.set_prompt("That didn't work. Scan the new QR code with your device to try again.");
+                                    } else if *view_model.imp().racing.borrow() {
+                                        view_model.set_prompt("Scan the QR code with your device, or use your security key.");
+                                    } else {
+                                        view_model.set_prompt("Scan the QR code with your device to begin authentication.");
+                                    }
                                     let texture = view_model.draw_qr_code(&qr_code);
                                     view_model.set_qr_code_paintable(&texture);
                                     view_model.set_qr_code_visible(true);
@@ -170,9 +347,14 @@ impl ViewModel {
                                 ViewUpdate::HybridConnecting => {
                                     view_model.set_qr_code_visible(false);
                                     _ = view_model.qr_code_paintable().take();
-                                    view_model.set_prompt(
-                                        "Connecting to your device. Make sure both devices are near each other and have Bluetooth enabled.",
-                                    );
+                                    let linked = view_model.selected_device().is_some_and(|d| {
+                                        d.transport() == Transport::HybridLinked.as_str()
+                                    });
+                                    view_model.set_prompt(if linked {
+                                        "Check your device for a notification. Make sure both devices are near each other and have Bluetooth enabled."
+                                    } else {
+                                        "Connecting to your device. Make sure both devices are near each other and have Bluetooth enabled."
+                                    });
                                     view_model.set_qr_spinner_visible(true);
                                 }
                                 ViewUpdate::HybridConnected => {
@@ -183,6 +365,35 @@ impl ViewModel {
                                     );
                                     view_model.set_qr_spinner_visible(false);
                                 }
+                                ViewUpdate::ConfirmTransfer(rp_ids) => {
+                                    view_model.set_failed(false);
+                                    if rp_ids.is_empty() {
+                                        view_model.set_prompt(
+                                            "Enter the passphrase of the archive to import its passkeys.",
+                                        );
+                                    } else {
+                                        view_model.set_prompt(format!(
+                                            "Choose a passphrase to protect your passkeys for {}. You will need it to import them on another computer.",
+                                            rp_ids.join(", ")
+                                        ));
+                                    }
+                                    view_model.set_passphrase_entry_visible(true);
+                                    view_model
+                                        .set_passphrase_confirm_entry_visible(!rp_ids.is_empty());
+                                    view_model.set_confirming_import(false);
+                                    view_model.set_confirming_transfer(true);
+                                }
+                                ViewUpdate::ConfirmImport(rp_ids) => {
+                                    view_model.set_failed(false);
+                                    view_model.set_prompt(format!(
+                                        "This archive is not protected by a passphrase. Import your passkeys for {}?",
+                                        rp_ids.join(", ")
+                                    ));
+                                    view_model.set_passphrase_entry_visible(false);
+                                    view_model.set_passphrase_confirm_entry_visible(false);
+                                    view_model.set_confirming_import(true);
+                                    view_model.set_confirming_transfer(true);
+                                }
                                 ViewUpdate::Completed => {
                                     view_model.set_qr_spinner_visible(false);
                                     view_model.set_completed(true);
@@ -230,8 +441,7 @@ impl ViewModel {
                 Transport::HybridLinked => "phone-symbolic",
                 Transport::Nfc => "nfc-symbolic",
                 Transport::Usb => "media-removable-symbolic",
-                // Transport::PasskeyProvider => ("symbolic-link-symbolic", "ACME Password Manager"),
-                // _ => "question-symbolic",
+                Transport::PasskeyProvider => "symbolic-link-symbolic",
             };
 
             let b = gtk::Box::builder()
@@ -243,19 +453,85 @@ impl ViewModel {
             b.append(&label);
 
             let button = gtk::Button::builder().name(device.id()).child(&b).build();
-            let tx = tx.clone();
-            button.connect_clicked(move |button| {
-                let id = button.widget_name().to_string();
+            {
                 let tx = tx.clone();
-                glib::spawn_future_local(async move {
-                    tx.send(ViewEvent::DeviceSelected(id)).await.unwrap();
+                button.connect_clicked(move |button| {
+                    let id = button.widget_name().to_string();
+                    let tx = tx.clone();
+                    glib::spawn_future_local(async move {
+                        tx.send(ViewEvent::DeviceSelected(id)).await.unwrap();
+                    });
                 });
-            });
-            button.into()
+            }
+            if transport != Transport::HybridLinked {
+                return button.into();
+            }
+
+            let row = gtk::Box::builder()
+                .orientation(gtk::Orientation::Horizontal)
+                .build();
+            button.set_hexpand(true);
+            row.append(&button);
+            row.append(&Self::linked_device_menu(device, tx.clone()));
+            row.into()
         });
         self.set_devices(device_list);
     }
 
+    /// Lets the user rename or forget a linked device.
+    fn linked_device_menu(device: &DeviceObject, tx: Sender<ViewEvent>) -> gtk::MenuButton {
+        let entry = gtk::Entry::builder()
+            .text(device.name())
+            .placeholder_text("Device name")
+            .build();
+        let forget_button = gtk::Button::builder()
+            .label("Forget this device")
+            .css_classes(["destructive-action"])
+            .build();
+        let content = gtk::Box::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(6)
+            .build();
+        content.append(&entry);
+        content.append(&forget_button);
+        let popover = gtk::Popover::builder().child(&content).build();
+
+        let id = device.id();
+        entry.connect_activate(clone!(
+            #[strong]
+            tx,
+            #[strong]
+            id,
+            #[weak]
+            popover,
+            move |entry| {
+                let event = ViewEvent::DeviceRenamed(id.clone(), entry.text().to_string());
+                let tx = tx.clone();
+                glib::spawn_future_local(async move {
+                    tx.send(event).await.unwrap();
+                });
+                popover.popdown();
+            }
+        ));
+        forget_button.connect_clicked(clone!(
+            #[weak]
+            popover,
+            move |_| {
+                let event = ViewEvent::DeviceForgotten(id.clone());
+                let tx = tx.clone();
+                glib::spawn_future_local(async move {
+                    tx.send(event).await.unwrap();
+                });
+                popover.popdown();
+            }
+        ));
+        gtk::MenuButton::builder()
+            .icon_name("view-more-symbolic")
+            .tooltip_text("Rename or forget this device")
+            .popover(&popover)
+            .build()
+    }
+
     fn update_credentials(&self, credentials: &[Credential]) {
         let vec: Vec<CredentialObject> = credentials
             .iter()
@@ -306,18 +582,41 @@ impl ViewModel {
             Transport::Usb => {
                 self.set_prompt("Insert your security key.");
             }
-            Transport::HybridQr => {
+            Transport::Nfc => {
+                self.set_prompt("Hold your security key against the NFC reader.");
+            }
+            Transport::Ble => {
+                self.set_prompt("Turn on your Bluetooth security key and bring it close.");
+            }
+            Transport::HybridQr | Transport::HybridLinked => {
                 self.set_prompt("");
             }
-            Transport::Internal => {}
-            _ => {
-                todo!();
+            Transport::Internal => {
+                self.set_prompt("Using the passkeys saved on this device.");
+            }
+            Transport::PasskeyProvider => {
+                let name = device.name.as_deref().unwrap_or("your password manager");
+                self.set_prompt(&format!("Using the passkeys saved in {name}."));
             }
         }
+        self.imp().racing.replace(false);
+        self.set_failed(false);
         let device_object: DeviceObject = device.into();
         self.set_selected_device(device_object);
     }
 
+    /// Shows the QR code of hybrid devices started together with security
+    /// keys, until one of them is used.
+    fn waiting_for_devices(&self, devices: &[Device]) {
+        self.imp().racing.replace(true);
+        self.set_failed(false);
+        self.set_prompt("Scan the QR code with your device, or use your security key.");
+        if let Some(device) = devices.iter().find(|d| d.transport == Transport::HybridQr) {
+            let device_object: DeviceObject = device.into();
+            self.set_selected_device(device_object);
+        }
+    }
+
     fn selecting_device(&self) {
         self.set_prompt("Multiple devices found. Please select with which to proceed.");
     }
@@ -326,6 +625,23 @@ impl ViewModel {
         self.send_event(ViewEvent::UsbPinEntered(pin)).await;
     }
 
+    pub async fn send_password(&self, password: String) {
+        self.send_event(ViewEvent::PasswordEntered(password)).await;
+    }
+
+    pub async fn send_passphrase(&self, passphrase: String) {
+        self.send_event(ViewEvent::PassphraseEntered(passphrase))
+            .await;
+    }
+
+    pub async fn confirm_change(&self) {
+        self.send_event(ViewEvent::ChangeConfirmed).await;
+    }
+
+    pub async fn retry(&self) {
+        self.send_event(ViewEvent::Retry).await;
+    }
+
     fn draw_qr_code(&self, qr_data: &str) -> Texture {
         let qr_code = QrCode::new(qr_data).expect("QR code to be valid");
         let svg_xml = qr_code.render::<qrcode::render::svg::Color>().build();
@@ -37,6 +37,15 @@ mod imp {
         #[template_child]
         pub usb_pin_entry: TemplateChild<gtk::PasswordEntry>,
 
+        #[template_child]
+        pub usb_pin_confirm_entry: TemplateChild<gtk::PasswordEntry>,
+
+        #[template_child]
+        pub passphrase_entry: TemplateChild<gtk::PasswordEntry>,
+
+        #[template_child]
+        pub passphrase_confirm_entry: TemplateChild<gtk::PasswordEntry>,
+
         #[template_child]
         pub qr_code_pic: TemplateChild<Picture>,
     }
@@ -44,10 +53,24 @@ mod imp {
     #[gtk::template_callbacks]
     impl CredentialsUiWindow {
         #[template_callback]
-        fn handle_usb_pin_entered(&self, entry: &gtk::PasswordEntry) {
+        fn handle_usb_pin_entered(&self, _entry: &gtk::PasswordEntry) {
             let view_model = &self.view_model.borrow();
             let view_model = view_model.as_ref().unwrap();
-            let pin = entry.text().to_string();
+            let pin = self.usb_pin_entry.text().to_string();
+            if self.usb_pin_confirm_entry.is_visible() {
+                if self.usb_pin_confirm_entry.text().is_empty() {
+                    self.usb_pin_confirm_entry.grab_focus();
+                    return;
+                }
+                if self.usb_pin_confirm_entry.text() != pin.as_str() {
+                    view_model.set_prompt("The PINs do not match. Enter them again.");
+                    self.usb_pin_confirm_entry.set_text("");
+                    return;
+                }
+            }
+            // The current PIN may be asked for before the new one.
+            self.usb_pin_entry.set_text("");
+            self.usb_pin_confirm_entry.set_text("");
             glib::spawn_future_local(clone!(
                 #[weak]
                 view_model,
@@ -56,6 +79,93 @@ mod imp {
                 }
             ));
         }
+
+        #[template_callback]
+        fn handle_password_entered(&self, entry: &gtk::PasswordEntry) {
+            let view_model = &self.view_model.borrow();
+            let view_model = view_model.as_ref().unwrap();
+            let password = entry.text().to_string();
+            // Don't keep the password around in the entry.
+            entry.set_text("");
+            glib::spawn_future_local(clone!(
+                #[weak]
+                view_model,
+                async move {
+                    view_model.send_password(password).await;
+                }
+            ));
+        }
+
+        #[template_callback]
+        fn handle_passphrase_entered(&self, _entry: &gtk::PasswordEntry) {
+            let view_model = &self.view_model.borrow();
+            let view_model = view_model.as_ref().unwrap();
+            let passphrase = self.passphrase_entry.text().to_string();
+            if self.passphrase_confirm_entry.is_visible() {
+                if self.passphrase_confirm_entry.text().is_empty() {
+                    self.passphrase_confirm_entry.grab_focus();
+                    return;
+                }
+                if self.passphrase_confirm_entry.text() != passphrase.as_str() {
+                    view_model.set_prompt("The passphrases do not match. Enter them again.");
+                    self.passphrase_confirm_entry.set_text("");
+                    return;
+                }
+            }
+            // Don't keep the passphrase around in the entries.
+            self.passphrase_entry.set_text("");
+            self.passphrase_confirm_entry.set_text("");
+            glib::spawn_future_local(clone!(
+                #[weak]
+                view_model,
+                async move {
+                    view_model.send_passphrase(passphrase).await;
+                }
+            ));
+        }
+
+        #[template_callback]
+        fn handle_change_confirmed(&self, _button: &gtk::Button) {
+            let view_model = &self.view_model.borrow();
+            let view_model = view_model.as_ref().unwrap();
+            // Only confirm once, the key may take a while to apply the change.
+            view_model.set_confirming_change(false);
+            glib::spawn_future_local(clone!(
+                #[weak]
+                view_model,
+                async move {
+                    view_model.confirm_change().await;
+                }
+            ));
+        }
+
+        #[template_callback]
+        fn handle_import_confirmed(&self, _button: &gtk::Button) {
+            let view_model = &self.view_model.borrow();
+            let view_model = view_model.as_ref().unwrap();
+            view_model.set_confirming_import(false);
+            glib::spawn_future_local(clone!(
+                #[weak]
+                view_model,
+                async move {
+                    // Plain archives have no passphrase.
+                    view_model.send_passphrase(String::new()).await;
+                }
+            ));
+        }
+
+        #[template_callback]
+        fn handle_retry_clicked(&self, _button: &gtk::Button) {
+            let view_model = &self.view_model.borrow();
+            let view_model = view_model.as_ref().unwrap();
+            glib::spawn_future_local(clone!(
+                #[weak]
+                view_model,
+                async move {
+                    view_model.retry().await;
+                }
+            ));
+        }
     }
 
     impl Default for CredentialsUiWindow {
@@ -66,6 +176,9 @@ mod imp {
                 view_model: RefCell::default(),
                 stack: TemplateChild::default(),
                 usb_pin_entry: TemplateChild::default(),
+                usb_pin_confirm_entry: TemplateChild::default(),
+                passphrase_entry: TemplateChild::default(),
+                passphrase_confirm_entry: TemplateChild::default(),
                 qr_code_pic: TemplateChild::default(),
             }
         }
@@ -161,11 +274,7 @@ impl CredentialsUiWindow {
                 let d = d
                     .and_downcast_ref::<DeviceObject>()
                     .expect("selected device to exist at notify");
-                match d.transport().try_into() {
-                    Ok(Transport::Usb) => stack.set_visible_child_name("usb"),
-                    Ok(Transport::HybridQr) => stack.set_visible_child_name("hybrid_qr"),
-                    _ => {}
-                };
+                Self::show_device_page(&stack, d);
             }
         ));
 
@@ -179,6 +288,16 @@ impl CredentialsUiWindow {
             }
         ));
 
+        view_model.connect_confirming_transfer_notify(clone!(
+            #[weak]
+            stack,
+            move |vm| {
+                if vm.confirming_transfer() {
+                    stack.set_visible_child_name("transfer");
+                }
+            }
+        ));
+
         view_model.connect_completed_notify(clone!(
             #[weak]
             stack,
@@ -195,6 +314,11 @@ impl CredentialsUiWindow {
             move |vm| {
                 if vm.failed() {
                     stack.set_visible_child_name("failed");
+                } else if stack.visible_child_name().as_deref() == Some("failed") {
+                    // The device recovered, go back to its prompts.
+                    if let Some(d) = vm.selected_device() {
+                        Self::show_device_page(&stack, &d);
+                    }
                 }
             }
         ));
@@ -208,6 +332,25 @@ impl CredentialsUiWindow {
         ));
     }
 
+    fn show_device_page(stack: &gtk::Stack, device: &DeviceObject) {
+        match device.transport().try_into() {
+            // NFC and BLE security keys use the same prompts as USB ones.
+            Ok(Transport::Usb | Transport::Nfc | Transport::Ble) => {
+                stack.set_visible_child_name("usb")
+            }
+            // Linked phones show the same page, without a QR code.
+            Ok(Transport::HybridQr | Transport::HybridLinked) => {
+                stack.set_visible_child_name("hybrid_qr")
+            }
+            // Credential providers show their own UI, if any, like the
+            // platform authenticator.
+            Ok(Transport::Internal | Transport::PasskeyProvider) => {
+                stack.set_visible_child_name("internal")
+            }
+            _ => {}
+        };
+    }
+
     fn save_window_size(&self) -> Result<(), glib::BoolError> {
         let imp = self.imp();
 
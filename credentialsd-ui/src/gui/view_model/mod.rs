@@ -13,8 +13,8 @@ use tracing::{error, info};
 use credentialsd_common::{
     client::FlowController,
     model::{
-        BackgroundEvent, Credential, Device, Error, HybridState, Operation, Transport, UsbState,
-        ViewUpdate,
+        AuthenticatorState, BackgroundEvent, Credential, Device, DeviceState, Error,
+        FingerprintSampleStatus, Operation, SecurityKeyChange, Transport, ViewUpdate,
     },
 };
 
@@ -32,11 +32,13 @@ where
     // This includes devices like platform authenticator, USB, hybrid
     devices: Vec<Device>,
     selected_device: Option<Device>,
+    /// Devices started together, until the user picks one or one of them is
+    /// used.
+    racing_devices: Vec<Device>,
 
     // providers: Vec<Provider>,
-    hybrid_qr_state: HybridState,
+    hybrid_qr_state: AuthenticatorState,
     hybrid_qr_code_data: Option<Vec<u8>>,
-    // hybrid_linked_state: HybridState,
 }
 
 impl<F: FlowController + Send> ViewModel<F> {
@@ -54,7 +56,8 @@ impl<F: FlowController + Send> ViewModel<F> {
             title: String::default(),
             devices: Vec::new(),
             selected_device: None,
-            hybrid_qr_state: HybridState::default(),
+            racing_devices: Vec::new(),
+            hybrid_qr_state: AuthenticatorState::default(),
             hybrid_qr_code_data: None,
         }
     }
@@ -63,6 +66,14 @@ impl<F: FlowController + Send> ViewModel<F> {
         self.title = match self.operation {
             Operation::Create => "Create new credential",
             Operation::Get => "Use a credential",
+            Operation::Export => "Export passkeys",
+            Operation::Import => "Import passkeys",
+            Operation::ManagePin => "Security key PIN",
+            Operation::ManageCredentials => "Passkeys on security key",
+            Operation::ManageFingerprints => "Fingerprints on security key",
+            Operation::ConfigureSecurityKey => "Security key settings",
+            Operation::InspectSecurityKey => "Security key details",
+            Operation::ResetSecurityKey => "Reset security key",
         }
         .to_string();
         self.tx_update
@@ -90,41 +101,165 @@ impl<F: FlowController + Send> ViewModel<F> {
         let device = self.devices.iter().find(|d| d.id == id).unwrap();
         tracing::debug!("Device selected: {:?}", device);
 
-        // Handle previous device
+        // The credential service cancels the previously selected device.
         if let Some(prev_device) = self.selected_device.replace(device.clone()) {
-            if *device == prev_device {
+            if *device == prev_device && self.racing_devices.is_empty() {
                 return;
             }
-            match prev_device.transport {
-                Transport::Usb => {
-                    todo!("Implement cancellation for USB");
-                }
-                Transport::HybridQr => {
-                    todo!("Implement cancellation for Hybrid QR");
-                }
-                _ => {
-                    todo!();
-                }
-            };
         }
+        self.racing_devices.clear();
+        self.start_device(device.clone()).await;
+    }
+
+    /// Listens on every device that needs no action from the user to start,
+    /// like Chrome and Windows do for logins: security keys blink while the
+    /// QR code is shown.
+    async fn race_devices(&mut self) {
+        let devices: Vec<Device> = self
+            .devices
+            .iter()
+            .filter(|d| {
+                matches!(
+                    d.transport,
+                    Transport::Usb | Transport::Nfc | Transport::HybridQr
+                )
+            })
+            .cloned()
+            .collect();
+        if devices.len() < 2 {
+            return;
+        }
+        tracing::debug!("Starting devices together: {:?}", devices);
+        self.hybrid_qr_state = AuthenticatorState::default();
+        self.hybrid_qr_code_data = None;
+        let ids = devices.iter().map(|d| d.id.clone()).collect();
+        let result = self.flow_controller.lock().await.select_devices(ids).await;
+        if result.is_err() {
+            // The user can still select a device themselves.
+            error!("Failed to start devices together");
+            return;
+        }
+        // Show the QR code until another device is used.
+        self.selected_device = devices
+            .iter()
+            .find(|d| d.transport == Transport::HybridQr)
+            .cloned();
+        self.racing_devices = devices.clone();
+        self.tx_update
+            .send(ViewUpdate::WaitingForDevices(devices))
+            .await
+            .unwrap();
+    }
 
-        // start discovery for newly selected device
-        match device.transport {
-            Transport::Usb => {
-                let mut cred_service = self.flow_controller.lock().await;
-                (*cred_service).get_usb_credential().await.unwrap();
+    /// Focuses a device that was started together with others, once the user
+    /// starts using it.
+    async fn focus_racing_device(&mut self, device_id: &str, state: &DeviceState) {
+        let in_use = !matches!(
+            state.state,
+            AuthenticatorState::Idle
+                | AuthenticatorState::Waiting
+                | AuthenticatorState::NeedsQrCodeScan(_)
+        );
+        if !in_use || self.selected_device.as_ref().map(|d| d.id.as_str()) == Some(device_id) {
+            return;
+        }
+        let Some(device) = self.racing_devices.iter().find(|d| d.id == device_id) else {
+            return;
+        };
+        self.selected_device = Some(device.clone());
+        self.tx_update
+            .send(ViewUpdate::WaitingForDevice(device.clone()))
+            .await
+            .unwrap();
+    }
+
+    /// Asks the user to confirm the transfer of platform passkeys with the
+    /// passphrase of the archive. Plain archives, whose passkeys are known
+    /// already, are imported without one.
+    async fn prompt_transfer(&mut self) {
+        let rp_ids = self
+            .flow_controller
+            .lock()
+            .await
+            .get_transfer_summary()
+            .await;
+        let update = match rp_ids {
+            Ok(rp_ids) if matches!(self.operation, Operation::Import) && !rp_ids.is_empty() => {
+                ViewUpdate::ConfirmImport(rp_ids)
             }
-            Transport::HybridQr => {
-                let mut cred_service = self.flow_controller.lock().await;
-                cred_service.get_hybrid_credential().await.unwrap();
+            Ok(rp_ids) => ViewUpdate::ConfirmTransfer(rp_ids),
+            Err(()) => {
+                let error_msg = "Failed to start the transfer of passkeys.";
+                tracing::error!(error_msg);
+                ViewUpdate::Failed(error_msg.to_string())
             }
-            _ => {
-                todo!()
+        };
+        self.tx_update.send(update).await.unwrap();
+    }
+
+    async fn confirm_transfer(&mut self, passphrase: String) {
+        let result = self
+            .flow_controller
+            .lock()
+            .await
+            .confirm_transfer(passphrase)
+            .await;
+        let update = match result {
+            Ok(count) => {
+                info!("Transferred {count} passkeys");
+                ViewUpdate::Completed
             }
+            Err(()) => ViewUpdate::Failed(String::from(match self.operation {
+                Operation::Import => {
+                    "The passkeys could not be imported. Check the passphrase and try again."
+                }
+                _ => {
+                    "The passkeys could not be exported. Choose a passphrase of at least 8 characters and try again."
+                }
+            })),
+        };
+        self.tx_update.send(update).await.unwrap();
+    }
+
+    /// Starts the selected device again, e.g. after it failed.
+    pub(crate) async fn retry(&mut self) {
+        if matches!(self.operation, Operation::Export | Operation::Import) {
+            tracing::debug!("Retrying transfer");
+            self.prompt_transfer().await;
+        } else if !self.racing_devices.is_empty() {
+            tracing::debug!("Retrying devices started together");
+            self.race_devices().await;
+        } else if let Some(device) = self.selected_device.clone() {
+            tracing::debug!("Retrying device: {:?}", device);
+            self.start_device(device).await;
+        }
+    }
+
+    async fn start_device(&mut self, device: Device) {
+        if device.transport == Transport::HybridQr {
+            self.hybrid_qr_state = AuthenticatorState::default();
+            self.hybrid_qr_code_data = None;
+        }
+
+        // Selecting a device cancels and restarts it if it is already running.
+        let result = self
+            .flow_controller
+            .lock()
+            .await
+            .select_device(device.id.clone())
+            .await;
+        if result.is_err() {
+            let error_msg = "Failed to start the selected device.";
+            tracing::error!(error_msg);
+            self.tx_update
+                .send(ViewUpdate::Failed(error_msg.to_string()))
+                .await
+                .unwrap();
+            return;
         }
 
         self.tx_update
-            .send(ViewUpdate::WaitingForDevice(device.clone()))
+            .send(ViewUpdate::WaitingForDevice(device))
             .await
             .unwrap();
     }
@@ -140,29 +275,77 @@ impl<F: FlowController + Send> ViewModel<F> {
             match event {
                 Event::View(ViewEvent::Initiated) => {
                     self.update_title().await;
-                    self.update_devices().await;
+                    match self.operation {
+                        Operation::Create => self.update_devices().await,
+                        Operation::Get => {
+                            self.update_devices().await;
+                            self.race_devices().await;
+                        }
+                        // Transfers only involve the platform authenticator.
+                        Operation::Export | Operation::Import => self.prompt_transfer().await,
+                        // Only security keys are offered, usually a single
+                        // USB one, which is used right away.
+                        Operation::ManagePin
+                        | Operation::ManageCredentials
+                        | Operation::ManageFingerprints
+                        | Operation::ConfigureSecurityKey
+                        | Operation::InspectSecurityKey
+                        | Operation::ResetSecurityKey => {
+                            self.update_devices().await;
+                            if let [device] = self.devices.as_slice() {
+                                let id = device.id.clone();
+                                self.select_device(&id).await;
+                            }
+                        }
+                    }
                 }
                 Event::View(ViewEvent::DeviceSelected(id)) => {
                     self.select_device(&id).await;
                     println!("Selected device {id}");
                 }
+                Event::View(ViewEvent::Retry) => {
+                    self.retry().await;
+                }
                 Event::View(ViewEvent::UsbPinEntered(pin)) => {
+                    let Some(device) = self.selected_device.clone() else {
+                        error!("Received a PIN without a selected device");
+                        continue;
+                    };
                     let mut cred_service = self.flow_controller.lock().await;
-                    if cred_service.enter_client_pin(pin).await.is_err() {
+                    if cred_service.enter_client_pin(device.id, pin).await.is_err() {
                         error!("Failed to send pin to device");
                     }
                 }
+                Event::View(ViewEvent::ChangeConfirmed) => {
+                    let mut cred_service = self.flow_controller.lock().await;
+                    if cred_service.confirm_change().await.is_err() {
+                        error!("Failed to confirm the change to the security key");
+                    }
+                }
+                Event::View(ViewEvent::PasswordEntered(password)) => {
+                    let mut cred_service = self.flow_controller.lock().await;
+                    if cred_service.enter_password(password).await.is_err() {
+                        error!("Failed to send password to platform authenticator");
+                    }
+                }
+                Event::View(ViewEvent::PassphraseEntered(passphrase)) => {
+                    self.confirm_transfer(passphrase).await;
+                }
                 Event::View(ViewEvent::CredentialSelected(cred_id)) => {
                     println!(
                         "Credential selected: {:?}. Current Device: {:?}",
                         cred_id, self.selected_device
                     );
 
+                    let Some(device) = self.selected_device.clone() else {
+                        error!("Received a credential without a selected device");
+                        continue;
+                    };
                     if self
                         .flow_controller
                         .lock()
                         .await
-                        .select_credential(cred_id)
+                        .select_credential(device.id, cred_id)
                         .await
                         .is_err()
                     {
@@ -174,52 +357,174 @@ impl<F: FlowController + Send> ViewModel<F> {
                             .unwrap();
                     }
                 }
+                Event::View(ViewEvent::DeviceRenamed(id, name)) => {
+                    let result = self
+                        .flow_controller
+                        .lock()
+                        .await
+                        .rename_device(id, name)
+                        .await;
+                    if result.is_err() {
+                        error!("Failed to rename device");
+                    }
+                    self.update_devices().await;
+                }
+                Event::View(ViewEvent::DeviceForgotten(id)) => {
+                    let result = self.flow_controller.lock().await.forget_device(id).await;
+                    if result.is_err() {
+                        error!("Failed to forget device");
+                    }
+                    self.update_devices().await;
+                }
                 Event::View(ViewEvent::UserCancelled) => {
                     break;
                 }
 
-                Event::Background(BackgroundEvent::UsbStateChanged(state)) => {
+                Event::Background(BackgroundEvent::DeviceStateChanged { device_id, state }) => {
+                    self.focus_racing_device(&device_id, &state).await;
+                    if self.selected_device.as_ref().map(|d| &d.id) != Some(&device_id) {
+                        tracing::debug!("Ignoring state of unselected device {device_id}");
+                        continue;
+                    }
+                    let DeviceState { transport, state } = state;
+                    let hybrid = matches!(transport, Transport::HybridQr | Transport::HybridLinked);
+                    if transport == Transport::HybridQr {
+                        tracing::debug!("Received HybridQrState::{:?}", &state);
+                        self.hybrid_qr_state = state.clone();
+                        if !matches!(state, AuthenticatorState::NeedsQrCodeScan(_)) {
+                            self.hybrid_qr_code_data = None;
+                        }
+                    }
                     match state {
-                        UsbState::Connected => {
-                            info!("Found USB device")
+                        AuthenticatorState::Connected if hybrid => {
+                            self.tx_update
+                                .send(ViewUpdate::HybridConnected)
+                                .await
+                                .unwrap();
                         }
-
-                        UsbState::NeedsPin { attempts_left } => {
+                        AuthenticatorState::Connected => {
+                            info!("Found security key")
+                        }
+                        AuthenticatorState::NeedsQrCodeScan(qr_code) => {
+                            self.hybrid_qr_code_data = Some(qr_code.clone().into_bytes());
+                            self.tx_update
+                                .send(ViewUpdate::HybridNeedsQrCode(qr_code))
+                                .await
+                                .unwrap();
+                        }
+                        AuthenticatorState::Connecting => {
+                            self.tx_update
+                                .send(ViewUpdate::HybridConnecting)
+                                .await
+                                .unwrap();
+                        }
+                        AuthenticatorState::NeedsPin { attempts_left } => {
                             self.tx_update
                                 .send(ViewUpdate::UsbNeedsPin { attempts_left })
                                 .await
                                 .unwrap();
                         }
-                        UsbState::NeedsUserVerification { attempts_left } => {
+                        AuthenticatorState::NeedsNewPin {
+                            min_length,
+                            must_change,
+                        } => {
+                            self.tx_update
+                                .send(ViewUpdate::UsbNeedsNewPin {
+                                    min_length,
+                                    must_change,
+                                })
+                                .await
+                                .unwrap();
+                        }
+                        AuthenticatorState::NeedsFingerprintSample {
+                            remaining_samples,
+                            last_sample,
+                        } => {
+                            self.tx_update
+                                .send(ViewUpdate::UsbNeedsFingerprintSample {
+                                    remaining_samples,
+                                    last_sample,
+                                })
+                                .await
+                                .unwrap();
+                        }
+                        AuthenticatorState::NeedsConfirmation(change) => {
+                            self.tx_update
+                                .send(ViewUpdate::UsbNeedsConfirmation(change))
+                                .await
+                                .unwrap();
+                        }
+                        AuthenticatorState::NeedsReplug => {
+                            self.tx_update
+                                .send(ViewUpdate::UsbNeedsReplug)
+                                .await
+                                .unwrap();
+                        }
+                        AuthenticatorState::NeedsUserVerification { attempts_left } => {
                             self.tx_update
                                 .send(ViewUpdate::UsbNeedsUserVerification { attempts_left })
                                 .await
                                 .unwrap();
                         }
-                        UsbState::NeedsUserPresence => {
+                        AuthenticatorState::NeedsUserPresence => {
                             self.tx_update
                                 .send(ViewUpdate::UsbNeedsUserPresence)
                                 .await
                                 .unwrap();
                         }
-                        UsbState::Completed => {
+                        AuthenticatorState::NeedsFingerprint {
+                            attempts_left,
+                            password_fallback,
+                        } => {
+                            self.tx_update
+                                .send(ViewUpdate::NeedsFingerprint {
+                                    attempts_left,
+                                    password_fallback,
+                                })
+                                .await
+                                .unwrap();
+                        }
+                        AuthenticatorState::NeedsPassword { attempts_left } => {
+                            self.tx_update
+                                .send(ViewUpdate::NeedsPassword { attempts_left })
+                                .await
+                                .unwrap();
+                        }
+                        AuthenticatorState::Completed => {
                             self.tx_update.send(ViewUpdate::Completed).await.unwrap();
                         }
-                        UsbState::SelectingDevice => {
+                        AuthenticatorState::SelectingDevice => {
                             self.tx_update
                                 .send(ViewUpdate::SelectingDevice)
                                 .await
                                 .unwrap();
                         }
-                        UsbState::Idle | UsbState::Waiting => {}
-                        UsbState::SelectCredential { creds } => {
+                        AuthenticatorState::Idle | AuthenticatorState::Waiting => {}
+                        AuthenticatorState::Disconnected => {
+                            self.tx_update
+                                .send(ViewUpdate::UsbDisconnected)
+                                .await
+                                .unwrap();
+                        }
+                        AuthenticatorState::SelectCredential { creds } => {
                             self.tx_update
                                 .send(ViewUpdate::SetCredentials(creds))
                                 .await
                                 .unwrap();
                         }
+                        AuthenticatorState::Failed(_) if transport == Transport::HybridQr => {
+                            self.tx_update.send(ViewUpdate::Failed(String::from("Something went wrong. Try again later or use a different authenticator."))).await.unwrap();
+                        }
+                        AuthenticatorState::Failed(_) if transport == Transport::HybridLinked => {
+                            self.tx_update
+                                .send(ViewUpdate::Failed(String::from(
+                                    "Could not reach your device. Try again later or scan a QR code instead.",
+                                )))
+                                .await
+                                .unwrap();
+                        }
                         // TODO: Provide more specific error messages using the wrapped Error.
-                        UsbState::Failed(err) => {
+                        AuthenticatorState::Failed(err) => {
                             let error_msg = String::from(match err {
                                 Error::NoCredentials => {
                                     "No matching credentials found on this authenticator."
@@ -227,6 +532,12 @@ impl<F: FlowController + Send> ViewModel<F> {
                                 Error::PinAttemptsExhausted => {
                                     "No more PIN attempts allowed. Try removing your device and plugging it back in."
                                 }
+                                Error::UserVerificationFailed => {
+                                    "Your identity could not be verified. Please try again."
+                                }
+                                Error::PinChangeRequired => {
+                                    "Your security key requires a new PIN. Change its PIN before using it."
+                                }
                                 Error::AuthenticatorError | Error::Internal(_) => {
                                     "Something went wrong while retrieving a credential. Please try again later or use a different authenticator."
                                 }
@@ -240,48 +551,6 @@ impl<F: FlowController + Send> ViewModel<F> {
                                 .unwrap()
                         }
                     }
-                }
-                Event::Background(BackgroundEvent::HybridQrStateChanged(state)) => {
-                    self.hybrid_qr_state = state.clone();
-                    tracing::debug!("Received HybridQrState::{:?}", &state);
-                    match state {
-                        HybridState::Idle => {
-                            self.hybrid_qr_code_data = None;
-                        }
-                        HybridState::Started(qr_code) => {
-                            self.hybrid_qr_code_data = Some(qr_code.clone().into_bytes());
-                            self.tx_update
-                                .send(ViewUpdate::HybridNeedsQrCode(qr_code))
-                                .await
-                                .unwrap();
-                        }
-                        HybridState::Connecting => {
-                            self.hybrid_qr_code_data = None;
-                            self.tx_update
-                                .send(ViewUpdate::HybridConnecting)
-                                .await
-                                .unwrap();
-                        }
-                        HybridState::Connected => {
-                            self.hybrid_qr_code_data = None;
-                            self.tx_update
-                                .send(ViewUpdate::HybridConnected)
-                                .await
-                                .unwrap();
-                        }
-                        HybridState::Completed => {
-                            self.hybrid_qr_code_data = None;
-                            self.tx_update.send(ViewUpdate::Completed).await.unwrap();
-                        }
-                        HybridState::UserCancelled => {
-                            self.hybrid_qr_code_data = None;
-                            break;
-                        }
-                        HybridState::Failed => {
-                            self.hybrid_qr_code_data = None;
-                            self.tx_update.send(ViewUpdate::Failed(String::from("Something went wrong. Try again later or use a different authenticator."))).await.unwrap();
-                        }
-                    };
                 } /*
                   Event::Background(BackgroundEvent::RequestCancelled(request_id)) => {
                       break;
@@ -298,6 +567,17 @@ pub enum ViewEvent {
     DeviceSelected(String),
     CredentialSelected(String),
     UsbPinEntered(String),
+    /// The user entered their login password for the platform authenticator.
+    PasswordEntered(String),
+    /// The user entered the passphrase of the archive of a transfer.
+    PassphraseEntered(String),
+    /// The user confirmed the change to the security key.
+    ChangeConfirmed,
+    /// The user renamed a linked device: ID and new name.
+    DeviceRenamed(String, String),
+    DeviceForgotten(String),
+    /// The user wants to try the selected device again after it failed.
+    Retry,
     UserCancelled,
 }
 
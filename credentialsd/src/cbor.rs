@@ -1,230 +1,867 @@
-use std::convert::TryInto;
-use std::io::{Error, Write};
-
-pub(crate) struct CborWriter<'a, W> {
-    writer: &'a mut W,
-}
-
-impl<W> CborWriter<'_, W>
-where
-    W: Write,
-{
-    pub fn new(writer: &'_ mut W) -> CborWriter<'_, W> {
-        CborWriter { writer }
-    }
-
-    pub fn write_bytes<T>(&mut self, data: T) -> Result<(), Error>
-    where
-        T: AsRef<[u8]>,
-    {
-        self.write_cbor_value(
-            MajorType::ByteString,
-            data.as_ref().len().try_into().unwrap(),
-            Some(data.as_ref()),
-        )?;
-        Ok(())
-    }
-
-    pub fn write_number(&mut self, num: i128) -> Result<(), Error> {
-        const POSITIVE_INTEGER_MASK: u8 = 0b000_00000;
-        const NEGATIVE_INTEGER_MASK: u8 = 0b001_00000;
-        let (mask, num) = if num >= 0 {
-            (POSITIVE_INTEGER_MASK, num as u64)
-        } else {
-            (NEGATIVE_INTEGER_MASK, (-num - 1) as u64)
+//! A small CBOR ([RFC 8949]) implementation for the data items exchanged with
+//! authenticators and relying parties.
+//!
+//! The encoder always produces the CTAP2 canonical form: integers, lengths and
+//! floats use their shortest encoding, lengths are always definite, and map
+//! keys are sorted and unique. The decoder accepts any well-formed item,
+//! including indefinite-length items, within the configured [`Decoder`]
+//! limits.
+//!
+//! [RFC 8949]: https://www.rfc-editor.org/rfc/rfc8949
+
+use std::{cmp::Ordering, collections::BTreeSet, fmt};
+
+use serde::Serialize;
+
+/// Default limit on nesting of arrays, maps and tags. CTAP2 canonical CBOR
+/// allows at most four levels, so this leaves plenty of room for RP data.
+const MAX_DEPTH: usize = 16;
+
+/// Default limit on the size of an encoded item, in bytes.
+const MAX_SIZE: usize = 1024 * 1024;
+
+const MAJOR_UNSIGNED: u8 = 0;
+const MAJOR_NEGATIVE: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_TAG: u8 = 6;
+const MAJOR_SIMPLE: u8 = 7;
+
+const BREAK: u8 = 0xff;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    /// Major types 0 and 1, which together cover -2^64 to 2^64 - 1.
+    Integer(i128),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    /// Entries are kept in the order they were decoded or constructed in; the
+    /// encoder sorts them.
+    Map(Vec<(Value, Value)>),
+    Tag(u64, Box<Value>),
+    Bool(bool),
+    Null,
+    Undefined,
+    /// Simple values other than `false`, `true`, `null` and `undefined`.
+    Simple(u8),
+    Float(f64),
+}
+
+impl Value {
+    /// Converts any serializable value, e.g. a COSE key or extension outputs
+    /// parsed by libwebauthn, into a [`Value`].
+    pub(crate) fn from_serialize<T: Serialize>(value: &T) -> Result<Self, Error> {
+        let data = serde_cbor_2::to_vec(value).map_err(|err| Error::Serialize(err.to_string()))?;
+        from_slice(&data)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(value: &[u8]) -> Self {
+        Value::Bytes(value.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Self {
+        Value::Bytes(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Integer(value.into())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Error {
+    /// The input ended in the middle of a data item.
+    UnexpectedEnd,
+    /// There is data left over after the top-level data item.
+    TrailingData,
+    /// The input is not well-formed CBOR.
+    Malformed(&'static str),
+    InvalidUtf8,
+    DuplicateKey,
+    DepthLimitExceeded,
+    SizeLimitExceeded,
+    /// The value cannot be represented in CBOR.
+    Unrepresentable(&'static str),
+    Serialize(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnexpectedEnd => f.write_str("unexpected end of CBOR data"),
+            Error::TrailingData => f.write_str("trailing data after CBOR item"),
+            Error::Malformed(reason) => write!(f, "malformed CBOR: {reason}"),
+            Error::InvalidUtf8 => f.write_str("CBOR text string is not valid UTF-8"),
+            Error::DuplicateKey => f.write_str("duplicate key in CBOR map"),
+            Error::DepthLimitExceeded => f.write_str("CBOR item is nested too deeply"),
+            Error::SizeLimitExceeded => f.write_str("CBOR item is too large"),
+            Error::Unrepresentable(reason) => write!(f, "cannot encode value as CBOR: {reason}"),
+            Error::Serialize(err) => write!(f, "failed to serialize value as CBOR: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Encodes a value in CTAP2 canonical form.
+pub(crate) fn to_vec(value: &Value) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    encode(value, &mut buf)?;
+    Ok(buf)
+}
+
+/// Decodes a single data item using the default [`Decoder`] limits.
+pub(crate) fn from_slice(data: &[u8]) -> Result<Value, Error> {
+    Decoder::default().decode(data)
+}
+
+fn encode(value: &Value, buf: &mut Vec<u8>) -> Result<(), Error> {
+    match value {
+        Value::Integer(n) => {
+            let (major, arg) = if *n >= 0 {
+                (MAJOR_UNSIGNED, u64::try_from(*n))
+            } else {
+                (MAJOR_NEGATIVE, u64::try_from(-1 - *n))
+            };
+            let arg = arg.map_err(|_| Error::Unrepresentable("integer out of range"))?;
+            write_header(buf, major, arg);
+        }
+        Value::Bytes(data) => {
+            write_header(buf, MAJOR_BYTES, data.len() as u64);
+            buf.extend_from_slice(data);
+        }
+        Value::Text(text) => {
+            write_header(buf, MAJOR_TEXT, text.len() as u64);
+            buf.extend_from_slice(text.as_bytes());
+        }
+        Value::Array(items) => {
+            write_header(buf, MAJOR_ARRAY, items.len() as u64);
+            for item in items {
+                encode(item, buf)?;
+            }
+        }
+        Value::Map(entries) => {
+            let mut encoded = Vec::with_capacity(entries.len());
+            for (key, value) in entries {
+                encoded.push((to_vec(key)?, to_vec(value)?));
+            }
+            encoded.sort_by(|(a, _), (b, _)| canonical_order(a, b));
+            if encoded.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+                return Err(Error::DuplicateKey);
+            }
+            write_header(buf, MAJOR_MAP, encoded.len() as u64);
+            for (key, value) in encoded {
+                buf.extend(key);
+                buf.extend(value);
+            }
+        }
+        Value::Tag(tag, item) => {
+            write_header(buf, MAJOR_TAG, *tag);
+            encode(item, buf)?;
+        }
+        Value::Bool(false) => buf.push(0xf4),
+        Value::Bool(true) => buf.push(0xf5),
+        Value::Null => buf.push(0xf6),
+        Value::Undefined => buf.push(0xf7),
+        Value::Simple(20..=31) => return Err(Error::Unrepresentable("reserved simple value")),
+        Value::Simple(n) => write_header(buf, MAJOR_SIMPLE, (*n).into()),
+        Value::Float(f) => encode_float(*f, buf),
+    }
+    Ok(())
+}
+
+/// Writes the initial byte and argument of a data item, using the shortest
+/// encoding of the argument.
+fn write_header(buf: &mut Vec<u8>, major: u8, arg: u64) {
+    let major = major << 5;
+    if arg < 24 {
+        buf.push(major | arg as u8);
+    } else if let Ok(arg) = u8::try_from(arg) {
+        buf.push(major | 24);
+        buf.push(arg);
+    } else if let Ok(arg) = u16::try_from(arg) {
+        buf.push(major | 25);
+        buf.extend(arg.to_be_bytes());
+    } else if let Ok(arg) = u32::try_from(arg) {
+        buf.push(major | 26);
+        buf.extend(arg.to_be_bytes());
+    } else {
+        buf.push(major | 27);
+        buf.extend(arg.to_be_bytes());
+    }
+}
+
+/// Writes a float using the shortest of half, single or double precision
+/// that represents it exactly.
+fn encode_float(value: f64, buf: &mut Vec<u8>) {
+    if value.is_nan() {
+        buf.extend([0xf9, 0x7e, 0x00]);
+    } else if let Some(half) = f16_from_f64(value) {
+        buf.push(0xf9);
+        buf.extend(half.to_be_bytes());
+    } else if (value as f32) as f64 == value {
+        buf.push(0xfa);
+        buf.extend((value as f32).to_be_bytes());
+    } else {
+        buf.push(0xfb);
+        buf.extend(value.to_be_bytes());
+    }
+}
+
+/// CTAP2 canonical map key order: by major type, then by encoded length, then
+/// bytewise.
+///
+/// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-errata-20220621.html#ctap2-canonical-cbor-encoding-form
+fn canonical_order(a: &[u8], b: &[u8]) -> Ordering {
+    (a[0] >> 5)
+        .cmp(&(b[0] >> 5))
+        .then(a.len().cmp(&b.len()))
+        .then(a.cmp(b))
+}
+
+fn f16_from_f64(value: f64) -> Option<u16> {
+    let bits = value.to_bits();
+    let sign = ((bits >> 48) & 0x8000) as u16;
+    if value.is_infinite() {
+        return Some(sign | 0x7c00);
+    }
+    if value == 0.0 {
+        return Some(sign);
+    }
+    let exponent = ((bits >> 52) & 0x7ff) as i32 - 1023;
+    let mantissa = bits & ((1 << 52) - 1);
+    if (-14..=15).contains(&exponent) {
+        // Normal half-precision numbers have 10 bits of mantissa.
+        if mantissa & ((1 << 42) - 1) != 0 {
+            return None;
+        }
+        Some(sign | (((exponent + 15) as u16) << 10) | (mantissa >> 42) as u16)
+    } else if (-24..-14).contains(&exponent) {
+        // Subnormal half-precision numbers are multiples of 2^-24.
+        let multiple = value.abs() * 2f64.powi(24);
+        if multiple.fract() != 0.0 {
+            return None;
+        }
+        Some(sign | multiple as u16)
+    } else {
+        None
+    }
+}
+
+fn f16_to_f64(half: u16) -> f64 {
+    let exponent = (half >> 10) & 0x1f;
+    let mantissa = f64::from(half & 0x3ff);
+    let magnitude = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        0x1f if mantissa == 0.0 => f64::INFINITY,
+        0x1f => f64::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f64.powi(i32::from(exponent) - 15),
+    };
+    if half & 0x8000 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Decodes CBOR data items from untrusted input.
+pub(crate) struct Decoder {
+    /// Maximum nesting of arrays, maps and tags.
+    pub max_depth: usize,
+    /// Maximum size of the encoded input, in bytes.
+    pub max_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self {
+            max_depth: MAX_DEPTH,
+            max_size: MAX_SIZE,
+        }
+    }
+}
+
+impl Decoder {
+    /// Decodes a single data item, which must span all of `data`.
+    pub(crate) fn decode(&self, data: &[u8]) -> Result<Value, Error> {
+        if data.len() > self.max_size {
+            return Err(Error::SizeLimitExceeded);
+        }
+        let mut reader = Reader {
+            data,
+            pos: 0,
+            max_depth: self.max_depth,
         };
-        if num < 24 {
-            let d: u8 = num.try_into().unwrap();
-            self.writer.write_all(&[mask | d])?;
-            Ok(())
-        } else if num < 2u64.pow(8) {
-            let d: u8 = num.try_into().unwrap();
-            self.writer.write_all(&[mask | 24])?;
-            self.writer.write_all(&d.to_be_bytes())?;
-            Ok(())
-        } else if num < 2u64.pow(16) {
-            let d: u16 = num.try_into().unwrap();
-            self.writer.write_all(&[mask | 25])?;
-            self.writer.write_all(&d.to_be_bytes())?;
-            Ok(())
-        } else if num < 2u64.pow(32) {
-            let d: u32 = num.try_into().unwrap();
-            self.writer.write_all(&[mask | 26])?;
-            self.writer.write_all(&d.to_be_bytes())?;
-            Ok(())
-        } else if num < 2u64.pow(64) {
-            let d: u64 = num;
-            self.writer.write_all(&[mask | 27])?;
-            self.writer.write_all(&d.to_be_bytes())?;
-            Ok(())
-        } else {
-            Err(Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "value too large".to_string(),
-            ))
-        }
-    }
-
-    pub fn write_map_start(&mut self, len: usize) -> Result<(), Error> {
-        self.write_cbor_value(MajorType::Map, len as u64, None)?;
-        Ok(())
-    }
-
-    pub fn write_array_start(&mut self, len: usize) -> Result<(), Error> {
-        self.write_cbor_value(MajorType::Array, len as u64, None)?;
-        Ok(())
-    }
-
-    pub fn write_text(&mut self, text: &str) -> Result<(), Error> {
-        let data = text.as_bytes();
-        self.write_cbor_value(
-            MajorType::TextString,
-            data.len().try_into().unwrap(),
-            Some(data),
-        )?;
-        Ok(())
-    }
-
-    fn write_cbor_value(
-        &mut self,
-        major_type: MajorType,
-        len: u64,
-        data: Option<&[u8]>,
-    ) -> Result<(), Error> {
-        let major_type_mask = match major_type {
-            MajorType::PositiveInteger => 0b000_00000,
-            MajorType::NegativeInteger => 0b001_00000,
-            MajorType::ByteString => 0b010_00000,
-            MajorType::TextString => 0b011_00000,
-            MajorType::Array => 0b100_00000,
-            MajorType::Map => 0b101_00000,
-            MajorType::Tag => 0b110_00000,
-            MajorType::Float => 0b111_00000,
+        let value = reader.read_value(0)?;
+        if reader.pos != data.len() {
+            return Err(Error::TrailingData);
+        }
+        Ok(value)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    max_depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn peek(&self) -> Result<u8, Error> {
+        self.data.get(self.pos).copied().ok_or(Error::UnexpectedEnd)
+    }
+
+    fn read_slice(&mut self, len: u64) -> Result<&'a [u8], Error> {
+        let len = usize::try_from(len).map_err(|_| Error::UnexpectedEnd)?;
+        if len > self.remaining() {
+            return Err(Error::UnexpectedEnd);
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn read_uint(&mut self, len: u64) -> Result<u64, Error> {
+        Ok(self
+            .read_slice(len)?
+            .iter()
+            .fold(0, |acc, b| (acc << 8) | u64::from(*b)))
+    }
+
+    /// Reads the initial byte and argument of a data item. Returns the major
+    /// type, the additional information and the argument, which is `None` for
+    /// indefinite lengths (and the "break" stop code).
+    fn read_header(&mut self) -> Result<(u8, u8, Option<u64>), Error> {
+        let initial = self.peek()?;
+        self.pos += 1;
+        let major = initial >> 5;
+        let info = initial & 0x1f;
+        let arg = match info {
+            0..=23 => Some(info.into()),
+            24 => Some(self.read_uint(1)?),
+            25 => Some(self.read_uint(2)?),
+            26 => Some(self.read_uint(4)?),
+            27 => Some(self.read_uint(8)?),
+            28..=30 => return Err(Error::Malformed("reserved additional information")),
+            _ => None,
         };
+        Ok((major, info, arg))
+    }
+
+    fn read_value(&mut self, depth: usize) -> Result<Value, Error> {
+        let (major, info, arg) = self.read_header()?;
+        match (major, arg) {
+            (MAJOR_UNSIGNED, Some(n)) => Ok(Value::Integer(n.into())),
+            (MAJOR_NEGATIVE, Some(n)) => Ok(Value::Integer(-1 - i128::from(n))),
+            (MAJOR_BYTES, Some(len)) => Ok(Value::Bytes(self.read_slice(len)?.to_vec())),
+            (MAJOR_BYTES, None) => Ok(Value::Bytes(self.read_chunks(MAJOR_BYTES)?)),
+            (MAJOR_TEXT, Some(len)) => {
+                let text =
+                    std::str::from_utf8(self.read_slice(len)?).map_err(|_| Error::InvalidUtf8)?;
+                Ok(Value::Text(text.to_string()))
+            }
+            (MAJOR_TEXT, None) => {
+                let data = self.read_chunks(MAJOR_TEXT)?;
+                String::from_utf8(data)
+                    .map(Value::Text)
+                    .map_err(|_| Error::InvalidUtf8)
+            }
+            (MAJOR_ARRAY, len) => {
+                let depth = self.enter(depth)?;
+                let mut items = Vec::with_capacity(self.capacity(len, 1)?);
+                while self.has_next(len, items.len())? {
+                    items.push(self.read_value(depth)?);
+                }
+                Ok(Value::Array(items))
+            }
+            (MAJOR_MAP, len) => {
+                let depth = self.enter(depth)?;
+                let mut entries = Vec::with_capacity(self.capacity(len, 2)?);
+                let mut keys = BTreeSet::new();
+                while self.has_next(len, entries.len())? {
+                    let key = self.read_value(depth)?;
+                    if !keys.insert(to_vec(&key)?) {
+                        return Err(Error::DuplicateKey);
+                    }
+                    let value = self.read_value(depth)?;
+                    entries.push((key, value));
+                }
+                Ok(Value::Map(entries))
+            }
+            (MAJOR_TAG, Some(tag)) => {
+                let depth = self.enter(depth)?;
+                Ok(Value::Tag(tag, Box::new(self.read_value(depth)?)))
+            }
+            (MAJOR_SIMPLE, Some(arg)) => match info {
+                0..=19 => Ok(Value::Simple(info)),
+                20 => Ok(Value::Bool(false)),
+                21 => Ok(Value::Bool(true)),
+                22 => Ok(Value::Null),
+                23 => Ok(Value::Undefined),
+                24 if arg < 32 => Err(Error::Malformed("invalid simple value")),
+                24 => Ok(Value::Simple(arg as u8)),
+                25 => Ok(Value::Float(f16_to_f64(arg as u16))),
+                26 => Ok(Value::Float(f32::from_bits(arg as u32).into())),
+                _ => Ok(Value::Float(f64::from_bits(arg))),
+            },
+            (MAJOR_SIMPLE, None) => Err(Error::Malformed("unexpected break")),
+            _ => Err(Error::Malformed("invalid indefinite length")),
+        }
+    }
+
+    fn enter(&self, depth: usize) -> Result<usize, Error> {
+        if depth >= self.max_depth {
+            return Err(Error::DepthLimitExceeded);
+        }
+        Ok(depth + 1)
+    }
+
+    /// Returns how many items to preallocate for a container. Every item takes
+    /// at least one byte, so lengths that can't fit in the remaining input are
+    /// rejected before allocating.
+    fn capacity(&self, len: Option<u64>, items_per_entry: u64) -> Result<usize, Error> {
+        match len {
+            Some(len) if len.saturating_mul(items_per_entry) > self.remaining() as u64 => {
+                Err(Error::UnexpectedEnd)
+            }
+            Some(len) => Ok(len as usize),
+            None => Ok(0),
+        }
+    }
+
+    /// Whether another entry follows in a container with `len` entries (or an
+    /// indefinite length) of which `read` have been read so far.
+    fn has_next(&mut self, len: Option<u64>, read: usize) -> Result<bool, Error> {
+        match len {
+            Some(len) => Ok((read as u64) < len),
+            None if self.peek()? == BREAK => {
+                self.pos += 1;
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
 
-        let mut major_type_buf = [0; 9];
-        if len < 24 {
-            let l: u8 = len.try_into().unwrap();
-            self.writer.write_all(&[l | major_type_mask])?;
-        } else if len < 2u64.pow(8) {
-            let l: u8 = len.try_into().unwrap();
-            major_type_buf[0] = 24u8 | major_type_mask;
-            major_type_buf[1..2].copy_from_slice(&l.to_be_bytes());
-            self.writer.write_all(&major_type_buf[0..2])?;
-        } else if len < 2u64.pow(16) {
-            let l: u16 = len.try_into().unwrap();
-            major_type_buf[0] = 25u8 | major_type_mask;
-            major_type_buf[1..3].copy_from_slice(&l.to_be_bytes());
-            self.writer.write_all(&major_type_buf[0..3])?;
-        } else if len < 2u64.pow(32) {
-            let l: u32 = len.try_into().unwrap();
-            major_type_buf[0] = 26u8 | major_type_mask;
-            major_type_buf[1..5].copy_from_slice(&l.to_be_bytes());
-            self.writer.write_all(&major_type_buf[0..5])?;
-        } else if len < 2u64.pow(64) {
-            let l: u64 = len;
-            major_type_buf[0] = 27u8 | major_type_mask;
-            major_type_buf[1..9].copy_from_slice(&l.to_be_bytes());
-            self.writer.write_all(&major_type_buf[0..9])?;
-        } else {
-            return Err(Error::new(
-                std::io::ErrorKind::Unsupported,
-                "Value too large".to_string(),
-            ));
-        }
-        if let Some(data) = data {
-            self.writer.write_all(data)?;
-        }
-        Ok(())
-    }
-}
-
-#[allow(dead_code)]
-enum MajorType {
-    PositiveInteger,
-    NegativeInteger,
-    ByteString,
-    TextString,
-    Array,
-    Map,
-    Tag,
-    Float,
+    /// Reads the chunks of an indefinite-length byte or text string, which
+    /// must be definite-length strings of the same major type.
+    fn read_chunks(&mut self, major: u8) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        loop {
+            if self.peek()? == BREAK {
+                self.pos += 1;
+                return Ok(data);
+            }
+            match self.read_header()? {
+                (chunk_major, _, Some(len)) if chunk_major == major => {
+                    let chunk = self.read_slice(len)?;
+                    if major == MAJOR_TEXT && std::str::from_utf8(chunk).is_err() {
+                        return Err(Error::InvalidUtf8);
+                    }
+                    data.extend_from_slice(chunk);
+                }
+                _ => {
+                    return Err(Error::Malformed(
+                        "invalid chunk in indefinite-length string",
+                    ))
+                }
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::CborWriter;
+    use super::{from_slice, to_vec, Decoder, Error, Value};
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn text(s: &str) -> Value {
+        Value::from(s)
+    }
+
+    fn int(n: i128) -> Value {
+        Value::Integer(n)
+    }
+
+    /// Checks that `value` encodes to `expected`, and decodes back.
+    fn assert_round_trip(value: Value, expected: &str) {
+        assert_eq!(hex(expected), to_vec(&value).unwrap(), "encoding {value:?}");
+        assert_eq!(
+            value,
+            from_slice(&hex(expected)).unwrap(),
+            "decoding {expected}"
+        );
+    }
+
+    #[test]
+    fn write_positive_number() {
+        assert_eq!(vec![0b000_11001, 0x01, 0xf4], to_vec(&int(500)).unwrap());
+    }
+
+    #[test]
+    fn write_negative_number() {
+        assert_eq!(vec![0b001_10101], to_vec(&int(-22)).unwrap());
+    }
+
+    #[test]
+    fn write_negative_number_u8() {
+        assert_eq!(vec![0b001_11001, 0x01, 0xf3], to_vec(&int(-500)).unwrap());
+    }
+
+    #[test]
+    fn write_map_start() {
+        let map = Value::Map((0..800).map(|n| (int(n), Value::Null)).collect());
+        let buf = to_vec(&map).unwrap();
+        assert_eq!(&buf[..3], &[0b101_11001, 0b0000_0011, 0b0010_0000]);
+    }
+
+    // Test vectors from RFC 8949, Appendix A.
+
+    #[test]
+    fn rfc8949_integers() {
+        assert_round_trip(int(0), "00");
+        assert_round_trip(int(1), "01");
+        assert_round_trip(int(10), "0a");
+        assert_round_trip(int(23), "17");
+        assert_round_trip(int(24), "1818");
+        assert_round_trip(int(25), "1819");
+        assert_round_trip(int(100), "1864");
+        assert_round_trip(int(1000), "1903e8");
+        assert_round_trip(int(1000000), "1a000f4240");
+        assert_round_trip(int(1000000000000), "1b000000e8d4a51000");
+        assert_round_trip(int(18446744073709551615), "1bffffffffffffffff");
+        assert_round_trip(int(-18446744073709551616), "3bffffffffffffffff");
+        assert_round_trip(int(-1), "20");
+        assert_round_trip(int(-10), "29");
+        assert_round_trip(int(-100), "3863");
+        assert_round_trip(int(-1000), "3903e7");
+        assert_round_trip(
+            Value::Tag(2, Box::new(Value::Bytes(hex("010000000000000000")))),
+            "c249010000000000000000",
+        );
+    }
+
+    #[test]
+    fn rfc8949_integers_out_of_range() {
+        assert!(matches!(
+            to_vec(&int(18446744073709551616)),
+            Err(Error::Unrepresentable(_))
+        ));
+        assert!(matches!(
+            to_vec(&int(-18446744073709551617)),
+            Err(Error::Unrepresentable(_))
+        ));
+    }
+
+    #[test]
+    fn rfc8949_floats() {
+        assert_round_trip(Value::Float(0.0), "f90000");
+        assert_round_trip(Value::Float(-0.0), "f98000");
+        assert_round_trip(Value::Float(1.0), "f93c00");
+        assert_round_trip(Value::Float(1.1), "fb3ff199999999999a");
+        assert_round_trip(Value::Float(1.5), "f93e00");
+        assert_round_trip(Value::Float(65504.0), "f97bff");
+        assert_round_trip(Value::Float(100000.0), "fa47c35000");
+        assert_round_trip(Value::Float(3.4028234663852886e+38), "fa7f7fffff");
+        assert_round_trip(Value::Float(1.0e+300), "fb7e37e43c8800759c");
+        assert_round_trip(Value::Float(5.960464477539063e-8), "f90001");
+        assert_round_trip(Value::Float(0.00006103515625), "f90400");
+        assert_round_trip(Value::Float(-4.0), "f9c400");
+        assert_round_trip(Value::Float(-4.1), "fbc010666666666666");
+        assert_round_trip(Value::Float(f64::INFINITY), "f97c00");
+        assert_round_trip(Value::Float(f64::NEG_INFINITY), "f9fc00");
+        assert_eq!(hex("f97e00"), to_vec(&Value::Float(f64::NAN)).unwrap());
+
+        // Non-preferred encodings decode to the same values.
+        for nan in ["f97e00", "fa7fc00000", "fb7ff8000000000000"] {
+            assert!(matches!(from_slice(&hex(nan)), Ok(Value::Float(f)) if f.is_nan()));
+        }
+        assert_eq!(
+            Value::Float(f64::INFINITY),
+            from_slice(&hex("fa7f800000")).unwrap()
+        );
+        assert_eq!(
+            Value::Float(f64::NEG_INFINITY),
+            from_slice(&hex("fbfff0000000000000")).unwrap()
+        );
+        assert_eq!(
+            Value::Float(1.0),
+            from_slice(&hex("fb3ff0000000000000")).unwrap()
+        );
+    }
+
+    #[test]
+    fn rfc8949_simple_values() {
+        assert_round_trip(Value::Bool(false), "f4");
+        assert_round_trip(Value::Bool(true), "f5");
+        assert_round_trip(Value::Null, "f6");
+        assert_round_trip(Value::Undefined, "f7");
+        assert_round_trip(Value::Simple(16), "f0");
+        assert_round_trip(Value::Simple(255), "f8ff");
+        assert!(matches!(from_slice(&hex("f818")), Err(Error::Malformed(_))));
+        assert!(matches!(
+            to_vec(&Value::Simple(24)),
+            Err(Error::Unrepresentable(_))
+        ));
+    }
+
+    #[test]
+    fn rfc8949_tags() {
+        assert_round_trip(
+            Value::Tag(0, Box::new(text("2013-03-21T20:04:00Z"))),
+            "c074323031332d30332d32315432303a30343a30305a",
+        );
+        assert_round_trip(Value::Tag(1, Box::new(int(1363896240))), "c11a514b67b0");
+        assert_round_trip(
+            Value::Tag(23, Box::new(Value::Bytes(hex("01020304")))),
+            "d74401020304",
+        );
+        assert_round_trip(
+            Value::Tag(32, Box::new(text("http://www.example.com"))),
+            "d82076687474703a2f2f7777772e6578616d706c652e636f6d",
+        );
+    }
+
+    #[test]
+    fn rfc8949_strings() {
+        assert_round_trip(Value::Bytes(vec![]), "40");
+        assert_round_trip(Value::Bytes(hex("01020304")), "4401020304");
+        assert_round_trip(text(""), "60");
+        assert_round_trip(text("a"), "6161");
+        assert_round_trip(text("IETF"), "6449455446");
+        assert_round_trip(text("\"\\"), "62225c");
+        assert_round_trip(text("\u{00fc}"), "62c3bc");
+        assert_round_trip(text("\u{6c34}"), "63e6b0b4");
+        assert_round_trip(text("\u{10151}"), "64f0908591");
+    }
+
+    #[test]
+    fn rfc8949_arrays_and_maps() {
+        assert_round_trip(Value::Array(vec![]), "80");
+        assert_round_trip(Value::Array(vec![int(1), int(2), int(3)]), "83010203");
+        let nested = Value::Array(vec![
+            int(1),
+            Value::Array(vec![int(2), int(3)]),
+            Value::Array(vec![int(4), int(5)]),
+        ]);
+        assert_round_trip(nested, "8301820203820405");
+        assert_round_trip(
+            Value::Array((1..=25).map(int).collect()),
+            "98190102030405060708090a0b0c0d0e0f101112131415161718181819",
+        );
+        assert_round_trip(Value::Map(vec![]), "a0");
+        assert_round_trip(
+            Value::Map(vec![(int(1), int(2)), (int(3), int(4))]),
+            "a201020304",
+        );
+        assert_round_trip(
+            Value::Map(vec![
+                (text("a"), int(1)),
+                (text("b"), Value::Array(vec![int(2), int(3)])),
+            ]),
+            "a26161016162820203",
+        );
+        assert_round_trip(
+            Value::Array(vec![text("a"), Value::Map(vec![(text("b"), text("c"))])]),
+            "826161a161626163",
+        );
+        assert_round_trip(
+            Value::Map(
+                ["a", "b", "c", "d", "e"]
+                    .into_iter()
+                    .map(|k| (text(k), text(&k.to_uppercase())))
+                    .collect(),
+            ),
+            "a56161614161626142616361436164614461656145",
+        );
+    }
 
     #[test]
-    fn write_bytes() {
-        let mut buf: Vec<u8> = Vec::with_capacity(16);
-        let mut cbor_writer = CborWriter::new(&mut buf);
-        let data: &[u8] = &[0x01, 0x23, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xff];
-        cbor_writer.write_bytes(data).unwrap();
+    fn rfc8949_indefinite_lengths() {
+        let cases = [
+            ("5f42010243030405ff", Value::Bytes(hex("0102030405"))),
+            ("7f657374726561646d696e67ff", text("streaming")),
+            ("9fff", Value::Array(vec![])),
+            (
+                "9f018202039f0405ffff",
+                Value::Array(vec![
+                    int(1),
+                    Value::Array(vec![int(2), int(3)]),
+                    Value::Array(vec![int(4), int(5)]),
+                ]),
+            ),
+            (
+                "83019f0203ff820405",
+                Value::Array(vec![
+                    int(1),
+                    Value::Array(vec![int(2), int(3)]),
+                    Value::Array(vec![int(4), int(5)]),
+                ]),
+            ),
+            (
+                "9f0102030405060708090a0b0c0d0e0f101112131415161718181819ff",
+                Value::Array((1..=25).map(int).collect()),
+            ),
+            (
+                "bf61610161629f0203ffff",
+                Value::Map(vec![
+                    (text("a"), int(1)),
+                    (text("b"), Value::Array(vec![int(2), int(3)])),
+                ]),
+            ),
+            (
+                "826161bf61626163ff",
+                Value::Array(vec![text("a"), Value::Map(vec![(text("b"), text("c"))])]),
+            ),
+            (
+                "bf6346756ef563416d7421ff",
+                Value::Map(vec![
+                    (text("Fun"), Value::Bool(true)),
+                    (text("Amt"), int(-2)),
+                ]),
+            ),
+        ];
+        for (encoded, expected) in cases {
+            assert_eq!(expected, from_slice(&hex(encoded)).unwrap(), "{encoded}");
+        }
+        // Indefinite lengths are never produced by the encoder.
         assert_eq!(
-            buf,
-            &[
-                0b010_01001,
-                0x01,
-                0x23,
-                0x34,
-                0x56,
-                0x78,
-                0x9a,
-                0xbc,
-                0xde,
-                0xff
-            ]
+            hex("a263416d74216346756ef5"),
+            to_vec(&from_slice(&hex("bf6346756ef563416d7421ff")).unwrap()).unwrap()
         );
     }
 
     #[test]
-    fn write_bytes_over24() {
-        let mut buf: Vec<u8> = Vec::new();
-        let mut cbor_writer = CborWriter::new(&mut buf);
-        let data = vec![0; 32];
-        cbor_writer.write_bytes(data.clone()).unwrap();
-        assert_eq!(&buf[0..2], &[0b010_11000, 32u8]);
-        assert_eq!(&buf[2..34], &data);
+    fn encode_sorts_map_keys_canonically() {
+        let map = Value::Map(vec![
+            (Value::Bool(false), int(8)),
+            (text("aa"), int(5)),
+            (Value::Array(vec![int(-1)]), int(6)),
+            (int(-1), int(3)),
+            (text("z"), int(4)),
+            (int(100), int(2)),
+            (int(10), int(1)),
+        ]);
+        assert_eq!(
+            hex("a70a011864022003617a0462616105812006f408"),
+            to_vec(&map).unwrap()
+        );
     }
 
     #[test]
-    fn write_uint() {
-        let mut buf: Vec<u8> = Vec::with_capacity(16);
-        let mut cbor_writer = CborWriter::new(&mut buf);
-        cbor_writer.write_number(22_i128).unwrap();
-        assert_eq!(buf, &[0b000_10110]);
+    fn encode_rejects_duplicate_keys() {
+        let map = Value::Map(vec![(int(1), int(1)), (int(1), int(2))]);
+        assert_eq!(Err(Error::DuplicateKey), to_vec(&map));
     }
 
     #[test]
-    fn write_number_u8() {
-        let mut buf: Vec<u8> = Vec::with_capacity(16);
-        let mut cbor_writer = CborWriter::new(&mut buf);
-        cbor_writer.write_number(500_i128).unwrap();
-        assert_eq!(buf, &[0b000_11001, 0x01, 0xf4]);
+    fn decode_rejects_duplicate_keys() {
+        assert_eq!(Err(Error::DuplicateKey), from_slice(&hex("a2616101616102")));
     }
 
     #[test]
-    fn write_negative_number() {
-        let mut buf: Vec<u8> = Vec::with_capacity(16);
-        let mut cbor_writer = CborWriter::new(&mut buf);
-        cbor_writer.write_number(-22_i128).unwrap();
-        assert_eq!(buf, &[0b001_10101]);
+    fn decode_rejects_malformed_input() {
+        assert_eq!(Err(Error::UnexpectedEnd), from_slice(&[]));
+        assert_eq!(Err(Error::UnexpectedEnd), from_slice(&hex("1903")));
+        assert_eq!(Err(Error::UnexpectedEnd), from_slice(&hex("440102")));
+        assert_eq!(Err(Error::UnexpectedEnd), from_slice(&hex("9f01")));
+        assert_eq!(Err(Error::TrailingData), from_slice(&hex("0102")));
+        assert_eq!(Err(Error::InvalidUtf8), from_slice(&hex("62c328")));
+        assert!(matches!(from_slice(&hex("1c")), Err(Error::Malformed(_))));
+        assert!(matches!(from_slice(&hex("ff")), Err(Error::Malformed(_))));
+        assert!(matches!(from_slice(&hex("1f")), Err(Error::Malformed(_))));
+        // Chunks of an indefinite-length string must be of the same type.
+        assert!(matches!(
+            from_slice(&hex("5f6161ff")),
+            Err(Error::Malformed(_))
+        ));
     }
 
     #[test]
-    fn write_negative_number_u8() {
-        let mut buf: Vec<u8> = Vec::with_capacity(16);
-        let mut cbor_writer = CborWriter::new(&mut buf);
-        cbor_writer.write_number(-500_i128).unwrap();
-        assert_eq!(buf, &[0b001_11001, 0x01, 0xf3]);
+    fn decode_rejects_huge_lengths_without_allocating() {
+        assert_eq!(
+            Err(Error::UnexpectedEnd),
+            from_slice(&hex("9bffffffffffffffff00"))
+        );
+        assert_eq!(
+            Err(Error::UnexpectedEnd),
+            from_slice(&hex("5bffffffffffffffff00"))
+        );
     }
 
     #[test]
-    fn write_map_start() {
-        let mut buf: Vec<u8> = Vec::with_capacity(3);
-        let mut cbor_writer = CborWriter::new(&mut buf);
-        cbor_writer.write_map_start(800).unwrap();
-        assert_eq!(buf, &[0b101_11001, 0b0000_0011, 0b0010_0000,]);
+    fn decode_enforces_limits() {
+        let decoder = Decoder {
+            max_depth: 2,
+            max_size: 8,
+        };
+        assert!(decoder.decode(&hex("818101")).is_ok());
+        assert_eq!(
+            Err(Error::DepthLimitExceeded),
+            decoder.decode(&hex("81818101"))
+        );
+        assert_eq!(
+            Err(Error::DepthLimitExceeded),
+            decoder.decode(&hex("81c1c101"))
+        );
+        assert_eq!(
+            Err(Error::SizeLimitExceeded),
+            decoder.decode(&hex("4800000000000000000000"))
+        );
+    }
+
+    #[test]
+    fn from_serialize() {
+        #[derive(serde::Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Extensions {
+            min_pin_length: u32,
+            cred_protect: u8,
+        }
+        let value = Value::from_serialize(&Extensions {
+            min_pin_length: 4,
+            cred_protect: 2,
+        })
+        .unwrap();
+        assert_eq!(
+            hex("a26b6372656450726f74656374026c6d696e50696e4c656e67746804"),
+            to_vec(&value).unwrap()
+        );
     }
 }
@@ -0,0 +1,187 @@
+//! Daemon configuration, read from a TOML file at startup.
+
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+/// Environment variable that overrides the path of the configuration file.
+const CONFIG_PATH_ENV: &str = "CREDENTIALSD_CONFIG";
+
+const SYSTEM_CONFIG_PATH: &str = "/etc/credentialsd/config.toml";
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Transports to offer to the user, in the order they are listed in.
+    pub transports: Vec<TransportKind>,
+    /// Where the platform authenticator keeps the private keys of new
+    /// credentials.
+    pub platform_keys: PlatformKeyBackend,
+    /// Where the platform authenticator stores its credentials.
+    pub platform_storage: PlatformStorage,
+    /// How the platform authenticator verifies the user, in the order the
+    /// methods are tried.
+    pub user_verification: Vec<UserVerificationMethod>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            transports: vec![
+                TransportKind::Usb,
+                TransportKind::HybridLinked,
+                TransportKind::HybridQr,
+            ],
+            platform_keys: PlatformKeyBackend::Auto,
+            platform_storage: PlatformStorage::File,
+            user_verification: vec![
+                UserVerificationMethod::Fingerprint,
+                UserVerificationMethod::Password,
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransportKind {
+    Usb,
+    /// Phones linked in an earlier hybrid QR ceremony. Also makes the QR code
+    /// ask phones to link, and stores what they send.
+    HybridLinked,
+    HybridQr,
+    /// Security keys tapped on a PC/SC reader. Needs `pcscd` to be running.
+    Nfc,
+    /// Security keys speaking CTAP over Bluetooth Low Energy. Needs BlueZ.
+    Ble,
+    /// Passkeys stored on this computer, with keys bound to the TPM or kept
+    /// in software, see `platform-keys`.
+    Internal,
+    /// Third-party credential providers, e.g. password managers, each offered
+    /// as a device unless the user turned it off.
+    Providers,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlatformKeyBackend {
+    /// Bind keys to the TPM if there is one, and keep them in software
+    /// otherwise.
+    #[default]
+    Auto,
+    /// Bind keys to the TPM. Without a usable TPM, the platform authenticator
+    /// is not offered.
+    Tpm,
+    /// Keep keys in software, encrypted in the user's data directory.
+    Software,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlatformStorage {
+    /// A file in `$XDG_DATA_HOME/credentialsd`, encrypted with a key from the
+    /// user's keyring.
+    #[default]
+    File,
+    /// The default collection of the Secret Service, usually the login
+    /// keyring, so that credentials are unlocked along with it.
+    SecretService,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UserVerificationMethod {
+    /// A finger enrolled with fprintd.
+    Fingerprint,
+    /// The user's login password, checked by the `credentialsd` PAM service.
+    Password,
+}
+
+impl Config {
+    /// Loads the configuration from the first file found of:
+    /// - the path in `$CREDENTIALSD_CONFIG`,
+    /// - `$XDG_CONFIG_HOME/credentialsd/config.toml`,
+    /// - `/etc/credentialsd/config.toml`.
+    ///
+    /// If none of them exist, the default configuration is used.
+    pub fn load() -> Result<Self, Box<dyn Error>> {
+        let path = match std::env::var_os(CONFIG_PATH_ENV) {
+            Some(path) => Some(PathBuf::from(path)),
+            None => dirs::config_dir()
+                .map(|dir| dir.join("credentialsd").join("config.toml"))
+                .filter(|path| path.exists())
+                .or_else(|| Some(PathBuf::from(SYSTEM_CONFIG_PATH)).filter(|path| path.exists())),
+        };
+        match path {
+            Some(path) => Self::from_file(&path),
+            None => {
+                tracing::debug!("No configuration file found, using defaults");
+                Ok(Self::default())
+            }
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        tracing::debug!("Loading configuration from {}", path.display());
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {err}", path.display()))?;
+        let config = toml::from_str(&contents)
+            .map_err(|err| format!("Failed to parse {}: {err}", path.display()))?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        Config, PlatformKeyBackend, PlatformStorage, TransportKind, UserVerificationMethod,
+    };
+
+    #[test]
+    fn test_empty_config_uses_defaults() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(Config::default(), config);
+    }
+
+    #[test]
+    fn test_transports_are_kept_in_order() {
+        let config: Config = toml::from_str(r#"transports = ["hybrid-qr", "usb"]"#).unwrap();
+        assert_eq!(
+            vec![TransportKind::HybridQr, TransportKind::Usb],
+            config.transports
+        );
+    }
+
+    #[test]
+    fn test_unknown_transport_is_rejected() {
+        assert!(toml::from_str::<Config>(r#"transports = ["carrier-pigeon"]"#).is_err());
+    }
+
+    #[test]
+    fn test_platform_keys_backend() {
+        let config: Config = toml::from_str(r#"platform-keys = "tpm""#).unwrap();
+        assert_eq!(PlatformKeyBackend::Tpm, config.platform_keys);
+        assert!(toml::from_str::<Config>(r#"platform-keys = "hsm""#).is_err());
+    }
+
+    #[test]
+    fn test_platform_storage() {
+        let config: Config = toml::from_str(r#"platform-storage = "secret-service""#).unwrap();
+        assert_eq!(PlatformStorage::SecretService, config.platform_storage);
+    }
+
+    #[test]
+    fn test_user_verification_methods() {
+        let config: Config = toml::from_str(r#"user-verification = ["password"]"#).unwrap();
+        assert_eq!(
+            vec![UserVerificationMethod::Password],
+            config.user_verification
+        );
+        let config: Config = toml::from_str("user-verification = []").unwrap();
+        assert!(config.user_verification.is_empty());
+        assert!(toml::from_str::<Config>(r#"user-verification = ["retina"]"#).is_err());
+    }
+}
@@ -1,6 +1,8 @@
 use libwebauthn::proto::ctap2::Ctap2COSEAlgorithmIdentifier;
 use tracing::debug;
 
+use crate::cbor;
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(i64)]
 pub(super) enum CoseKeyType {
@@ -9,30 +11,114 @@ pub(super) enum CoseKeyType {
     RS256,
 }
 
+/// COSE algorithm identifiers used for WebAuthn credentials.
+///
+/// https://www.iana.org/assignments/cose/cose.xhtml#algorithms
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum CoseKeyAlgorithmIdentifier {
+    /// ECDSA w/ SHA-256
     ES256,
+    /// ECDSA w/ SHA-384
+    ES384,
+    /// ECDSA w/ SHA-512
+    ES512,
+    /// ECDSA using P-256 curve and SHA-256
+    ESP256,
+    /// ECDSA using P-384 curve and SHA-384
+    ESP384,
+    /// ECDSA using P-521 curve and SHA-512
+    ESP512,
+    /// ECDSA using secp256k1 curve and SHA-256
+    ES256K,
+    /// EdDSA
     EdDSA,
+    /// EdDSA using the Ed25519 parameter set
+    Ed25519,
+    /// EdDSA using the Ed448 parameter set
+    Ed448,
+    /// RSASSA-PSS w/ SHA-256
+    PS256,
+    /// RSASSA-PSS w/ SHA-384
+    PS384,
+    /// RSASSA-PSS w/ SHA-512
+    PS512,
+    /// RSASSA-PKCS1-v1_5 using SHA-256
     RS256,
+    /// RSASSA-PKCS1-v1_5 using SHA-384
+    RS384,
+    /// RSASSA-PKCS1-v1_5 using SHA-512
+    RS512,
+    /// RSASSA-PKCS1-v1_5 using SHA-1
+    RS1,
+    /// CBOR Object Signing Algorithm for ML-DSA-44
+    MLDSA44,
+    /// CBOR Object Signing Algorithm for ML-DSA-65
+    MLDSA65,
+    /// CBOR Object Signing Algorithm for ML-DSA-87
+    MLDSA87,
+    /// Any other algorithm. The authenticator decides whether it supports it.
+    Other(i64),
+}
+
+impl From<i64> for CoseKeyAlgorithmIdentifier {
+    fn from(value: i64) -> Self {
+        match value {
+            -7 => Self::ES256,
+            -35 => Self::ES384,
+            -36 => Self::ES512,
+            -9 => Self::ESP256,
+            -51 => Self::ESP384,
+            -52 => Self::ESP512,
+            -47 => Self::ES256K,
+            -8 => Self::EdDSA,
+            -19 => Self::Ed25519,
+            -53 => Self::Ed448,
+            -37 => Self::PS256,
+            -38 => Self::PS384,
+            -39 => Self::PS512,
+            -257 => Self::RS256,
+            -258 => Self::RS384,
+            -259 => Self::RS512,
+            -65535 => Self::RS1,
+            -48 => Self::MLDSA44,
+            -49 => Self::MLDSA65,
+            -50 => Self::MLDSA87,
+            other => Self::Other(other),
+        }
+    }
 }
 
 impl From<CoseKeyAlgorithmIdentifier> for i64 {
     fn from(value: CoseKeyAlgorithmIdentifier) -> Self {
         match value {
             CoseKeyAlgorithmIdentifier::ES256 => -7,
+            CoseKeyAlgorithmIdentifier::ES384 => -35,
+            CoseKeyAlgorithmIdentifier::ES512 => -36,
+            CoseKeyAlgorithmIdentifier::ESP256 => -9,
+            CoseKeyAlgorithmIdentifier::ESP384 => -51,
+            CoseKeyAlgorithmIdentifier::ESP512 => -52,
+            CoseKeyAlgorithmIdentifier::ES256K => -47,
             CoseKeyAlgorithmIdentifier::EdDSA => -8,
+            CoseKeyAlgorithmIdentifier::Ed25519 => -19,
+            CoseKeyAlgorithmIdentifier::Ed448 => -53,
+            CoseKeyAlgorithmIdentifier::PS256 => -37,
+            CoseKeyAlgorithmIdentifier::PS384 => -38,
+            CoseKeyAlgorithmIdentifier::PS512 => -39,
             CoseKeyAlgorithmIdentifier::RS256 => -257,
+            CoseKeyAlgorithmIdentifier::RS384 => -258,
+            CoseKeyAlgorithmIdentifier::RS512 => -259,
+            CoseKeyAlgorithmIdentifier::RS1 => -65535,
+            CoseKeyAlgorithmIdentifier::MLDSA44 => -48,
+            CoseKeyAlgorithmIdentifier::MLDSA65 => -49,
+            CoseKeyAlgorithmIdentifier::MLDSA87 => -50,
+            CoseKeyAlgorithmIdentifier::Other(alg) => alg,
         }
     }
 }
 
 impl From<CoseKeyAlgorithmIdentifier> for i128 {
     fn from(value: CoseKeyAlgorithmIdentifier) -> Self {
-        match value {
-            CoseKeyAlgorithmIdentifier::ES256 => -7,
-            CoseKeyAlgorithmIdentifier::EdDSA => -8,
-            CoseKeyAlgorithmIdentifier::RS256 => -257,
-        }
+        i64::from(value).into()
     }
 }
 
@@ -43,16 +129,76 @@ impl TryFrom<Ctap2COSEAlgorithmIdentifier> for CoseKeyAlgorithmIdentifier {
         match value {
             Ctap2COSEAlgorithmIdentifier::EDDSA => Ok(CoseKeyAlgorithmIdentifier::EdDSA),
             Ctap2COSEAlgorithmIdentifier::ES256 => Ok(CoseKeyAlgorithmIdentifier::ES256),
-            Ctap2COSEAlgorithmIdentifier::TOPT => {
+            // -9 was provisionally registered as "TOTP" when libwebauthn added
+            // it; it is now ESP256.
+            Ctap2COSEAlgorithmIdentifier::TOPT => Ok(CoseKeyAlgorithmIdentifier::ESP256),
+            // libwebauthn discards the value of identifiers it doesn't know, so
+            // there's nothing we can recover here.
+            Ctap2COSEAlgorithmIdentifier::Unknown => {
                 debug!("Unknown public key algorithm type: {:?}", value);
                 Err(Error::Unsupported)
             }
-            Ctap2COSEAlgorithmIdentifier::Unknown => Err(Error::Unsupported),
         }
     }
 }
 
-#[derive(Clone, Copy, PartialEq)]
+impl TryFrom<CoseKeyAlgorithmIdentifier> for Ctap2COSEAlgorithmIdentifier {
+    type Error = Error;
+
+    fn try_from(value: CoseKeyAlgorithmIdentifier) -> Result<Self, Self::Error> {
+        // libwebauthn models pubKeyCredParams as a closed enum, so only these
+        // can be requested from the authenticators that it drives.
+        match value {
+            CoseKeyAlgorithmIdentifier::ES256 => Ok(Ctap2COSEAlgorithmIdentifier::ES256),
+            CoseKeyAlgorithmIdentifier::EdDSA => Ok(Ctap2COSEAlgorithmIdentifier::EDDSA),
+            CoseKeyAlgorithmIdentifier::ESP256 => Ok(Ctap2COSEAlgorithmIdentifier::TOPT),
+            _ => Err(Error::Unsupported),
+        }
+    }
+}
+
+/// COSE key types.
+///
+/// https://www.iana.org/assignments/cose/cose.xhtml#key-type
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CoseKeyTypeIdentifier {
+    /// Octet Key Pair
+    Okp,
+    /// Elliptic Curve Keys w/ x- and y-coordinate pair
+    Ec2,
+    /// RSA Key
+    Rsa,
+    /// Algorithm Key Pair, used by ML-DSA
+    Akp,
+}
+
+impl From<CoseKeyTypeIdentifier> for i64 {
+    fn from(value: CoseKeyTypeIdentifier) -> Self {
+        match value {
+            CoseKeyTypeIdentifier::Okp => 1,
+            CoseKeyTypeIdentifier::Ec2 => 2,
+            CoseKeyTypeIdentifier::Rsa => 3,
+            CoseKeyTypeIdentifier::Akp => 7,
+        }
+    }
+}
+
+impl TryFrom<i64> for CoseKeyTypeIdentifier {
+    type Error = Error;
+
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::Okp),
+            2 => Ok(Self::Ec2),
+            3 => Ok(Self::Rsa),
+            7 => Ok(Self::Akp),
+            _ => Err(Error::Unsupported),
+        }
+    }
+}
+
+/// https://www.iana.org/assignments/cose/cose.xhtml#elliptic-curves
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum CoseEllipticCurveIdentifier {
     /// P-256 Elliptic Curve using uncompressed points.
     P256,
@@ -60,8 +206,16 @@ pub enum CoseEllipticCurveIdentifier {
     P384,
     /// P-521 Elliptic Curve using uncompressed points.
     P521,
+    /// X25519 for use w/ ECDH only
+    X25519,
+    /// X448 for use w/ ECDH only
+    X448,
     /// Ed25519 Elliptic Curve using compressed points.
     Ed25519,
+    /// Ed448 for use w/ EdDSA only
+    Ed448,
+    /// SECG secp256k1 curve
+    Secp256k1,
 }
 
 impl From<CoseEllipticCurveIdentifier> for i64 {
@@ -70,13 +224,367 @@ impl From<CoseEllipticCurveIdentifier> for i64 {
             CoseEllipticCurveIdentifier::P256 => 1,
             CoseEllipticCurveIdentifier::P384 => 2,
             CoseEllipticCurveIdentifier::P521 => 3,
+            CoseEllipticCurveIdentifier::X25519 => 4,
+            CoseEllipticCurveIdentifier::X448 => 5,
             CoseEllipticCurveIdentifier::Ed25519 => 6,
+            CoseEllipticCurveIdentifier::Ed448 => 7,
+            CoseEllipticCurveIdentifier::Secp256k1 => 8,
+        }
+    }
+}
+
+impl TryFrom<i64> for CoseEllipticCurveIdentifier {
+    type Error = Error;
+
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::P256),
+            2 => Ok(Self::P384),
+            3 => Ok(Self::P521),
+            4 => Ok(Self::X25519),
+            5 => Ok(Self::X448),
+            6 => Ok(Self::Ed25519),
+            7 => Ok(Self::Ed448),
+            8 => Ok(Self::Secp256k1),
+            _ => Err(Error::Unsupported),
         }
     }
 }
 
+/// A COSE_Key, as found in the attested credential data of a new credential.
+///
+/// https://www.rfc-editor.org/rfc/rfc9052#section-7
+#[derive(Debug, PartialEq)]
+pub(crate) struct CoseKey {
+    pub algorithm: CoseKeyAlgorithmIdentifier,
+    parameters: CoseKeyParameters,
+}
+
+#[derive(Debug, PartialEq)]
+enum CoseKeyParameters {
+    Ec2 {
+        curve: CoseEllipticCurveIdentifier,
+        x: Vec<u8>,
+        y: Vec<u8>,
+    },
+    Okp {
+        curve: CoseEllipticCurveIdentifier,
+        x: Vec<u8>,
+    },
+    Rsa {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+    /// Key types or curves we don't know how to represent as SPKI.
+    Other,
+}
+
+impl TryFrom<&cbor::Value> for CoseKey {
+    type Error = Error;
+
+    fn try_from(value: &cbor::Value) -> Result<Self, Self::Error> {
+        let cbor::Value::Map(entries) = value else {
+            return Err(Error::InvalidKey);
+        };
+        let get = |label: i128| {
+            entries
+                .iter()
+                .find(|(key, _)| *key == cbor::Value::Integer(label))
+                .map(|(_, value)| value)
+        };
+        let get_int = |label| match get(label) {
+            Some(cbor::Value::Integer(n)) => i64::try_from(*n).map_err(|_| Error::InvalidKey),
+            _ => Err(Error::InvalidKey),
+        };
+        let get_bytes = |label| match get(label) {
+            Some(cbor::Value::Bytes(data)) => Ok(data.clone()),
+            _ => Err(Error::InvalidKey),
+        };
+
+        // The algorithm is optional in COSE, but required for WebAuthn
+        // credential public keys.
+        let algorithm = get_int(3)?.into();
+        let key_type = get_int(1)?;
+        let curve = || get_int(-1).map(CoseEllipticCurveIdentifier::try_from);
+        let parameters = match CoseKeyTypeIdentifier::try_from(key_type) {
+            Ok(CoseKeyTypeIdentifier::Ec2) => match curve()? {
+                Ok(curve) => CoseKeyParameters::Ec2 {
+                    curve,
+                    x: get_bytes(-2)?,
+                    y: get_bytes(-3)?,
+                },
+                Err(_) => CoseKeyParameters::Other,
+            },
+            Ok(CoseKeyTypeIdentifier::Okp) => match curve()? {
+                Ok(curve) => CoseKeyParameters::Okp {
+                    curve,
+                    x: get_bytes(-2)?,
+                },
+                Err(_) => CoseKeyParameters::Other,
+            },
+            Ok(CoseKeyTypeIdentifier::Rsa) => CoseKeyParameters::Rsa {
+                n: get_bytes(-1)?,
+                e: get_bytes(-2)?,
+            },
+            Ok(CoseKeyTypeIdentifier::Akp) | Err(_) => {
+                debug!("No SubjectPublicKeyInfo mapping for COSE key type {key_type}");
+                CoseKeyParameters::Other
+            }
+        };
+        Ok(Self {
+            algorithm,
+            parameters,
+        })
+    }
+}
+
+impl CoseKey {
+    /// Encodes the key as a DER SubjectPublicKeyInfo, as returned by
+    /// `AuthenticatorAttestationResponse.getPublicKey()`. Returns `None` for
+    /// key types that have no SPKI representation here, which WebAuthn allows.
+    ///
+    /// https://www.w3.org/TR/webauthn-3/#sctn-public-key-easy
+    pub(crate) fn to_spki(&self) -> Option<Vec<u8>> {
+        let (algorithm, public_key) = match &self.parameters {
+            CoseKeyParameters::Ec2 { curve, x, y } => {
+                let (curve_oid, len) = match curve {
+                    CoseEllipticCurveIdentifier::P256 => (OID_SECP256R1, 32),
+                    CoseEllipticCurveIdentifier::P384 => (OID_SECP384R1, 48),
+                    CoseEllipticCurveIdentifier::P521 => (OID_SECP521R1, 66),
+                    CoseEllipticCurveIdentifier::Secp256k1 => (OID_SECP256K1, 32),
+                    _ => return None,
+                };
+                // Uncompressed point
+                let mut point = vec![0x04];
+                point.extend(left_pad(x, len)?);
+                point.extend(left_pad(y, len)?);
+                let algorithm = [der_oid(OID_EC_PUBLIC_KEY), der_oid(curve_oid)].concat();
+                (algorithm, point)
+            }
+            CoseKeyParameters::Okp { curve, x } => {
+                let oid = match curve {
+                    CoseEllipticCurveIdentifier::Ed25519 => OID_ED25519,
+                    CoseEllipticCurveIdentifier::Ed448 => OID_ED448,
+                    _ => return None,
+                };
+                (der_oid(oid), x.clone())
+            }
+            CoseKeyParameters::Rsa { n, e } => {
+                let algorithm = [der_oid(OID_RSA_ENCRYPTION), vec![0x05, 0x00]].concat();
+                let public_key = der_tlv(0x30, &[der_integer(n), der_integer(e)].concat());
+                (algorithm, public_key)
+            }
+            CoseKeyParameters::Other => return None,
+        };
+        let bit_string = der_tlv(0x03, &[&[0x00], public_key.as_slice()].concat());
+        Some(der_tlv(
+            0x30,
+            &[der_tlv(0x30, &algorithm), bit_string].concat(),
+        ))
+    }
+}
+
+// Content octets of the object identifiers used in SubjectPublicKeyInfo.
+/// 1.2.840.10045.2.1
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+/// 1.2.840.10045.3.1.7
+const OID_SECP256R1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+/// 1.3.132.0.34
+const OID_SECP384R1: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+/// 1.3.132.0.35
+const OID_SECP521R1: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x23];
+/// 1.3.132.0.10
+const OID_SECP256K1: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x0a];
+/// 1.3.101.112
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+/// 1.3.101.113
+const OID_ED448: &[u8] = &[0x2b, 0x65, 0x71];
+/// 1.2.840.113549.1.1.1
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+
+fn left_pad(data: &[u8], len: usize) -> Option<Vec<u8>> {
+    if data.len() > len {
+        return None;
+    }
+    let mut padded = vec![0; len - data.len()];
+    padded.extend_from_slice(data);
+    Some(padded)
+}
+
+fn der_tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let len_bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        out.push(0x80 | len_bytes.len() as u8);
+        out.extend(len_bytes);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+fn der_oid(oid: &[u8]) -> Vec<u8> {
+    der_tlv(0x06, oid)
+}
+
+/// Encodes an unsigned big-endian integer.
+fn der_integer(data: &[u8]) -> Vec<u8> {
+    let data = match data.iter().position(|b| *b != 0) {
+        Some(start) => &data[start..],
+        None => &[0],
+    };
+    if data[0] & 0x80 != 0 {
+        der_tlv(0x02, &[&[0x00], data].concat())
+    } else {
+        der_tlv(0x02, data)
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     InvalidKey,
     Unsupported,
 }
+
+#[cfg(test)]
+mod test {
+    use libwebauthn::proto::ctap2::Ctap2COSEAlgorithmIdentifier;
+
+    use crate::cbor::Value;
+
+    use super::{
+        CoseEllipticCurveIdentifier, CoseKey, CoseKeyAlgorithmIdentifier, CoseKeyTypeIdentifier,
+    };
+
+    fn cose_key(entries: Vec<(i128, Value)>) -> CoseKey {
+        let map = Value::Map(
+            entries
+                .into_iter()
+                .map(|(k, v)| (Value::Integer(k), v))
+                .collect(),
+        );
+        CoseKey::try_from(&map).unwrap()
+    }
+
+    #[test]
+    fn test_ec2_key_to_spki() {
+        let key = cose_key(vec![
+            (1, Value::Integer(2)),
+            (3, Value::Integer(-7)),
+            (-1, Value::Integer(1)),
+            (-2, Value::Bytes(vec![0x11; 32])),
+            (-3, Value::Bytes(vec![0x22; 32])),
+        ]);
+        assert_eq!(CoseKeyAlgorithmIdentifier::ES256, key.algorithm);
+        let spki = key.to_spki().unwrap();
+        let prefix = [
+            0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06,
+            0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00, 0x04,
+        ];
+        assert_eq!(prefix, spki[..27]);
+        assert_eq!([0x11; 32], spki[27..59]);
+        assert_eq!([0x22; 32], spki[59..]);
+    }
+
+    #[test]
+    fn test_okp_key_to_spki() {
+        let key = cose_key(vec![
+            (1, Value::Integer(1)),
+            (3, Value::Integer(-8)),
+            (-1, Value::Integer(6)),
+            (-2, Value::Bytes(vec![0x33; 32])),
+        ]);
+        let spki = key.to_spki().unwrap();
+        let prefix = [
+            0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+        ];
+        assert_eq!(prefix, spki[..12]);
+        assert_eq!([0x33; 32], spki[12..]);
+    }
+
+    #[test]
+    fn test_rsa_key_to_spki() {
+        let key = cose_key(vec![
+            (1, Value::Integer(3)),
+            (3, Value::Integer(-257)),
+            (-1, Value::Bytes(vec![0xff; 256])),
+            (-2, Value::Bytes(vec![0x01, 0x00, 0x01])),
+        ]);
+        assert_eq!(CoseKeyAlgorithmIdentifier::RS256, key.algorithm);
+        let spki = key.to_spki().unwrap();
+        // SEQUENCE (290 bytes) { SEQUENCE { rsaEncryption, NULL }, BIT STRING (271 bytes) {
+        // SEQUENCE (266 bytes) { INTEGER (257 bytes) ...
+        let prefix = [
+            0x30, 0x82, 0x01, 0x22, 0x30, 0x0d, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d,
+            0x01, 0x01, 0x01, 0x05, 0x00, 0x03, 0x82, 0x01, 0x0f, 0x00, 0x30, 0x82, 0x01, 0x0a,
+            0x02, 0x82, 0x01, 0x01, 0x00, 0xff,
+        ];
+        assert_eq!(prefix, spki[..34]);
+        assert_eq!([0x02, 0x03, 0x01, 0x00, 0x01], spki[spki.len() - 5..]);
+        assert_eq!(294, spki.len());
+    }
+
+    #[test]
+    fn test_unsupported_key_has_no_spki() {
+        let key = cose_key(vec![
+            (1, Value::Integer(7)),
+            (3, Value::Integer(-48)),
+            (-1, Value::Bytes(vec![0x44; 1312])),
+        ]);
+        assert_eq!(CoseKeyAlgorithmIdentifier::MLDSA44, key.algorithm);
+        assert_eq!(None, key.to_spki());
+
+        let map = Value::Map(vec![(Value::Integer(1), Value::Integer(2))]);
+        assert!(CoseKey::try_from(&map).is_err());
+    }
+
+    #[test]
+    fn test_algorithm_identifiers_round_trip() {
+        for alg in [
+            -7, -35, -36, -9, -51, -52, -47, -8, -19, -53, -37, -38, -39, -257, -258, -259, -65535,
+            -48, -49, -50, -1234,
+        ] {
+            assert_eq!(alg, i64::from(CoseKeyAlgorithmIdentifier::from(alg)));
+        }
+        assert_eq!(
+            CoseKeyAlgorithmIdentifier::Other(-1234),
+            CoseKeyAlgorithmIdentifier::from(-1234)
+        );
+    }
+
+    #[test]
+    fn test_libwebauthn_algorithm_identifiers_round_trip() {
+        for alg in [
+            Ctap2COSEAlgorithmIdentifier::ES256,
+            Ctap2COSEAlgorithmIdentifier::EDDSA,
+            Ctap2COSEAlgorithmIdentifier::TOPT,
+        ] {
+            let identifier = CoseKeyAlgorithmIdentifier::try_from(alg).unwrap();
+            assert_eq!(
+                alg,
+                Ctap2COSEAlgorithmIdentifier::try_from(identifier).unwrap()
+            );
+            assert_eq!(alg as i64, i64::from(identifier));
+        }
+        assert!(Ctap2COSEAlgorithmIdentifier::try_from(CoseKeyAlgorithmIdentifier::ES384).is_err());
+    }
+
+    #[test]
+    fn test_key_parameter_identifiers_round_trip() {
+        for kty in [1, 2, 3, 7] {
+            let identifier = CoseKeyTypeIdentifier::try_from(kty).unwrap();
+            assert_eq!(kty, i64::from(identifier));
+        }
+        for crv in 1..=8 {
+            let identifier = CoseEllipticCurveIdentifier::try_from(crv).unwrap();
+            assert_eq!(crv, i64::from(identifier));
+        }
+        assert!(CoseEllipticCurveIdentifier::try_from(0).is_err());
+    }
+}
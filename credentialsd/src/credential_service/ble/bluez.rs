@@ -0,0 +1,434 @@
+//! A minimal client for BlueZ's D-Bus API, covering what is needed to find,
+//! pair and talk to FIDO authenticators.
+//!
+//! See `doc/org.bluez.*.rst` in the BlueZ sources for the API.
+
+use std::{collections::HashMap, time::Duration};
+
+use futures_lite::StreamExt;
+use zbus::{
+    fdo::{ObjectManagerProxy, PropertiesChangedStream, PropertiesProxy},
+    proxy,
+    zvariant::{OwnedObjectPath, OwnedValue, Value},
+    Connection,
+};
+
+use super::super::ctap::DeviceError;
+
+const SERVICE: &str = "org.bluez";
+const ADAPTER_INTERFACE: &str = "org.bluez.Adapter1";
+const DEVICE_INTERFACE: &str = "org.bluez.Device1";
+const CHARACTERISTIC_INTERFACE: &str = "org.bluez.GattCharacteristic1";
+
+pub(super) const FIDO_SERVICE_UUID: &str = "0000fffd-0000-1000-8000-00805f9b34fb";
+pub(super) const CONTROL_POINT_UUID: &str = "f1d0fff1-dead-beef-f1d0-fff1deadbeef";
+pub(super) const STATUS_UUID: &str = "f1d0fff2-dead-beef-f1d0-fff1deadbeef";
+pub(super) const CONTROL_POINT_LENGTH_UUID: &str = "f1d0fff3-dead-beef-f1d0-fff1deadbeef";
+pub(super) const SERVICE_REVISION_BITFIELD_UUID: &str = "f1d0fff4-dead-beef-f1d0-fff1deadbeef";
+
+/// Bit of fidoServiceRevisionBitfield for CTAP2 support.
+const REVISION_FIDO2: u8 = 0x20;
+
+/// How long to wait for BlueZ to discover the GATT services after connecting.
+const SERVICES_RESOLVED_TIMEOUT: Duration = Duration::from_secs(10);
+
+type ManagedObjects =
+    HashMap<OwnedObjectPath, HashMap<zbus::names::OwnedInterfaceName, HashMap<String, OwnedValue>>>;
+
+#[proxy(
+    gen_blocking = false,
+    interface = "org.bluez.Adapter1",
+    default_service = "org.bluez"
+)]
+trait Adapter {
+    fn set_discovery_filter(&self, filter: HashMap<&str, Value<'_>>) -> zbus::Result<()>;
+    fn start_discovery(&self) -> zbus::Result<()>;
+    fn stop_discovery(&self) -> zbus::Result<()>;
+}
+
+#[proxy(
+    gen_blocking = false,
+    interface = "org.bluez.Device1",
+    default_service = "org.bluez"
+)]
+trait Device {
+    fn pair(&self) -> zbus::Result<()>;
+    fn connect(&self) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn services_resolved(&self) -> zbus::Result<bool>;
+    #[zbus(property)]
+    fn set_trusted(&self, value: bool) -> zbus::Result<()>;
+}
+
+#[proxy(
+    gen_blocking = false,
+    interface = "org.bluez.GattCharacteristic1",
+    default_service = "org.bluez"
+)]
+trait GattCharacteristic {
+    fn read_value(&self, options: HashMap<&str, Value<'_>>) -> zbus::Result<Vec<u8>>;
+    fn write_value(&self, value: &[u8], options: HashMap<&str, Value<'_>>) -> zbus::Result<()>;
+    fn start_notify(&self) -> zbus::Result<()>;
+}
+
+impl From<zbus::Error> for DeviceError {
+    fn from(value: zbus::Error) -> Self {
+        match value {
+            zbus::Error::MethodError(name, _, _)
+                if name.as_str() == "org.bluez.Error.NotConnected" =>
+            {
+                DeviceError::Disconnected
+            }
+            err => DeviceError::Transport(format!("BlueZ request failed: {err}")),
+        }
+    }
+}
+
+impl From<zbus::fdo::Error> for DeviceError {
+    fn from(value: zbus::fdo::Error) -> Self {
+        zbus::Error::from(value).into()
+    }
+}
+
+/// A FIDO authenticator that BlueZ knows about.
+#[derive(Debug)]
+pub(super) struct Authenticator {
+    pub path: OwnedObjectPath,
+    pub name: Option<String>,
+    pub paired: bool,
+}
+
+pub(super) struct BluezClient {
+    conn: Connection,
+}
+
+impl BluezClient {
+    pub fn new(conn: Connection) -> Self {
+        Self { conn }
+    }
+
+    async fn managed_objects(&self) -> Result<ManagedObjects, DeviceError> {
+        let object_manager = ObjectManagerProxy::builder(&self.conn)
+            .destination(SERVICE)?
+            .path("/")?
+            .build()
+            .await?;
+        Ok(object_manager.get_managed_objects().await?)
+    }
+
+    /// Starts discovering BLE devices that advertise the FIDO service on the
+    /// first powered adapter.
+    pub async fn start_discovery(&self) -> Result<Discovery, DeviceError> {
+        let objects = self.managed_objects().await?;
+        let path = objects
+            .iter()
+            .filter_map(|(path, interfaces)| Some((path, interfaces.get(ADAPTER_INTERFACE)?)))
+            .find(|(_, adapter)| property::<bool>(adapter, "Powered").unwrap_or(false))
+            .map(|(path, _)| path.clone())
+            .ok_or_else(|| DeviceError::Transport("No powered Bluetooth adapter".to_string()))?;
+        let adapter = AdapterProxy::builder(&self.conn)
+            .path(path)?
+            .build()
+            .await?;
+        adapter
+            .set_discovery_filter(HashMap::from([
+                ("UUIDs", Value::from(vec![FIDO_SERVICE_UUID])),
+                ("Transport", Value::from("le")),
+            ]))
+            .await?;
+        adapter.start_discovery().await?;
+        Ok(Discovery { adapter })
+    }
+
+    /// Returns a FIDO authenticator that is in range, preferring those that
+    /// are already paired.
+    pub async fn find_authenticator(&self) -> Result<Option<Authenticator>, DeviceError> {
+        let objects = self.managed_objects().await?;
+        let mut authenticators: Vec<Authenticator> = objects
+            .into_iter()
+            .filter_map(|(path, mut interfaces)| {
+                let device = interfaces.remove(DEVICE_INTERFACE)?;
+                let uuids: Vec<String> = property(&device, "UUIDs").unwrap_or_default();
+                if !uuids.iter().any(|uuid| uuid == FIDO_SERVICE_UUID) {
+                    return None;
+                }
+                // Devices stay in BlueZ's cache after they go out of range,
+                // but only those in range have an RSSI.
+                let connected = property(&device, "Connected").unwrap_or(false);
+                if !connected && !device.contains_key("RSSI") {
+                    return None;
+                }
+                Some(Authenticator {
+                    path,
+                    name: property(&device, "Alias").or_else(|| property(&device, "Name")),
+                    paired: property(&device, "Paired").unwrap_or(false),
+                })
+            })
+            .collect();
+        authenticators.sort_by_key(|authenticator| !authenticator.paired);
+        Ok(authenticators.into_iter().next())
+    }
+
+    /// Pairs with and connects to the authenticator, and opens its FIDO
+    /// service.
+    ///
+    /// If the authenticator asks for a passkey while pairing, it is requested
+    /// by the Bluetooth agent of the desktop environment.
+    pub async fn connect(&self, authenticator: &Authenticator) -> Result<FidoService, DeviceError> {
+        let device = DeviceProxy::builder(&self.conn)
+            .path(authenticator.path.clone())?
+            .build()
+            .await?;
+        if !authenticator.paired {
+            tracing::debug!("Pairing with BLE authenticator {}", authenticator.path);
+            device.pair().await?;
+            device.set_trusted(true).await?;
+        }
+        device.connect().await?;
+        self.wait_for_services(&device).await?;
+
+        let objects = self.managed_objects().await?;
+        let prefix = format!("{}/", authenticator.path.as_str());
+        let characteristics: HashMap<String, OwnedObjectPath> = objects
+            .into_iter()
+            .filter(|(path, _)| path.as_str().starts_with(&prefix))
+            .filter_map(|(path, interfaces)| {
+                let uuid = property(interfaces.get(CHARACTERISTIC_INTERFACE)?, "UUID")?;
+                Some((uuid, path))
+            })
+            .collect();
+        let control_point = self
+            .characteristic(&characteristics, CONTROL_POINT_UUID)
+            .await?;
+        let status = self.characteristic(&characteristics, STATUS_UUID).await?;
+        let control_point_length = self
+            .characteristic(&characteristics, CONTROL_POINT_LENGTH_UUID)
+            .await?;
+        let revision = self
+            .characteristic(&characteristics, SERVICE_REVISION_BITFIELD_UUID)
+            .await?;
+
+        // Authenticators that also support U2F need to be told which protocol to use.
+        let revisions = revision.read_value(HashMap::new()).await?;
+        if revisions
+            .first()
+            .is_none_or(|bits| bits & REVISION_FIDO2 == 0)
+        {
+            return Err(DeviceError::InvalidResponse(
+                "Authenticator does not support CTAP2".to_string(),
+            ));
+        }
+        revision
+            .write_value(&[REVISION_FIDO2], HashMap::new())
+            .await?;
+
+        let max_len = match control_point_length.read_value(HashMap::new()).await?[..] {
+            [high, low] => u16::from_be_bytes([high, low]) as usize,
+            ref value => {
+                return Err(DeviceError::InvalidResponse(format!(
+                    "Invalid fidoControlPointLength: {value:?}"
+                )))
+            }
+        };
+
+        // Subscribe before enabling notifications, so that none are missed.
+        let notifications = PropertiesProxy::builder(&self.conn)
+            .destination(SERVICE)?
+            .path(status.inner().path().to_owned())?
+            .build()
+            .await?
+            .receive_properties_changed()
+            .await?;
+        status.start_notify().await?;
+
+        Ok(FidoService {
+            control_point,
+            notifications,
+            max_len,
+        })
+    }
+
+    async fn characteristic(
+        &self,
+        characteristics: &HashMap<String, OwnedObjectPath>,
+        uuid: &str,
+    ) -> Result<GattCharacteristicProxy<'static>, DeviceError> {
+        let path = characteristics.get(uuid).ok_or_else(|| {
+            DeviceError::InvalidResponse(format!("Missing FIDO characteristic {uuid}"))
+        })?;
+        Ok(GattCharacteristicProxy::builder(&self.conn)
+            .path(path.clone())?
+            .build()
+            .await?)
+    }
+
+    async fn wait_for_services(&self, device: &DeviceProxy<'_>) -> Result<(), DeviceError> {
+        let mut changes = device.receive_services_resolved_changed().await;
+        if device.services_resolved().await? {
+            return Ok(());
+        }
+        let resolved = async {
+            while let Some(change) = changes.next().await {
+                if change.get().await? {
+                    return Ok(());
+                }
+            }
+            Err(DeviceError::Disconnected)
+        };
+        tokio::time::timeout(SERVICES_RESOLVED_TIMEOUT, resolved)
+            .await
+            .map_err(|_| {
+                DeviceError::Transport("Timed out discovering the FIDO service".to_string())
+            })?
+    }
+}
+
+/// A discovery session on an adapter.
+pub(super) struct Discovery {
+    adapter: AdapterProxy<'static>,
+}
+
+impl Discovery {
+    pub async fn stop(self) -> Result<(), DeviceError> {
+        Ok(self.adapter.stop_discovery().await?)
+    }
+}
+
+/// The FIDO service of a connected authenticator.
+pub(super) struct FidoService {
+    control_point: GattCharacteristicProxy<'static>,
+    notifications: PropertiesChangedStream,
+    /// Maximum length of a fragment, as read from fidoControlPointLength.
+    pub max_len: usize,
+}
+
+impl FidoService {
+    /// Writes a fragment to fidoControlPoint.
+    pub async fn write(&self, fragment: &[u8]) -> Result<(), DeviceError> {
+        Ok(self
+            .control_point
+            .write_value(fragment, HashMap::new())
+            .await?)
+    }
+
+    /// Waits for the next fragment notified on fidoStatus.
+    pub async fn read(&mut self) -> Result<Vec<u8>, DeviceError> {
+        while let Some(signal) = self.notifications.next().await {
+            let args = signal.args()?;
+            if args.interface_name() != CHARACTERISTIC_INTERFACE {
+                continue;
+            }
+            if let Some(value) = args.changed_properties().get("Value") {
+                return Vec::<u8>::try_from(value.try_to_owned().map_err(zbus::Error::from)?)
+                    .map_err(|err| DeviceError::InvalidResponse(err.to_string()));
+            }
+        }
+        Err(DeviceError::Disconnected)
+    }
+}
+
+fn property<T>(properties: &HashMap<String, OwnedValue>, name: &str) -> Option<T>
+where
+    T: TryFrom<OwnedValue>,
+{
+    properties.get(name)?.try_clone().ok()?.try_into().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::mock::{self, Authenticator, MockBluez, Script},
+        BluezClient, DeviceError,
+    };
+
+    const UNPAIRED: &str = "00_11_22_33_44_55";
+    const PAIRED: &str = "66_77_88_99_AA_BB";
+    const OUT_OF_RANGE: &str = "CC_DD_EE_FF_00_11";
+
+    #[tokio::test]
+    async fn test_discovery_is_started_and_stopped() {
+        let bluez = MockBluez::serve(Vec::new(), &[]).await;
+        let client = BluezClient::new(bluez.conn.clone());
+
+        let discovery = client.start_discovery().await.unwrap();
+        assert!(bluez.calls.lock().unwrap().discovering);
+        discovery.stop().await.unwrap();
+        assert!(!bluez.calls.lock().unwrap().discovering);
+    }
+
+    #[tokio::test]
+    async fn test_paired_authenticators_in_range_are_preferred() {
+        let script = Script::default();
+        let paired = Authenticator {
+            paired: true,
+            ..Authenticator::new(PAIRED, &script)
+        };
+        let bluez = MockBluez::serve(
+            vec![Authenticator::new(UNPAIRED, &script), paired],
+            &[OUT_OF_RANGE],
+        )
+        .await;
+        let client = BluezClient::new(bluez.conn.clone());
+
+        let authenticator = client.find_authenticator().await.unwrap().unwrap();
+        assert_eq!(mock::device_path(PAIRED), authenticator.path.as_str());
+        assert!(authenticator.paired);
+        assert_eq!(Some("Mock Security Key"), authenticator.name.as_deref());
+    }
+
+    #[tokio::test]
+    async fn test_authenticators_out_of_range_are_ignored() {
+        let bluez = MockBluez::serve(Vec::new(), &[OUT_OF_RANGE]).await;
+        let client = BluezClient::new(bluez.conn.clone());
+
+        assert!(client.find_authenticator().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unpaired_authenticators_are_paired_and_trusted() {
+        let bluez =
+            MockBluez::serve(vec![Authenticator::new(UNPAIRED, &Script::default())], &[]).await;
+        let client = BluezClient::new(bluez.conn.clone());
+
+        let authenticator = client.find_authenticator().await.unwrap().unwrap();
+        let service = client.connect(&authenticator).await.unwrap();
+        assert_eq!(usize::from(mock::MAX_FRAGMENT_LEN), service.max_len);
+        let calls = bluez.calls.lock().unwrap();
+        assert_eq!(vec![mock::device_path(UNPAIRED)], calls.paired);
+        assert_eq!(vec![mock::device_path(UNPAIRED)], calls.trusted);
+    }
+
+    #[tokio::test]
+    async fn test_paired_authenticators_are_not_paired_again() {
+        let paired = Authenticator {
+            paired: true,
+            ..Authenticator::new(PAIRED, &Script::default())
+        };
+        let bluez = MockBluez::serve(vec![paired], &[]).await;
+        let client = BluezClient::new(bluez.conn.clone());
+
+        let authenticator = client.find_authenticator().await.unwrap().unwrap();
+        client.connect(&authenticator).await.unwrap();
+        let calls = bluez.calls.lock().unwrap();
+        assert!(calls.paired.is_empty());
+        assert!(calls.trusted.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_u2f_only_authenticators_are_rejected() {
+        let u2f_only = Authenticator {
+            // U2F 1.1 and 1.2
+            revisions: 0xc0,
+            ..Authenticator::new(UNPAIRED, &Script::default())
+        };
+        let bluez = MockBluez::serve(vec![u2f_only], &[]).await;
+        let client = BluezClient::new(bluez.conn.clone());
+
+        let authenticator = client.find_authenticator().await.unwrap().unwrap();
+        assert!(matches!(
+            client.connect(&authenticator).await,
+            Err(DeviceError::InvalidResponse(_))
+        ));
+    }
+}
@@ -0,0 +1,183 @@
+//! CTAP framing over BLE, as specified in the "Bluetooth Smart / Bluetooth
+//! Low Energy Technology" section of the CTAP 2.1 specification.
+//!
+//! Frames are split into fragments that fit into one write to the
+//! fidoControlPoint characteristic, or one notification of fidoStatus. The
+//! first fragment carries the command and the length of the whole frame, and
+//! the following ones a sequence number.
+
+use super::super::ctap::DeviceError;
+
+pub(super) const CMD_KEEPALIVE: u8 = 0x82;
+pub(super) const CMD_MSG: u8 = 0x83;
+pub(super) const CMD_CANCEL: u8 = 0xbe;
+pub(super) const CMD_ERROR: u8 = 0xbf;
+
+/// Length of the header of the first fragment: command, and frame length.
+const INIT_HEADER_LEN: usize = 3;
+/// Length of the header of the following fragments: sequence number.
+const CONT_HEADER_LEN: usize = 1;
+/// Sequence numbers wrap around after this.
+const MAX_SEQ: u8 = 0x7f;
+
+#[derive(Debug, PartialEq)]
+pub(super) struct Frame {
+    pub cmd: u8,
+    pub data: Vec<u8>,
+}
+
+/// Splits a frame into fragments of at most `max_len` bytes.
+pub(super) fn fragment(cmd: u8, data: &[u8], max_len: usize) -> Result<Vec<Vec<u8>>, DeviceError> {
+    let len = u16::try_from(data.len()).map_err(|_| {
+        DeviceError::InvalidResponse(format!("Frame is too long: {} bytes", data.len()))
+    })?;
+    if max_len <= INIT_HEADER_LEN {
+        return Err(DeviceError::InvalidResponse(format!(
+            "Invalid fidoControlPointLength: {max_len}"
+        )));
+    }
+    let (first, mut rest) = data.split_at(data.len().min(max_len - INIT_HEADER_LEN));
+    let mut fragment = vec![cmd];
+    fragment.extend(len.to_be_bytes());
+    fragment.extend(first);
+    let mut fragments = vec![fragment];
+
+    let mut seq = 0;
+    while !rest.is_empty() {
+        let (chunk, remaining) = rest.split_at(rest.len().min(max_len - CONT_HEADER_LEN));
+        let mut fragment = vec![seq];
+        fragment.extend(chunk);
+        fragments.push(fragment);
+        rest = remaining;
+        seq = if seq == MAX_SEQ { 0 } else { seq + 1 };
+    }
+    Ok(fragments)
+}
+
+/// Collects fragments until a whole frame has been received.
+#[derive(Debug, Default)]
+pub(super) struct Reassembler {
+    /// Command and expected length of the frame being received, if any.
+    header: Option<(u8, usize)>,
+    data: Vec<u8>,
+    next_seq: u8,
+}
+
+impl Reassembler {
+    /// Adds a fragment, returning the frame once it is complete.
+    pub fn push(&mut self, fragment: &[u8]) -> Result<Option<Frame>, DeviceError> {
+        let (cmd, len) = match self.header {
+            None => {
+                let [cmd, hlen, llen, data @ ..] = fragment else {
+                    return Err(DeviceError::InvalidResponse(format!(
+                        "Initial fragment is too short: {} bytes",
+                        fragment.len()
+                    )));
+                };
+                if cmd & 0x80 == 0 {
+                    return Err(DeviceError::InvalidResponse(format!(
+                        "Expected an initial fragment, got sequence number {cmd}"
+                    )));
+                }
+                let header = (*cmd, u16::from_be_bytes([*hlen, *llen]) as usize);
+                self.header = Some(header);
+                self.data.clear();
+                self.data.extend(data);
+                self.next_seq = 0;
+                header
+            }
+            Some(header) => {
+                let [seq, data @ ..] = fragment else {
+                    return Err(DeviceError::InvalidResponse(
+                        "Empty continuation fragment".to_string(),
+                    ));
+                };
+                if *seq != self.next_seq {
+                    self.header = None;
+                    return Err(DeviceError::InvalidResponse(format!(
+                        "Expected sequence number {}, got {seq}",
+                        self.next_seq
+                    )));
+                }
+                self.data.extend(data);
+                self.next_seq = if *seq == MAX_SEQ { 0 } else { seq + 1 };
+                header
+            }
+        };
+        if self.data.len() < len {
+            return Ok(None);
+        }
+        self.header = None;
+        if self.data.len() > len {
+            return Err(DeviceError::InvalidResponse(format!(
+                "Expected {len} bytes in frame, got {}",
+                self.data.len()
+            )));
+        }
+        Ok(Some(Frame {
+            cmd,
+            data: std::mem::take(&mut self.data),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fragment, Frame, Reassembler, CMD_KEEPALIVE, CMD_MSG};
+
+    #[test]
+    fn test_short_frame_is_not_fragmented() {
+        let fragments = fragment(CMD_MSG, &[0x04], 20).unwrap();
+        assert_eq!(vec![vec![CMD_MSG, 0x00, 0x01, 0x04]], fragments);
+    }
+
+    #[test]
+    fn test_long_frame_is_fragmented() {
+        let data: Vec<u8> = (0..40).collect();
+        let fragments = fragment(CMD_MSG, &data, 20).unwrap();
+        assert_eq!(3, fragments.len());
+        assert_eq!([CMD_MSG, 0x00, 40], fragments[0][..3]);
+        assert_eq!(data[..17], fragments[0][3..]);
+        assert_eq!(0, fragments[1][0]);
+        assert_eq!(data[17..36], fragments[1][1..]);
+        assert_eq!(1, fragments[2][0]);
+        assert_eq!(data[36..], fragments[2][1..]);
+    }
+
+    #[test]
+    fn test_fragments_are_reassembled() {
+        let data: Vec<u8> = (0..=255).cycle().take(3000).collect();
+        let mut reassembler = Reassembler::default();
+        let mut frames = Vec::new();
+        for fragment in fragment(CMD_MSG, &data, 20).unwrap() {
+            frames.extend(reassembler.push(&fragment).unwrap());
+        }
+        assert_eq!(vec![Frame { cmd: CMD_MSG, data }], frames);
+    }
+
+    #[test]
+    fn test_keepalives_are_single_fragments() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(
+            Some(Frame {
+                cmd: CMD_KEEPALIVE,
+                data: vec![0x02]
+            }),
+            reassembler
+                .push(&[CMD_KEEPALIVE, 0x00, 0x01, 0x02])
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_out_of_order_fragments_are_rejected() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(
+            None,
+            reassembler
+                .push(&[CMD_MSG, 0x00, 0x04, 0x00, 0x01])
+                .unwrap()
+        );
+        assert!(reassembler.push(&[0x01, 0x02, 0x03]).is_err());
+    }
+}
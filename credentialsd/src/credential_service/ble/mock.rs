@@ -0,0 +1,360 @@
+//! A mock of BlueZ's D-Bus API, with FIDO authenticators that follow a
+//! script.
+//!
+//! It is served over a peer-to-peer connection in tests, where the bus name
+//! that the BlueZ client talks to is not used.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use zbus::{
+    connection::Builder, fdo::ObjectManager, interface, object_server::SignalEmitter,
+    zvariant::Value, Connection, Guid,
+};
+
+use super::{
+    bluez::{
+        CONTROL_POINT_LENGTH_UUID, CONTROL_POINT_UUID, FIDO_SERVICE_UUID,
+        SERVICE_REVISION_BITFIELD_UUID, STATUS_UUID,
+    },
+    framing::{self, Frame, Reassembler},
+};
+
+const ADAPTER_PATH: &str = "/org/bluez/hci0";
+/// Small enough that requests span several fragments.
+pub(super) const MAX_FRAGMENT_LEN: u16 = 20;
+
+/// Frames an authenticator expects, in order, and the frames it replies with
+/// to each of them.
+pub(super) type Script = Arc<Mutex<VecDeque<(Frame, Vec<Frame>)>>>;
+
+/// A FIDO authenticator in range of the adapter.
+pub(super) struct Authenticator {
+    /// Bluetooth address, as it appears in the object path.
+    pub address: &'static str,
+    pub paired: bool,
+    /// Value of fidoServiceRevisionBitfield.
+    pub revisions: u8,
+    pub script: Script,
+}
+
+impl Authenticator {
+    /// An unpaired authenticator that supports CTAP2 and U2F.
+    pub fn new(address: &'static str, script: &Script) -> Self {
+        Self {
+            address,
+            paired: false,
+            revisions: 0xe0,
+            script: script.clone(),
+        }
+    }
+}
+
+/// Returns the object path of the device with the given address.
+pub(super) fn device_path(address: &str) -> String {
+    format!("{ADAPTER_PATH}/dev_{address}")
+}
+
+/// What the mock BlueZ has been asked to do.
+#[derive(Debug, Default)]
+pub(super) struct Calls {
+    pub discovering: bool,
+    /// Object paths of the devices that were paired with.
+    pub paired: Vec<String>,
+    /// Object paths of the devices that were marked as trusted.
+    pub trusted: Vec<String>,
+}
+
+pub(super) struct MockBluez {
+    /// The client end of the connection, to give to the code under test.
+    pub conn: Connection,
+    pub calls: Arc<Mutex<Calls>>,
+    // Objects are served as long as the server end is alive.
+    _server: Connection,
+}
+
+impl MockBluez {
+    /// Serves a powered adapter with the given authenticators in range, and
+    /// paired authenticators at the `remembered` addresses that are not.
+    pub async fn serve(authenticators: Vec<Authenticator>, remembered: &[&str]) -> Self {
+        let calls = Arc::new(Mutex::new(Calls::default()));
+        let (server, client) = tokio::net::UnixStream::pair().unwrap();
+        let mut server = Builder::unix_stream(server)
+            .server(Guid::generate())
+            .unwrap()
+            .p2p()
+            .serve_at("/", ObjectManager)
+            .unwrap()
+            .serve_at(
+                ADAPTER_PATH,
+                MockAdapter {
+                    calls: calls.clone(),
+                },
+            )
+            .unwrap();
+        for address in remembered {
+            server = server
+                .serve_at(device_path(address), RememberedDevice)
+                .unwrap();
+        }
+        for authenticator in authenticators {
+            let path = device_path(authenticator.address);
+            let service_path = format!("{path}/service0010");
+            let status_path = format!("{service_path}/char0012");
+            let characteristics = [
+                ("char000e", CONTROL_POINT_UUID, Vec::new()),
+                ("char0012", STATUS_UUID, Vec::new()),
+                (
+                    "char0014",
+                    CONTROL_POINT_LENGTH_UUID,
+                    MAX_FRAGMENT_LEN.to_be_bytes().to_vec(),
+                ),
+                (
+                    "char0016",
+                    SERVICE_REVISION_BITFIELD_UUID,
+                    vec![authenticator.revisions],
+                ),
+            ];
+            server = server
+                .serve_at(
+                    path.clone(),
+                    MockDevice {
+                        path: path.clone(),
+                        paired: authenticator.paired,
+                        trusted: authenticator.paired,
+                        connected: false,
+                        calls: calls.clone(),
+                    },
+                )
+                .unwrap()
+                .serve_at(service_path.clone(), MockGattService)
+                .unwrap();
+            for (name, uuid, value) in characteristics {
+                server = server
+                    .serve_at(
+                        format!("{service_path}/{name}"),
+                        MockCharacteristic {
+                            uuid,
+                            value,
+                            status_path: status_path.clone(),
+                            script: authenticator.script.clone(),
+                            reassembler: Reassembler::default(),
+                        },
+                    )
+                    .unwrap();
+            }
+        }
+        let (server, client) =
+            futures_lite::future::zip(server.build(), Builder::unix_stream(client).p2p().build())
+                .await;
+        Self {
+            conn: client.unwrap(),
+            calls,
+            _server: server.unwrap(),
+        }
+    }
+}
+
+struct MockAdapter {
+    calls: Arc<Mutex<Calls>>,
+}
+
+#[interface(name = "org.bluez.Adapter1")]
+impl MockAdapter {
+    fn set_discovery_filter(&self, filter: HashMap<String, Value<'_>>) {
+        assert_eq!(
+            Some(&Value::from(vec![FIDO_SERVICE_UUID])),
+            filter.get("UUIDs")
+        );
+    }
+
+    fn start_discovery(&self) {
+        self.calls.lock().unwrap().discovering = true;
+    }
+
+    fn stop_discovery(&self) {
+        self.calls.lock().unwrap().discovering = false;
+    }
+
+    #[zbus(property)]
+    fn powered(&self) -> bool {
+        true
+    }
+}
+
+struct MockDevice {
+    path: String,
+    paired: bool,
+    trusted: bool,
+    connected: bool,
+    calls: Arc<Mutex<Calls>>,
+}
+
+#[interface(name = "org.bluez.Device1")]
+impl MockDevice {
+    fn pair(&mut self) {
+        self.paired = true;
+        self.calls.lock().unwrap().paired.push(self.path.clone());
+    }
+
+    async fn connect(&mut self, #[zbus(signal_emitter)] emitter: SignalEmitter<'_>) {
+        self.connected = true;
+        _ = self.services_resolved_changed(&emitter).await;
+    }
+
+    #[zbus(property, name = "UUIDs")]
+    fn uuids(&self) -> Vec<String> {
+        vec![FIDO_SERVICE_UUID.to_string()]
+    }
+
+    #[zbus(property, name = "RSSI")]
+    fn rssi(&self) -> i16 {
+        -60
+    }
+
+    #[zbus(property)]
+    fn alias(&self) -> &str {
+        "Mock Security Key"
+    }
+
+    #[zbus(property)]
+    fn paired(&self) -> bool {
+        self.paired
+    }
+
+    #[zbus(property)]
+    fn trusted(&self) -> bool {
+        self.trusted
+    }
+
+    #[zbus(property)]
+    fn set_trusted(&mut self, value: bool) {
+        self.trusted = value;
+        if value {
+            self.calls.lock().unwrap().trusted.push(self.path.clone());
+        }
+    }
+
+    #[zbus(property)]
+    fn connected(&self) -> bool {
+        self.connected
+    }
+
+    #[zbus(property)]
+    fn services_resolved(&self) -> bool {
+        self.connected
+    }
+}
+
+/// A paired authenticator that BlueZ still knows about, but that is out of
+/// range, so it has no RSSI.
+struct RememberedDevice;
+
+#[interface(name = "org.bluez.Device1")]
+impl RememberedDevice {
+    fn pair(&self) {
+        panic!("Out of range devices cannot be paired with");
+    }
+
+    fn connect(&self) {
+        panic!("Out of range devices cannot be connected to");
+    }
+
+    #[zbus(property, name = "UUIDs")]
+    fn uuids(&self) -> Vec<String> {
+        vec![FIDO_SERVICE_UUID.to_string()]
+    }
+
+    #[zbus(property)]
+    fn paired(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn connected(&self) -> bool {
+        false
+    }
+}
+
+struct MockGattService;
+
+#[interface(name = "org.bluez.GattService1")]
+impl MockGattService {
+    #[zbus(property, name = "UUID")]
+    fn uuid(&self) -> &str {
+        FIDO_SERVICE_UUID
+    }
+}
+
+/// A characteristic of the FIDO service. Frames written to the control point
+/// are checked against the script, and the scripted replies are notified on
+/// the status characteristic.
+struct MockCharacteristic {
+    uuid: &'static str,
+    value: Vec<u8>,
+    status_path: String,
+    script: Script,
+    reassembler: Reassembler,
+}
+
+#[interface(name = "org.bluez.GattCharacteristic1")]
+impl MockCharacteristic {
+    fn read_value(&self, _options: HashMap<String, Value<'_>>) -> Vec<u8> {
+        self.value.clone()
+    }
+
+    async fn write_value(
+        &mut self,
+        value: Vec<u8>,
+        _options: HashMap<String, Value<'_>>,
+        #[zbus(connection)] conn: &Connection,
+    ) {
+        if self.uuid != CONTROL_POINT_UUID {
+            self.value = value;
+            return;
+        }
+        let Some(frame) = self.reassembler.push(&value).unwrap() else {
+            return;
+        };
+        let (expected, replies) = self
+            .script
+            .lock()
+            .unwrap()
+            .pop_front()
+            .expect("a frame to be scripted");
+        assert_eq!(expected, frame);
+
+        let status = conn
+            .object_server()
+            .interface::<_, MockCharacteristic>(self.status_path.as_str())
+            .await
+            .unwrap();
+        for reply in replies {
+            let fragments =
+                framing::fragment(reply.cmd, &reply.data, MAX_FRAGMENT_LEN.into()).unwrap();
+            for fragment in fragments {
+                status.get_mut().await.value = fragment;
+                status
+                    .get()
+                    .await
+                    .value_changed(status.signal_emitter())
+                    .await
+                    .unwrap();
+            }
+        }
+    }
+
+    fn start_notify(&self) {}
+
+    #[zbus(property, name = "UUID")]
+    fn uuid(&self) -> &str {
+        self.uuid
+    }
+
+    #[zbus(property)]
+    fn value(&self) -> Vec<u8> {
+        self.value.clone()
+    }
+}
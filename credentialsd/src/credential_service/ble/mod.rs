@@ -0,0 +1,384 @@
+//! Authenticators speaking CTAP over Bluetooth Low Energy, reached through
+//! BlueZ.
+
+mod bluez;
+mod framing;
+#[cfg(test)]
+mod mock;
+
+use std::{thread, time::Duration};
+
+use async_stream::stream;
+use tokio::{
+    runtime::Handle,
+    sync::mpsc::{self, Sender},
+};
+use zbus::Connection;
+
+use credentialsd_common::model::{CredentialRequest, Error, Transport};
+
+use self::{
+    bluez::{Authenticator, BluezClient, FidoService},
+    framing::{Reassembler, CMD_CANCEL, CMD_ERROR, CMD_KEEPALIVE, CMD_MSG},
+};
+use super::{
+    ctap::{self, send_state, CtapDevice, CtapOperation, CtapResponse, DeviceError, KeepAlive},
+    management::ManagementRequest,
+    transport::{AuthenticatorState, AuthenticatorStates, AuthenticatorTransport},
+};
+
+/// How often to look for authenticators while discovering.
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait for the next fragment from the authenticator. While busy,
+/// authenticators send keepalives every few hundred milliseconds.
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const STATUS_PROCESSING: u8 = 0x01;
+const STATUS_UP_NEEDED: u8 = 0x02;
+
+#[derive(Debug, Default)]
+pub struct BleHandler {
+    /// Connection to BlueZ. If not set, the system bus is connected to for
+    /// each request.
+    conn: Option<Connection>,
+}
+
+impl BleHandler {
+    /// Runs the operation on the first authenticator found, streaming its
+    /// states.
+    fn run(&self, operation: CtapOperation) -> AuthenticatorStates {
+        let conn = self.conn.clone();
+        let rt = Handle::current();
+        let (tx, mut rx) = mpsc::channel(32);
+        // Blocking tasks cannot be aborted: dropping the stream closes the
+        // channel instead, which the task checks for.
+        tokio::task::spawn_blocking(move || BleHandler::process(rt, conn, tx, operation));
+        Box::pin(stream! {
+            while let Some(state) = rx.recv().await {
+                yield state
+            }
+        })
+    }
+
+    /// Runs the whole BLE flow. The CTAP ceremony is blocking, so this runs
+    /// on a blocking thread, and stops once the receiver of the state updates
+    /// is dropped.
+    fn process(
+        rt: Handle,
+        conn: Option<Connection>,
+        tx: Sender<AuthenticatorState>,
+        operation: CtapOperation,
+    ) {
+        let state = match Self::process_device(&rt, conn, &tx, &operation) {
+            Ok(response) => ctap::complete(&tx, response, "ble"),
+            Err(DeviceError::Cancelled) => {
+                tracing::debug!("BLE request cancelled");
+                return;
+            }
+            Err(err) => {
+                tracing::warn!("Failed to use BLE authenticator: {err}");
+                AuthenticatorState::Failed(err.into())
+            }
+        };
+        _ = tx.blocking_send(state);
+    }
+
+    /// Waits for a FIDO authenticator to come in range, and runs the operation with it.
+    fn process_device(
+        rt: &Handle,
+        conn: Option<Connection>,
+        tx: &Sender<AuthenticatorState>,
+        operation: &CtapOperation,
+    ) -> Result<CtapResponse, DeviceError> {
+        let conn = match conn {
+            Some(conn) => conn,
+            None => rt.block_on(Connection::system()).map_err(|err| {
+                DeviceError::Transport(format!("Failed to connect to the system bus: {err}"))
+            })?,
+        };
+        let client = BluezClient::new(conn);
+        send_state(tx, AuthenticatorState::Waiting)?;
+        loop {
+            let authenticator = Self::discover(rt, &client, tx)?;
+            tracing::debug!(
+                "Found BLE authenticator {}",
+                authenticator
+                    .name
+                    .as_deref()
+                    .unwrap_or(authenticator.path.as_str())
+            );
+            let service = rt.block_on(client.connect(&authenticator))?;
+            send_state(tx, AuthenticatorState::Connected)?;
+
+            let mut device = BleDevice { rt, service };
+            match ctap::run(&mut device, tx, operation) {
+                // Authenticators may go out of range or to sleep, so let the user try again.
+                Err(DeviceError::Disconnected) => {
+                    tracing::debug!("BLE authenticator disconnected during the ceremony");
+                    send_state(tx, AuthenticatorState::Waiting)?;
+                }
+                response => return response,
+            }
+        }
+    }
+
+    /// Looks for authenticators until one is in range. Discovery is stopped
+    /// again before connecting, as BlueZ recommends.
+    fn discover(
+        rt: &Handle,
+        client: &BluezClient,
+        tx: &Sender<AuthenticatorState>,
+    ) -> Result<Authenticator, DeviceError> {
+        let discovery = rt.block_on(client.start_discovery())?;
+        let authenticator = loop {
+            match rt.block_on(client.find_authenticator()) {
+                Ok(Some(authenticator)) => break Ok(authenticator),
+                Ok(None) if tx.is_closed() => break Err(DeviceError::Cancelled),
+                Ok(None) => thread::sleep(DISCOVERY_INTERVAL),
+                Err(err) => break Err(err),
+            }
+        };
+        if let Err(err) = rt.block_on(discovery.stop()) {
+            tracing::debug!("Failed to stop Bluetooth discovery: {err}");
+        }
+        authenticator
+    }
+}
+
+impl AuthenticatorTransport for BleHandler {
+    fn transport(&self) -> Transport {
+        Transport::Ble
+    }
+
+    fn start(&self, _device_id: &str, request: &CredentialRequest) -> AuthenticatorStates {
+        self.run(CtapOperation::Ceremony(Box::new(request.clone())))
+    }
+
+    fn can_manage(&self, request: &ManagementRequest) -> bool {
+        ctap::can_manage(request)
+    }
+
+    fn manage(
+        &self,
+        _device_id: &str,
+        request: &ManagementRequest,
+    ) -> Result<AuthenticatorStates, Error> {
+        Ok(self.run(CtapOperation::Manage(request.clone())))
+    }
+}
+
+/// A connected authenticator, driven from a blocking thread.
+struct BleDevice<'a> {
+    rt: &'a Handle,
+    service: FidoService,
+}
+
+impl BleDevice<'_> {
+    fn send_frame(&mut self, cmd: u8, data: &[u8]) -> Result<(), DeviceError> {
+        for fragment in framing::fragment(cmd, data, self.service.max_len)? {
+            self.rt.block_on(self.service.write(&fragment))?;
+        }
+        Ok(())
+    }
+
+    fn receive_frame(&mut self) -> Result<framing::Frame, DeviceError> {
+        let mut reassembler = Reassembler::default();
+        loop {
+            let fragment = self
+                .rt
+                .block_on(tokio::time::timeout(FRAGMENT_TIMEOUT, self.service.read()))
+                .map_err(|_| DeviceError::Disconnected)??;
+            if let Some(frame) = reassembler.push(&fragment)? {
+                return Ok(frame);
+            }
+        }
+    }
+}
+
+impl CtapDevice for BleDevice<'_> {
+    fn send_cbor(
+        &mut self,
+        request: &[u8],
+        on_keepalive: &mut dyn FnMut(KeepAlive) -> bool,
+    ) -> Result<Vec<u8>, DeviceError> {
+        self.send_frame(CMD_MSG, request)?;
+        loop {
+            let frame = self.receive_frame()?;
+            match frame.cmd {
+                CMD_MSG => return Ok(frame.data),
+                CMD_KEEPALIVE => {
+                    let keepalive = match frame.data.first() {
+                        Some(&STATUS_UP_NEEDED) => KeepAlive::UserPresenceNeeded,
+                        Some(&STATUS_PROCESSING) => KeepAlive::Processing,
+                        status => {
+                            return Err(DeviceError::InvalidResponse(format!(
+                                "Unknown BLE keepalive status: {status:?}"
+                            )))
+                        }
+                    };
+                    if !on_keepalive(keepalive) {
+                        _ = self.send_frame(CMD_CANCEL, &[]);
+                        return Err(DeviceError::Cancelled);
+                    }
+                }
+                CMD_ERROR => {
+                    return Err(DeviceError::Transport(format!(
+                        "Authenticator returned BLE error {:?}",
+                        frame.data
+                    )))
+                }
+                cmd => {
+                    return Err(DeviceError::InvalidResponse(format!(
+                        "Unexpected BLE command {cmd:#04x}"
+                    )))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl BleHandler {
+    fn with_connection(conn: Connection) -> Self {
+        Self { conn: Some(conn) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use futures_lite::StreamExt;
+    use libwebauthn::ops::webauthn::{GetAssertionRequest, UserVerificationRequirement};
+    use tokio::runtime::Handle;
+
+    use credentialsd_common::model::{CredentialRequest, Error};
+
+    use super::{
+        bluez::BluezClient,
+        framing::{Frame, CMD_CANCEL, CMD_KEEPALIVE, CMD_MSG},
+        mock::{self, Authenticator, MockBluez, Script},
+        BleDevice, BleHandler,
+    };
+    use crate::credential_service::{
+        ctap::{CtapDevice, DeviceError, KeepAlive},
+        transport::{AuthenticatorState, AuthenticatorTransport},
+    };
+
+    const ADDRESS: &str = "00_11_22_33_44_55";
+
+    fn frame(cmd: u8, data: &[u8]) -> Frame {
+        Frame {
+            cmd,
+            data: data.to_vec(),
+        }
+    }
+
+    /// Connects to the authenticator at [`ADDRESS`], and runs `f` with it on
+    /// a blocking thread, like the BLE handler does.
+    async fn with_device<T: Send + 'static>(
+        bluez: &MockBluez,
+        f: impl FnOnce(&mut BleDevice) -> T + Send + 'static,
+    ) -> T {
+        let client = BluezClient::new(bluez.conn.clone());
+        let authenticator = client.find_authenticator().await.unwrap().unwrap();
+        let service = client.connect(&authenticator).await.unwrap();
+        let rt = Handle::current();
+        tokio::task::spawn_blocking(move || f(&mut BleDevice { rt: &rt, service }))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ble_ceremony_with_mock_bluez() {
+        // getInfo: {1: ["FIDO_2_0"], 3: h'00..00'}
+        let mut get_info = b"\x00\xa2\x01\x81\x68FIDO_2_0\x03\x50".to_vec();
+        get_info.extend([0; 16]);
+        // getAssertion: {1: "example.com", 2: h'00..00', 5: {"up": true}}
+        let mut get_assertion = b"\x02\xa3\x01\x6bexample.com\x02\x58\x20".to_vec();
+        get_assertion.extend([0; 32]);
+        get_assertion.extend(b"\x05\xa1\x62up\xf5");
+        let script: Script = Arc::new(Mutex::new(VecDeque::from([
+            (frame(CMD_MSG, b"\x04"), vec![frame(CMD_MSG, &get_info)]),
+            (
+                frame(CMD_MSG, &get_assertion),
+                vec![
+                    frame(CMD_KEEPALIVE, &[0x02]),
+                    // CTAP2_ERR_NO_CREDENTIALS
+                    frame(CMD_MSG, &[0x2e]),
+                ],
+            ),
+        ])));
+        let bluez = MockBluez::serve(vec![Authenticator::new(ADDRESS, &script)], &[]).await;
+
+        let handler = BleHandler::with_connection(bluez.conn.clone());
+        let request = CredentialRequest::GetPublicKeyCredentialRequest(GetAssertionRequest {
+            relying_party_id: "example.com".to_string(),
+            hash: vec![0; 32],
+            allow: Vec::new(),
+            extensions: None,
+            user_verification: UserVerificationRequirement::Discouraged,
+            timeout: Duration::from_secs(30),
+        });
+        let states: Vec<_> = handler.start("", &request).collect().await;
+
+        assert!(matches!(
+            states[..],
+            [
+                AuthenticatorState::Waiting,
+                AuthenticatorState::Connected,
+                AuthenticatorState::NeedsUserPresence,
+                AuthenticatorState::Failed(Error::NoCredentials),
+            ]
+        ));
+        assert!(script.lock().unwrap().is_empty());
+        let calls = bluez.calls.lock().unwrap();
+        assert!(!calls.discovering);
+        assert_eq!(vec![mock::device_path(ADDRESS)], calls.paired);
+        assert_eq!(vec![mock::device_path(ADDRESS)], calls.trusted);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_long_frames_are_fragmented_and_reassembled() {
+        let request: Vec<u8> = (0..100).collect();
+        let response: Vec<u8> = (0..70).rev().collect();
+        let script: Script = Arc::new(Mutex::new(VecDeque::from([(
+            frame(CMD_MSG, &request),
+            vec![frame(CMD_KEEPALIVE, &[0x01]), frame(CMD_MSG, &response)],
+        )])));
+        let bluez = MockBluez::serve(vec![Authenticator::new(ADDRESS, &script)], &[]).await;
+
+        let (result, keepalives) = with_device(&bluez, move |device| {
+            let mut keepalives = Vec::new();
+            let result = device.send_cbor(&request, &mut |keepalive| {
+                keepalives.push(keepalive);
+                true
+            });
+            (result, keepalives)
+        })
+        .await;
+
+        assert_eq!(response, result.unwrap());
+        assert_eq!(vec![KeepAlive::Processing], keepalives);
+        assert!(script.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cancel_is_sent_when_keepalive_is_refused() {
+        let script: Script = Arc::new(Mutex::new(VecDeque::from([
+            (frame(CMD_MSG, b"\x04"), vec![frame(CMD_KEEPALIVE, &[0x02])]),
+            (frame(CMD_CANCEL, &[]), Vec::new()),
+        ])));
+        let bluez = MockBluez::serve(vec![Authenticator::new(ADDRESS, &script)], &[]).await;
+
+        let result = with_device(&bluez, |device| device.send_cbor(b"\x04", &mut |_| false)).await;
+
+        assert!(matches!(result, Err(DeviceError::Cancelled)));
+        assert!(script.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,1307 @@
+//! CTAP2 ceremonies with authenticators on transports that libwebauthn does
+//! not let us use, such as NFC and BLE.
+//!
+//! libwebauthn does not let us plug in our own transports, so this drives
+//! the authenticator directly, reusing libwebauthn's CTAP2 messages and PIN
+//! protocols. Transports run ceremonies on a blocking thread, and report the
+//! same states as USB authenticators. Of the management requests, only
+//! reading `authenticatorGetInfo` is supported.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use libwebauthn::{
+    ops::webauthn::{
+        Assertion, CredentialProtectionPolicy, Ctap2HMACGetSecretOutput,
+        GetAssertionHmacOrPrfInput, GetAssertionPrfOutput, GetAssertionRequest,
+        GetAssertionResponse, HMACGetSecretOutput, MakeCredentialHmacOrPrfInput,
+        MakeCredentialLargeBlobExtension, MakeCredentialResponse, PRFValue, ResidentKeyRequirement,
+        UserVerificationRequirement,
+    },
+    pin::{pin_hash, PinUvAuthProtocol, PinUvAuthProtocolOne, PinUvAuthProtocolTwo},
+    proto::{
+        ctap2::{
+            cbor::{CborRequest, CborResponse},
+            Ctap2AttestationStatement, Ctap2AuthTokenPermissionRole, Ctap2ClientPinRequest,
+            Ctap2CommandCode, Ctap2GetAssertionRequest, Ctap2GetAssertionResponse,
+            Ctap2GetInfoResponse, Ctap2MakeCredentialOptions, Ctap2MakeCredentialRequest,
+            Ctap2MakeCredentialResponse,
+        },
+        CtapError,
+    },
+};
+use ring::digest;
+use serde::de::DeserializeOwned;
+use serde_cbor_2::Value;
+use tokio::sync::mpsc::{self, Sender};
+
+use credentialsd_common::model::{
+    AuthenticatorInfo, CredentialRequest, Error, MakeCredentialRequestInternal,
+};
+
+use crate::cbor;
+
+use super::{
+    management::{ManagementRequest, ManagementResponse},
+    transport::AuthenticatorState,
+    usb::find_selected_assertion,
+    AuthenticatorResponse, CredentialResponse,
+};
+
+/// Status updates while the authenticator is processing a request.
+#[derive(Debug, PartialEq)]
+pub(super) enum KeepAlive {
+    Processing,
+    UserPresenceNeeded,
+}
+
+#[derive(Debug)]
+pub(super) enum DeviceError {
+    /// The authenticator went away, e.g. a card was removed from the reader.
+    Disconnected,
+    /// The transport is not available, or failed.
+    Transport(String),
+    /// The authenticator returned a CTAP error.
+    Ctap(CtapError),
+    /// The authenticator returned something we could not parse.
+    InvalidResponse(String),
+    /// The request was cancelled by the user.
+    Cancelled,
+}
+
+impl Display for DeviceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Disconnected => f.write_str("Authenticator disconnected"),
+            Self::Transport(msg) => write!(f, "Transport error: {msg}"),
+            Self::Ctap(err) => write!(f, "CTAP error: {err:?}"),
+            Self::InvalidResponse(msg) => write!(f, "Invalid response: {msg}"),
+            Self::Cancelled => f.write_str("Cancelled"),
+        }
+    }
+}
+
+impl From<DeviceError> for Error {
+    fn from(value: DeviceError) -> Self {
+        match value {
+            DeviceError::Ctap(CtapError::PINAuthBlocked) => Error::PinAttemptsExhausted,
+            DeviceError::Ctap(CtapError::UvBlocked) => Error::UserVerificationFailed,
+            DeviceError::Ctap(CtapError::PINPolicyViolation) => Error::PinChangeRequired,
+            DeviceError::Ctap(CtapError::NoCredentials) => Error::NoCredentials,
+            DeviceError::Ctap(CtapError::CredentialExcluded) => Error::CredentialExcluded,
+            DeviceError::Transport(msg) => Error::Internal(msg),
+            _ => Error::AuthenticatorError,
+        }
+    }
+}
+
+/// An authenticator that CTAP2 messages can be sent to.
+pub(super) trait CtapDevice {
+    /// Sends an encoded CTAP2 command, returning the CTAP2 response including
+    /// its status byte.
+    ///
+    /// `on_keepalive` is called whenever the authenticator reports that it is
+    /// still busy, and cancels the request by returning `false`.
+    fn send_cbor(
+        &mut self,
+        request: &[u8],
+        on_keepalive: &mut dyn FnMut(KeepAlive) -> bool,
+    ) -> Result<Vec<u8>, DeviceError>;
+}
+
+/// How a ceremony interacts with the user.
+pub(super) trait Prompt {
+    /// Asks the user for the authenticator's PIN, returning `None` if the
+    /// request was cancelled.
+    fn request_pin(&mut self, attempts_left: Option<u32>) -> Option<String>;
+
+    /// Relays that the authenticator is still busy, returning `false` if the
+    /// request was cancelled.
+    fn keepalive(&mut self, keepalive: KeepAlive) -> bool;
+}
+
+/// What to do with the authenticator once it is found.
+#[derive(Clone, Debug)]
+pub(super) enum CtapOperation {
+    Ceremony(Box<CredentialRequest>),
+    Manage(ManagementRequest),
+}
+
+/// What the authenticator returned for a [`CtapOperation`].
+pub(super) enum CtapResponse {
+    Ceremony(AuthenticatorResponse),
+    Managed(ManagementResponse),
+}
+
+/// How the user will be verified for a request.
+enum UserVerification {
+    None,
+    /// The authenticator verifies the user itself, e.g. with a fingerprint.
+    BuiltIn,
+    /// The user entered their PIN, which got us this pinUvAuthParam.
+    PinUvAuthParam {
+        protocol: u32,
+        param: Vec<u8>,
+        /// The secret that the PIN was sent with, which can be reused.
+        shared_secret: SharedSecret,
+    },
+}
+
+/// A secret shared with the authenticator through authenticatorClientPIN's
+/// key agreement. It protects PINs and tokens, and hmac-secret salts and
+/// outputs.
+struct SharedSecret {
+    protocol: Box<dyn PinUvAuthProtocol>,
+    /// Our half of the key agreement, sent along with what the secret
+    /// protects.
+    public_key: cosey::PublicKey,
+    secret: Vec<u8>,
+}
+
+fn make_credential(
+    device: &mut impl CtapDevice,
+    prompt: &mut impl Prompt,
+    request: &MakeCredentialRequestInternal,
+) -> Result<MakeCredentialResponse, DeviceError> {
+    let algorithms = &request.algorithms;
+    let request = &request.ctap;
+    let info = get_info(device, prompt)?;
+    let require_resident_key = match request.resident_key {
+        Some(ResidentKeyRequirement::Discouraged) => Some(false),
+        Some(ResidentKeyRequirement::Preferred) => info.option_enabled("rk").then_some(true),
+        Some(ResidentKeyRequirement::Required) => Some(true),
+        None => None,
+    };
+    let mut ctap_request = Ctap2MakeCredentialRequest {
+        hash: request.hash.clone().into(),
+        relying_party: request.relying_party.clone(),
+        user: request.user.clone(),
+        // Encoded by make_credential_command().
+        algorithms: Vec::new(),
+        exclude: request.exclude.clone(),
+        extensions: None,
+        options: Some(Ctap2MakeCredentialOptions {
+            require_resident_key,
+            deprecated_require_user_verification: None,
+        }),
+        pin_auth_param: None,
+        pin_auth_proto: None,
+        enterprise_attestation: None,
+    };
+    if let Some(extensions) = &request.extensions {
+        // libwebauthn doesn't export the type of CTAP2 extensions, but they
+        // can still be built up from the default.
+        let ctap_extensions = ctap_request.extensions.insert(Default::default());
+        if let Some(cred_protect) = &extensions.cred_protect {
+            if cred_protect.enforce_policy
+                && cred_protect.policy != CredentialProtectionPolicy::UserVerificationOptional
+                && !info.is_uv_protected()
+            {
+                tracing::info!("Authenticator cannot enforce the requested credProtect policy");
+                return Err(DeviceError::Ctap(CtapError::UnsupportedExtension));
+            }
+            ctap_extensions.cred_protect = Some(cred_protect.policy.clone().into());
+        }
+        ctap_extensions.large_blob_key = match extensions.large_blob {
+            MakeCredentialLargeBlobExtension::Required if !info.option_enabled("largeBlobs") => {
+                tracing::info!("Authenticator does not support the required largeBlob extension");
+                return Err(DeviceError::Ctap(CtapError::UnsupportedExtension));
+            }
+            MakeCredentialLargeBlobExtension::Required => Some(true),
+            MakeCredentialLargeBlobExtension::Preferred => {
+                info.option_enabled("largeBlobs").then_some(true)
+            }
+            MakeCredentialLargeBlobExtension::None => None,
+        };
+        ctap_extensions.cred_blob = extensions.cred_blob.clone();
+        ctap_extensions.min_pin_length = extensions.min_pin_length;
+        // Creating a credential with hmac-secret, which backs PRF, needs no
+        // shared secret, unlike evaluating it.
+        ctap_extensions.hmac_secret = match extensions.hmac_or_prf {
+            MakeCredentialHmacOrPrfInput::None => None,
+            MakeCredentialHmacOrPrfInput::HmacGetSecret | MakeCredentialHmacOrPrfInput::Prf => {
+                Some(true)
+            }
+        };
+    }
+    match user_verification(
+        device,
+        prompt,
+        &info,
+        request.user_verification,
+        Ctap2AuthTokenPermissionRole::MAKE_CREDENTIAL,
+        &request.relying_party.id,
+        &request.hash,
+    )? {
+        UserVerification::None => {}
+        UserVerification::BuiltIn => {
+            if let Some(options) = ctap_request.options.as_mut() {
+                options.deprecated_require_user_verification = Some(true);
+            }
+        }
+        UserVerification::PinUvAuthParam {
+            protocol, param, ..
+        } => {
+            ctap_request.pin_auth_proto = Some(protocol);
+            ctap_request.pin_auth_param = Some(param.into());
+        }
+    }
+    let data = command(
+        device,
+        prompt,
+        &make_credential_command(&ctap_request, algorithms)?,
+    )?;
+    let response: Ctap2MakeCredentialResponse = parse(&data)?;
+    let mut response = response.into_make_credential_output(request, Some(&info));
+    response.attestation_statement = Ctap2AttestationStatement::None(attestation_statement(&data)?);
+    Ok(response)
+}
+
+/// Encodes an authenticatorMakeCredential request with the algorithms that
+/// the RP requested as its pubKeyCredParams. libwebauthn can only encode the
+/// few algorithms it knows about, so they are put in as they are.
+fn make_credential_command(
+    request: &Ctap2MakeCredentialRequest,
+    algorithms: &[i64],
+) -> Result<CborRequest, DeviceError> {
+    let mut command = CborRequest::from(request);
+    let pub_key_cred_params = algorithms
+        .iter()
+        .map(|alg| {
+            cbor::Value::Map(vec![
+                ("alg".into(), (*alg).into()),
+                ("type".into(), "public-key".into()),
+            ])
+        })
+        .collect();
+    replace_member(&mut command, 0x04, |_| {
+        cbor::Value::Array(pub_key_cred_params)
+    })?;
+    Ok(command)
+}
+
+/// Replaces a member of the parameters of an encoded request, for what
+/// libwebauthn cannot encode itself. `replace` gets the current value, if
+/// any.
+fn replace_member(
+    command: &mut CborRequest,
+    key: i128,
+    replace: impl FnOnce(Option<cbor::Value>) -> cbor::Value,
+) -> Result<(), DeviceError> {
+    let encoding_error = |err| DeviceError::Transport(format!("Failed to encode request: {err}"));
+    let cbor::Value::Map(mut entries) =
+        cbor::from_slice(&command.encoded_data).map_err(encoding_error)?
+    else {
+        unreachable!("CTAP2 requests are maps");
+    };
+    let key = cbor::Value::Integer(key);
+    let value = entries
+        .iter()
+        .position(|(k, _)| *k == key)
+        .map(|i| entries.remove(i).1);
+    entries.push((key, replace(value)));
+    command.encoded_data = cbor::to_vec(&cbor::Value::Map(entries)).map_err(encoding_error)?;
+    Ok(())
+}
+
+/// Reads the attStmt map of an authenticatorMakeCredential response as the
+/// authenticator sent it.
+///
+/// libwebauthn picks the first of its attestation statement structs whose
+/// fields match, without looking at `fmt`, so e.g. `tpm` statements lose the
+/// members that `packed` ones don't have. The raw map is passed through to
+/// the RP instead.
+fn attestation_statement(data: &[u8]) -> Result<BTreeMap<Value, Value>, DeviceError> {
+    match parse::<BTreeMap<u8, Value>>(data)?.remove(&0x03) {
+        Some(Value::Map(att_stmt)) => Ok(att_stmt),
+        _ => Err(DeviceError::InvalidResponse("Invalid attStmt".to_string())),
+    }
+}
+
+fn get_assertion(
+    device: &mut impl CtapDevice,
+    prompt: &mut impl Prompt,
+    request: &GetAssertionRequest,
+) -> Result<GetAssertionResponse, DeviceError> {
+    let info = get_info(device, prompt)?;
+    // credBlob and largeBlobKey are passed through as is.
+    let mut ctap_request = Ctap2GetAssertionRequest::from(request.clone());
+    let user_verification = user_verification(
+        device,
+        prompt,
+        &info,
+        request.user_verification,
+        Ctap2AuthTokenPermissionRole::GET_ASSERTION,
+        &request.relying_party_id,
+        &request.hash,
+    )?;
+    if let Some(options) = ctap_request.options.as_mut() {
+        options.require_user_verification = matches!(user_verification, UserVerification::BuiltIn);
+    }
+    let mut shared_secret = None;
+    if let UserVerification::PinUvAuthParam {
+        protocol,
+        param,
+        shared_secret: pin_shared_secret,
+    } = user_verification
+    {
+        ctap_request.pin_auth_proto = Some(protocol);
+        ctap_request.pin_auth_param = Some(param.into());
+        shared_secret = Some(pin_shared_secret);
+    }
+    let mut ctap_command = CborRequest::from(&ctap_request);
+    // libwebauthn only computes the hmac-secret input, which backs PRF, over
+    // its own transports. If it can't be added here, the assertion is still
+    // returned, just without the outputs.
+    let hmac_secret = match hmac_secret_salts(request) {
+        Some(salts)
+            if info
+                .extensions
+                .iter()
+                .flatten()
+                .any(|ext| ext == "hmac-secret") =>
+        {
+            match add_hmac_secret(
+                device,
+                prompt,
+                &info,
+                &mut ctap_command,
+                shared_secret,
+                &salts,
+            ) {
+                Ok(shared_secret) => Some(shared_secret),
+                Err(err @ (DeviceError::Ctap(_) | DeviceError::InvalidResponse(_))) => {
+                    tracing::warn!("Failed to set up hmac-secret, asserting without it: {err}");
+                    None
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Some(_) => {
+            tracing::info!("Authenticator does not support hmac-secret, asserting without it");
+            None
+        }
+        None => None,
+    };
+
+    let data = command(device, prompt, &ctap_command)?;
+    let assertion = parse_assertion(&data, request, hmac_secret.as_ref())?;
+    let count = assertion.credentials_count.unwrap_or(1);
+    let mut assertions = vec![assertion];
+    for _ in 1..count {
+        let data = command(
+            device,
+            prompt,
+            &CborRequest::new(Ctap2CommandCode::AuthenticatorGetNextAssertion),
+        )?;
+        assertions.push(parse_assertion(&data, request, hmac_secret.as_ref())?);
+    }
+    Ok(GetAssertionResponse { assertions })
+}
+
+fn parse_assertion(
+    data: &[u8],
+    request: &GetAssertionRequest,
+    hmac_secret: Option<&SharedSecret>,
+) -> Result<Assertion, DeviceError> {
+    let response: Ctap2GetAssertionResponse = parse(data)?;
+    let output = hmac_secret.and_then(|shared_secret| {
+        let output = response
+            .authenticator_data
+            .extensions
+            .as_ref()?
+            .hmac_secret
+            .as_ref()?;
+        hmac_secret_output(shared_secret, output)
+    });
+    let mut assertion = response.into_assertion_output(request, None);
+    let hmac_or_prf = request
+        .extensions
+        .as_ref()
+        .map(|extensions| &extensions.hmac_or_prf);
+    if let (Some(output), Some(hmac_or_prf)) = (output, hmac_or_prf) {
+        let outputs = assertion
+            .unsigned_extensions_output
+            .get_or_insert_with(Default::default);
+        match hmac_or_prf {
+            GetAssertionHmacOrPrfInput::None => {}
+            GetAssertionHmacOrPrfInput::HmacGetSecret(_) => outputs.hmac_get_secret = Some(output),
+            GetAssertionHmacOrPrfInput::Prf { .. } => {
+                outputs.prf = Some(GetAssertionPrfOutput {
+                    results: Some(PRFValue {
+                        first: output.output1,
+                        second: output.output2,
+                    }),
+                })
+            }
+        }
+    }
+    Ok(assertion)
+}
+
+/// Returns the salts to evaluate hmac-secret with, concatenated, if the
+/// request asks for hmac-secret or PRF outputs.
+fn hmac_secret_salts(request: &GetAssertionRequest) -> Option<Vec<u8>> {
+    match &request.extensions.as_ref()?.hmac_or_prf {
+        GetAssertionHmacOrPrfInput::None => None,
+        GetAssertionHmacOrPrfInput::HmacGetSecret(input) => {
+            let mut salts = input.salt1.to_vec();
+            salts.extend(input.salt2.iter().flatten());
+            Some(salts)
+        }
+        GetAssertionHmacOrPrfInput::Prf {
+            eval,
+            eval_by_credential,
+        } => {
+            // Only one set of salts can be sent, before we know which
+            // credential will be used, so like libwebauthn, this takes the
+            // inputs of the first allowed credential that has some.
+            let inputs = eval_by_credential
+                .iter()
+                .find(|(id, _)| {
+                    URL_SAFE_NO_PAD.decode(id).is_ok_and(|id| {
+                        request
+                            .allow
+                            .iter()
+                            .any(|credential| credential.id.as_slice() == id.as_slice())
+                    })
+                })
+                .map(|(_, inputs)| inputs)
+                .or(eval.as_ref())?;
+            // https://w3c.github.io/webauthn/#prf-extension
+            let salt = |input: &[u8]| {
+                digest::digest(&digest::SHA256, &[b"WebAuthn PRF\x00", input].concat())
+            };
+            let mut salts = salt(&inputs.first).as_ref().to_vec();
+            if let Some(second) = &inputs.second {
+                salts.extend(salt(second).as_ref());
+            }
+            Some(salts)
+        }
+    }
+}
+
+/// Adds the hmac-secret input with the salts to the getAssertion command,
+/// returning the shared secret that the output will be encrypted with. The
+/// shared secret that the PIN was sent with is reused, if there is one.
+fn add_hmac_secret(
+    device: &mut impl CtapDevice,
+    prompt: &mut impl Prompt,
+    info: &Ctap2GetInfoResponse,
+    ctap_command: &mut CborRequest,
+    shared_secret: Option<SharedSecret>,
+    salts: &[u8],
+) -> Result<SharedSecret, DeviceError> {
+    let shared_secret = match shared_secret {
+        Some(shared_secret) => shared_secret,
+        None => key_agreement(device, prompt, pin_protocol(info))?,
+    };
+    let protocol = &shared_secret.protocol;
+    let salt_enc = protocol
+        .encrypt(&shared_secret.secret, salts)
+        .map_err(|err| DeviceError::InvalidResponse(format!("{err:?}")))?;
+    let salt_auth = protocol.authenticate(&shared_secret.secret, &salt_enc);
+    let public_key = cbor::Value::from_serialize(&shared_secret.public_key)
+        .map_err(|err| DeviceError::Transport(format!("Failed to encode request: {err}")))?;
+    let input = cbor::Value::Map(vec![
+        (cbor::Value::Integer(0x01), public_key),
+        (cbor::Value::Integer(0x02), salt_enc.into()),
+        (cbor::Value::Integer(0x03), salt_auth.into()),
+        (
+            cbor::Value::Integer(0x04),
+            i64::from(protocol.version() as u32).into(),
+        ),
+    ]);
+    replace_member(ctap_command, 0x04, |extensions| {
+        let mut extensions = match extensions {
+            Some(cbor::Value::Map(extensions)) => extensions,
+            _ => Vec::new(),
+        };
+        extensions.push(("hmac-secret".into(), input));
+        cbor::Value::Map(extensions)
+    })?;
+    Ok(shared_secret)
+}
+
+/// Decrypts the hmac-secret output of an assertion. Outputs that can't be
+/// decrypted are left out of the assertion.
+fn hmac_secret_output(
+    shared_secret: &SharedSecret,
+    output: &Ctap2HMACGetSecretOutput,
+) -> Option<HMACGetSecretOutput> {
+    // libwebauthn keeps the encrypted output to itself, but it serializes as
+    // a byte string.
+    let Ok(Value::Bytes(encrypted)) = serde_cbor_2::value::to_value(output) else {
+        return None;
+    };
+    let decrypted = match shared_secret
+        .protocol
+        .decrypt(&shared_secret.secret, &encrypted)
+    {
+        Ok(decrypted) => decrypted,
+        Err(err) => {
+            tracing::warn!("Failed to decrypt hmac-secret output: {err:?}");
+            return None;
+        }
+    };
+    let (output1, output2) = match decrypted.len() {
+        32 => (decrypted.as_slice(), None),
+        64 => (&decrypted[..32], Some(&decrypted[32..])),
+        len => {
+            tracing::warn!("Unexpected length of hmac-secret output: {len}");
+            return None;
+        }
+    };
+    Some(HMACGetSecretOutput {
+        output1: output1.try_into().ok()?,
+        output2: output2.map(|output| output.try_into()).transpose().ok()?,
+    })
+}
+
+fn get_info(
+    device: &mut impl CtapDevice,
+    prompt: &mut impl Prompt,
+) -> Result<Ctap2GetInfoResponse, DeviceError> {
+    let data = command(
+        device,
+        prompt,
+        &CborRequest::new(Ctap2CommandCode::AuthenticatorGetInfo),
+    )?;
+    parse(&data)
+}
+
+/// Reads what the authenticator reports about itself, with its PIN and UV
+/// retries.
+fn authenticator_info(
+    device: &mut impl CtapDevice,
+    prompt: &mut impl Prompt,
+) -> Result<AuthenticatorInfo, DeviceError> {
+    let data = command(
+        device,
+        prompt,
+        &CborRequest::new(Ctap2CommandCode::AuthenticatorGetInfo),
+    )?;
+    let info: Ctap2GetInfoResponse = parse(&data)?;
+    let pin_retries = if info.option_enabled("clientPin") {
+        let request =
+            Ctap2ClientPinRequest::new_get_pin_retries(Some(pin_protocol(&info).version()));
+        retries(device, prompt, &request).and_then(|response| response.pin_retries)
+    } else {
+        None
+    };
+    let uv_retries = if info.option_enabled("uv") {
+        let request = Ctap2ClientPinRequest::new_get_uv_retries();
+        retries(device, prompt, &request).and_then(|response| response.uv_retries)
+    } else {
+        None
+    };
+    Ok(AuthenticatorInfo {
+        versions: info.versions,
+        aaguid: info.aaguid.into_vec(),
+        options: info.options.unwrap_or_default(),
+        // libwebauthn drops the identifiers of algorithms that it doesn't
+        // know, so they are read from the response itself.
+        algorithms: algorithms(&data)?,
+        extensions: info.extensions.unwrap_or_default(),
+        pin_retries,
+        uv_retries,
+        firmware_version: info.firmware_version,
+        transports: info.transports.unwrap_or_default(),
+    })
+}
+
+/// Asks for the PIN or UV retries, which authenticators may not report.
+fn retries(
+    device: &mut impl CtapDevice,
+    prompt: &mut impl Prompt,
+    request: &Ctap2ClientPinRequest,
+) -> Option<ClientPinResponse> {
+    command(device, prompt, &request.into())
+        .and_then(|data| parse_client_pin(&data))
+        .inspect_err(|err| tracing::warn!("Failed to get the retries of the authenticator: {err}"))
+        .ok()
+}
+
+/// The COSE identifiers of the algorithms in a getInfo response.
+fn algorithms(data: &[u8]) -> Result<Vec<i32>, DeviceError> {
+    let invalid = || DeviceError::InvalidResponse("Invalid algorithms".to_string());
+    let Some(Value::Array(algorithms)) = parse::<BTreeMap<u8, Value>>(data)?.remove(&0x0a) else {
+        return Ok(Vec::new());
+    };
+    let alg = Value::Text("alg".to_string());
+    algorithms
+        .iter()
+        .map(|algorithm| match algorithm {
+            Value::Map(algorithm) => match algorithm.get(&alg) {
+                Some(Value::Integer(id)) => i32::try_from(*id).map_err(|_| invalid()),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        })
+        .collect()
+}
+
+fn user_verification(
+    device: &mut impl CtapDevice,
+    prompt: &mut impl Prompt,
+    info: &Ctap2GetInfoResponse,
+    requirement: UserVerificationRequirement,
+    permissions: Ctap2AuthTokenPermissionRole,
+    rp_id: &str,
+    client_data_hash: &[u8],
+) -> Result<UserVerification, DeviceError> {
+    if matches!(requirement, UserVerificationRequirement::Discouraged) {
+        return Ok(UserVerification::None);
+    }
+    if !info.option_enabled("clientPin") {
+        if info.option_enabled("uv") || requirement.is_required() {
+            // If the authenticator cannot verify the user, it will reject the request.
+            return Ok(UserVerification::BuiltIn);
+        }
+        return Ok(UserVerification::None);
+    }
+
+    loop {
+        let request =
+            Ctap2ClientPinRequest::new_get_pin_retries(Some(pin_protocol(info).version()));
+        let retries = parse_client_pin(&command(device, prompt, &(&request).into())?)?;
+        let pin = prompt
+            .request_pin(retries.pin_retries)
+            .ok_or(DeviceError::Cancelled)?;
+
+        let shared_secret = key_agreement(device, prompt, pin_protocol(info))?;
+        let protocol = &shared_secret.protocol;
+        let pin_hash_enc = protocol
+            .encrypt(&shared_secret.secret, &pin_hash(pin.as_bytes()))
+            .map_err(|err| DeviceError::InvalidResponse(format!("{err:?}")))?;
+        let request = if info.option_enabled("pinUvAuthToken") {
+            Ctap2ClientPinRequest::new_get_pin_token_with_perm(
+                protocol.version(),
+                shared_secret.public_key.clone(),
+                &pin_hash_enc,
+                permissions,
+                Some(rp_id),
+            )
+        } else {
+            Ctap2ClientPinRequest::new_get_pin_token(
+                protocol.version(),
+                shared_secret.public_key.clone(),
+                &pin_hash_enc,
+            )
+        };
+        let token = match command(device, prompt, &(&request).into()) {
+            Ok(data) => parse_client_pin(&data)?
+                .pin_uv_auth_token
+                .ok_or_else(|| DeviceError::InvalidResponse("Missing PIN token".to_string()))?,
+            Err(DeviceError::Ctap(CtapError::PINInvalid)) => {
+                tracing::debug!("Incorrect PIN entered, asking again");
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        let token = protocol
+            .decrypt(&shared_secret.secret, &token)
+            .map_err(|err| DeviceError::InvalidResponse(format!("{err:?}")))?;
+        return Ok(UserVerification::PinUvAuthParam {
+            protocol: protocol.version() as u32,
+            param: protocol.authenticate(&token, client_data_hash),
+            shared_secret,
+        });
+    }
+}
+
+/// Returns the newest PIN/UV auth protocol that the authenticator supports.
+fn pin_protocol(info: &Ctap2GetInfoResponse) -> Box<dyn PinUvAuthProtocol> {
+    match &info.pin_auth_protos {
+        Some(protocols) if protocols.contains(&2) => Box::new(PinUvAuthProtocolTwo::new()),
+        _ => Box::new(PinUvAuthProtocolOne::new()),
+    }
+}
+
+/// Agrees on a shared secret with the authenticator.
+fn key_agreement(
+    device: &mut impl CtapDevice,
+    prompt: &mut impl Prompt,
+    protocol: Box<dyn PinUvAuthProtocol>,
+) -> Result<SharedSecret, DeviceError> {
+    let request = Ctap2ClientPinRequest::new_get_key_agreement(protocol.version());
+    let key_agreement = parse_client_pin(&command(device, prompt, &(&request).into())?)?
+        .key_agreement
+        .ok_or_else(|| DeviceError::InvalidResponse("Missing key agreement".to_string()))?;
+    let (public_key, secret) = protocol
+        .encapsulate(&key_agreement)
+        .map_err(|err| DeviceError::InvalidResponse(format!("{err:?}")))?;
+    Ok(SharedSecret {
+        protocol,
+        public_key,
+        secret,
+    })
+}
+
+/// Sends a CTAP2 command, returning the response data if it succeeded.
+fn command(
+    device: &mut impl CtapDevice,
+    prompt: &mut impl Prompt,
+    request: &CborRequest,
+) -> Result<Vec<u8>, DeviceError> {
+    let response = device.send_cbor(&request.ctap_hid_data(), &mut |keepalive| {
+        prompt.keepalive(keepalive)
+    })?;
+    let response = CborResponse::try_from(&response)
+        .map_err(|err| DeviceError::InvalidResponse(err.to_string()))?;
+    match response.status_code {
+        CtapError::Ok => Ok(response.data.unwrap_or_default()),
+        err => Err(DeviceError::Ctap(err)),
+    }
+}
+
+fn parse<T: DeserializeOwned>(data: &[u8]) -> Result<T, DeviceError> {
+    serde_cbor_2::from_slice(data).map_err(|err| DeviceError::InvalidResponse(err.to_string()))
+}
+
+/// The fields of an authenticatorClientPIN response that we use.
+#[derive(Debug, Default)]
+struct ClientPinResponse {
+    key_agreement: Option<cosey::PublicKey>,
+    pin_uv_auth_token: Option<Vec<u8>>,
+    pin_retries: Option<u32>,
+    uv_retries: Option<u32>,
+}
+
+fn parse_client_pin(data: &[u8]) -> Result<ClientPinResponse, DeviceError> {
+    let invalid = |field: &str| DeviceError::InvalidResponse(format!("Invalid {field}"));
+    let mut response = ClientPinResponse::default();
+    for (key, value) in parse::<BTreeMap<u8, Value>>(data)? {
+        match (key, value) {
+            (0x01, value) => {
+                response.key_agreement = Some(
+                    serde_cbor_2::value::from_value(value).map_err(|_| invalid("keyAgreement"))?,
+                );
+            }
+            (0x02, Value::Bytes(token)) => response.pin_uv_auth_token = Some(token),
+            (0x03, Value::Integer(retries)) => {
+                response.pin_retries =
+                    Some(u32::try_from(retries).map_err(|_| invalid("pinRetries"))?);
+            }
+            (0x05, Value::Integer(retries)) => {
+                response.uv_retries =
+                    Some(u32::try_from(retries).map_err(|_| invalid("uvRetries"))?);
+            }
+            (0x02, _) => return Err(invalid("pinUvAuthToken")),
+            (0x03, _) => return Err(invalid("pinRetries")),
+            (0x05, _) => return Err(invalid("uvRetries")),
+            _ => {}
+        }
+    }
+    Ok(response)
+}
+
+/// Whether the management request can be handled by authenticators driven
+/// here.
+pub(super) fn can_manage(request: &ManagementRequest) -> bool {
+    matches!(request, ManagementRequest::GetInfo)
+}
+
+/// Runs the ceremony or management request, relaying requests for user
+/// interaction as state updates.
+pub(super) fn run(
+    device: &mut impl CtapDevice,
+    tx: &Sender<AuthenticatorState>,
+    operation: &CtapOperation,
+) -> Result<CtapResponse, DeviceError> {
+    let mut prompt = StatePrompt {
+        tx,
+        needs_user_presence: false,
+    };
+    match operation {
+        CtapOperation::Ceremony(request) => match &**request {
+            CredentialRequest::CreatePublicKeyCredentialRequest(request) => {
+                make_credential(device, &mut prompt, request)
+                    .map(|response| CtapResponse::Ceremony(response.into()))
+            }
+            CredentialRequest::GetPublicKeyCredentialRequest(request) => {
+                get_assertion(device, &mut prompt, request)
+                    .map(|response| CtapResponse::Ceremony(response.into()))
+            }
+        },
+        CtapOperation::Manage(ManagementRequest::GetInfo) => {
+            authenticator_info(device, &mut prompt)
+                .map(|info| CtapResponse::Managed(ManagementResponse::Info(info)))
+        }
+        CtapOperation::Manage(request) => Err(DeviceError::Transport(format!(
+            "Unsupported management request: {request:?}"
+        ))),
+    }
+}
+
+/// Turns the authenticator response into the final state of the device,
+/// letting the user choose a credential if there are several.
+pub(super) fn complete(
+    tx: &Sender<AuthenticatorState>,
+    response: CtapResponse,
+    transport: &str,
+) -> AuthenticatorState {
+    match response {
+        CtapResponse::Ceremony(response) => match complete_ceremony(tx, response, transport) {
+            Ok(response) => AuthenticatorState::Completed(response),
+            Err(err) => AuthenticatorState::Failed(err),
+        },
+        CtapResponse::Managed(response) => AuthenticatorState::Managed(Box::new(response)),
+    }
+}
+
+fn complete_ceremony(
+    tx: &Sender<AuthenticatorState>,
+    response: AuthenticatorResponse,
+    transport: &str,
+) -> Result<CredentialResponse, Error> {
+    match response {
+        AuthenticatorResponse::CredentialCreated(response) => Ok(
+            CredentialResponse::from_make_credential(&response, &[transport], "cross-platform"),
+        ),
+        AuthenticatorResponse::CredentialsAsserted(response) if response.assertions.len() == 1 => {
+            Ok(CredentialResponse::from_get_assertion(
+                &response.assertions[0],
+                "cross-platform",
+            ))
+        }
+        AuthenticatorResponse::CredentialsAsserted(response) => {
+            let (cred_tx, mut cred_rx) = mpsc::channel(1);
+            _ = tx.blocking_send(AuthenticatorState::SelectCredential {
+                response: response.clone(),
+                cred_tx,
+            });
+            let cred_id = cred_rx.blocking_recv().ok_or_else(|| {
+                Error::Internal("Cred channel disconnected prematurely".to_string())
+            })?;
+            let assertion =
+                find_selected_assertion(&response, &cred_id).ok_or(Error::NoCredentials)?;
+            Ok(CredentialResponse::from_get_assertion(
+                &assertion,
+                "cross-platform",
+            ))
+        }
+    }
+}
+
+pub(super) fn send_state(
+    tx: &Sender<AuthenticatorState>,
+    state: AuthenticatorState,
+) -> Result<(), DeviceError> {
+    tx.blocking_send(state).map_err(|_| DeviceError::Cancelled)
+}
+
+/// Relays the authenticator's requests for user interaction as state updates.
+struct StatePrompt<'a> {
+    tx: &'a Sender<AuthenticatorState>,
+    needs_user_presence: bool,
+}
+
+impl Prompt for StatePrompt<'_> {
+    fn request_pin(&mut self, attempts_left: Option<u32>) -> Option<String> {
+        let (pin_tx, mut pin_rx) = mpsc::channel(1);
+        self.needs_user_presence = false;
+        self.tx
+            .blocking_send(AuthenticatorState::NeedsPin {
+                attempts_left,
+                pin_tx,
+            })
+            .ok()?;
+        pin_rx.blocking_recv()
+    }
+
+    fn keepalive(&mut self, keepalive: KeepAlive) -> bool {
+        if keepalive == KeepAlive::UserPresenceNeeded && !self.needs_user_presence {
+            self.needs_user_presence = true;
+            return self
+                .tx
+                .blocking_send(AuthenticatorState::NeedsUserPresence)
+                .is_ok();
+        }
+        !self.tx.is_closed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use credentialsd_common::model::MakeCredentialRequestInternal;
+    use libwebauthn::{
+        ops::webauthn::{
+            CredentialProtectionExtension, CredentialProtectionPolicy, GetAssertionHmacOrPrfInput,
+            GetAssertionRequest, GetAssertionRequestExtensions, MakeCredentialLargeBlobExtension,
+            MakeCredentialRequest, MakeCredentialsRequestExtensions, PRFValue,
+            UserVerificationRequirement,
+        },
+        pin::PinUvAuthProtocolOne,
+        proto::{
+            ctap2::{cbor::CborRequest, Ctap2GetAssertionRequest},
+            CtapError,
+        },
+    };
+    use ring::digest;
+    use serde_cbor_2::Value;
+
+    use crate::cbor;
+
+    use super::{
+        add_hmac_secret, attestation_statement, authenticator_info, get_assertion,
+        hmac_secret_salts, make_credential, parse, parse_assertion, parse_client_pin, CtapDevice,
+        DeviceError, KeepAlive, Prompt, SharedSecret,
+    };
+
+    /// A request that the device expects, if it checks it, and the keepalives
+    /// and response that it replies with.
+    type Exchange = (Option<Vec<u8>>, Vec<KeepAlive>, Vec<u8>);
+
+    /// An authenticator that checks the requests it receives against a script,
+    /// and replies with the scripted keepalives and responses.
+    #[derive(Default)]
+    struct ScriptedDevice {
+        script: VecDeque<Exchange>,
+        received: Vec<Vec<u8>>,
+    }
+
+    impl ScriptedDevice {
+        fn expect(mut self, request: &[u8], keepalives: Vec<KeepAlive>, response: &[u8]) -> Self {
+            self.script
+                .push_back((Some(request.to_vec()), keepalives, response.to_vec()));
+            self
+        }
+
+        fn expect_any(mut self, response: &[u8]) -> Self {
+            self.script.push_back((None, Vec::new(), response.to_vec()));
+            self
+        }
+    }
+
+    impl CtapDevice for ScriptedDevice {
+        fn send_cbor(
+            &mut self,
+            request: &[u8],
+            on_keepalive: &mut dyn FnMut(KeepAlive) -> bool,
+        ) -> Result<Vec<u8>, DeviceError> {
+            let (expected, keepalives, response) =
+                self.script.pop_front().expect("a request to be scripted");
+            if let Some(expected) = expected {
+                assert_eq!(expected, request);
+            }
+            self.received.push(request.to_vec());
+            for keepalive in keepalives {
+                if !on_keepalive(keepalive) {
+                    return Err(DeviceError::Cancelled);
+                }
+            }
+            Ok(response)
+        }
+    }
+
+    #[derive(Default)]
+    struct TestPrompt {
+        keepalives: Vec<KeepAlive>,
+    }
+
+    impl Prompt for TestPrompt {
+        fn request_pin(&mut self, _attempts_left: Option<u32>) -> Option<String> {
+            panic!("No PIN expected");
+        }
+
+        fn keepalive(&mut self, keepalive: KeepAlive) -> bool {
+            self.keepalives.push(keepalive);
+            true
+        }
+    }
+
+    fn make_credential_request() -> MakeCredentialRequestInternal {
+        MakeCredentialRequestInternal::new(MakeCredentialRequest::dummy(), vec![-7])
+    }
+
+    fn get_assertion_request() -> GetAssertionRequest {
+        GetAssertionRequest {
+            relying_party_id: "example.com".to_string(),
+            hash: vec![0; 32],
+            allow: Vec::new(),
+            extensions: None,
+            user_verification: UserVerificationRequirement::Discouraged,
+            timeout: std::time::Duration::from_secs(30),
+        }
+    }
+
+    /// The getInfo response of an authenticator without any options:
+    /// {1: ["FIDO_2_0"], 3: h'00..00'}
+    fn get_info_response() -> Vec<u8> {
+        let mut get_info = b"\x00\xa2\x01\x81\x68FIDO_2_0\x03\x50".to_vec();
+        get_info.extend([0; 16]);
+        get_info
+    }
+
+    #[test]
+    fn test_parse_pin_retries() {
+        // {3: 8}
+        let response = parse_client_pin(b"\xa1\x03\x08").unwrap();
+        assert_eq!(Some(8), response.pin_retries);
+        assert!(response.key_agreement.is_none());
+    }
+
+    #[test]
+    fn test_authenticator_info_keeps_unknown_algorithms() {
+        // {1: ["FIDO_2_1"], 3: h'00..00', 4: {"clientPin": true},
+        //  10: [{"alg": -7, "type": "public-key"}, {"alg": -48, "type": "public-key"}]}
+        let mut get_info = b"\x00\xa4\x01\x81\x68FIDO_2_1\x03\x50".to_vec();
+        get_info.extend([0; 16]);
+        get_info.extend(b"\x04\xa1\x69clientPin\xf5\x0a\x82");
+        get_info.extend(b"\xa2\x63alg\x26\x64type\x6apublic-key");
+        get_info.extend(b"\xa2\x63alg\x38\x2f\x64type\x6apublic-key");
+        // clientPIN's getPinRetries: {3: 8}
+        let mut device = ScriptedDevice::default()
+            .expect(b"\x04", Vec::new(), &get_info)
+            .expect_any(b"\x00\xa1\x03\x08");
+        let info = authenticator_info(&mut device, &mut TestPrompt::default()).unwrap();
+        assert_eq!(vec!["FIDO_2_1".to_string()], info.versions);
+        assert_eq!(vec![-7, -48], info.algorithms);
+        assert_eq!(Some(8), info.pin_retries);
+        assert_eq!(None, info.uv_retries);
+        assert!(device.script.is_empty());
+    }
+
+    #[test]
+    fn test_attestation_statement_is_kept_as_sent() {
+        // {1: "tpm", 3: {"ver": "2.0", "pubArea": h'01'}}
+        let data = b"\xa2\x01\x63tpm\x03\xa2\x63ver\x632.0\x67pubArea\x41\x01";
+        let att_stmt = attestation_statement(data).unwrap();
+        assert_eq!(
+            Some(&Value::Text("2.0".to_string())),
+            att_stmt.get(&Value::Text("ver".to_string()))
+        );
+        assert_eq!(
+            Some(&Value::Bytes(vec![0x01])),
+            att_stmt.get(&Value::Text("pubArea".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_ctap_errors_are_returned() {
+        // getAssertion: {1: "example.com", 2: h'00..00', 5: {"up": true}}
+        let mut get_assertion_request_bytes = b"\x02\xa3\x01\x6bexample.com\x02\x58\x20".to_vec();
+        get_assertion_request_bytes.extend([0; 32]);
+        get_assertion_request_bytes.extend(b"\x05\xa1\x62up\xf5");
+        let mut device = ScriptedDevice::default()
+            .expect(b"\x04", Vec::new(), &get_info_response())
+            .expect(
+                &get_assertion_request_bytes,
+                vec![KeepAlive::UserPresenceNeeded],
+                b"\x2e",
+            );
+        let mut prompt = TestPrompt::default();
+        let result = get_assertion(&mut device, &mut prompt, &get_assertion_request());
+        assert!(matches!(
+            result,
+            Err(DeviceError::Ctap(CtapError::NoCredentials))
+        ));
+        assert_eq!(vec![KeepAlive::UserPresenceNeeded], prompt.keepalives);
+        assert!(device.script.is_empty());
+    }
+
+    #[test]
+    fn test_algorithms_are_requested_as_sent() {
+        let mut device = ScriptedDevice::default()
+            .expect(b"\x04", Vec::new(), &get_info_response())
+            .expect_any(b"\x26");
+        let mut request = make_credential_request();
+        // ES384, ML-DSA-44, an unregistered algorithm and ES256
+        request.algorithms = vec![-35, -48, -1234, -7];
+        let result = make_credential(&mut device, &mut TestPrompt::default(), &request);
+        assert!(matches!(
+            result,
+            Err(DeviceError::Ctap(CtapError::UnsupportedAlgorithm))
+        ));
+
+        let sent = &device.received[1];
+        assert_eq!(0x01, sent[0]);
+        let cbor::Value::Map(entries) = cbor::from_slice(&sent[1..]).unwrap() else {
+            panic!("Expected a map");
+        };
+        let pub_key_cred_params = entries
+            .iter()
+            .find(|(key, _)| *key == cbor::Value::Integer(0x04))
+            .map(|(_, value)| value.clone());
+        let expected = request
+            .algorithms
+            .iter()
+            .map(|alg| {
+                cbor::Value::Map(vec![
+                    ("alg".into(), (*alg).into()),
+                    ("type".into(), "public-key".into()),
+                ])
+            })
+            .collect();
+        assert_eq!(Some(cbor::Value::Array(expected)), pub_key_cred_params);
+    }
+
+    #[test]
+    fn test_enforced_cred_protect_fails_without_user_verification() {
+        let mut device =
+            ScriptedDevice::default().expect(b"\x04", Vec::new(), &get_info_response());
+        let mut request = make_credential_request();
+        request.ctap.extensions = Some(MakeCredentialsRequestExtensions {
+            cred_protect: Some(CredentialProtectionExtension {
+                policy: CredentialProtectionPolicy::UserVerificationRequired,
+                enforce_policy: true,
+            }),
+            ..Default::default()
+        });
+        let result = make_credential(&mut device, &mut TestPrompt::default(), &request);
+        assert!(matches!(
+            result,
+            Err(DeviceError::Ctap(CtapError::UnsupportedExtension))
+        ));
+        assert!(device.script.is_empty());
+    }
+
+    #[test]
+    fn test_required_large_blob_fails_without_support() {
+        let mut device =
+            ScriptedDevice::default().expect(b"\x04", Vec::new(), &get_info_response());
+        let mut request = make_credential_request();
+        request.ctap.extensions = Some(MakeCredentialsRequestExtensions {
+            large_blob: MakeCredentialLargeBlobExtension::Required,
+            ..Default::default()
+        });
+        let result = make_credential(&mut device, &mut TestPrompt::default(), &request);
+        assert!(matches!(
+            result,
+            Err(DeviceError::Ctap(CtapError::UnsupportedExtension))
+        ));
+        assert!(device.script.is_empty());
+    }
+
+    fn prf_request() -> GetAssertionRequest {
+        let mut request = get_assertion_request();
+        request.extensions = Some(GetAssertionRequestExtensions {
+            hmac_or_prf: GetAssertionHmacOrPrfInput::Prf {
+                eval: Some(PRFValue {
+                    first: [1; 32],
+                    second: None,
+                }),
+                eval_by_credential: Default::default(),
+            },
+            ..Default::default()
+        });
+        request
+    }
+
+    /// An authenticatorGetAssertion response for credential h'01', with the
+    /// given extension outputs in its authenticator data.
+    fn get_assertion_response(extensions: Option<cbor::Value>) -> Vec<u8> {
+        let mut auth_data = vec![0; 32];
+        // UP, and ED if there are extension outputs
+        auth_data.push(if extensions.is_some() { 0x81 } else { 0x01 });
+        auth_data.extend(1u32.to_be_bytes());
+        if let Some(extensions) = extensions {
+            auth_data.extend(cbor::to_vec(&extensions).unwrap());
+        }
+        cbor::to_vec(&cbor::Value::Map(vec![
+            (
+                cbor::Value::Integer(0x01),
+                cbor::Value::Map(vec![
+                    ("id".into(), vec![0x01].into()),
+                    ("type".into(), "public-key".into()),
+                ]),
+            ),
+            (cbor::Value::Integer(0x02), auth_data.into()),
+            (cbor::Value::Integer(0x03), vec![0x00].into()),
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_prf_is_skipped_without_hmac_secret() {
+        let mut response = b"\x00".to_vec();
+        response.extend(get_assertion_response(None));
+        let mut device = ScriptedDevice::default()
+            .expect(b"\x04", Vec::new(), &get_info_response())
+            .expect_any(&response);
+        let result = get_assertion(&mut device, &mut TestPrompt::default(), &prf_request());
+        let assertions = result.unwrap().assertions;
+        assert_eq!(1, assertions.len());
+        assert!(assertions[0]
+            .unsigned_extensions_output
+            .as_ref()
+            .is_none_or(|outputs| outputs.prf.is_none()));
+        assert!(device.script.is_empty());
+    }
+
+    #[test]
+    fn test_prf_is_evaluated_with_hmac_secret() {
+        let request = prf_request();
+        let shared_secret = SharedSecret {
+            protocol: Box::new(PinUvAuthProtocolOne::new()),
+            public_key: cosey::PublicKey::P256Key(cosey::P256PublicKey {
+                x: cosey::Bytes::from_slice(&[2; 32]).unwrap(),
+                y: cosey::Bytes::from_slice(&[3; 32]).unwrap(),
+            }),
+            secret: vec![4; 32],
+        };
+        let mut command = CborRequest::from(&Ctap2GetAssertionRequest::from(request.clone()));
+        let salts = hmac_secret_salts(&request).unwrap();
+        let shared_secret = add_hmac_secret(
+            &mut ScriptedDevice::default(),
+            &mut TestPrompt::default(),
+            &parse(&get_info_response()[1..]).unwrap(),
+            &mut command,
+            Some(shared_secret),
+            &salts,
+        )
+        .unwrap();
+
+        // The salt is sent encrypted, as hashed for PRF.
+        let member = |value: &cbor::Value, key: &cbor::Value| {
+            let cbor::Value::Map(entries) = value else {
+                panic!("Expected a map");
+            };
+            entries
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value.clone())
+                .unwrap()
+        };
+        let sent = cbor::from_slice(&command.encoded_data).unwrap();
+        let input = member(
+            &member(&sent, &cbor::Value::Integer(0x04)),
+            &"hmac-secret".into(),
+        );
+        let cbor::Value::Bytes(salt_enc) = member(&input, &cbor::Value::Integer(0x02)) else {
+            panic!("Expected saltEnc");
+        };
+        let salt = shared_secret
+            .protocol
+            .decrypt(&shared_secret.secret, &salt_enc)
+            .unwrap();
+        let mut prf_input = b"WebAuthn PRF\x00".to_vec();
+        prf_input.extend([1; 32]);
+        assert_eq!(
+            digest::digest(&digest::SHA256, &prf_input).as_ref(),
+            salt.as_slice()
+        );
+        assert_eq!(
+            cbor::Value::Integer(1),
+            member(&input, &cbor::Value::Integer(0x04))
+        );
+
+        // The output comes back decrypted as the first PRF result.
+        let output = shared_secret
+            .protocol
+            .encrypt(&shared_secret.secret, &[5; 32])
+            .unwrap();
+        let response = get_assertion_response(Some(cbor::Value::Map(vec![(
+            "hmac-secret".into(),
+            output.into(),
+        )])));
+        let assertion = parse_assertion(&response, &request, Some(&shared_secret)).unwrap();
+        let prf = assertion.unsigned_extensions_output.unwrap().prf.unwrap();
+        let results = prf.results.unwrap();
+        assert_eq!([5; 32], results.first);
+        assert_eq!(None, results.second);
+    }
+}
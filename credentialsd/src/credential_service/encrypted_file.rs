@@ -0,0 +1,169 @@
+//! Files encrypted with a per-user key, for secrets that the daemon keeps
+//! between sessions.
+//!
+//! Each file has its own key, kept in the user's keyring through the Secret
+//! Service API rather than on disk. A copy of the file, e.g. in a backup, can
+//! only be read with the keyring, and the daemon can only read it once the
+//! keyring is unlocked.
+
+use std::{
+    fmt::Debug,
+    fs::{self, DirBuilder, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::PathBuf,
+};
+
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+pub(crate) const KEY_LEN: usize = 32;
+
+/// A JSON value stored encrypted with AES-256-GCM in `dir/name`.
+pub(crate) struct EncryptedFile {
+    dir: PathBuf,
+    name: &'static str,
+    /// Binds the ciphertext to its purpose and format version.
+    aad: &'static [u8],
+    key: LessSafeKey,
+}
+
+impl Debug for EncryptedFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedFile")
+            .field("dir", &self.dir)
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptedFile {
+    /// Uses `key`, which is kept in the user's keyring, see
+    /// [`platform::file_key`](super::platform::file_key).
+    pub(crate) fn new(
+        dir: PathBuf,
+        name: &'static str,
+        aad: &'static [u8],
+        key: &[u8; KEY_LEN],
+    ) -> Self {
+        let key = UnboundKey::new(&AES_256_GCM, key).expect("key to be KEY_LEN bytes");
+        Self {
+            dir,
+            name,
+            aad,
+            key: LessSafeKey::new(key),
+        }
+    }
+
+    pub(crate) fn load<T: DeserializeOwned>(&self) -> io::Result<T> {
+        let mut data = fs::read(self.dir.join(self.name))?;
+        if data.len() < NONCE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is truncated", self.name),
+            ));
+        }
+        let mut ciphertext = data.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&data).expect("nonce to be NONCE_LEN bytes");
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::from(self.aad), &mut ciphertext)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Failed to decrypt {}", self.name),
+                )
+            })?;
+        serde_json::from_slice(plaintext).map_err(io::Error::from)
+    }
+
+    pub(crate) fn save<T: Serialize + ?Sized>(&self, value: &T) -> io::Result<()> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)?;
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| io::Error::other("Failed to generate nonce"))?;
+        let mut data = serde_json::to_vec(value)?;
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(self.aad),
+                &mut data,
+            )
+            .map_err(|_| io::Error::other(format!("Failed to encrypt {}", self.name)))?;
+
+        // Write to a temporary file first, so that a crash does not lose the
+        // previous contents.
+        let tmp_path = self.dir.join(format!("{}.tmp", self.name));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(&nonce)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, self.dir.join(self.name))
+    }
+}
+
+/// The directory that per-user data is stored in: `$XDG_DATA_HOME/credentialsd`.
+pub(crate) fn data_dir() -> io::Result<PathBuf> {
+    Ok(dirs::data_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No data directory"))?
+        .join("credentialsd"))
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{fs, io, path::PathBuf};
+
+    use super::{EncryptedFile, KEY_LEN};
+
+    /// Key of the encrypted files in tests, which don't have a keyring.
+    pub(crate) const KEY: [u8; KEY_LEN] = [7; KEY_LEN];
+
+    /// A directory that is removed when dropped.
+    pub(crate) struct TempDir(pub PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("credentialsd-test-{}", rand::random::<u64>())))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_files_are_only_readable_with_their_key() {
+        let dir = TempDir::new();
+        EncryptedFile::new(dir.0.clone(), "file", b"aad", &KEY)
+            .save("secret")
+            .unwrap();
+
+        let file = EncryptedFile::new(dir.0.clone(), "file", b"aad", &KEY);
+        assert_eq!("secret", file.load::<String>().unwrap());
+        let file = EncryptedFile::new(dir.0.clone(), "file", b"aad", &[8; KEY_LEN]);
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            file.load::<String>().unwrap_err().kind()
+        );
+        // Nothing but the ciphertext is stored.
+        let names: Vec<_> = fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(vec!["file"], names);
+    }
+}
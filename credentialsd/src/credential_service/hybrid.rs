@@ -1,45 +1,57 @@
-use core::panic;
 use std::fmt::Debug;
+use std::sync::Arc;
 
 use async_stream::stream;
-use futures_lite::Stream;
 use tokio::sync::broadcast;
 use tokio::sync::mpsc::{self, Sender};
 use tracing::{debug, error};
 
-use libwebauthn::transport::cable::channel::{CableUpdate, CableUxUpdate};
+use libwebauthn::transport::cable::channel::{CableChannel, CableUpdate, CableUxUpdate};
+use libwebauthn::transport::cable::known_devices::{CableKnownDevice, ClientPayloadHint};
 use libwebauthn::transport::cable::qr_code_device::{CableQrCodeDevice, QrCodeOperationHint};
 use libwebauthn::transport::{Channel, Device};
 use libwebauthn::webauthn::{Error as WebAuthnError, WebAuthn};
 
-use credentialsd_common::model::{CredentialRequest, Error};
+use credentialsd_common::model::{CredentialRequest, CredentialResponse, Error, Transport};
 
-use super::AuthenticatorResponse;
+use super::{
+    libwebauthn_request,
+    linked_devices::LinkedDeviceStore,
+    transport::{
+        AbortOnDrop, AuthenticatorState, AuthenticatorStates, AuthenticatorTransport,
+        TransportDevice,
+    },
+    AuthenticatorResponse,
+};
 
-pub(crate) trait HybridHandler {
-    fn start(
-        &self,
-        request: &CredentialRequest,
-    ) -> impl Stream<Item = HybridEvent> + Unpin + Send + Sized + 'static;
-}
+/// How many QR codes to offer in a row before giving up, e.g. when Bluetooth
+/// is unavailable.
+const MAX_QR_CODE_ATTEMPTS: usize = 3;
 
 #[derive(Debug)]
-pub struct InternalHybridHandler {}
+pub struct InternalHybridHandler {
+    /// Where to store the linking information that phones send, if linking
+    /// is enabled.
+    store: Option<Arc<LinkedDeviceStore>>,
+}
+
 impl InternalHybridHandler {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(store: Option<Arc<LinkedDeviceStore>>) -> Self {
+        Self { store }
     }
 }
 
-impl HybridHandler for InternalHybridHandler {
-    fn start(
-        &self,
-        request: &CredentialRequest,
-    ) -> impl Stream<Item = HybridEvent> + Unpin + Send + Sized + 'static {
+impl AuthenticatorTransport for InternalHybridHandler {
+    fn transport(&self) -> Transport {
+        Transport::HybridQr
+    }
+
+    fn start(&self, _device_id: &str, request: &CredentialRequest) -> AuthenticatorStates {
         tracing::debug!("Starting hybrid operation");
         let request = request.clone();
+        let store = self.store.clone();
         let (tx, mut rx) = mpsc::channel(16);
-        tokio::spawn(async move {
+        let task = tokio::spawn(async move {
             let hint = match request {
                 CredentialRequest::CreatePublicKeyCredentialRequest(_) => {
                     QrCodeOperationHint::MakeCredential
@@ -48,172 +60,277 @@ impl HybridHandler for InternalHybridHandler {
                     QrCodeOperationHint::GetAssertionRequest
                 }
             };
-            let mut device = CableQrCodeDevice::new_transient(hint);
-            let qr_code = device.qr_code.to_string();
-            if let Err(err) = tx.send(HybridStateInternal::Init(qr_code)).await {
-                tracing::error!("Failed to send caBLE update: {:?}", err);
-                return;
-            };
-            tokio::spawn(async move {
-                let mut channel = match device.channel().await {
-                    Ok(channel) => channel,
-                    Err(e) => {
-                        tracing::error!("Failed to open hybrid channel: {:?}", e);
-                        panic!();
-                    }
+            // A failed attempt is followed by a new QR code, as the secrets
+            // in the previous one can't be used again.
+            for attempt in 1..=MAX_QR_CODE_ATTEMPTS {
+                // Phones only send linking information if we ask them to.
+                let mut device = match &store {
+                    Some(store) => CableQrCodeDevice::new_persistent(hint, store.clone()),
+                    None => CableQrCodeDevice::new_transient(hint),
                 };
-
-                let state_sender_clone = tx.clone();
-                let ux_updates_rx = channel.get_ux_update_receiver();
-                tokio::spawn(async move {
-                    handle_hybrid_updates(&state_sender_clone, ux_updates_rx).await;
-                    debug!("Reached end of Hybrid updates stream.");
-                });
-
-                tracing::debug!("Polling hybrid channel for updates.");
-                let response: Result<AuthenticatorResponse, Error> = loop {
-                    match &request {
-                        CredentialRequest::CreatePublicKeyCredentialRequest(make_request) => {
-                            match channel.webauthn_make_credential(make_request).await {
-                                Ok(response) => break Ok(response.into()),
-                                Err(WebAuthnError::Ctap(ctap_error)) => {
-                                    if ctap_error.is_retryable_user_error() {
-                                        tracing::debug!("Retrying credential creation operation because of CTAP error: {:?}", ctap_error);
-                                        continue;
-                                    } else {
-                                        tracing::error!(
-                                            "Received CTAP unrecoverable CTAP error: {:?}",
-                                            ctap_error
-                                        );
-                                        break Err(Error::AuthenticatorError);
-                                    }
-                                }
-                                Err(err) => {
-                                    tracing::error!(
-                                        "Received unrecoverable error from authenticator: {:?}",
-                                        err
-                                    );
-                                    break Err(Error::AuthenticatorError);
-                                }
-                            };
-                        }
-                        CredentialRequest::GetPublicKeyCredentialRequest(get_request) => {
-                            match channel.webauthn_get_assertion(get_request).await {
-                                Ok(response) => break Ok(response.into()),
-                                Err(WebAuthnError::Ctap(ctap_error)) => {
-                                    if ctap_error.is_retryable_user_error() {
-                                        tracing::debug!("Retrying assertion operation because of CTAP error: {:?}", ctap_error);
-                                        continue;
-                                    } else {
-                                        tracing::error!(
-                                            "Received CTAP unrecoverable CTAP error: {:?}",
-                                            ctap_error
-                                        );
-                                        break Err(Error::AuthenticatorError);
-                                    }
-                                }
-                                Err(err) => {
-                                    tracing::error!(
-                                        "Received unrecoverable error from authenticator: {:?}",
-                                        err
-                                    );
-                                    break Err(Error::AuthenticatorError);
-                                }
-                            };
-                        }
+                let qr_code = device.qr_code.to_string();
+                if let Err(err) = tx.send(HybridStateInternal::Init(qr_code)).await {
+                    tracing::error!("Failed to send caBLE update: {:?}", err);
+                    return;
+                };
+                // Keep the channel in this task, so that dropping the stream
+                // closes the tunnel.
+                let response = match device.channel().await {
+                    Ok(mut channel) => run_ceremony(&mut channel, &tx, &request).await,
+                    Err(err) => {
+                        tracing::error!("Failed to open hybrid channel: {:?}", err);
+                        Err(Error::AuthenticatorError)
                     }
                 };
-                let terminal_state = match response {
-                    Ok(auth_response) => HybridStateInternal::Completed(Box::new(auth_response)),
-                    Err(_) => HybridStateInternal::Failed,
+                let state = match response {
+                    Ok(response) => HybridStateInternal::Completed(Box::new(response)),
+                    Err(_) => {
+                        tracing::debug!("Hybrid attempt {attempt} failed");
+                        HybridStateInternal::Failed
+                    }
                 };
-                if let Err(err) = tx.send(terminal_state).await {
-                    tracing::error!("Failed to send caBLE update: {:?}", err)
+                let completed = matches!(state, HybridStateInternal::Completed(_));
+                if let Err(err) = tx.send(state).await {
+                    tracing::error!("Failed to send caBLE update: {:?}", err);
+                    return;
                 }
-            });
+                if completed {
+                    return;
+                }
+            }
         });
+        let task = AbortOnDrop(task.abort_handle());
         Box::pin(stream! {
+            let _task = task;
             while let Some(state) = rx.recv().await {
-                yield HybridEvent { state }
+                yield state.into()
             }
         })
     }
 }
 
-/// Used to communicate privileged state between handler and credential service.
-#[derive(Clone, Debug)]
-pub(super) enum HybridStateInternal {
-    /// Awaiting BLE advert from phone. Content is the FIDO string to be
-    /// displayed to the user, which contains QR secret and public key.
-    Init(String),
+/// Phones linked in earlier hybrid ceremonies, each offered as a device and
+/// contacted through the tunnel service instead of by scanning a QR code.
+#[derive(Debug)]
+pub struct LinkedHybridHandler {
+    store: Arc<LinkedDeviceStore>,
+}
 
-    /// BLE advertisement has been received from phone, tunnel is being established
-    Connecting,
+impl LinkedHybridHandler {
+    pub fn new(store: Arc<LinkedDeviceStore>) -> Self {
+        Self { store }
+    }
+}
 
-    /// Hybrid tunnel has been established
-    Connected,
+impl AuthenticatorTransport for LinkedHybridHandler {
+    fn transport(&self) -> Transport {
+        Transport::HybridLinked
+    }
 
-    /// Authenticator data
-    Completed(Box<AuthenticatorResponse>),
+    fn devices(&self) -> Vec<TransportDevice> {
+        self.store
+            .list()
+            .into_iter()
+            .map(|device| TransportDevice {
+                id: device.id,
+                name: Some(device.name),
+                ..Default::default()
+            })
+            .collect()
+    }
 
-    Failed,
-    // TODO(cancellation)
-    // This isn't actually sent from the server.
-    #[allow(dead_code)]
-    UserCancelled,
+    fn start(&self, device_id: &str, request: &CredentialRequest) -> AuthenticatorStates {
+        let request = request.clone();
+        let device = self.store.get(device_id);
+        let store = self.store.clone();
+        let (tx, mut rx) = mpsc::channel(16);
+        let task = tokio::spawn(async move {
+            // Forgotten since it was listed.
+            let Some(device) = device else {
+                _ = tx.send(HybridStateInternal::Failed).await;
+                return;
+            };
+            tracing::debug!("Starting hybrid operation with {}", device.name);
+            let hint = match request {
+                CredentialRequest::CreatePublicKeyCredentialRequest(_) => {
+                    ClientPayloadHint::MakeCredential
+                }
+                CredentialRequest::GetPublicKeyCredentialRequest(_) => {
+                    ClientPayloadHint::GetAssertion
+                }
+            };
+            // The tunnel service notifies the phone as soon as we connect.
+            if let Err(err) = tx.send(HybridStateInternal::Connecting).await {
+                tracing::error!("Failed to send caBLE update: {:?}", err);
+                return;
+            }
+            let device = CableKnownDevice::new(hint, &device.info, store).await;
+            let mut channel = match device {
+                Ok(mut device) => device.channel().await,
+                Err(err) => Err(err),
+            };
+            let state = match channel {
+                Ok(ref mut channel) => match run_ceremony(channel, &tx, &request).await {
+                    Ok(response) => HybridStateInternal::Completed(Box::new(response)),
+                    Err(_) => HybridStateInternal::Failed,
+                },
+                Err(err) => {
+                    tracing::error!("Failed to open hybrid channel to linked device: {:?}", err);
+                    HybridStateInternal::Failed
+                }
+            };
+            if let Err(err) = tx.send(state).await {
+                tracing::error!("Failed to send caBLE update: {:?}", err)
+            }
+        });
+        let task = AbortOnDrop(task.abort_handle());
+        Box::pin(stream! {
+            let _task = task;
+            while let Some(state) = rx.recv().await {
+                yield state.into()
+            }
+        })
+    }
+
+    fn rename_device(&self, device_id: &str, name: &str) -> Result<(), Error> {
+        self.store
+            .rename(device_id, name)
+            .map_err(|err| Error::Internal(err.to_string()))
+    }
+
+    fn forget_device(&self, device_id: &str) -> Result<(), Error> {
+        self.store
+            .forget(device_id)
+            .map_err(|err| Error::Internal(err.to_string()))
+    }
 }
 
-// this is here to prevent making HybridStateInternal public to the whole crate.
-/// Messages between hybrid handler and credential service.
-pub struct HybridEvent {
-    pub(super) state: HybridStateInternal,
+/// Sends the request to the phone over an established tunnel, and reports
+/// its progress until the ceremony ends.
+async fn run_ceremony(
+    channel: &mut CableChannel,
+    tx: &Sender<HybridStateInternal>,
+    request: &CredentialRequest,
+) -> Result<AuthenticatorResponse, Error> {
+    let state_sender_clone = tx.clone();
+    let ux_updates_rx = channel.get_ux_update_receiver();
+    tokio::spawn(async move {
+        handle_hybrid_updates(&state_sender_clone, ux_updates_rx).await;
+        debug!("Reached end of Hybrid updates stream.");
+    });
+
+    tracing::debug!("Polling hybrid channel for updates.");
+    loop {
+        match request {
+            CredentialRequest::CreatePublicKeyCredentialRequest(make_request) => {
+                let Some(make_request) = libwebauthn_request(make_request) else {
+                    break Err(Error::AuthenticatorError);
+                };
+                match channel.webauthn_make_credential(&make_request).await {
+                    Ok(response) => break Ok(response.into()),
+                    Err(WebAuthnError::Ctap(ctap_error)) => {
+                        if ctap_error.is_retryable_user_error() {
+                            tracing::debug!("Retrying credential creation operation because of CTAP error: {:?}", ctap_error);
+                            continue;
+                        } else {
+                            tracing::error!(
+                                "Received CTAP unrecoverable CTAP error: {:?}",
+                                ctap_error
+                            );
+                            break Err(Error::AuthenticatorError);
+                        }
+                    }
+                    Err(err) => {
+                        tracing::error!(
+                            "Received unrecoverable error from authenticator: {:?}",
+                            err
+                        );
+                        break Err(Error::AuthenticatorError);
+                    }
+                };
+            }
+            CredentialRequest::GetPublicKeyCredentialRequest(get_request) => {
+                match channel.webauthn_get_assertion(get_request).await {
+                    Ok(response) => break Ok(response.into()),
+                    Err(WebAuthnError::Ctap(ctap_error)) => {
+                        if ctap_error.is_retryable_user_error() {
+                            tracing::debug!(
+                                "Retrying assertion operation because of CTAP error: {:?}",
+                                ctap_error
+                            );
+                            continue;
+                        } else {
+                            tracing::error!(
+                                "Received CTAP unrecoverable CTAP error: {:?}",
+                                ctap_error
+                            );
+                            break Err(Error::AuthenticatorError);
+                        }
+                    }
+                    Err(err) => {
+                        tracing::error!(
+                            "Received unrecoverable error from authenticator: {:?}",
+                            err
+                        );
+                        break Err(Error::AuthenticatorError);
+                    }
+                };
+            }
+        }
+    }
 }
 
-/// Used to communicate privileged state between credential service and UI.
+/// Used to communicate privileged state between handler and credential service.
 #[derive(Clone, Debug)]
-pub enum HybridState {
-    /// Awaiting BLE advert from phone. Content is the FIDO string to be displayed to the user, which contains QR secret
-    /// and public key.
+pub(super) enum HybridStateInternal {
+    /// Awaiting BLE advert from phone. Content is the FIDO string to be
+    /// displayed to the user, which contains QR secret and public key.
     Init(String),
 
     /// BLE advertisement has been received from phone, tunnel is being established
     Connecting,
 
-    /// Tunnel is established, waiting for user to release credential on their device.
+    /// Hybrid tunnel has been established
     Connected,
 
-    /// Authenticator data has been received
-    Completed,
+    /// Authenticator data
+    Completed(Box<AuthenticatorResponse>),
 
-    /// Hybrid operation failed.
     Failed,
-
-    // This isn't actually sent from the server.
-    UserCancelled,
 }
 
-impl From<HybridStateInternal> for HybridState {
+impl From<HybridStateInternal> for AuthenticatorState {
     fn from(value: HybridStateInternal) -> Self {
         match value {
-            HybridStateInternal::Init(qr_code) => HybridState::Init(qr_code),
-            HybridStateInternal::Connecting => HybridState::Connecting,
-            HybridStateInternal::Connected => HybridState::Connected,
-            HybridStateInternal::Completed(_) => HybridState::Completed,
-            HybridStateInternal::UserCancelled => HybridState::UserCancelled,
-            HybridStateInternal::Failed => HybridState::Failed,
+            HybridStateInternal::Init(qr_code) => AuthenticatorState::NeedsQrCodeScan(qr_code),
+            HybridStateInternal::Connecting => AuthenticatorState::Connecting,
+            HybridStateInternal::Connected => AuthenticatorState::Connected,
+            HybridStateInternal::Completed(response) => {
+                AuthenticatorState::Completed(credential_response(&response))
+            }
+            HybridStateInternal::Failed => AuthenticatorState::Failed(Error::AuthenticatorError),
         }
     }
 }
 
-impl From<HybridState> for credentialsd_common::model::HybridState {
-    fn from(value: HybridState) -> Self {
-        match value {
-            HybridState::Init(qr_code) => credentialsd_common::model::HybridState::Started(qr_code),
-            HybridState::Connecting => credentialsd_common::model::HybridState::Connecting,
-            HybridState::Connected => credentialsd_common::model::HybridState::Connected,
-            HybridState::Completed => credentialsd_common::model::HybridState::Completed,
-            HybridState::UserCancelled => credentialsd_common::model::HybridState::UserCancelled,
-            HybridState::Failed => credentialsd_common::model::HybridState::Failed,
+fn credential_response(response: &AuthenticatorResponse) -> CredentialResponse {
+    match response {
+        AuthenticatorResponse::CredentialCreated(make_credential_response) => {
+            CredentialResponse::from_make_credential(
+                make_credential_response,
+                &["hybrid"],
+                "cross-platform",
+            )
+        }
+        AuthenticatorResponse::CredentialsAsserted(get_assertion_response) => {
+            CredentialResponse::from_get_assertion(
+                // When doing hybrid, the authenticator is capable of displaying it's own UI.
+                // So we assume here, it only ever returns one assertion.
+                // In case this doesn't hold true, we have to implement credential selection here,
+                // as is done for USB.
+                &get_assertion_response.assertions[0],
+                "cross-platform",
+            )
         }
     }
 }
@@ -255,16 +372,20 @@ async fn handle_hybrid_updates(
 pub(super) mod test {
     use std::task::Poll;
 
-    use futures_lite::Stream;
+    use futures_lite::{Stream, StreamExt};
     use libwebauthn::{
         fido::{AuthenticatorData, AuthenticatorDataFlags},
         ops::webauthn::{Assertion, GetAssertionResponse},
         proto::ctap2::{Ctap2PublicKeyCredentialDescriptor, Ctap2Transport},
     };
 
-    use credentialsd_common::model::CredentialRequest;
+    use credentialsd_common::model::{CredentialRequest, Transport};
+
+    use super::HybridStateInternal;
+    use crate::credential_service::transport::{
+        AuthenticatorState, AuthenticatorStates, AuthenticatorTransport,
+    };
 
-    use super::{HybridEvent, HybridHandler, HybridStateInternal};
     #[derive(Debug)]
     pub struct DummyHybridHandler {
         stream: DummyHybridStateStream,
@@ -286,17 +407,18 @@ pub(super) mod test {
             }
         }
     }
-    impl HybridHandler for DummyHybridHandler {
-        fn start(
-            &self,
-            _request: &CredentialRequest,
-        ) -> impl Stream<Item = HybridEvent> + Send + Sized + Unpin + 'static {
-            self.stream.clone()
+    impl AuthenticatorTransport for DummyHybridHandler {
+        fn transport(&self) -> Transport {
+            Transport::HybridQr
+        }
+
+        fn start(&self, _device_id: &str, _request: &CredentialRequest) -> AuthenticatorStates {
+            Box::pin(self.stream.clone().map(AuthenticatorState::from))
         }
     }
 
     #[derive(Clone, Debug)]
-    pub struct DummyHybridStateStream {
+    pub(super) struct DummyHybridStateStream {
         states: Vec<HybridStateInternal>,
     }
 
@@ -348,7 +470,7 @@ pub(super) mod test {
     }
 
     impl Stream for DummyHybridStateStream {
-        type Item = HybridEvent;
+        type Item = HybridStateInternal;
 
         fn poll_next(
             self: std::pin::Pin<&mut Self>,
@@ -358,7 +480,7 @@ pub(super) mod test {
                 Poll::Ready(None)
             } else {
                 let state = (self.get_mut()).states.remove(0);
-                Poll::Ready(Some(HybridEvent { state }))
+                Poll::Ready(Some(state))
             }
         }
     }
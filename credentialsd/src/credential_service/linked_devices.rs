@@ -0,0 +1,267 @@
+//! Phones that were linked during a hybrid QR ceremony, so that they can be
+//! contacted again through the tunnel service without scanning a QR code.
+//!
+//! The linking information lets anyone who has it ask the phone for
+//! credentials, so it is stored encrypted with a key from the user's keyring,
+//! in files only readable by the user.
+
+use std::{io, path::PathBuf, sync::Mutex};
+
+use async_trait::async_trait;
+use libwebauthn::transport::cable::known_devices::{
+    CableKnownDeviceId, CableKnownDeviceInfo, CableKnownDeviceInfoStore,
+};
+use serde::{Deserialize, Serialize};
+use zbus::Connection;
+
+use super::{
+    encrypted_file::{self, EncryptedFile, KEY_LEN},
+    platform,
+};
+
+const DEVICES_FILE: &str = "linked-devices";
+/// Binds the ciphertext to its purpose and format version.
+const AAD: &[u8] = b"credentialsd linked devices v1";
+
+/// A phone that can be contacted through the tunnel service.
+#[derive(Clone, Debug)]
+pub struct LinkedDevice {
+    pub id: CableKnownDeviceId,
+    /// Name to show to the user: the one they chose, or else the one the
+    /// phone sent.
+    pub name: String,
+    pub info: CableKnownDeviceInfo,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct StoredDevice {
+    id: CableKnownDeviceId,
+    /// Name chosen by the user. Kept when the phone sends new linking information.
+    display_name: Option<String>,
+    name: String,
+    contact_id: Vec<u8>,
+    link_id: Vec<u8>,
+    link_secret: Vec<u8>,
+    public_key: Vec<u8>,
+    tunnel_domain: String,
+}
+
+impl StoredDevice {
+    fn new(id: &CableKnownDeviceId, info: &CableKnownDeviceInfo) -> Self {
+        Self {
+            id: id.clone(),
+            display_name: None,
+            name: info.name.clone(),
+            contact_id: info.contact_id.clone(),
+            link_id: info.link_id.to_vec(),
+            link_secret: info.link_secret.to_vec(),
+            public_key: info.public_key.to_vec(),
+            tunnel_domain: info.tunnel_domain.clone(),
+        }
+    }
+
+    fn to_linked_device(&self) -> Option<LinkedDevice> {
+        Some(LinkedDevice {
+            id: self.id.clone(),
+            name: self
+                .display_name
+                .clone()
+                .unwrap_or_else(|| self.name.clone()),
+            info: CableKnownDeviceInfo {
+                contact_id: self.contact_id.clone(),
+                link_id: self.link_id.clone().try_into().ok()?,
+                link_secret: self.link_secret.clone().try_into().ok()?,
+                public_key: self.public_key.clone().try_into().ok()?,
+                name: self.name.clone(),
+                tunnel_domain: self.tunnel_domain.clone(),
+            },
+        })
+    }
+}
+
+/// Linked phones of the current user, persisted in their data directory.
+#[derive(Debug)]
+pub struct LinkedDeviceStore {
+    file: EncryptedFile,
+    devices: Mutex<Vec<StoredDevice>>,
+}
+
+impl LinkedDeviceStore {
+    /// Opens the store in `$XDG_DATA_HOME/credentialsd`, with its key from
+    /// the user's keyring on the session bus at `conn`.
+    pub async fn open(conn: &Connection) -> io::Result<Self> {
+        let key = platform::file_key(conn, DEVICES_FILE).await?;
+        Ok(Self::open_at(encrypted_file::data_dir()?, &key))
+    }
+
+    /// Opens the store in `dir`. Linked devices that cannot be read are
+    /// dropped, as the user can link their phones again.
+    fn open_at(dir: PathBuf, key: &[u8; KEY_LEN]) -> Self {
+        let file = EncryptedFile::new(dir, DEVICES_FILE, AAD, key);
+        let devices = match file.load() {
+            Ok(devices) => devices,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                tracing::warn!("Failed to load linked devices, ignoring them: {err}");
+                Vec::new()
+            }
+        };
+        Self {
+            file,
+            devices: Mutex::new(devices),
+        }
+    }
+
+    pub fn list(&self) -> Vec<LinkedDevice> {
+        let devices = self.devices.lock().unwrap();
+        devices
+            .iter()
+            .filter_map(StoredDevice::to_linked_device)
+            .collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<LinkedDevice> {
+        let devices = self.devices.lock().unwrap();
+        devices
+            .iter()
+            .find(|device| device.id == id)
+            .and_then(StoredDevice::to_linked_device)
+    }
+
+    /// Sets the name shown for the device. An empty name restores the one
+    /// sent by the phone.
+    pub fn rename(&self, id: &str, name: &str) -> io::Result<()> {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices
+            .iter_mut()
+            .find(|device| device.id == id)
+            .ok_or_else(|| unknown_device(id))?;
+        let name = name.trim();
+        device.display_name = (!name.is_empty()).then(|| name.to_string());
+        self.file.save(&*devices)
+    }
+
+    pub fn forget(&self, id: &str) -> io::Result<()> {
+        let mut devices = self.devices.lock().unwrap();
+        let len = devices.len();
+        devices.retain(|device| device.id != id);
+        if devices.len() == len {
+            return Err(unknown_device(id));
+        }
+        self.file.save(&*devices)
+    }
+}
+
+#[async_trait]
+impl CableKnownDeviceInfoStore for LinkedDeviceStore {
+    async fn put_known_device(
+        &self,
+        device_id: &CableKnownDeviceId,
+        device: &CableKnownDeviceInfo,
+    ) {
+        tracing::debug!("Storing linking information for {}", device.name);
+        let mut devices = self.devices.lock().unwrap();
+        let mut stored = StoredDevice::new(device_id, device);
+        if let Some(existing) = devices.iter_mut().find(|d| d.id == *device_id) {
+            stored.display_name = existing.display_name.take();
+            *existing = stored;
+        } else {
+            devices.push(stored);
+        }
+        if let Err(err) = self.file.save(&*devices) {
+            tracing::error!("Failed to save linked devices: {err}");
+        }
+    }
+
+    async fn delete_known_device(&self, device_id: &CableKnownDeviceId) {
+        tracing::debug!("Phone {device_id} unlinked itself");
+        let mut devices = self.devices.lock().unwrap();
+        devices.retain(|device| device.id != *device_id);
+        if let Err(err) = self.file.save(&*devices) {
+            tracing::error!("Failed to save linked devices: {err}");
+        }
+    }
+}
+
+fn unknown_device(id: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("Unknown linked device: {id}"),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use libwebauthn::transport::cable::known_devices::{
+        CableKnownDeviceInfo, CableKnownDeviceInfoStore,
+    };
+
+    use super::{LinkedDeviceStore, DEVICES_FILE};
+    use crate::credential_service::encrypted_file::test::{TempDir, KEY};
+
+    fn phone(name: &str) -> CableKnownDeviceInfo {
+        CableKnownDeviceInfo {
+            contact_id: b"fcm-token".to_vec(),
+            link_id: [1; 8],
+            link_secret: [2; 32],
+            public_key: [4; 65],
+            name: name.to_string(),
+            tunnel_domain: "cable.ua5v.com".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_linked_devices_are_persisted_encrypted() {
+        let dir = TempDir::new();
+        let store = LinkedDeviceStore::open_at(dir.0.clone(), &KEY);
+        store
+            .put_known_device(&"phone".to_string(), &phone("Pixel 9"))
+            .await;
+
+        let data = fs::read(dir.0.join(DEVICES_FILE)).unwrap();
+        assert!(!data.windows(9).any(|w| w == b"fcm-token"));
+
+        let store = LinkedDeviceStore::open_at(dir.0.clone(), &KEY);
+        let devices = store.list();
+        assert_eq!(1, devices.len());
+        assert_eq!("Pixel 9", devices[0].name);
+        assert_eq!([2; 32], devices[0].info.link_secret);
+    }
+
+    #[tokio::test]
+    async fn test_renamed_devices_keep_their_name_when_relinked() {
+        let dir = TempDir::new();
+        let store = LinkedDeviceStore::open_at(dir.0.clone(), &KEY);
+        store
+            .put_known_device(&"phone".to_string(), &phone("Pixel 9"))
+            .await;
+        store.rename("phone", "Work phone").unwrap();
+        store
+            .put_known_device(&"phone".to_string(), &phone("Pixel 9 Pro"))
+            .await;
+
+        let store = LinkedDeviceStore::open_at(dir.0.clone(), &KEY);
+        let device = store.get("phone").unwrap();
+        assert_eq!("Work phone", device.name);
+        assert_eq!("Pixel 9 Pro", device.info.name);
+
+        store.rename("phone", "").unwrap();
+        assert_eq!("Pixel 9 Pro", store.get("phone").unwrap().name);
+    }
+
+    #[tokio::test]
+    async fn test_forgotten_devices_are_removed() {
+        let dir = TempDir::new();
+        let store = LinkedDeviceStore::open_at(dir.0.clone(), &KEY);
+        store
+            .put_known_device(&"phone".to_string(), &phone("Pixel 9"))
+            .await;
+        store.forget("phone").unwrap();
+        assert!(store.forget("phone").is_err());
+
+        let store = LinkedDeviceStore::open_at(dir.0.clone(), &KEY);
+        assert!(store.list().is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+//! Managing security keys, e.g. setting their PIN, deleting the passkeys
+//! stored on them, enrolling fingerprints or resetting them.
+//!
+//! Like credential requests, management requests are completed by the user in
+//! the trusted UI: they pick the security key there, and every secret, like
+//! the current and new PIN, is entered there rather than passed by the client.
+//! Changes to the settings of a key, and resets, are confirmed there too.
+//! Only USB security keys can be managed, though NFC and BLE ones can be
+//! read with [`ManagementRequest::GetInfo`].
+
+use credentialsd_common::model::{
+    AuthenticatorInfo, Fingerprint, Operation, PinInfo, SecurityKeyCredential,
+};
+
+/// The length limit of PINs in UTF-8, which leaves room for the padding of
+/// the encrypted PIN in `authenticatorClientPIN`.
+pub(super) const MAX_PIN_BYTES: usize = 63;
+
+/// The minimum length of PINs of keys that do not report one.
+pub(super) const DEFAULT_MIN_PIN_LENGTH: u32 = 4;
+
+#[derive(Clone, Debug)]
+pub enum ManagementRequest {
+    /// Reads what the key reports about itself in `authenticatorGetInfo`.
+    GetInfo,
+    /// Reads whether the key has a PIN, how many attempts are left, and what
+    /// new PINs must look like.
+    GetPinInfo,
+    /// Sets the PIN of a key that has none, or changes the current one.
+    SetPin,
+    /// Lists the discoverable credentials on the key, and how many more it
+    /// can store.
+    ListCredentials,
+    DeleteCredential {
+        credential_id: Vec<u8>,
+    },
+    /// Replaces the display name of the user of a credential.
+    UpdateUserDisplayName {
+        credential_id: Vec<u8>,
+        display_name: String,
+    },
+    ListFingerprints,
+    /// Enrolls a new fingerprint, captured in as many samples as the key
+    /// needs, and names it if a name is given.
+    EnrollFingerprint {
+        name: Option<String>,
+    },
+    RenameFingerprint {
+        template_id: Vec<u8>,
+        name: String,
+    },
+    RemoveFingerprint {
+        template_id: Vec<u8>,
+    },
+    /// Requires user verification for every use of the key, or stops
+    /// requiring it.
+    SetAlwaysUv {
+        enable: bool,
+    },
+    /// Raises the minimum length of PINs, and lets the given relying parties
+    /// read it when credentials are created.
+    SetMinPinLength {
+        min_length: u32,
+        rp_ids: Vec<String>,
+    },
+    EnableEnterpriseAttestation,
+    /// Deletes all credentials, fingerprints and the PIN of the key.
+    Reset,
+}
+
+impl ManagementRequest {
+    /// The operation that the UI is launched with.
+    pub(super) fn operation(&self) -> Operation {
+        match self {
+            Self::GetInfo => Operation::InspectSecurityKey,
+            Self::GetPinInfo | Self::SetPin => Operation::ManagePin,
+            Self::ListCredentials
+            | Self::DeleteCredential { .. }
+            | Self::UpdateUserDisplayName { .. } => Operation::ManageCredentials,
+            Self::ListFingerprints
+            | Self::EnrollFingerprint { .. }
+            | Self::RenameFingerprint { .. }
+            | Self::RemoveFingerprint { .. } => Operation::ManageFingerprints,
+            Self::SetAlwaysUv { .. }
+            | Self::SetMinPinLength { .. }
+            | Self::EnableEnterpriseAttestation => Operation::ConfigureSecurityKey,
+            Self::Reset => Operation::ResetSecurityKey,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ManagementResponse {
+    Info(AuthenticatorInfo),
+    PinInfo(PinInfo),
+    PinSet,
+    Credentials {
+        credentials: Vec<SecurityKeyCredential>,
+        remaining: u32,
+    },
+    CredentialDeleted,
+    UserUpdated,
+    Fingerprints(Vec<Fingerprint>),
+    FingerprintEnrolled {
+        template_id: Vec<u8>,
+    },
+    FingerprintRenamed,
+    FingerprintRemoved,
+    /// The key is configured as requested, possibly because it was already.
+    Configured,
+    ResetDone,
+}
+
+/// Why a new PIN was rejected before it was sent to the key.
+#[derive(Debug, PartialEq)]
+pub(super) enum NewPinError {
+    TooShort,
+    TooLong,
+    /// The key requires its PIN to be changed, so it cannot be set again.
+    Unchanged,
+}
+
+/// Checks a new PIN against the policy of the key, as the key would.
+///
+/// `current_pin` is given if the key requires its PIN to be changed.
+pub(super) fn check_new_pin(
+    pin: &str,
+    min_length: u32,
+    current_pin: Option<&str>,
+) -> Result<(), NewPinError> {
+    if pin.chars().count() < min_length as usize {
+        return Err(NewPinError::TooShort);
+    }
+    if pin.len() > MAX_PIN_BYTES {
+        return Err(NewPinError::TooLong);
+    }
+    if current_pin == Some(pin) {
+        return Err(NewPinError::Unchanged);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_new_pin, NewPinError};
+
+    #[test]
+    fn test_new_pins_follow_the_policy_of_the_key() {
+        assert_eq!(Ok(()), check_new_pin("1234", 4, None));
+        assert_eq!(Err(NewPinError::TooShort), check_new_pin("123", 4, None));
+        // The minimum length is in code points, not bytes.
+        assert_eq!(Err(NewPinError::TooShort), check_new_pin("ééé", 4, None));
+        assert_eq!(Ok(()), check_new_pin(&"é".repeat(31), 4, None));
+        assert_eq!(
+            Err(NewPinError::TooLong),
+            check_new_pin(&"é".repeat(32), 4, None)
+        );
+        assert_eq!(
+            Err(NewPinError::Unchanged),
+            check_new_pin("123456", 6, Some("123456"))
+        );
+        assert_eq!(Ok(()), check_new_pin("654321", 6, Some("123456")));
+    }
+}
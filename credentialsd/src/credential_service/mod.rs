@@ -1,4 +1,13 @@
+pub mod ble;
+mod ctap;
+mod encrypted_file;
 pub mod hybrid;
+pub mod linked_devices;
+pub mod management;
+pub mod nfc;
+pub mod platform;
+pub mod provider;
+pub mod transport;
 pub mod usb;
 
 use std::{
@@ -10,29 +19,35 @@ use std::{
     task::Poll,
 };
 
-use futures_lite::{FutureExt, Stream, StreamExt};
+use futures_lite::{Stream, StreamExt};
 use libwebauthn::{
     self,
-    ops::webauthn::{GetAssertionResponse, MakeCredentialResponse},
+    ops::webauthn::{GetAssertionResponse, MakeCredentialRequest, MakeCredentialResponse},
+    proto::ctap2::{
+        Ctap2COSEAlgorithmIdentifier, Ctap2CredentialType, Ctap2PublicKeyCredentialType,
+    },
 };
 use tokio::sync::oneshot::Sender;
 
 use credentialsd_common::{
     model::{
-        CredentialRequest, CredentialResponse, Device, Error as CredentialServiceError, Operation,
-        Transport,
+        CredentialRequest, CredentialResponse, Device, Error as CredentialServiceError,
+        MakeCredentialRequestInternal, Operation, Transport,
     },
     server::{RequestId, ViewRequest},
 };
 
-use crate::credential_service::{hybrid::HybridEvent, usb::UsbEvent};
+use crate::cose::CoseKeyAlgorithmIdentifier;
 
 use self::{
-    hybrid::{HybridHandler, HybridState, HybridStateInternal},
-    usb::{UsbHandler, UsbStateInternal},
+    management::{ManagementRequest, ManagementResponse},
+    platform::{
+        store::PlatformCredentialStore, PendingTransfer, TransferRequest, TransferResponse,
+    },
+    transport::{AuthenticatorStates, TransportRegistry},
 };
 
-pub use usb::UsbState;
+pub use transport::AuthenticatorState;
 
 /// Used by the credential service to control the UI.
 pub trait UiController {
@@ -59,40 +74,57 @@ impl RequestContext {
     }
 }
 
+/// A transfer of platform passkeys waiting for the user's confirmation.
 #[derive(Debug)]
-pub struct CredentialService<H: HybridHandler, U: UsbHandler, UC: UiController> {
-    devices: Vec<Device>,
+struct TransferContext {
+    transfer: Arc<PendingTransfer>,
+    response_channel: Sender<Result<TransferResponse, CredentialServiceError>>,
+    request_id: RequestId,
+}
+
+/// A management request waiting for the user to pick a security key.
+#[derive(Debug)]
+struct ManagementContext {
+    request: ManagementRequest,
+    response_channel: Sender<Result<ManagementResponse, CredentialServiceError>>,
+    request_id: RequestId,
+}
+
+#[derive(Debug)]
+pub struct CredentialService<UC: UiController> {
+    transports: TransportRegistry,
+
+    /// Credentials of the platform authenticator, if it is offered, which
+    /// users can export and import.
+    platform_credentials: Option<Arc<PlatformCredentialStore>>,
 
     /// Current request and channel to respond to caller.
     ctx: Arc<Mutex<Option<RequestContext>>>,
 
-    hybrid_handler: H,
-    usb_handler: U,
+    /// Current transfer of platform passkeys. Transfers and credential
+    /// requests exclude each other, as they share the UI.
+    transfer: Mutex<Option<TransferContext>>,
+
+    /// Current management request of a security key, which excludes the
+    /// others too.
+    management: Arc<Mutex<Option<ManagementContext>>>,
 
     ui_control_client: Arc<UC>,
 }
 
-impl<H: HybridHandler + Debug, U: UsbHandler + Debug, UC: UiController + Debug>
-    CredentialService<H, U, UC>
-{
-    pub fn new(hybrid_handler: H, usb_handler: U, ui_control_client: Arc<UC>) -> Self {
-        let devices = vec![
-            Device {
-                id: String::from("0"),
-                transport: Transport::Usb,
-            },
-            Device {
-                id: String::from("1"),
-                transport: Transport::HybridQr,
-            },
-        ];
+impl<UC: UiController + Debug> CredentialService<UC> {
+    pub fn new(
+        transports: TransportRegistry,
+        platform_credentials: Option<Arc<PlatformCredentialStore>>,
+        ui_control_client: Arc<UC>,
+    ) -> Self {
         Self {
-            devices,
+            transports,
+            platform_credentials,
 
             ctx: Arc::new(Mutex::new(None)),
-
-            hybrid_handler,
-            usb_handler,
+            transfer: Mutex::new(None),
+            management: Arc::new(Mutex::new(None)),
 
             ui_control_client,
         }
@@ -105,7 +137,10 @@ impl<H: HybridHandler + Debug, U: UsbHandler + Debug, UC: UiController + Debug>
     ) {
         let request_id = {
             let mut cred_request = self.ctx.lock().unwrap();
-            if cred_request.is_some() {
+            if cred_request.is_some()
+                || self.transfer.lock().unwrap().is_some()
+                || self.management.lock().unwrap().is_some()
+            {
                 tx.send(Err(CredentialServiceError::Internal(
                     "Already a request in progress.".to_string(),
                 )))
@@ -148,7 +183,195 @@ impl<H: HybridHandler + Debug, U: UsbHandler + Debug, UC: UiController + Debug>
         tracing::debug!("Finished setting up request {request_id}");
     }
 
+    /// Starts a transfer of platform passkeys, which the user confirms in
+    /// the UI, by entering the passphrase of the archive unless it is plain.
+    pub async fn init_transfer(
+        &self,
+        request: TransferRequest,
+        tx: Sender<Result<TransferResponse, CredentialServiceError>>,
+    ) {
+        let Some(store) = self.platform_credentials.clone() else {
+            _ = tx.send(Err(CredentialServiceError::Internal(
+                "The platform authenticator is not enabled".to_string(),
+            )));
+            return;
+        };
+        let operation = match request {
+            TransferRequest::Export { .. } => Operation::Export,
+            TransferRequest::Import { .. } => Operation::Import,
+        };
+        let transfer =
+            match tokio::task::spawn_blocking(move || PendingTransfer::new(store, request)).await {
+                Ok(Ok(transfer)) => transfer,
+                Ok(Err(err)) => {
+                    _ = tx.send(Err(err));
+                    return;
+                }
+                Err(err) => {
+                    _ = tx.send(Err(CredentialServiceError::Internal(err.to_string())));
+                    return;
+                }
+            };
+        let request_id = {
+            let cred_request = self.ctx.lock().unwrap();
+            let mut pending = self.transfer.lock().unwrap();
+            if cred_request.is_some()
+                || pending.is_some()
+                || self.management.lock().unwrap().is_some()
+            {
+                _ = tx.send(Err(CredentialServiceError::Internal(
+                    "Already a request in progress.".to_string(),
+                )));
+                return;
+            }
+            let request_id: RequestId = rand::random();
+            _ = pending.insert(TransferContext {
+                transfer: Arc::new(transfer),
+                response_channel: tx,
+                request_id,
+            });
+            request_id
+        };
+        let view_request = ViewRequest {
+            operation,
+            id: request_id,
+        };
+        if let Err(err) = self.ui_control_client.launch_ui(view_request).await {
+            tracing::error!("Failed to launch UI for transfer: {err}. Cancelling request.");
+            if let Some(ctx) = self.transfer.lock().unwrap().take() {
+                _ = ctx
+                    .response_channel
+                    .send(Err(CredentialServiceError::Internal(err.to_string())));
+            }
+        }
+        tracing::debug!("Finished setting up transfer {request_id}");
+    }
+
+    /// The relying parties whose passkeys the current transfer exports, to
+    /// show to the user.
+    pub fn transfer_summary(&self) -> Result<Vec<String>, CredentialServiceError> {
+        self.transfer
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|ctx| ctx.transfer.rp_ids())
+            .ok_or_else(|| CredentialServiceError::Internal("No transfer in progress".to_string()))
+    }
+
+    /// Completes the current transfer with the passphrase entered by the
+    /// user, returning the number of passkeys transferred. The transfer stays
+    /// pending if it fails, e.g. with a wrong passphrase, so that the user
+    /// can try again.
+    pub async fn confirm_transfer(
+        &self,
+        passphrase: String,
+    ) -> Result<u32, CredentialServiceError> {
+        let (transfer, request_id) = self
+            .transfer
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|ctx| (ctx.transfer.clone(), ctx.request_id))
+            .ok_or_else(|| {
+                CredentialServiceError::Internal("No transfer in progress".to_string())
+            })?;
+        let response = tokio::task::spawn_blocking(move || transfer.complete(&passphrase))
+            .await
+            .map_err(|err| CredentialServiceError::Internal(err.to_string()))??;
+        let count = response.count();
+        let ctx = self
+            .transfer
+            .lock()
+            .unwrap()
+            .take_if(|ctx| ctx.request_id == request_id);
+        if let Some(ctx) = ctx {
+            if ctx.response_channel.send(Ok(response)).is_err() {
+                tracing::error!(
+                    "Attempted to send transfer response to caller, but channel was closed."
+                );
+            }
+        }
+        Ok(count)
+    }
+
+    /// Starts a management request, which the user completes in the UI by
+    /// picking a security key and entering its PIN.
+    pub async fn init_management(
+        &self,
+        request: ManagementRequest,
+        tx: Sender<Result<ManagementResponse, CredentialServiceError>>,
+    ) {
+        if self.transports.management_devices(&request).is_empty() {
+            _ = tx.send(Err(CredentialServiceError::Internal(
+                "No enabled security keys can handle this request".to_string(),
+            )));
+            return;
+        }
+        let operation = request.operation();
+        let request_id = {
+            let cred_request = self.ctx.lock().unwrap();
+            let mut pending = self.management.lock().unwrap();
+            if cred_request.is_some()
+                || pending.is_some()
+                || self.transfer.lock().unwrap().is_some()
+            {
+                _ = tx.send(Err(CredentialServiceError::Internal(
+                    "Already a request in progress.".to_string(),
+                )));
+                return;
+            }
+            let request_id: RequestId = rand::random();
+            _ = pending.insert(ManagementContext {
+                request,
+                response_channel: tx,
+                request_id,
+            });
+            request_id
+        };
+        let view_request = ViewRequest {
+            operation,
+            id: request_id,
+        };
+        if let Err(err) = self.ui_control_client.launch_ui(view_request).await {
+            tracing::error!("Failed to launch UI for management: {err}. Cancelling request.");
+            if let Some(ctx) = self.management.lock().unwrap().take() {
+                _ = ctx
+                    .response_channel
+                    .send(Err(CredentialServiceError::Internal(err.to_string())));
+            }
+        }
+        tracing::debug!("Finished setting up management request {request_id}");
+    }
+
     pub async fn cancel_request(&self, request_id: RequestId) {
+        if let Some(ctx) = self
+            .management
+            .lock()
+            .unwrap()
+            .take_if(|ctx| ctx.request_id == request_id)
+        {
+            tracing::debug!("Cancelling management request {request_id}");
+            _ = ctx
+                .response_channel
+                .send(Err(CredentialServiceError::Internal(format!(
+                    "Cancelled request {request_id}."
+                ))));
+            return;
+        }
+        if let Some(ctx) = self
+            .transfer
+            .lock()
+            .unwrap()
+            .take_if(|ctx| ctx.request_id == request_id)
+        {
+            tracing::debug!("Cancelling transfer {request_id}");
+            _ = ctx
+                .response_channel
+                .send(Err(CredentialServiceError::Internal(format!(
+                    "Cancelled request {request_id}."
+                ))));
+            return;
+        }
         let mut guard = self.ctx.lock().expect("Lock to be taken");
         if let Some(ctx) = guard.take_if(|ctx| ctx.request_id == request_id) {
             if request_id == ctx.request_id {
@@ -167,113 +390,246 @@ impl<H: HybridHandler + Debug, U: UsbHandler + Debug, UC: UiController + Debug>
     }
 
     pub async fn get_available_public_key_devices(&self) -> Result<Vec<Device>, ()> {
-        Ok(self.devices.to_owned())
+        let management = self
+            .management
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|ctx| ctx.request.clone());
+        match management {
+            Some(request) => Ok(self.transports.management_devices(&request)),
+            None => Ok(self.transports.devices()),
+        }
     }
 
-    pub fn get_hybrid_credential(
+    /// Sets the name shown for a device, e.g. a linked phone.
+    pub fn rename_device(&self, device_id: &str, name: &str) -> Result<(), CredentialServiceError> {
+        self.transports.rename_device(device_id, name)
+    }
+
+    /// Removes a device, e.g. a linked phone, so that it is no longer offered.
+    pub fn forget_device(&self, device_id: &str) -> Result<(), CredentialServiceError> {
+        self.transports.forget_device(device_id)
+    }
+
+    /// Lists the devices of the installed credential providers, including
+    /// those turned off, along with whether they are turned on.
+    pub fn credential_providers(&self) -> Vec<(Device, bool)> {
+        self.transports
+            .transport_devices(Transport::PasskeyProvider)
+    }
+
+    /// Turns the device of a credential provider on or off. Providers that
+    /// are off are not offered as devices.
+    pub fn set_credential_provider_enabled(
         &self,
-    ) -> Pin<Box<dyn Stream<Item = HybridState> + Send + 'static>> {
+        device_id: &str,
+        enabled: bool,
+    ) -> Result<(), CredentialServiceError> {
+        self.transports.set_device_enabled(device_id, enabled)
+    }
+
+    /// Starts the transport for the given device for the current request.
+    pub fn start_device(&self, device_id: &str) -> Result<DeviceStates, CredentialServiceError> {
+        let (transport, id) = self.transports.get(device_id).ok_or_else(|| {
+            CredentialServiceError::Internal(format!("Unknown device: {device_id}"))
+        })?;
+        let management = self
+            .management
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|ctx| ctx.request.clone());
+        if let Some(request) = management {
+            tracing::debug!("Starting management request on device {device_id}");
+            let states = transport.manage(&id, &request)?;
+            let ctx = self.management.clone();
+            let transport = transport.transport();
+            let states = states.map(move |state| {
+                if let AuthenticatorState::Managed(response) = &state {
+                    complete_management(&ctx, (**response).clone());
+                }
+                DeviceState {
+                    transport: transport.clone(),
+                    state,
+                }
+            });
+            return Ok(Box::pin(states));
+        }
         let guard = self.ctx.lock().unwrap();
         if let Some(RequestContext { ref request, .. }) = *guard {
-            let stream = self.hybrid_handler.start(request);
+            tracing::debug!(
+                "Starting {} transport for device {device_id}",
+                transport.transport().as_str()
+            );
+            let stream = transport.start(&id, request);
             let ctx = self.ctx.clone();
-            Box::pin(HybridStateStream { inner: stream, ctx })
+            Ok(Box::pin(DeviceStateStream {
+                inner: stream,
+                transport: transport.transport(),
+                ctx,
+            }))
         } else {
             tracing::error!(
-                "Attempted to start hybrid credential flow, but no request context was found."
+                "Attempted to start device {device_id}, but no request context was found."
             );
-            todo!("Handle error when context is not set up.")
+            Err(CredentialServiceError::Internal(
+                "No request in progress".to_string(),
+            ))
         }
     }
 
-    pub fn get_usb_credential(&self) -> Pin<Box<dyn Stream<Item = UsbState> + Send + 'static>> {
-        let guard = self.ctx.lock().unwrap();
-        if let Some(RequestContext { ref request, .. }) = *guard {
-            let stream = self.usb_handler.start(request);
-            let ctx = self.ctx.clone();
-            Box::pin(UsbStateStream { inner: stream, ctx })
-        } else {
-            tracing::error!(
-                "Attempted to start hybrid credential flow, but no request context was found."
-            );
-            todo!("Handle error when context is not set up.")
+    /// Starts the transports of several devices at once for the current
+    /// request, e.g. to blink security keys while a QR code is shown.
+    ///
+    /// The first device to return a credential wins. The others are cancelled
+    /// by dropping their streams, which their transports cancel any ongoing
+    /// operation on.
+    ///
+    /// Only devices that require a separate user gesture can be raced. The
+    /// platform authenticator, credential providers and linked phones treat
+    /// being selected in the trusted UI as the user's consent, so starting
+    /// them alongside other devices is rejected.
+    pub fn start_devices(
+        &self,
+        device_ids: &[String],
+    ) -> Result<DeviceRace, CredentialServiceError> {
+        if device_ids.len() > 1 {
+            for id in device_ids {
+                let (transport, _) = self.transports.get(id).ok_or_else(|| {
+                    CredentialServiceError::Internal(format!("Unknown device: {id}"))
+                })?;
+                if matches!(
+                    transport.transport(),
+                    Transport::Internal | Transport::PasskeyProvider | Transport::HybridLinked
+                ) {
+                    return Err(CredentialServiceError::Internal(format!(
+                        "Device {id} must be selected on its own"
+                    )));
+                }
+            }
+        }
+        let streams = device_ids
+            .iter()
+            .map(|id| Ok((id.clone(), self.start_device(id)?)))
+            .collect::<Result<_, CredentialServiceError>>()?;
+        Ok(DeviceRace { streams })
+    }
+}
+
+/// The states of a device started for the current request.
+pub type DeviceStates = Pin<Box<dyn Stream<Item = DeviceState> + Send + 'static>>;
+
+/// State of a device started for the current request, shared with the UI.
+#[derive(Clone, Debug)]
+pub struct DeviceState {
+    pub transport: Transport,
+    pub state: AuthenticatorState,
+}
+
+impl DeviceState {
+    /// Whether the device returned a credential.
+    pub fn is_completed(&self) -> bool {
+        matches!(
+            self.state,
+            AuthenticatorState::Completed(_) | AuthenticatorState::Managed(_)
+        )
+    }
+
+    /// Whether the device will not send any more updates.
+    ///
+    /// USB security keys and hybrid QR codes recover from failures: the
+    /// former when the key is plugged in again, the latter by offering a new
+    /// QR code. Their streams end when they give up.
+    pub fn is_terminal(&self) -> bool {
+        match self.state {
+            AuthenticatorState::Completed(_) | AuthenticatorState::Managed(_) => true,
+            AuthenticatorState::Failed(_) => {
+                !matches!(self.transport, Transport::Usb | Transport::HybridQr)
+            }
+            _ => false,
         }
     }
 }
 
-pub struct HybridStateStream<H> {
-    inner: H,
+impl From<&DeviceState> for credentialsd_common::model::DeviceState {
+    fn from(value: &DeviceState) -> Self {
+        Self {
+            transport: value.transport.clone(),
+            state: (&value.state).into(),
+        }
+    }
+}
+
+/// Tags the states of a transport with its kind, completing the request when
+/// it returns a credential.
+struct DeviceStateStream {
+    inner: AuthenticatorStates,
+    transport: Transport,
     ctx: Arc<Mutex<Option<RequestContext>>>,
 }
 
-impl<H> Stream for HybridStateStream<H>
-where
-    H: Stream<Item = HybridEvent> + Unpin + Sized,
-{
-    type Item = HybridState;
+impl Stream for DeviceStateStream {
+    type Item = DeviceState;
 
     fn poll_next(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        let ctx = &self.ctx.clone();
-        match Box::pin(Box::pin(self).as_mut().inner.next()).poll(cx) {
+        match self.inner.poll_next(cx) {
             Poll::Pending => Poll::Pending,
-            Poll::Ready(Some(HybridEvent { state })) => {
-                if let HybridStateInternal::Completed(hybrid_response) = &state {
-                    let response = match &**hybrid_response {
-                        AuthenticatorResponse::CredentialCreated(make_credential_response) => {
-                            CredentialResponse::from_make_credential(
-                                make_credential_response,
-                                &["hybrid"],
-                                "cross-platform",
-                            )
-                        }
-                        AuthenticatorResponse::CredentialsAsserted(get_assertion_response) => {
-                            CredentialResponse::from_get_assertion(
-                                // When doing hybrid, the authenticator is capable of displaying it's own UI.
-                                // So we assume here, it only ever returns one assertion.
-                                // In case this doesn't hold true, we have to implement credential selection here,
-                                // as is done for USB.
-                                &get_assertion_response.assertions[0],
-                                "cross-platform",
-                            )
-                        }
-                    };
-                    complete_request(ctx, response.clone());
+            Poll::Ready(Some(state)) => {
+                if let AuthenticatorState::Completed(response) = &state {
+                    complete_request(&self.ctx, response.clone());
                 }
-                Poll::Ready(Some(state.into()))
+                Poll::Ready(Some(DeviceState {
+                    transport: self.transport.clone(),
+                    state,
+                }))
             }
             Poll::Ready(None) => Poll::Ready(None),
         }
     }
 }
 
-struct UsbStateStream<H> {
-    inner: H,
-    ctx: Arc<Mutex<Option<RequestContext>>>,
+/// The states of several devices started for the same request, with the ID
+/// of the device that each one belongs to.
+///
+/// Ends after a device completes, dropping the streams of the others.
+pub struct DeviceRace {
+    streams: Vec<(String, DeviceStates)>,
 }
 
-impl<H> Stream for UsbStateStream<H>
-where
-    H: Stream<Item = UsbEvent> + Unpin + Sized,
-{
-    type Item = UsbState;
+impl Stream for DeviceRace {
+    type Item = (String, DeviceState);
 
     fn poll_next(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        let ctx = &self.ctx.clone();
-        match Box::pin(Box::pin(self).as_mut().inner.next()).poll(cx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(Some(UsbEvent { state })) => {
-                if let UsbStateInternal::Completed(response) = &state {
-                    complete_request(ctx, response.clone());
+        let mut i = 0;
+        while i < self.streams.len() {
+            let (device_id, stream) = &mut self.streams[i];
+            match stream.poll_next(cx) {
+                Poll::Ready(Some(state)) => {
+                    let device_id = device_id.clone();
+                    if state.is_completed() {
+                        tracing::debug!("Device {device_id} won, cancelling the others");
+                        self.streams.clear();
+                    }
+                    return Poll::Ready(Some((device_id, state)));
+                }
+                Poll::Ready(None) => {
+                    // This device gave up, keep waiting for the others.
+                    drop(self.streams.remove(i));
                 }
-                Poll::Ready(Some(state.into()))
+                Poll::Pending => i += 1,
             }
-            Poll::Ready(None) => Poll::Ready(None),
+        }
+        if self.streams.is_empty() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
         }
     }
 }
@@ -286,6 +642,49 @@ fn complete_request(ctx: &Mutex<Option<RequestContext>>, response: CredentialRes
     }
 }
 
+fn complete_management(ctx: &Mutex<Option<ManagementContext>>, response: ManagementResponse) {
+    if let Some(ctx) = ctx.lock().unwrap().take() {
+        if ctx.response_channel.send(Ok(response)).is_err() {
+            tracing::error!(
+                "Attempted to send management response to caller, but channel was closed."
+            );
+        }
+    } else {
+        tracing::error!(
+            "Tried to consume management context to respond to caller, but none was found."
+        )
+    }
+}
+
+/// Returns the request as libwebauthn models it, for the authenticators that
+/// it drives. libwebauthn only knows a few algorithms, so the others that the
+/// RP requested are dropped, and there is no request if none are left.
+fn libwebauthn_request(request: &MakeCredentialRequestInternal) -> Option<MakeCredentialRequest> {
+    let algorithms: Vec<Ctap2CredentialType> = request
+        .algorithms
+        .iter()
+        .map(|alg| CoseKeyAlgorithmIdentifier::from(*alg))
+        .filter_map(|alg| match Ctap2COSEAlgorithmIdentifier::try_from(alg) {
+            Ok(algorithm) => Some(Ctap2CredentialType {
+                public_key_type: Ctap2PublicKeyCredentialType::PublicKey,
+                algorithm,
+            }),
+            Err(_) => {
+                tracing::debug!("Cannot request {alg:?} through libwebauthn, skipping.");
+                None
+            }
+        })
+        .collect();
+    if algorithms.is_empty() {
+        tracing::info!("None of the requested algorithms can be requested through libwebauthn.");
+        return None;
+    }
+    Some(MakeCredentialRequest {
+        algorithms,
+        ..request.ctap.clone()
+    })
+}
+
 #[derive(Debug, Clone)]
 enum AuthenticatorResponse {
     CredentialCreated(Box<MakeCredentialResponse>),
@@ -306,54 +705,264 @@ impl From<GetAssertionResponse> for AuthenticatorResponse {
 
 #[cfg(test)]
 mod test {
-    use std::{sync::Arc, time::Duration};
+    use std::{
+        pin::Pin,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use futures_lite::{Stream, StreamExt};
 
     use libwebauthn::{
         ops::webauthn::{ResidentKeyRequirement, UserVerificationRequirement},
         proto::ctap2::{
-            Ctap2COSEAlgorithmIdentifier, Ctap2CredentialType, Ctap2PublicKeyCredentialRpEntity,
-            Ctap2PublicKeyCredentialType, Ctap2PublicKeyCredentialUserEntity,
+            Ctap2COSEAlgorithmIdentifier, Ctap2PublicKeyCredentialRpEntity,
+            Ctap2PublicKeyCredentialUserEntity,
         },
     };
     use tokio::sync::{oneshot, Mutex as AsyncMutex};
 
     use crate::{
         credential_service::usb::InProcessUsbHandler,
-        dbus::test::{DummyFlowServer, DummyUiServer},
+        dbus::test::{DummyFlowClient, DummyFlowServer, DummyUiServer},
         webauthn,
     };
-    use credentialsd_common::model::{CredentialRequest, MakeCredentialRequest, Operation};
+    use credentialsd_common::model::{
+        CredentialRequest, CredentialResponse, Error as CredentialServiceError,
+        MakeCredentialRequest, MakeCredentialRequestInternal, Operation, Transport,
+    };
 
     use super::{
         hybrid::{test::DummyHybridHandler, HybridStateInternal},
+        libwebauthn_request,
+        management::ManagementRequest,
+        transport::{
+            AuthenticatorState, AuthenticatorStates, AuthenticatorTransport, TransportRegistry,
+        },
         AuthenticatorResponse, CredentialService,
     };
 
+    /// A transport that never finds an authenticator, and records when it is
+    /// cancelled.
+    #[derive(Debug)]
+    struct PendingTransport(Arc<AtomicBool>);
+
+    impl AuthenticatorTransport for PendingTransport {
+        fn transport(&self) -> Transport {
+            Transport::Usb
+        }
+
+        fn start(&self, _device_id: &str, _request: &CredentialRequest) -> AuthenticatorStates {
+            Box::pin(PendingStream(self.0.clone()))
+        }
+    }
+
+    /// A transport like the platform authenticator, whose selection in the
+    /// trusted UI counts as user presence.
+    #[derive(Debug)]
+    struct ConsentTransport;
+
+    impl AuthenticatorTransport for ConsentTransport {
+        fn transport(&self) -> Transport {
+            Transport::Internal
+        }
+
+        fn start(&self, _device_id: &str, _request: &CredentialRequest) -> AuthenticatorStates {
+            Box::pin(futures_lite::stream::pending())
+        }
+    }
+
+    struct PendingStream(Arc<AtomicBool>);
+
+    impl Stream for PendingStream {
+        type Item = AuthenticatorState;
+
+        fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Pending
+        }
+    }
+
+    impl Drop for PendingStream {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_libwebauthn_requests_keep_known_algorithms() {
+        let CredentialRequest::CreatePublicKeyCredentialRequest(mut request) =
+            create_credential_request()
+        else {
+            unreachable!();
+        };
+        // ES384, ESP256, ML-DSA-44, ES256
+        request.algorithms = vec![-35, -9, -48, -7];
+        let algorithms: Vec<_> = libwebauthn_request(&request)
+            .unwrap()
+            .algorithms
+            .into_iter()
+            .map(|credential_type| credential_type.algorithm)
+            .collect();
+        assert_eq!(
+            vec![
+                Ctap2COSEAlgorithmIdentifier::TOPT,
+                Ctap2COSEAlgorithmIdentifier::ES256
+            ],
+            algorithms
+        );
+
+        request.algorithms = vec![-35, -48];
+        assert!(libwebauthn_request(&request).is_none());
+    }
+
+    #[test]
+    fn test_first_device_to_complete_wins() {
+        let (request_tx, request_rx) = oneshot::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut transports = TransportRegistry::new();
+        transports.register(PendingTransport(cancelled.clone()));
+        transports.register(DummyHybridHandler::default());
+        let [pending, hybrid] = transports.devices().try_into().unwrap();
+        let (_ui_server, ui_client) = DummyUiServer::<DummyFlowClient>::new(Vec::new());
+        let cred_service = CredentialService::new(transports, None, Arc::new(ui_client));
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                cred_service
+                    .init_request(&create_credential_request(), request_tx)
+                    .await;
+                let race = cred_service
+                    .start_devices(&[pending.id, hybrid.id.clone()])
+                    .unwrap();
+                let states: Vec<_> = race.collect().await;
+                let (device_id, state) = states.last().unwrap();
+                assert_eq!(&hybrid.id, device_id);
+                assert!(state.is_completed());
+            });
+        assert!(cancelled.load(Ordering::Relaxed));
+        request_rx
+            .blocking_recv()
+            .expect("response to be sent")
+            .expect("a credential to be returned");
+    }
+
+    #[test]
+    fn test_devices_selected_as_consent_are_not_raced() {
+        let mut transports = TransportRegistry::new();
+        transports.register(PendingTransport(Arc::default()));
+        transports.register(ConsentTransport);
+        let [usb, internal] = transports.devices().try_into().unwrap();
+        let (_ui_server, ui_client) = DummyUiServer::<DummyFlowClient>::new(Vec::new());
+        let cred_service = CredentialService::new(transports, None, Arc::new(ui_client));
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let (tx, _rx) = oneshot::channel();
+                cred_service
+                    .init_request(&create_credential_request(), tx)
+                    .await;
+                assert!(cred_service
+                    .start_devices(&[usb.id.clone(), internal.id.clone()])
+                    .is_err());
+                assert!(cred_service.start_devices(&[internal.id]).is_ok());
+            });
+    }
+
+    #[test]
+    fn test_only_usb_security_keys_are_managed() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let mut transports = TransportRegistry::new();
+        transports.register(DummyHybridHandler::default());
+        let (_ui_server, ui_client) = DummyUiServer::<DummyFlowClient>::new(Vec::new());
+        let cred_service = CredentialService::new(transports, None, Arc::new(ui_client));
+        let (tx, rx) = oneshot::channel();
+        runtime.block_on(cred_service.init_management(ManagementRequest::GetPinInfo, tx));
+        assert!(rx.blocking_recv().unwrap().is_err());
+
+        let mut transports = TransportRegistry::new();
+        transports.register(InProcessUsbHandler {});
+        transports.register(DummyHybridHandler::default());
+        let [usb, hybrid] = transports.devices().try_into().unwrap();
+        let (_ui_server, ui_client) = DummyUiServer::<DummyFlowClient>::new(Vec::new());
+        let cred_service = CredentialService::new(transports, None, Arc::new(ui_client));
+        runtime.block_on(async {
+            let (tx, _rx) = oneshot::channel();
+            cred_service
+                .init_management(ManagementRequest::SetPin, tx)
+                .await;
+            let devices = cred_service.get_available_public_key_devices().await;
+            assert_eq!(Ok(vec![usb]), devices);
+            assert!(cred_service.start_device(&hybrid.id).is_err());
+
+            let (tx, rx) = oneshot::channel();
+            cred_service
+                .init_request(&create_credential_request(), tx)
+                .await;
+            assert!(rx.await.unwrap().is_err());
+        });
+    }
+
     #[test]
     fn test_hybrid_sets_credential() {
         tracing_subscriber::fmt::init();
-        let request = create_credential_request();
-        let qr_code = String::from("FIDO:/078241338926040702789239694720083010994762289662861130514766991835876383562063181103169246410435938367110394959927031730060360967994421343201235185697538107096654083332");
         let authenticator_response = create_authenticator_response();
+        request_hybrid_credential(vec![
+            HybridStateInternal::Init(QR_CODE.to_string()),
+            HybridStateInternal::Connecting,
+            HybridStateInternal::Completed(Box::new(authenticator_response)),
+        ])
+        .expect("a credential to be returned");
+    }
+
+    #[test]
+    fn test_hybrid_failure_does_not_complete_request() {
+        let authenticator_response = create_authenticator_response();
+        request_hybrid_credential(vec![
+            HybridStateInternal::Init(QR_CODE.to_string()),
+            HybridStateInternal::Connecting,
+            HybridStateInternal::Failed,
+            HybridStateInternal::Init(QR_CODE.to_string()),
+            HybridStateInternal::Connecting,
+            HybridStateInternal::Completed(Box::new(authenticator_response)),
+        ])
+        .expect("a credential to be returned after the failed attempt");
+    }
+
+    const QR_CODE: &str = "FIDO:/078241338926040702789239694720083010994762289662861130514766991835876383562063181103169246410435938367110394959927031730060360967994421343201235185697538107096654083332";
 
+    /// Selects a hybrid device that goes through the given states, and
+    /// returns the response sent to the caller.
+    fn request_hybrid_credential(
+        states: Vec<HybridStateInternal>,
+    ) -> Result<CredentialResponse, CredentialServiceError> {
+        let request = create_credential_request();
         let (request_tx, request_rx) = oneshot::channel();
         tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
             .unwrap()
             .block_on(async move {
-                let hybrid_handler = DummyHybridHandler::new(vec![
-                    HybridStateInternal::Init(qr_code),
-                    HybridStateInternal::Connecting,
-                    HybridStateInternal::Completed(Box::new(authenticator_response)),
-                ]);
-                let usb_handler = InProcessUsbHandler {};
+                let hybrid_handler = DummyHybridHandler::new(states);
+                let mut transports = TransportRegistry::new();
+                transports.register(InProcessUsbHandler {});
+                transports.register(hybrid_handler);
                 let (ui_server, ui_client) = DummyUiServer::new(Vec::new());
                 let ui_server = Arc::new(ui_server);
                 let user = ui_server.clone();
                 let cred_service = Arc::new(AsyncMutex::new(CredentialService::new(
-                    hybrid_handler,
-                    usb_handler,
+                    transports,
+                    None,
                     Arc::new(ui_client),
                 )));
                 let (mut flow_server, flow_client) = DummyFlowServer::new(cred_service.clone());
@@ -371,8 +980,7 @@ mod test {
                     .await
                     .expect("request to complete")
                     .expect("response to be sent")
-                    .expect("a credential to be returned");
-            });
+            })
     }
 
     fn create_credential_request() -> CredentialRequest {
@@ -400,22 +1008,16 @@ mod test {
             },
             resident_key: Some(ResidentKeyRequirement::Preferred),
             user_verification: UserVerificationRequirement::Preferred,
-            algorithms: vec![
-                Ctap2CredentialType {
-                    algorithm: Ctap2COSEAlgorithmIdentifier::ES256,
-                    public_key_type: Ctap2PublicKeyCredentialType::PublicKey,
-                },
-                Ctap2CredentialType {
-                    algorithm: Ctap2COSEAlgorithmIdentifier::EDDSA,
-                    public_key_type: Ctap2PublicKeyCredentialType::PublicKey,
-                },
-            ],
+            algorithms: Vec::new(),
             exclude: None,
             extensions: None,
             timeout: Duration::from_secs(60),
         };
 
-        CredentialRequest::CreatePublicKeyCredentialRequest(make_request)
+        CredentialRequest::CreatePublicKeyCredentialRequest(MakeCredentialRequestInternal::new(
+            make_request,
+            vec![-7, -8],
+        ))
     }
 
     fn create_authenticator_response() -> AuthenticatorResponse {
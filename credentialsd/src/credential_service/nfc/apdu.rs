@@ -0,0 +1,296 @@
+//! CTAP framing over ISO 7816-4 APDUs, as specified in the "NFC" section of
+//! the CTAP 2.1 specification.
+
+use std::{fmt::Display, thread, time::Duration};
+
+use super::super::ctap::{DeviceError, KeepAlive};
+
+/// AID of the FIDO applet.
+const FIDO_AID: [u8; 8] = [0xA0, 0x00, 0x00, 0x06, 0x47, 0x2F, 0x00, 0x01];
+
+const CLA: u8 = 0x80;
+/// Set on the CLA byte of every command but the last when chaining.
+const CLA_CHAINING: u8 = 0x10;
+const INS_SELECT: u8 = 0xA4;
+const INS_GET_RESPONSE: u8 = 0xC0;
+const INS_NFCCTAP_MSG: u8 = 0x10;
+const INS_NFCCTAP_GETRESPONSE: u8 = 0x11;
+/// P1 of NFCCTAP_MSG, telling the authenticator that we support NFCCTAP_GETRESPONSE.
+const P1_SUPPORTS_GETRESPONSE: u8 = 0x80;
+
+const SW_NO_ERROR: u16 = 0x9000;
+/// The authenticator is still processing the request; its status is in the data.
+const SW_STATUS_UPDATE: u16 = 0x9100;
+/// SW1 for "more data available", with SW2 being the number of bytes.
+const SW1_MORE_DATA: u8 = 0x61;
+
+const STATUS_PROCESSING: u8 = 0x01;
+const STATUS_UP_NEEDED: u8 = 0x02;
+
+/// Maximum data length of a short APDU.
+const MAX_SHORT_DATA: usize = 255;
+
+/// Delay between polls while the authenticator is busy.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub(super) enum NfcError {
+    /// PC/SC is not available, or failed.
+    Pcsc(String),
+    /// The card left the field of the reader.
+    CardRemoved,
+    /// The card returned an unexpected status word.
+    Status(u16),
+    /// The authenticator returned something we could not parse.
+    InvalidResponse(String),
+    /// The request was cancelled by the user.
+    Cancelled,
+}
+
+impl Display for NfcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pcsc(msg) => write!(f, "PC/SC error: {msg}"),
+            Self::CardRemoved => f.write_str("Card removed"),
+            Self::Status(sw) => write!(f, "Unexpected status word {sw:#06x}"),
+            Self::InvalidResponse(msg) => write!(f, "Invalid response: {msg}"),
+            Self::Cancelled => f.write_str("Cancelled"),
+        }
+    }
+}
+
+impl From<NfcError> for DeviceError {
+    fn from(value: NfcError) -> Self {
+        match value {
+            NfcError::Pcsc(msg) => DeviceError::Transport(msg),
+            NfcError::CardRemoved => DeviceError::Disconnected,
+            NfcError::Status(sw) => {
+                DeviceError::InvalidResponse(format!("Unexpected status word {sw:#06x}"))
+            }
+            NfcError::InvalidResponse(msg) => DeviceError::InvalidResponse(msg),
+            NfcError::Cancelled => DeviceError::Cancelled,
+        }
+    }
+}
+
+/// Something that exchanges APDUs with a card.
+pub(super) trait Transmit {
+    /// Sends a command APDU, returning the response APDU including its status word.
+    fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>, NfcError>;
+}
+
+/// Versions of the FIDO applet, as returned when selecting it.
+#[derive(Debug, PartialEq)]
+pub(super) enum AppletVersion {
+    Fido2,
+    /// The authenticator only supports CTAP1.
+    U2f,
+}
+
+pub(super) fn select_applet(card: &mut impl Transmit) -> Result<AppletVersion, NfcError> {
+    let mut apdu = vec![0x00, INS_SELECT, 0x04, 0x00, FIDO_AID.len() as u8];
+    apdu.extend(FIDO_AID);
+    apdu.push(0x00);
+    let (data, sw) = send(card, &apdu)?;
+    if sw != SW_NO_ERROR {
+        return Err(NfcError::Status(sw));
+    }
+    match data.as_slice() {
+        b"FIDO_2_0" => Ok(AppletVersion::Fido2),
+        b"U2F_V2" => Ok(AppletVersion::U2f),
+        _ => Err(NfcError::InvalidResponse(format!(
+            "Unknown applet version: {}",
+            String::from_utf8_lossy(&data)
+        ))),
+    }
+}
+
+/// Sends an encoded CTAP2 command, returning the CTAP2 response including
+/// its status byte.
+///
+/// `on_keepalive` is called whenever the authenticator reports that it is
+/// still busy, and can cancel the request by returning `false`.
+pub(super) fn send_cbor(
+    card: &mut impl Transmit,
+    request: &[u8],
+    on_keepalive: &mut dyn FnMut(KeepAlive) -> bool,
+) -> Result<Vec<u8>, NfcError> {
+    let mut chunks = request.chunks(MAX_SHORT_DATA).peekable();
+    let (mut data, mut sw) = loop {
+        let chunk = chunks.next().unwrap_or_default();
+        let last = chunks.peek().is_none();
+        let cla = if last { CLA } else { CLA | CLA_CHAINING };
+        let mut apdu = vec![cla, INS_NFCCTAP_MSG, P1_SUPPORTS_GETRESPONSE, 0x00];
+        apdu.push(chunk.len() as u8);
+        apdu.extend(chunk);
+        if last {
+            apdu.push(0x00);
+            break send(card, &apdu)?;
+        }
+        let (_, sw) = send(card, &apdu)?;
+        if sw != SW_NO_ERROR {
+            return Err(NfcError::Status(sw));
+        }
+    };
+
+    while sw == SW_STATUS_UPDATE {
+        let keepalive = match data.first() {
+            Some(&STATUS_UP_NEEDED) => KeepAlive::UserPresenceNeeded,
+            Some(&STATUS_PROCESSING) => KeepAlive::Processing,
+            status => {
+                return Err(NfcError::InvalidResponse(format!(
+                    "Unknown NFCCTAP status: {status:?}"
+                )))
+            }
+        };
+        if !on_keepalive(keepalive) {
+            return Err(NfcError::Cancelled);
+        }
+        thread::sleep(POLL_INTERVAL);
+        (data, sw) = send(card, &[CLA, INS_NFCCTAP_GETRESPONSE, 0x00, 0x00, 0x00])?;
+    }
+    if sw != SW_NO_ERROR {
+        return Err(NfcError::Status(sw));
+    }
+    Ok(data)
+}
+
+/// Sends an APDU, collecting the rest of the response if the card has more
+/// data than fits into one response APDU.
+fn send(card: &mut impl Transmit, apdu: &[u8]) -> Result<(Vec<u8>, u16), NfcError> {
+    let mut data = Vec::new();
+    let mut response = card.transmit(apdu)?;
+    loop {
+        let sw = split_status_word(&mut response)?;
+        data.append(&mut response);
+        let [sw1, sw2] = sw.to_be_bytes();
+        if sw1 != SW1_MORE_DATA {
+            break Ok((data, sw));
+        }
+        response = card.transmit(&[0x00, INS_GET_RESPONSE, 0x00, 0x00, sw2])?;
+    }
+}
+
+fn split_status_word(response: &mut Vec<u8>) -> Result<u16, NfcError> {
+    if response.len() < 2 {
+        return Err(NfcError::InvalidResponse(
+            "Response APDU is missing its status word".to_string(),
+        ));
+    }
+    let sw = response.split_off(response.len() - 2);
+    Ok(u16::from_be_bytes([sw[0], sw[1]]))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use super::{
+        select_applet, send_cbor, AppletVersion, KeepAlive, NfcError, Transmit, MAX_SHORT_DATA,
+    };
+
+    /// A card that checks the APDUs it receives against a script, and replies
+    /// with the scripted responses.
+    #[derive(Default)]
+    struct ScriptedCard {
+        script: VecDeque<(Vec<u8>, Vec<u8>)>,
+    }
+
+    impl ScriptedCard {
+        fn expect(mut self, command: &[u8], response: &[u8]) -> Self {
+            self.script.push_back((command.to_vec(), response.to_vec()));
+            self
+        }
+
+        fn is_done(&self) -> bool {
+            self.script.is_empty()
+        }
+    }
+
+    impl Transmit for ScriptedCard {
+        fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>, NfcError> {
+            let (command, response) = self.script.pop_front().expect("an APDU to be scripted");
+            assert_eq!(command, apdu);
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn test_select_applet() {
+        let mut card = ScriptedCard::default().expect(
+            b"\x00\xa4\x04\x00\x08\xa0\x00\x00\x06\x47\x2f\x00\x01\x00",
+            b"FIDO_2_0\x90\x00",
+        );
+        assert_eq!(AppletVersion::Fido2, select_applet(&mut card).unwrap());
+        assert!(card.is_done());
+    }
+
+    #[test]
+    fn test_select_missing_applet() {
+        let mut card = ScriptedCard::default().expect(
+            b"\x00\xa4\x04\x00\x08\xa0\x00\x00\x06\x47\x2f\x00\x01\x00",
+            b"\x6a\x82",
+        );
+        assert!(matches!(
+            select_applet(&mut card),
+            Err(NfcError::Status(0x6a82))
+        ));
+    }
+
+    #[test]
+    fn test_long_requests_are_chained() {
+        let request = vec![0xab; MAX_SHORT_DATA + 1];
+        let mut first = vec![0x90, 0x10, 0x80, 0x00, 0xff];
+        first.extend(&request[..MAX_SHORT_DATA]);
+        let mut card = ScriptedCard::default()
+            .expect(&first, b"\x90\x00")
+            .expect(b"\x80\x10\x80\x00\x01\xab\x00", b"\x00\x90\x00");
+        assert_eq!(
+            vec![0x00],
+            send_cbor(&mut card, &request, &mut |_| true).unwrap()
+        );
+        assert!(card.is_done());
+    }
+
+    #[test]
+    fn test_long_responses_are_collected() {
+        let mut card = ScriptedCard::default()
+            .expect(b"\x80\x10\x80\x00\x01\x04\x00", b"\x00\xa1\x61\x02")
+            .expect(b"\x00\xc0\x00\x00\x02", b"\x01\x02\x90\x00");
+        assert_eq!(
+            vec![0x00, 0xa1, 0x01, 0x02],
+            send_cbor(&mut card, &[0x04], &mut |_| true).unwrap()
+        );
+        assert!(card.is_done());
+    }
+
+    #[test]
+    fn test_status_updates_are_polled() {
+        let mut card = ScriptedCard::default()
+            .expect(b"\x80\x10\x80\x00\x01\x02\x00", b"\x01\x91\x00")
+            .expect(b"\x80\x11\x00\x00\x00", b"\x02\x91\x00")
+            .expect(b"\x80\x11\x00\x00\x00", b"\x00\x90\x00");
+        let mut keepalives = Vec::new();
+        let response = send_cbor(&mut card, &[0x02], &mut |keepalive| {
+            keepalives.push(keepalive);
+            true
+        })
+        .unwrap();
+        assert_eq!(vec![0x00], response);
+        assert_eq!(
+            vec![KeepAlive::Processing, KeepAlive::UserPresenceNeeded],
+            keepalives
+        );
+        assert!(card.is_done());
+    }
+
+    #[test]
+    fn test_status_updates_can_be_cancelled() {
+        let mut card =
+            ScriptedCard::default().expect(b"\x80\x10\x80\x00\x01\x02\x00", b"\x02\x91\x00");
+        assert!(matches!(
+            send_cbor(&mut card, &[0x02], &mut |_| false),
+            Err(NfcError::Cancelled)
+        ));
+    }
+}
@@ -0,0 +1,146 @@
+//! Authenticators on NFC cards, reached through PC/SC readers.
+
+mod apdu;
+mod pcsc;
+
+use std::{ffi::CString, time::Duration};
+
+use async_stream::stream;
+use tokio::sync::mpsc::{self, Sender};
+
+use credentialsd_common::model::{CredentialRequest, Error, Transport};
+
+use self::{
+    apdu::{AppletVersion, NfcError},
+    pcsc::{Card, PcscContext},
+};
+use super::{
+    ctap::{self, send_state, CtapDevice, CtapOperation, CtapResponse, DeviceError, KeepAlive},
+    management::ManagementRequest,
+    transport::{AuthenticatorState, AuthenticatorStates, AuthenticatorTransport},
+};
+
+/// How long to wait for readers or cards to change before checking whether
+/// the request was cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Default)]
+pub struct NfcHandler {}
+
+impl NfcHandler {
+    /// Runs the operation on the first authenticator found, streaming its
+    /// states.
+    fn run(operation: CtapOperation) -> AuthenticatorStates {
+        let (tx, mut rx) = mpsc::channel(32);
+        // Blocking tasks cannot be aborted: dropping the stream closes the
+        // channel instead, which the task checks for.
+        tokio::task::spawn_blocking(move || NfcHandler::process(tx, operation));
+        Box::pin(stream! {
+            while let Some(state) = rx.recv().await {
+                yield state
+            }
+        })
+    }
+
+    /// Runs the whole NFC flow. PC/SC calls block, so this runs on a blocking
+    /// thread, and stops once the receiver of the state updates is dropped.
+    fn process(tx: Sender<AuthenticatorState>, operation: CtapOperation) {
+        let state = match Self::process_card(&tx, &operation) {
+            Ok(response) => ctap::complete(&tx, response, "nfc"),
+            Err(DeviceError::Cancelled) => {
+                tracing::debug!("NFC request cancelled");
+                return;
+            }
+            Err(err) => {
+                tracing::warn!("Failed to use NFC authenticator: {err}");
+                AuthenticatorState::Failed(err.into())
+            }
+        };
+        _ = tx.blocking_send(state);
+    }
+
+    /// Waits for a FIDO authenticator to be tapped, and runs the operation with it.
+    fn process_card(
+        tx: &Sender<AuthenticatorState>,
+        operation: &CtapOperation,
+    ) -> Result<CtapResponse, DeviceError> {
+        let mut pcsc = PcscContext::establish()?;
+        send_state(tx, AuthenticatorState::Waiting)?;
+        // Cards that are not FIDO authenticators are ignored until they are removed.
+        let mut ignored: Vec<CString> = Vec::new();
+        loop {
+            let readers = pcsc.wait_for_cards(POLL_INTERVAL)?;
+            if tx.is_closed() {
+                return Err(DeviceError::Cancelled);
+            }
+            ignored.retain(|reader| readers.contains(reader));
+            let Some(reader) = readers.into_iter().find(|r| !ignored.contains(r)) else {
+                continue;
+            };
+            let mut card = match pcsc.connect(&reader) {
+                Ok(card) => card,
+                Err(NfcError::CardRemoved) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            match apdu::select_applet(&mut card) {
+                Ok(AppletVersion::Fido2) => {}
+                Ok(AppletVersion::U2f) => {
+                    tracing::info!("Ignoring card in {reader:?}: U2F-only authenticators are not supported over NFC");
+                    ignored.push(reader);
+                    continue;
+                }
+                Err(NfcError::CardRemoved) => continue,
+                Err(err) => {
+                    tracing::debug!(
+                        "Ignoring card in {reader:?}, which is not a FIDO authenticator: {err}"
+                    );
+                    ignored.push(reader);
+                    continue;
+                }
+            }
+            tracing::debug!("Found NFC authenticator in {reader:?}");
+            send_state(tx, AuthenticatorState::Connected)?;
+
+            match ctap::run(&mut card, tx, operation) {
+                // Cards easily slip out of the field, so let the user tap again.
+                Err(DeviceError::Disconnected) => {
+                    tracing::debug!("NFC authenticator removed during the ceremony");
+                    send_state(tx, AuthenticatorState::Waiting)?;
+                }
+                response => return response,
+            }
+        }
+    }
+}
+
+impl AuthenticatorTransport for NfcHandler {
+    fn transport(&self) -> Transport {
+        Transport::Nfc
+    }
+
+    fn start(&self, _device_id: &str, request: &CredentialRequest) -> AuthenticatorStates {
+        Self::run(CtapOperation::Ceremony(Box::new(request.clone())))
+    }
+
+    fn can_manage(&self, request: &ManagementRequest) -> bool {
+        ctap::can_manage(request)
+    }
+
+    fn manage(
+        &self,
+        _device_id: &str,
+        request: &ManagementRequest,
+    ) -> Result<AuthenticatorStates, Error> {
+        Ok(Self::run(CtapOperation::Manage(request.clone())))
+    }
+}
+
+impl CtapDevice for Card<'_> {
+    fn send_cbor(
+        &mut self,
+        request: &[u8],
+        on_keepalive: &mut dyn FnMut(KeepAlive) -> bool,
+    ) -> Result<Vec<u8>, DeviceError> {
+        Ok(apdu::send_cbor(self, request, on_keepalive)?)
+    }
+}
@@ -0,0 +1,400 @@
+//! Minimal bindings to the PC/SC daemon (`pcscd`), through `libpcsclite`.
+//!
+//! The library is loaded at runtime, so that credentialsd still runs on
+//! systems without pcsc-lite installed when the NFC transport is disabled.
+
+use std::{
+    ffi::{c_char, c_long, c_uchar, c_ulong, c_void, CStr, CString},
+    ptr,
+    time::Duration,
+};
+
+use libloading::Library;
+
+use super::apdu::{NfcError, Transmit};
+
+const LIBRARY_NAME: &str = "libpcsclite.so.1";
+
+// pcsc-lite defines DWORD as `unsigned long` and LONG as `long`.
+type Dword = c_ulong;
+type Long = c_long;
+type Context = Long;
+type Handle = Long;
+
+const SCARD_S_SUCCESS: Long = 0;
+const SCARD_E_TIMEOUT: Long = 0x8010_000A_u32 as Long;
+const SCARD_E_NO_READERS_AVAILABLE: Long = 0x8010_002E_u32 as Long;
+const SCARD_W_REMOVED_CARD: Long = 0x8010_0069_u32 as Long;
+const SCARD_W_RESET_CARD: Long = 0x8010_0068_u32 as Long;
+const SCARD_E_NO_SMARTCARD: Long = 0x8010_000C_u32 as Long;
+const SCARD_E_NO_SERVICE: Long = 0x8010_001D_u32 as Long;
+
+const SCARD_SCOPE_SYSTEM: Dword = 2;
+const SCARD_SHARE_SHARED: Dword = 2;
+const SCARD_PROTOCOL_T0: Dword = 1;
+const SCARD_PROTOCOL_T1: Dword = 2;
+const SCARD_LEAVE_CARD: Dword = 0;
+
+const SCARD_STATE_UNAWARE: Dword = 0x0000;
+const SCARD_STATE_CHANGED: Dword = 0x0002;
+const SCARD_STATE_PRESENT: Dword = 0x0020;
+const SCARD_STATE_MUTE: Dword = 0x0200;
+
+/// Pseudo-reader that reports readers being added or removed.
+const PNP_NOTIFICATION: &CStr = c"\\\\?PnP?\\Notification";
+
+/// Large enough for a short APDU response, or an extended one of up to 64 KiB.
+const MAX_RESPONSE_SIZE: usize = 65538;
+
+#[repr(C)]
+struct ReaderState {
+    reader: *const c_char,
+    user_data: *mut c_void,
+    current_state: Dword,
+    event_state: Dword,
+    atr_len: Dword,
+    atr: [c_uchar; 33],
+}
+
+#[repr(C)]
+struct IoRequest {
+    protocol: Dword,
+    pci_length: Dword,
+}
+
+type EstablishContextFn =
+    unsafe extern "C" fn(Dword, *const c_void, *const c_void, *mut Context) -> Long;
+type ReleaseContextFn = unsafe extern "C" fn(Context) -> Long;
+type ListReadersFn = unsafe extern "C" fn(Context, *const c_char, *mut c_char, *mut Dword) -> Long;
+type GetStatusChangeFn = unsafe extern "C" fn(Context, Dword, *mut ReaderState, Dword) -> Long;
+type ConnectFn =
+    unsafe extern "C" fn(Context, *const c_char, Dword, Dword, *mut Handle, *mut Dword) -> Long;
+type DisconnectFn = unsafe extern "C" fn(Handle, Dword) -> Long;
+type TransmitFn = unsafe extern "C" fn(
+    Handle,
+    *const IoRequest,
+    *const c_uchar,
+    Dword,
+    *mut IoRequest,
+    *mut c_uchar,
+    *mut Dword,
+) -> Long;
+
+struct Api {
+    establish_context: EstablishContextFn,
+    release_context: ReleaseContextFn,
+    list_readers: ListReadersFn,
+    get_status_change: GetStatusChangeFn,
+    connect: ConnectFn,
+    disconnect: DisconnectFn,
+    transmit: TransmitFn,
+    // The function pointers above are only valid while the library is loaded.
+    _library: Library,
+}
+
+impl Api {
+    fn load() -> Result<Self, NfcError> {
+        // SAFETY: pcsc-lite does not run any initialisation code on load.
+        let library = unsafe { Library::new(LIBRARY_NAME) }
+            .map_err(|err| NfcError::Pcsc(format!("Failed to load {LIBRARY_NAME}: {err}")))?;
+        // SAFETY: the signatures match those in pcsc-lite's winscard.h.
+        unsafe {
+            Ok(Self {
+                establish_context: *symbol(&library, b"SCardEstablishContext\0")?,
+                release_context: *symbol(&library, b"SCardReleaseContext\0")?,
+                list_readers: *symbol(&library, b"SCardListReaders\0")?,
+                get_status_change: *symbol(&library, b"SCardGetStatusChange\0")?,
+                connect: *symbol(&library, b"SCardConnect\0")?,
+                disconnect: *symbol(&library, b"SCardDisconnect\0")?,
+                transmit: *symbol(&library, b"SCardTransmit\0")?,
+                _library: library,
+            })
+        }
+    }
+}
+
+unsafe fn symbol<'lib, T>(
+    library: &'lib Library,
+    name: &[u8],
+) -> Result<libloading::Symbol<'lib, T>, NfcError> {
+    library.get(name).map_err(|err| {
+        NfcError::Pcsc(format!(
+            "{LIBRARY_NAME} is missing {}: {err}",
+            String::from_utf8_lossy(&name[..name.len() - 1])
+        ))
+    })
+}
+
+fn check(function: &str, rv: Long) -> Result<(), NfcError> {
+    match rv {
+        SCARD_S_SUCCESS => Ok(()),
+        SCARD_W_REMOVED_CARD | SCARD_W_RESET_CARD | SCARD_E_NO_SMARTCARD => {
+            Err(NfcError::CardRemoved)
+        }
+        SCARD_E_NO_SERVICE => Err(NfcError::Pcsc(format!(
+            "{function} failed: pcscd is not running"
+        ))),
+        _ => Err(NfcError::Pcsc(format!("{function} failed: {rv:#x}"))),
+    }
+}
+
+/// A connection to `pcscd`.
+pub(super) struct PcscContext {
+    api: Api,
+    context: Context,
+    /// Last known state of each reader, so that we only wake up on changes.
+    readers: Vec<(CString, Dword)>,
+    /// Last known state of the PnP notification pseudo-reader.
+    pnp_state: Dword,
+}
+
+impl PcscContext {
+    pub fn establish() -> Result<Self, NfcError> {
+        let api = Api::load()?;
+        let mut context = 0;
+        // SAFETY: the reserved parameters may be null, and `context` outlives the call.
+        let rv = unsafe {
+            (api.establish_context)(SCARD_SCOPE_SYSTEM, ptr::null(), ptr::null(), &mut context)
+        };
+        check("SCardEstablishContext", rv)?;
+        Ok(Self {
+            api,
+            context,
+            readers: Vec::new(),
+            pnp_state: SCARD_STATE_UNAWARE,
+        })
+    }
+
+    fn list_readers(&self) -> Result<Vec<CString>, NfcError> {
+        let mut len: Dword = 0;
+        // SAFETY: a null buffer asks for the required length.
+        let rv = unsafe {
+            (self.api.list_readers)(self.context, ptr::null(), ptr::null_mut(), &mut len)
+        };
+        if rv == SCARD_E_NO_READERS_AVAILABLE {
+            return Ok(Vec::new());
+        }
+        check("SCardListReaders", rv)?;
+        let mut buf = vec![0 as c_char; len as usize];
+        // SAFETY: `buf` is `len` bytes long.
+        let rv = unsafe {
+            (self.api.list_readers)(self.context, ptr::null(), buf.as_mut_ptr(), &mut len)
+        };
+        if rv == SCARD_E_NO_READERS_AVAILABLE {
+            return Ok(Vec::new());
+        }
+        check("SCardListReaders", rv)?;
+        // The reader names are a list of NUL-terminated strings, ended by an empty string.
+        let bytes: Vec<u8> = buf[..len as usize].iter().map(|c| *c as u8).collect();
+        Ok(bytes
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| CString::new(name).expect("split on NUL bytes"))
+            .collect())
+    }
+
+    /// Waits up to `timeout` for readers or cards to change, returning the
+    /// readers that currently have a card present.
+    pub fn wait_for_cards(&mut self, timeout: Duration) -> Result<Vec<CString>, NfcError> {
+        let names = self.list_readers()?;
+        self.readers.retain(|(name, _)| names.contains(name));
+        for name in names {
+            if !self.readers.iter().any(|(n, _)| *n == name) {
+                self.readers.push((name, SCARD_STATE_UNAWARE));
+            }
+        }
+
+        let mut states: Vec<ReaderState> = self
+            .readers
+            .iter()
+            .map(|(name, state)| (name.as_ptr(), *state))
+            .chain(std::iter::once((PNP_NOTIFICATION.as_ptr(), self.pnp_state)))
+            .map(|(reader, current_state)| ReaderState {
+                reader,
+                user_data: ptr::null_mut(),
+                current_state,
+                event_state: 0,
+                atr_len: 0,
+                atr: [0; 33],
+            })
+            .collect();
+        // SAFETY: the reader names in `states` are borrowed from `self.readers`,
+        // which is not modified until the call returns.
+        let rv = unsafe {
+            (self.api.get_status_change)(
+                self.context,
+                timeout.as_millis() as Dword,
+                states.as_mut_ptr(),
+                states.len() as Dword,
+            )
+        };
+        if rv != SCARD_E_TIMEOUT {
+            check("SCardGetStatusChange", rv)?;
+            for (reader, state) in self.readers.iter_mut().zip(states.iter()) {
+                reader.1 = state.event_state & !SCARD_STATE_CHANGED;
+            }
+            if let Some(pnp) = states.last() {
+                self.pnp_state = pnp.event_state & !SCARD_STATE_CHANGED;
+            }
+        }
+        Ok(self
+            .readers
+            .iter()
+            .filter(|(_, state)| state & SCARD_STATE_PRESENT != 0 && state & SCARD_STATE_MUTE == 0)
+            .map(|(name, _)| name.clone())
+            .collect())
+    }
+
+    pub fn connect(&self, reader: &CStr) -> Result<Card<'_>, NfcError> {
+        let mut handle = 0;
+        let mut protocol = 0;
+        // SAFETY: `reader` is NUL-terminated, and the out-parameters outlive the call.
+        let rv = unsafe {
+            (self.api.connect)(
+                self.context,
+                reader.as_ptr(),
+                SCARD_SHARE_SHARED,
+                SCARD_PROTOCOL_T0 | SCARD_PROTOCOL_T1,
+                &mut handle,
+                &mut protocol,
+            )
+        };
+        check("SCardConnect", rv)?;
+        Ok(Card {
+            context: self,
+            handle,
+            protocol,
+        })
+    }
+}
+
+impl Drop for PcscContext {
+    fn drop(&mut self) {
+        // SAFETY: the context was established in `establish()`.
+        unsafe { (self.api.release_context)(self.context) };
+    }
+}
+
+/// A card connected through a reader, disconnected when dropped.
+pub(super) struct Card<'ctx> {
+    context: &'ctx PcscContext,
+    handle: Handle,
+    protocol: Dword,
+}
+
+impl Transmit for Card<'_> {
+    fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>, NfcError> {
+        let send_pci = IoRequest {
+            protocol: self.protocol,
+            pci_length: std::mem::size_of::<IoRequest>() as Dword,
+        };
+        let mut response = vec![0; MAX_RESPONSE_SIZE];
+        let mut response_len = response.len() as Dword;
+        // SAFETY: the buffers and their lengths match, and outlive the call.
+        let rv = unsafe {
+            (self.context.api.transmit)(
+                self.handle,
+                &send_pci,
+                apdu.as_ptr(),
+                apdu.len() as Dword,
+                ptr::null_mut(),
+                response.as_mut_ptr(),
+                &mut response_len,
+            )
+        };
+        check("SCardTransmit", rv)?;
+        response.truncate(response_len as usize);
+        Ok(response)
+    }
+}
+
+impl Drop for Card<'_> {
+    fn drop(&mut self) {
+        // SAFETY: the handle was returned by `SCardConnect`.
+        unsafe { (self.context.api.disconnect)(self.handle, SCARD_LEAVE_CARD) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{self, Read, Write},
+        net::TcpStream,
+        thread,
+        time::Duration,
+    };
+
+    use super::{
+        super::apdu::{self, AppletVersion, NfcError},
+        check, PcscContext, SCARD_E_NO_SERVICE, SCARD_S_SUCCESS, SCARD_W_REMOVED_CARD,
+    };
+
+    /// Where the vpcd driver of vsmartcard waits for virtual cards.
+    const VPCD_ADDRESS: &str = "localhost:35963";
+    /// Control message from vpcd asking for the ATR of the card.
+    const VPCD_CTRL_ATR: u8 = 0x04;
+    /// ATR of a contactless card speaking T=1.
+    const ATR: &[u8] = b"\x3b\x80\x80\x01\x01";
+    /// getInfo: {1: ["FIDO_2_0"], 3: h'00..00'}
+    const GET_INFO: &[u8] = b"\x00\xa2\x01\x81\x68FIDO_2_0\x03\x50\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+
+    /// Plays a FIDO2 authenticator in a vpcd reader until vpcd hangs up.
+    ///
+    /// vpcd sends control messages and command APDUs, each prefixed with its
+    /// length as a big-endian u16, and expects responses in the same framing.
+    fn virtual_card(mut vpcd: TcpStream) -> io::Result<()> {
+        loop {
+            let mut len = [0; 2];
+            vpcd.read_exact(&mut len)?;
+            let mut message = vec![0; u16::from_be_bytes(len) as usize];
+            vpcd.read_exact(&mut message)?;
+            let response = match message.as_slice() {
+                [VPCD_CTRL_ATR] => ATR.to_vec(),
+                // Powering the card on or off, and resetting it, need no response.
+                [_] => continue,
+                [0x00, 0xa4, 0x04, 0x00, ..] => b"FIDO_2_0\x90\x00".to_vec(),
+                [0x80, 0x10, _, _, 0x01, 0x04, ..] => [GET_INFO, b"\x90\x00"].concat(),
+                _ => b"\x6d\x00".to_vec(),
+            };
+            vpcd.write_all(&(response.len() as u16).to_be_bytes())?;
+            vpcd.write_all(&response)?;
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(check("SCardTransmit", SCARD_S_SUCCESS).is_ok());
+        assert!(matches!(
+            check("SCardTransmit", SCARD_W_REMOVED_CARD),
+            Err(NfcError::CardRemoved)
+        ));
+        assert!(matches!(
+            check("SCardEstablishContext", SCARD_E_NO_SERVICE),
+            Err(NfcError::Pcsc(_))
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs pcscd with the vpcd driver of vsmartcard, see BUILDING.md"]
+    fn test_ctap_over_vpcd() {
+        let vpcd = TcpStream::connect(VPCD_ADDRESS).expect("vpcd to be listening");
+        thread::spawn(move || virtual_card(vpcd));
+
+        let mut pcsc = PcscContext::establish().unwrap();
+        let reader = (0..40)
+            .find_map(|_| {
+                pcsc.wait_for_cards(Duration::from_millis(250))
+                    .unwrap()
+                    .into_iter()
+                    .find(|reader| reader.to_bytes().starts_with(b"Virtual PCD"))
+            })
+            .expect("the virtual card to be inserted");
+        let mut card = pcsc.connect(&reader).unwrap();
+        assert_eq!(
+            AppletVersion::Fido2,
+            apdu::select_applet(&mut card).unwrap()
+        );
+        let response = apdu::send_cbor(&mut card, b"\x04", &mut |_| true).unwrap();
+        assert_eq!(GET_INFO, response);
+    }
+}
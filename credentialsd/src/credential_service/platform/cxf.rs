@@ -0,0 +1,580 @@
+//! Archives of platform passkeys in the FIDO Alliance Credential Exchange
+//! Format (CXF), to move them between credential managers.
+//!
+//! Passkeys are exported as the CXF [`Header`] of a single account, with one
+//! item per passkey. The Credential Exchange Protocol encrypts headers with
+//! HPKE for a manager that is online to receive them, which doesn't fit
+//! files. So we encrypt the JSON of the header with AES-256-GCM, under a key
+//! derived from a passphrase with PBKDF2-HMAC-SHA256:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "kdf": { "algorithm": "PBKDF2-HMAC-SHA256", "iterations": 600000, "salt": "..." },
+//!   "nonce": "...",
+//!   "ciphertext": "..."
+//! }
+//! ```
+//!
+//! Binary members are base64url-encoded without padding, like in CXF.
+//!
+//! Besides our own archives, plain CXF headers exported by other credential
+//! managers are imported. They are not encrypted, so they need no passphrase.
+//! Members that we don't use are ignored, and so are the human-readable ones
+//! that are missing.
+
+use std::{
+    fmt::Display,
+    num::NonZeroU32,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    keys::{Algorithm, KeyBackend},
+    store::PlatformCredential,
+};
+
+const ARCHIVE_VERSION: u32 = 1;
+const KDF_ALGORITHM: &str = "PBKDF2-HMAC-SHA256";
+/// The OWASP recommendation for PBKDF2-HMAC-SHA256.
+const KDF_ITERATIONS: u32 = 600_000;
+/// Archives asking for more are refused, so that opening one doesn't keep
+/// the daemon busy for minutes.
+const MAX_KDF_ITERATIONS: u32 = 10_000_000;
+const SALT_LEN: usize = 16;
+/// Binds the ciphertext to its purpose and format version.
+const AAD: &[u8] = b"credentialsd CXF archive v1";
+
+const EXPORTER_RP_ID: &str = "credentialsd.iinuwa.xyz";
+const EXPORTER_DISPLAY_NAME: &str = "credentialsd";
+
+#[derive(Debug, PartialEq)]
+pub(super) enum CxfError {
+    /// The archive or its contents could not be read.
+    Invalid(String),
+    /// The archive could not be decrypted with the passphrase.
+    WrongPassphrase,
+    Crypto,
+}
+
+impl Display for CxfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid(msg) => write!(f, "Invalid credential archive: {msg}"),
+            Self::WrongPassphrase => f.write_str("Wrong passphrase for credential archive"),
+            Self::Crypto => f.write_str("Failed to encrypt credential archive"),
+        }
+    }
+}
+
+/// The root of a CXF document.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct Header {
+    version: Version,
+    #[serde(default)]
+    exporter_rp_id: String,
+    #[serde(default)]
+    exporter_display_name: String,
+    #[serde(default)]
+    timestamp: u64,
+    accounts: Vec<Account>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Version {
+    major: u8,
+    minor: u8,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Account {
+    #[serde(with = "base64url")]
+    id: Vec<u8>,
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    email: String,
+    /// Collections group items in the exporting manager, and are not kept.
+    #[serde(default)]
+    collections: Vec<serde_json::Value>,
+    items: Vec<Item>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Item {
+    #[serde(with = "base64url")]
+    id: Vec<u8>,
+    #[serde(default)]
+    title: String,
+    credentials: Vec<Credential>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum Credential {
+    Passkey(Passkey),
+    /// Passwords, notes, etc., which the platform authenticator cannot keep.
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Passkey {
+    #[serde(with = "base64url")]
+    credential_id: Vec<u8>,
+    rp_id: String,
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    user_display_name: String,
+    #[serde(with = "base64url")]
+    user_handle: Vec<u8>,
+    /// The private key, as PKCS#8.
+    #[serde(with = "base64url")]
+    key: Vec<u8>,
+}
+
+/// An archive to import.
+#[derive(Debug)]
+pub(super) enum Archive {
+    /// Exported by credentialsd, and encrypted with a passphrase.
+    Encrypted(EncryptedArchive),
+    /// Exported by another credential manager, without encryption.
+    Plain(Header),
+}
+
+/// An encrypted CXF document.
+#[derive(Debug, Deserialize, Serialize)]
+pub(super) struct EncryptedArchive {
+    version: u32,
+    kdf: Kdf,
+    #[serde(with = "base64url")]
+    nonce: Vec<u8>,
+    #[serde(with = "base64url")]
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Kdf {
+    algorithm: String,
+    iterations: u32,
+    #[serde(with = "base64url")]
+    salt: Vec<u8>,
+}
+
+impl Archive {
+    /// Reads an archive, without decrypting it. CXF headers are told apart
+    /// from our encrypted archives by their accounts.
+    pub(super) fn parse(data: &[u8]) -> Result<Self, CxfError> {
+        let value: serde_json::Value = serde_json::from_slice(data).map_err(invalid)?;
+        if value.get("accounts").is_some() {
+            let header: Header = serde_json::from_value(value).map_err(invalid)?;
+            header.check_version()?;
+            return Ok(Self::Plain(header));
+        }
+        let archive: EncryptedArchive = serde_json::from_value(value).map_err(invalid)?;
+        if archive.version != ARCHIVE_VERSION {
+            return Err(CxfError::Invalid(format!(
+                "Unsupported version {}",
+                archive.version
+            )));
+        }
+        if archive.kdf.algorithm != KDF_ALGORITHM
+            || !(1..=MAX_KDF_ITERATIONS).contains(&archive.kdf.iterations)
+        {
+            return Err(CxfError::Invalid(format!(
+                "Unsupported key derivation: {} with {} iterations",
+                archive.kdf.algorithm, archive.kdf.iterations
+            )));
+        }
+        if archive.nonce.len() != NONCE_LEN {
+            return Err(CxfError::Invalid("Invalid nonce".to_string()));
+        }
+        Ok(Self::Encrypted(archive))
+    }
+
+    /// Whether the archive needs a passphrase to be opened.
+    pub(super) fn is_encrypted(&self) -> bool {
+        matches!(self, Self::Encrypted(_))
+    }
+
+    /// The relying parties of the passkeys in the archive, to show to the
+    /// user. Empty for encrypted archives, which can't be read yet.
+    pub(super) fn rp_ids(&self) -> Vec<String> {
+        let Self::Plain(header) = self else {
+            return Vec::new();
+        };
+        let mut rp_ids: Vec<String> = header
+            .passkeys()
+            .map(|passkey| passkey.rp_id.clone())
+            .collect();
+        rp_ids.sort();
+        rp_ids.dedup();
+        rp_ids
+    }
+
+    /// Decrypts the archive, returning the passkeys that it holds. Plain
+    /// archives ignore the passphrase. Credentials of other types, and
+    /// passkeys with keys that we cannot use, are skipped.
+    pub(super) fn open(&self, passphrase: &str) -> Result<Vec<PlatformCredential>, CxfError> {
+        let decrypted;
+        let header = match self {
+            Self::Encrypted(archive) => {
+                decrypted = archive.decrypt(passphrase)?;
+                &decrypted
+            }
+            Self::Plain(header) => header,
+        };
+        Ok(header
+            .passkeys()
+            .filter_map(|passkey| {
+                import(passkey)
+                    .inspect_err(|err| tracing::warn!("Skipping passkey from archive: {err}"))
+                    .ok()
+            })
+            .collect())
+    }
+}
+
+impl EncryptedArchive {
+    fn decrypt(&self, passphrase: &str) -> Result<Header, CxfError> {
+        let iterations = NonZeroU32::new(self.kdf.iterations).ok_or(CxfError::Crypto)?;
+        let key = derive_key(passphrase, &self.kdf.salt, iterations)?;
+        let nonce = Nonce::try_assume_unique_for_key(&self.nonce).map_err(|_| CxfError::Crypto)?;
+        let mut data = self.ciphertext.clone();
+        let plaintext = key
+            .open_in_place(nonce, Aad::from(AAD), &mut data)
+            .map_err(|_| CxfError::WrongPassphrase)?;
+        let header: Header = serde_json::from_slice(plaintext).map_err(invalid)?;
+        header.check_version()?;
+        Ok(header)
+    }
+}
+
+impl Header {
+    fn check_version(&self) -> Result<(), CxfError> {
+        if self.version.major != 1 {
+            return Err(CxfError::Invalid(format!(
+                "Unsupported CXF version {}.{}",
+                self.version.major, self.version.minor
+            )));
+        }
+        Ok(())
+    }
+
+    fn passkeys(&self) -> impl Iterator<Item = &Passkey> {
+        self.accounts
+            .iter()
+            .flat_map(|account| &account.items)
+            .flat_map(|item| &item.credentials)
+            .filter_map(|credential| match credential {
+                Credential::Passkey(passkey) => Some(passkey),
+                Credential::Other => None,
+            })
+    }
+}
+
+fn invalid(err: serde_json::Error) -> CxfError {
+    CxfError::Invalid(err.to_string())
+}
+
+/// Encrypts the credentials, which must have software keys, into an archive.
+pub(super) fn export(
+    credentials: &[PlatformCredential],
+    passphrase: &str,
+) -> Result<Vec<u8>, CxfError> {
+    let rng = SystemRandom::new();
+    let mut account_id = vec![0; 16];
+    rng.fill(&mut account_id).map_err(|_| CxfError::Crypto)?;
+    let header = Header {
+        version: Version { major: 1, minor: 0 },
+        exporter_rp_id: EXPORTER_RP_ID.to_string(),
+        exporter_display_name: EXPORTER_DISPLAY_NAME.to_string(),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs()),
+        accounts: vec![Account {
+            id: account_id,
+            username: String::new(),
+            email: String::new(),
+            collections: Vec::new(),
+            items: credentials.iter().map(export_item).collect(),
+        }],
+    };
+    let mut data = serde_json::to_vec(&header).map_err(|_| CxfError::Crypto)?;
+
+    let mut salt = vec![0; SALT_LEN];
+    rng.fill(&mut salt).map_err(|_| CxfError::Crypto)?;
+    let mut nonce = [0; NONCE_LEN];
+    rng.fill(&mut nonce).map_err(|_| CxfError::Crypto)?;
+    let iterations = NonZeroU32::new(KDF_ITERATIONS).expect("iterations to be non-zero");
+    derive_key(passphrase, &salt, iterations)?
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(AAD),
+            &mut data,
+        )
+        .map_err(|_| CxfError::Crypto)?;
+    let archive = EncryptedArchive {
+        version: ARCHIVE_VERSION,
+        kdf: Kdf {
+            algorithm: KDF_ALGORITHM.to_string(),
+            iterations: KDF_ITERATIONS,
+            salt,
+        },
+        nonce: nonce.to_vec(),
+        ciphertext: data,
+    };
+    serde_json::to_vec_pretty(&archive).map_err(|_| CxfError::Crypto)
+}
+
+fn export_item(credential: &PlatformCredential) -> Item {
+    Item {
+        id: credential.id.clone(),
+        title: credential.rp_id.clone(),
+        credentials: vec![Credential::Passkey(Passkey {
+            credential_id: credential.id.clone(),
+            rp_id: credential.rp_id.clone(),
+            username: credential.user_name.clone().unwrap_or_default(),
+            user_display_name: credential.user_display_name.clone().unwrap_or_default(),
+            user_handle: credential.user_id.clone(),
+            key: credential.algorithm.export(&credential.private_key),
+        })],
+    }
+}
+
+fn import(passkey: &Passkey) -> Result<PlatformCredential, String> {
+    if passkey.credential_id.is_empty() || passkey.rp_id.is_empty() {
+        return Err("Missing credential ID or RP ID".to_string());
+    }
+    if !(1..=64).contains(&passkey.user_handle.len()) {
+        return Err(format!("Invalid user handle for {}", passkey.rp_id));
+    }
+    let (algorithm, private_key) = Algorithm::import(&passkey.key)
+        .map_err(|err| format!("Unusable key for {}: {err}", passkey.rp_id))?;
+    Ok(PlatformCredential {
+        id: passkey.credential_id.clone(),
+        rp_id: passkey.rp_id.clone(),
+        user_id: passkey.user_handle.clone(),
+        user_name: Some(passkey.username.clone()).filter(|name| !name.is_empty()),
+        user_display_name: Some(passkey.user_display_name.clone()).filter(|name| !name.is_empty()),
+        algorithm,
+        key_backend: KeyBackend::Software,
+        private_key,
+        sign_count: 0,
+        // The passkey was synced or exported elsewhere already.
+        backup_eligible: true,
+        backed_up: true,
+    })
+}
+
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    iterations: NonZeroU32,
+) -> Result<LessSafeKey, CxfError> {
+    let mut key = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    let key = UnboundKey::new(&AES_256_GCM, &key).map_err(|_| CxfError::Crypto)?;
+    Ok(LessSafeKey::new(key))
+}
+
+mod base64url {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&URL_SAFE_NO_PAD.encode(data))
+    }
+
+    /// Padding is accepted, in case an archive was edited by hand.
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        let data = String::deserialize(deserializer)?;
+        URL_SAFE_NO_PAD
+            .decode(data.trim_end_matches('='))
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{export, Archive, CxfError};
+    use crate::credential_service::platform::{
+        keys::{Algorithm, KeyBackend, PlatformKeys},
+        store::test::credential,
+    };
+
+    /// A hand-made archive, encrypted with the passphrase `correct horse
+    /// battery staple`, to check that we read members that our exports don't
+    /// have. It holds a password, a P-256 passkey
+    /// for example.com without the public key in its PKCS#8 document, an
+    /// Ed25519 passkey for example.org, and a P-384 passkey, which is not
+    /// supported.
+    const SAMPLE_ARCHIVE: &[u8] = include_bytes!("testdata/sample.cxf.json");
+
+    /// A hand-made plain CXF header, like another credential manager would
+    /// export. It holds a password, a P-256 passkey for example.net with
+    /// extensions, an Ed25519 passkey for example.org without user names,
+    /// and an RSA passkey, which is not supported.
+    const PLAIN_ARCHIVE: &[u8] = include_bytes!("testdata/plain.cxf.json");
+
+    #[test]
+    fn test_exported_credentials_are_imported() {
+        let keys = PlatformKeys::software();
+        let mut credentials = Vec::new();
+        for (id, algorithm) in [(1, Algorithm::Es256), (2, Algorithm::EdDsa)] {
+            let (_, private_key, _) = keys.generate(algorithm).unwrap();
+            let mut credential = credential(id, &[id; 8]);
+            credential.algorithm = algorithm;
+            credential.private_key = private_key;
+            credential.sign_count = 7;
+            credential.user_display_name = Some("Alice".to_string());
+            credentials.push(credential);
+        }
+
+        let data = export(&credentials, "passphrase").unwrap();
+        let archive = Archive::parse(&data).unwrap();
+        assert!(archive.is_encrypted());
+        assert!(archive.rp_ids().is_empty());
+        assert_eq!(
+            CxfError::WrongPassphrase,
+            archive.open("wrong").unwrap_err()
+        );
+        let imported = archive.open("passphrase").unwrap();
+        assert_eq!(2, imported.len());
+        for (credential, imported) in credentials.iter().zip(&imported) {
+            assert_eq!(credential.id, imported.id);
+            assert_eq!("example.com", imported.rp_id);
+            assert_eq!(credential.user_id, imported.user_id);
+            assert_eq!(Some("alice"), imported.user_name.as_deref());
+            assert_eq!(Some("Alice"), imported.user_display_name.as_deref());
+            assert_eq!(credential.algorithm, imported.algorithm);
+            assert_eq!(KeyBackend::Software, imported.key_backend);
+            assert_eq!(0, imported.sign_count);
+            keys.sign(
+                imported.key_backend,
+                imported.algorithm,
+                &imported.private_key,
+                b"data",
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sample_archive_is_imported() {
+        let archive = Archive::parse(SAMPLE_ARCHIVE).unwrap();
+        let imported = archive.open("correct horse battery staple").unwrap();
+        let summary: Vec<_> = imported
+            .iter()
+            .map(|cred| {
+                (
+                    cred.rp_id.as_str(),
+                    cred.user_name.as_deref(),
+                    cred.algorithm,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("example.com", Some("alice"), Algorithm::Es256),
+                ("example.org", Some("bob"), Algorithm::EdDsa),
+            ],
+            summary
+        );
+        let keys = PlatformKeys::software();
+        for credential in imported {
+            keys.sign(
+                credential.key_backend,
+                credential.algorithm,
+                &credential.private_key,
+                b"data",
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_plain_archive_is_imported() {
+        let archive = Archive::parse(PLAIN_ARCHIVE).unwrap();
+        assert!(!archive.is_encrypted());
+        assert_eq!(
+            vec!["example.edu", "example.net", "example.org"],
+            archive.rp_ids()
+        );
+        let imported = archive.open("").unwrap();
+        let summary: Vec<_> = imported
+            .iter()
+            .map(|cred| {
+                (
+                    cred.rp_id.as_str(),
+                    cred.user_name.as_deref(),
+                    cred.user_display_name.as_deref(),
+                    cred.algorithm,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "example.net",
+                    Some("alice"),
+                    Some("Alice"),
+                    Algorithm::Es256
+                ),
+                ("example.org", None, None, Algorithm::EdDsa),
+            ],
+            summary
+        );
+        let keys = PlatformKeys::software();
+        for credential in imported {
+            assert_eq!(b"alice-handle", credential.user_id.as_slice());
+            keys.sign(
+                credential.key_backend,
+                credential.algorithm,
+                &credential.private_key,
+                b"data",
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_invalid_archives_are_rejected() {
+        assert!(matches!(
+            Archive::parse(b"not json"),
+            Err(CxfError::Invalid(_))
+        ));
+        let mut archive: serde_json::Value = serde_json::from_slice(SAMPLE_ARCHIVE).unwrap();
+        archive["kdf"]["iterations"] = 100_000_000.into();
+        assert!(matches!(
+            Archive::parse(&serde_json::to_vec(&archive).unwrap()),
+            Err(CxfError::Invalid(_))
+        ));
+        let mut header: serde_json::Value = serde_json::from_slice(PLAIN_ARCHIVE).unwrap();
+        header["version"]["major"] = 2.into();
+        assert!(matches!(
+            Archive::parse(&serde_json::to_vec(&header).unwrap()),
+            Err(CxfError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,353 @@
+//! Fingerprint verification through fprintd (`net.reactivated.Fprint`).
+//!
+//! The default reader is claimed for the current user only while verifying,
+//! so that other programs, e.g. the screen locker, can use it the rest of the
+//! time. Users without enrolled fingers are not prompted.
+//!
+//! See <https://fprint.freedesktop.org/fprintd-dev/>.
+
+use futures_lite::{future, StreamExt};
+use tokio::{runtime::Handle, sync::mpsc::Sender};
+use zbus::{zvariant::OwnedObjectPath, Connection};
+
+use super::user_verification::PasswordChannel;
+use crate::credential_service::{ctap::DeviceError, transport::AuthenticatorState};
+
+const MAX_FINGERPRINT_ATTEMPTS: u32 = 3;
+
+/// Any of the user's enrolled fingers.
+const ANY_FINGER: &str = "any";
+/// The user calling fprintd.
+const CURRENT_USER: &str = "";
+
+#[zbus::proxy(
+    gen_blocking = false,
+    interface = "net.reactivated.Fprint.Manager",
+    default_service = "net.reactivated.Fprint",
+    default_path = "/net/reactivated/Fprint/Manager"
+)]
+trait Manager {
+    fn get_default_device(&self) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[zbus::proxy(
+    gen_blocking = false,
+    interface = "net.reactivated.Fprint.Device",
+    default_service = "net.reactivated.Fprint"
+)]
+trait Device {
+    fn list_enrolled_fingers(&self, username: &str) -> zbus::Result<Vec<String>>;
+    fn claim(&self, username: &str) -> zbus::Result<()>;
+    fn release(&self) -> zbus::Result<()>;
+    fn verify_start(&self, finger_name: &str) -> zbus::Result<()>;
+    fn verify_stop(&self) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn verify_status(&self, result: &str, done: bool) -> zbus::Result<()>;
+}
+
+/// How fingerprint verification ended.
+#[derive(Debug, PartialEq)]
+pub(super) enum FingerprintOutcome {
+    Matched,
+    /// The finger did not match in any of the attempts.
+    NoMatch,
+    /// The user entered their password instead.
+    Password(String),
+    /// There is no reader, no enrolled finger, or the reader failed.
+    Unavailable,
+}
+
+/// What happened while waiting for a finger.
+enum Event {
+    Status(String, bool),
+    Password(String),
+    Cancelled,
+}
+
+#[derive(Debug)]
+pub(super) struct Fprintd {
+    conn: Connection,
+    runtime: Handle,
+}
+
+impl Fprintd {
+    pub(super) async fn connect() -> zbus::Result<Self> {
+        Ok(Self::new(Connection::system().await?))
+    }
+
+    pub(super) fn new(conn: Connection) -> Self {
+        Self {
+            conn,
+            runtime: Handle::current(),
+        }
+    }
+
+    /// Verifies the user's fingerprint, prompting them through `tx`. If
+    /// `passwords` is given, the user may enter their password instead.
+    ///
+    /// This blocks, so it must be called from a blocking thread.
+    pub(super) fn verify(
+        &self,
+        tx: &Sender<AuthenticatorState>,
+        passwords: Option<&mut PasswordChannel>,
+    ) -> Result<FingerprintOutcome, DeviceError> {
+        self.runtime.block_on(async {
+            let device = match self.claim().await {
+                Ok(Some(device)) => device,
+                Ok(None) => return Ok(FingerprintOutcome::Unavailable),
+                Err(err) => {
+                    tracing::info!("Fingerprint reader unavailable: {err}");
+                    return Ok(FingerprintOutcome::Unavailable);
+                }
+            };
+            let outcome = match scan(&device, tx, passwords).await {
+                Err(DeviceError::Transport(err)) => {
+                    tracing::warn!("Fingerprint verification failed: {err}");
+                    Ok(FingerprintOutcome::Unavailable)
+                }
+                outcome => outcome,
+            };
+            if let Err(err) = device.release().await {
+                tracing::debug!("Failed to release fingerprint reader: {err}");
+            }
+            outcome
+        })
+    }
+
+    /// Claims the default reader, if the user has enrolled fingers.
+    async fn claim(&self) -> zbus::Result<Option<DeviceProxy<'_>>> {
+        let path = ManagerProxy::new(&self.conn)
+            .await?
+            .get_default_device()
+            .await?;
+        let device = DeviceProxy::builder(&self.conn).path(path)?.build().await?;
+        // fprintd fails with NoEnrolledPrints rather than returning nothing.
+        match device.list_enrolled_fingers(CURRENT_USER).await {
+            Ok(fingers) if !fingers.is_empty() => {}
+            _ => {
+                tracing::debug!("No enrolled fingers, skipping fingerprint verification");
+                return Ok(None);
+            }
+        }
+        device.claim(CURRENT_USER).await?;
+        Ok(Some(device))
+    }
+}
+
+/// Waits for a matching finger on the claimed reader.
+async fn scan(
+    device: &DeviceProxy<'_>,
+    tx: &Sender<AuthenticatorState>,
+    mut passwords: Option<&mut PasswordChannel>,
+) -> Result<FingerprintOutcome, DeviceError> {
+    let mut statuses = device
+        .receive_verify_status()
+        .await
+        .map_err(fprintd_error)?;
+    for attempt in 0..MAX_FINGERPRINT_ATTEMPTS {
+        let state = AuthenticatorState::NeedsFingerprint {
+            attempts_left: Some(MAX_FINGERPRINT_ATTEMPTS - attempt),
+            password_tx: passwords.as_ref().map(|passwords| passwords.tx.clone()),
+        };
+        tx.send(state).await.map_err(|_| DeviceError::Cancelled)?;
+        device
+            .verify_start(ANY_FINGER)
+            .await
+            .map_err(fprintd_error)?;
+        let outcome = loop {
+            let status = async {
+                let status = statuses.next().await.and_then(|status| {
+                    let args = status.args().ok()?;
+                    Some(Event::Status(args.result.to_string(), args.done))
+                });
+                status.unwrap_or_else(|| Event::Status("verify-disconnected".to_string(), true))
+            };
+            let password = async {
+                match passwords.as_mut() {
+                    Some(passwords) => match passwords.rx.recv().await {
+                        Some(password) => Event::Password(password),
+                        None => Event::Cancelled,
+                    },
+                    None => future::pending().await,
+                }
+            };
+            let cancelled = async {
+                tx.closed().await;
+                Event::Cancelled
+            };
+            match future::or(status, future::or(password, cancelled)).await {
+                Event::Status(result, _) if result == "verify-match" => {
+                    break Some(Ok(FingerprintOutcome::Matched))
+                }
+                Event::Status(result, _) if result == "verify-no-match" => break None,
+                Event::Status(result, false) => {
+                    tracing::debug!("Fingerprint not read, retrying: {result}");
+                }
+                Event::Status(result, true) => {
+                    break Some(Err(DeviceError::Transport(format!(
+                        "Fingerprint reader failed: {result}"
+                    ))))
+                }
+                Event::Password(password) => {
+                    break Some(Ok(FingerprintOutcome::Password(password)))
+                }
+                Event::Cancelled => break Some(Err(DeviceError::Cancelled)),
+            }
+        };
+        if let Err(err) = device.verify_stop().await {
+            tracing::debug!("Failed to stop fingerprint verification: {err}");
+        }
+        if let Some(outcome) = outcome {
+            return outcome;
+        }
+        tracing::info!("Fingerprint did not match");
+    }
+    Ok(FingerprintOutcome::NoMatch)
+}
+
+fn fprintd_error(err: zbus::Error) -> DeviceError {
+    DeviceError::Transport(err.to_string())
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex, MutexGuard},
+    };
+
+    use zbus::{
+        connection::Builder, interface, object_server::SignalEmitter, zvariant::ObjectPath,
+        Connection, Guid,
+    };
+
+    use super::Fprintd;
+
+    const DEVICE_PATH: &str = "/net/reactivated/Fprint/Device/0";
+
+    pub(in crate::credential_service::platform) struct MockReader {
+        pub(in crate::credential_service::platform) fingers: Vec<String>,
+        /// Results of the scans, one per started verification. Without any
+        /// left, the reader waits for a finger that never comes.
+        script: VecDeque<&'static str>,
+        pub(in crate::credential_service::platform) claimed: bool,
+        pub(in crate::credential_service::platform) stopped: bool,
+        pub(in crate::credential_service::platform) released: bool,
+    }
+
+    type State = Arc<Mutex<MockReader>>;
+
+    struct MockManager;
+
+    #[interface(name = "net.reactivated.Fprint.Manager")]
+    impl MockManager {
+        fn get_default_device(&self) -> ObjectPath<'static> {
+            ObjectPath::from_static_str_unchecked(DEVICE_PATH)
+        }
+    }
+
+    struct MockDevice {
+        state: State,
+    }
+
+    #[interface(name = "net.reactivated.Fprint.Device")]
+    impl MockDevice {
+        fn list_enrolled_fingers(&self, username: &str) -> Vec<String> {
+            assert_eq!("", username);
+            self.state.lock().unwrap().fingers.clone()
+        }
+
+        fn claim(&self, username: &str) {
+            assert_eq!("", username);
+            self.state.lock().unwrap().claimed = true;
+        }
+
+        fn release(&self) {
+            let mut state = self.state.lock().unwrap();
+            assert!(state.claimed);
+            state.released = true;
+        }
+
+        async fn verify_start(
+            &self,
+            finger_name: &str,
+            #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+        ) {
+            assert_eq!("any", finger_name);
+            let result = {
+                let mut state = self.state.lock().unwrap();
+                assert!(state.claimed);
+                state.script.pop_front()
+            };
+            if let Some(result) = result {
+                if result == "verify-retry-scan" {
+                    Self::verify_status(&emitter, result, false).await.unwrap();
+                    let next = self.state.lock().unwrap().script.pop_front().unwrap();
+                    Self::verify_status(&emitter, next, true).await.unwrap();
+                } else {
+                    Self::verify_status(&emitter, result, true).await.unwrap();
+                }
+            }
+        }
+
+        fn verify_stop(&self) {
+            self.state.lock().unwrap().stopped = true;
+        }
+
+        #[zbus(signal)]
+        async fn verify_status(
+            emitter: &SignalEmitter<'_>,
+            result: &str,
+            done: bool,
+        ) -> zbus::Result<()>;
+    }
+
+    /// The mock fprintd, served for as long as this lives.
+    pub(in crate::credential_service::platform) struct MockFprintd {
+        state: State,
+        _conn: Connection,
+    }
+
+    impl MockFprintd {
+        pub(in crate::credential_service::platform) fn reader(&self) -> MutexGuard<'_, MockReader> {
+            self.state.lock().unwrap()
+        }
+    }
+
+    /// Serves a mock fprintd with a reader and one enrolled finger, returning
+    /// a client of it.
+    pub(in crate::credential_service::platform) async fn mock_fprintd(
+        script: &[&'static str],
+    ) -> (Fprintd, MockFprintd) {
+        let state = Arc::new(Mutex::new(MockReader {
+            fingers: vec!["right-index-finger".to_string()],
+            script: script.iter().copied().collect(),
+            claimed: false,
+            stopped: false,
+            released: false,
+        }));
+        let (server, client) = tokio::net::UnixStream::pair().unwrap();
+        let server = Builder::unix_stream(server)
+            .server(Guid::generate())
+            .unwrap()
+            .p2p()
+            .serve_at("/net/reactivated/Fprint/Manager", MockManager)
+            .unwrap()
+            .serve_at(
+                DEVICE_PATH,
+                MockDevice {
+                    state: state.clone(),
+                },
+            )
+            .unwrap();
+        let (server, client) =
+            futures_lite::future::zip(server.build(), Builder::unix_stream(client).p2p().build())
+                .await;
+        let server = MockFprintd {
+            state,
+            _conn: server.unwrap(),
+        };
+        (Fprintd::new(client.unwrap()), server)
+    }
+}
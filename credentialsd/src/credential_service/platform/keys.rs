@@ -0,0 +1,343 @@
+//! Credential key pairs of the platform authenticator.
+//!
+//! Keys are either generated and used in software, and stored as PKCS#8
+//! documents with their credential, or bound to the TPM, see [`Tpm`].
+
+use openssl::{
+    bn::BigNumContext,
+    ec::{EcKey, EcPoint, PointConversionForm},
+    error::ErrorStack,
+    nid::Nid,
+    pkey::{Id, PKey, Private},
+};
+use ring::{
+    error::Unspecified,
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+};
+use serde::{Deserialize, Serialize};
+
+use super::tpm::{Tpm, TpmError};
+use crate::{config::PlatformKeyBackend, cose::CoseKeyAlgorithmIdentifier};
+
+/// Where the private key of a credential is kept.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum KeyBackend {
+    /// The key is a PKCS#8 document, usable on any machine.
+    Software,
+    /// The key is wrapped by the TPM's storage root key, and only usable in
+    /// that TPM.
+    Tpm,
+}
+
+#[derive(Debug)]
+pub(super) enum KeyError {
+    Software,
+    Tpm(TpmError),
+    /// The key is bound to a TPM, but we are not using one.
+    TpmUnavailable,
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Software => f.write_str("Software key operation failed"),
+            Self::Tpm(err) => err.fmt(f),
+            Self::TpmUnavailable => {
+                f.write_str("Credential is bound to a TPM that is not available")
+            }
+        }
+    }
+}
+
+/// Creates and uses the keys of platform credentials.
+#[derive(Debug)]
+pub struct PlatformKeys {
+    tpm: Option<Tpm>,
+}
+
+impl PlatformKeys {
+    /// Opens the backend that new keys are created with. `Auto` binds keys
+    /// to the TPM if there is one that we can use, and keeps them in
+    /// software otherwise.
+    pub fn open(backend: PlatformKeyBackend) -> Result<Self, String> {
+        let tpm = match backend {
+            PlatformKeyBackend::Software => None,
+            PlatformKeyBackend::Tpm => {
+                Some(Tpm::open().map_err(|err| format!("Failed to open the TPM: {err}"))?)
+            }
+            PlatformKeyBackend::Auto => match Tpm::open() {
+                Ok(tpm) => Some(tpm),
+                Err(err) => {
+                    tracing::info!(
+                        "No usable TPM, keeping platform credential keys in software: {err}"
+                    );
+                    None
+                }
+            },
+        };
+        Ok(Self { tpm })
+    }
+
+    /// Keeps keys in software.
+    #[cfg(test)]
+    pub(super) fn software() -> Self {
+        Self { tpm: None }
+    }
+
+    /// Where new keys are kept.
+    pub fn backend(&self) -> KeyBackend {
+        if self.tpm.is_some() {
+            KeyBackend::Tpm
+        } else {
+            KeyBackend::Software
+        }
+    }
+
+    /// Picks the algorithm of a new credential. With a TPM, ES256 is
+    /// preferred whenever the relying party accepts it, since that is the
+    /// only algorithm we bind to the TPM. Otherwise the relying party's order
+    /// of preference is followed.
+    pub(super) fn negotiate(&self, algorithms: &[i64]) -> Option<Algorithm> {
+        let es256_accepted = algorithms
+            .iter()
+            .any(|alg| CoseKeyAlgorithmIdentifier::from(*alg) == CoseKeyAlgorithmIdentifier::ES256);
+        if self.tpm.is_some() && es256_accepted {
+            Some(Algorithm::Es256)
+        } else {
+            Algorithm::negotiate(algorithms)
+        }
+    }
+
+    /// Generates a key pair, returning where the private key is kept, the
+    /// private key to store with the credential, and the public key.
+    pub(super) fn generate(
+        &self,
+        algorithm: Algorithm,
+    ) -> Result<(KeyBackend, Vec<u8>, cosey::PublicKey), KeyError> {
+        match &self.tpm {
+            Some(tpm) if algorithm == Algorithm::Es256 => {
+                let (private_key, public_key) = tpm.create_key().map_err(KeyError::Tpm)?;
+                Ok((KeyBackend::Tpm, private_key, public_key))
+            }
+            _ => {
+                let (private_key, public_key) =
+                    algorithm.generate().map_err(|_| KeyError::Software)?;
+                Ok((KeyBackend::Software, private_key, public_key))
+            }
+        }
+    }
+
+    /// Signs `data` with a private key returned by [`PlatformKeys::generate`].
+    pub(super) fn sign(
+        &self,
+        backend: KeyBackend,
+        algorithm: Algorithm,
+        private_key: &[u8],
+        data: &[u8],
+    ) -> Result<Vec<u8>, KeyError> {
+        match backend {
+            KeyBackend::Software => algorithm
+                .sign(private_key, data)
+                .map_err(|_| KeyError::Software),
+            KeyBackend::Tpm => self
+                .tpm
+                .as_ref()
+                .ok_or(KeyError::TpmUnavailable)?
+                .sign(private_key, data)
+                .map_err(KeyError::Tpm),
+        }
+    }
+}
+
+/// Signature algorithms supported by the platform authenticator.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub(super) enum Algorithm {
+    /// ECDSA with P-256 and SHA-256, with DER-encoded signatures.
+    Es256,
+    /// Ed25519.
+    EdDsa,
+}
+
+impl Algorithm {
+    /// Picks the first algorithm that we support in the relying party's
+    /// order of preference.
+    fn negotiate(algorithms: &[i64]) -> Option<Self> {
+        algorithms
+            .iter()
+            .find_map(|alg| match CoseKeyAlgorithmIdentifier::from(*alg) {
+                CoseKeyAlgorithmIdentifier::ES256 => Some(Self::Es256),
+                CoseKeyAlgorithmIdentifier::EdDSA => Some(Self::EdDsa),
+                _ => None,
+            })
+    }
+
+    /// Generates a key pair in software, returning the private key as PKCS#8
+    /// and the public key.
+    fn generate(self) -> Result<(Vec<u8>, cosey::PublicKey), Unspecified> {
+        let rng = SystemRandom::new();
+        match self {
+            Self::Es256 => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)?;
+                let key_pair =
+                    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+                        .map_err(|_| Unspecified)?;
+                // Uncompressed point: 0x04 || x || y
+                let point = key_pair.public_key().as_ref();
+                let public_key = cosey::P256PublicKey {
+                    x: cosey::Bytes::from_slice(&point[1..33]).map_err(|_| Unspecified)?,
+                    y: cosey::Bytes::from_slice(&point[33..65]).map_err(|_| Unspecified)?,
+                };
+                Ok((pkcs8.as_ref().to_vec(), public_key.into()))
+            }
+            Self::EdDsa => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)?;
+                let key_pair =
+                    Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).map_err(|_| Unspecified)?;
+                let public_key = cosey::Ed25519PublicKey {
+                    x: cosey::Bytes::from_slice(key_pair.public_key().as_ref())
+                        .map_err(|_| Unspecified)?,
+                };
+                Ok((pkcs8.as_ref().to_vec(), public_key.into()))
+            }
+        }
+    }
+
+    /// Encodes a software private key for an archive, as PKCS#8 v1, like CXF
+    /// passkeys. Ed25519 keys generated by ring are PKCS#8 v2 documents,
+    /// which include the public key and which OpenSSL cannot read.
+    pub(super) fn export(self, private_key: &[u8]) -> Vec<u8> {
+        const ED25519_V1_PREFIX: &[u8] = &[
+            0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22,
+            0x04, 0x20,
+        ];
+        const ED25519_V2_LEN: usize = 83;
+        match self {
+            // The seed follows the same prefix in both versions, except for
+            // the lengths and the version number.
+            Self::EdDsa if private_key.len() == ED25519_V2_LEN => {
+                let seed = &private_key[ED25519_V1_PREFIX.len()..ED25519_V1_PREFIX.len() + 32];
+                [ED25519_V1_PREFIX, seed].concat()
+            }
+            _ => private_key.to_vec(),
+        }
+    }
+
+    /// Reads a software private key from an archive, returning its algorithm
+    /// and the key as PKCS#8 that we can sign with.
+    ///
+    /// PKCS#8 documents may leave the public key out, which ring requires for
+    /// ECDSA keys, and encode the curve differently than ring accepts, so
+    /// ECDSA keys are re-encoded like the ones ring generates.
+    pub(super) fn import(pkcs8: &[u8]) -> Result<(Self, Vec<u8>), String> {
+        let key = PKey::private_key_from_pkcs8(pkcs8)
+            .map_err(|err| format!("Invalid PKCS#8 private key: {err}"))?;
+        let (algorithm, pkcs8) = match key.id() {
+            Id::EC => {
+                let ec_key = key.ec_key().map_err(|err| err.to_string())?;
+                let curve = ec_key.group().curve_name();
+                if curve != Some(Nid::X9_62_PRIME256V1) {
+                    return Err(format!("Unsupported elliptic curve: {curve:?}"));
+                }
+                (
+                    Self::Es256,
+                    p256_pkcs8(&ec_key).map_err(|err| err.to_string())?,
+                )
+            }
+            Id::ED25519 => (
+                Self::EdDsa,
+                key.private_key_to_pkcs8()
+                    .map_err(|err| format!("Failed to encode private key: {err}"))?,
+            ),
+            id => return Err(format!("Unsupported key type: {id:?}")),
+        };
+        // Make sure that we can use it.
+        algorithm
+            .sign(&pkcs8, b"")
+            .map_err(|_| "Private key is not usable".to_string())?;
+        Ok((algorithm, pkcs8))
+    }
+
+    /// Signs `data` with a private key returned by [`Algorithm::generate`] or
+    /// [`Algorithm::import`].
+    fn sign(self, private_key: &[u8], data: &[u8]) -> Result<Vec<u8>, Unspecified> {
+        match self {
+            Self::Es256 => {
+                let rng = SystemRandom::new();
+                let key_pair =
+                    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, private_key, &rng)
+                        .map_err(|_| Unspecified)?;
+                Ok(key_pair.sign(&rng, data)?.as_ref().to_vec())
+            }
+            Self::EdDsa => {
+                // Imported keys are PKCS#8 v1 documents, without the public
+                // key that ring includes in the keys it generates.
+                let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(private_key)
+                    .map_err(|_| Unspecified)?;
+                Ok(key_pair.sign(data).as_ref().to_vec())
+            }
+        }
+    }
+}
+
+/// Encodes a P-256 private key as PKCS#8 the way ring does: without the
+/// curve in the `ECPrivateKey`, and with the public key.
+fn p256_pkcs8(ec_key: &EcKey<Private>) -> Result<Vec<u8>, ErrorStack> {
+    const PREFIX: &[u8] = &[
+        0x30, 0x81, 0x87, 0x02, 0x01, 0x00, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d,
+        0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x04, 0x6d, 0x30,
+        0x6b, 0x02, 0x01, 0x01, 0x04, 0x20,
+    ];
+    const PUBLIC_KEY_PREFIX: &[u8] = &[0xa1, 0x44, 0x03, 0x42, 0x00];
+
+    let group = ec_key.group();
+    let mut ctx = BigNumContext::new()?;
+    let mut public_key = EcPoint::new(group)?;
+    public_key.mul_generator(group, ec_key.private_key(), &ctx)?;
+    let public_key = public_key.to_bytes(group, PointConversionForm::UNCOMPRESSED, &mut ctx)?;
+    Ok([
+        PREFIX,
+        &ec_key.private_key().to_vec_padded(32)?,
+        PUBLIC_KEY_PREFIX,
+        &public_key,
+    ]
+    .concat())
+}
+
+#[cfg(test)]
+mod test {
+    use ring::signature::{
+        UnparsedPublicKey, VerificationAlgorithm, ECDSA_P256_SHA256_ASN1, ED25519,
+    };
+
+    use super::Algorithm;
+
+    #[test]
+    fn test_negotiate_follows_relying_party_preference() {
+        // ES384, EdDSA, ES256
+        let algorithms = [-35, -8, -7];
+        assert_eq!(Some(Algorithm::EdDsa), Algorithm::negotiate(&algorithms));
+        assert_eq!(None, Algorithm::negotiate(&algorithms[..1]));
+    }
+
+    #[test]
+    fn test_signatures_verify_with_public_key() {
+        for algorithm in [Algorithm::Es256, Algorithm::EdDsa] {
+            let (private_key, public_key) = algorithm.generate().unwrap();
+            let signature = algorithm.sign(&private_key, b"data").unwrap();
+            let (verification, public_key): (&dyn VerificationAlgorithm, _) = match public_key {
+                cosey::PublicKey::P256Key(key) => {
+                    let mut point = vec![0x04];
+                    point.extend_from_slice(&key.x);
+                    point.extend_from_slice(&key.y);
+                    (&ECDSA_P256_SHA256_ASN1, point)
+                }
+                cosey::PublicKey::Ed25519Key(key) => (&ED25519, key.x.to_vec()),
+                _ => panic!("Unexpected key type for {algorithm:?}"),
+            };
+            UnparsedPublicKey::new(verification, public_key)
+                .verify(b"data", &signature)
+                .unwrap();
+        }
+    }
+}
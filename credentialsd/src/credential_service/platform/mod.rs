@@ -0,0 +1,560 @@
+//! The platform authenticator, which lets users create and use passkeys
+//! without a security key or a phone.
+//!
+//! Its credentials are always discoverable, and are kept in a
+//! [`PlatformCredentialStore`]. Their keys are bound to the TPM when there is
+//! one, and are generated and used in software otherwise, see [`PlatformKeys`].
+//! Credentials created here are reported as neither backup eligible nor
+//! backed up: nothing syncs them, and exporting them by hand does not count
+//! as a backup.
+//!
+//! Choosing this device in the trusted UI is how the user consents to a
+//! request, i.e. user presence. The user is verified with their fingerprint
+//! or login password when the relying party asks for it, see
+//! [`UserVerifier`]. Requests that require user verification are refused if
+//! none of its methods are available.
+
+mod cxf;
+mod fprintd;
+mod keys;
+mod pam;
+mod secret_service;
+pub mod store;
+mod tpm;
+mod transfer;
+mod user_verification;
+
+use std::{collections::BTreeMap, io, sync::Arc};
+
+use async_stream::stream;
+use libwebauthn::{
+    fido::{AttestedCredentialData, AuthenticatorData, AuthenticatorDataFlags},
+    ops::webauthn::{
+        Assertion, CredentialPropsExtension, GetAssertionRequest, GetAssertionResponse,
+        GetAssertionResponseExtensions, MakeCredentialResponse,
+        MakeCredentialsResponseUnsignedExtensions, UserVerificationRequirement,
+    },
+    proto::{
+        ctap2::{
+            Ctap2AttestationStatement, Ctap2PublicKeyCredentialDescriptor,
+            Ctap2PublicKeyCredentialType, Ctap2PublicKeyCredentialUserEntity,
+        },
+        CtapError,
+    },
+};
+use ring::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+};
+use tokio::sync::mpsc::{self, Sender};
+
+use credentialsd_common::model::{
+    CredentialRequest, CredentialResponse, MakeCredentialRequestInternal, Transport,
+};
+
+pub(crate) use self::secret_service::file_key;
+use self::store::{PlatformCredential, PlatformCredentialStore};
+pub use self::{
+    keys::{KeyBackend, PlatformKeys},
+    transfer::{PendingTransfer, TransferRequest, TransferResponse},
+    user_verification::UserVerifier,
+};
+use super::{
+    ctap::{send_state, DeviceError},
+    transport::{AuthenticatorState, AuthenticatorStates, AuthenticatorTransport},
+    usb::find_selected_assertion,
+};
+use crate::webauthn;
+
+const CREDENTIAL_ID_LEN: usize = 32;
+
+/// The platform authenticator does not attest to its model, so it uses the
+/// all-zero AAGUID, like authenticators returning `none` attestation.
+const AAGUID: [u8; 16] = [0; 16];
+
+/// Backup eligibility (BE), which libwebauthn does not name yet.
+const BACKUP_ELIGIBLE: AuthenticatorDataFlags = AuthenticatorDataFlags::RFU_2_1;
+
+/// Backup state (BS), which libwebauthn does not name yet.
+const BACKED_UP: AuthenticatorDataFlags = AuthenticatorDataFlags::RFU_2_2;
+
+/// What the platform authenticator can do, as reported to clients.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PlatformCapabilities {
+    pub key_backend: KeyBackend,
+    /// Whether the user can be verified, with at least one method.
+    pub user_verification: bool,
+}
+
+#[derive(Debug)]
+pub struct PlatformAuthenticator {
+    store: Arc<PlatformCredentialStore>,
+    keys: Arc<PlatformKeys>,
+    verifier: Arc<UserVerifier>,
+}
+
+impl PlatformAuthenticator {
+    pub fn new(
+        store: Arc<PlatformCredentialStore>,
+        keys: Arc<PlatformKeys>,
+        verifier: Arc<UserVerifier>,
+    ) -> Self {
+        Self {
+            store,
+            keys,
+            verifier,
+        }
+    }
+
+    pub fn capabilities(&self) -> PlatformCapabilities {
+        PlatformCapabilities {
+            key_backend: self.keys.backend(),
+            user_verification: self.verifier.is_available(),
+        }
+    }
+
+    /// Runs the request. The store is read and written, and the user
+    /// verified, with blocking calls, so this runs on a blocking thread.
+    fn process(
+        store: &PlatformCredentialStore,
+        keys: &PlatformKeys,
+        verifier: &UserVerifier,
+        tx: Sender<AuthenticatorState>,
+        request: CredentialRequest,
+    ) {
+        let result = send_state(&tx, AuthenticatorState::Connected).and_then(|()| match &request {
+            CredentialRequest::CreatePublicKeyCredentialRequest(request) => {
+                make_credential(store, keys, verifier, &tx, request).map(|response| {
+                    CredentialResponse::from_make_credential(&response, &["internal"], "platform")
+                })
+            }
+            CredentialRequest::GetPublicKeyCredentialRequest(request) => {
+                get_assertion(store, keys, verifier, &tx, request)
+                    .map(|assertion| CredentialResponse::from_get_assertion(&assertion, "platform"))
+            }
+        });
+        let state = match result {
+            Ok(response) => AuthenticatorState::Completed(response),
+            Err(DeviceError::Cancelled) => {
+                tracing::debug!("Platform authenticator request cancelled");
+                return;
+            }
+            Err(err) => {
+                tracing::warn!("Failed to make/get credential with platform authenticator: {err}");
+                AuthenticatorState::Failed(err.into())
+            }
+        };
+        _ = tx.blocking_send(state);
+    }
+}
+
+impl AuthenticatorTransport for PlatformAuthenticator {
+    fn transport(&self) -> Transport {
+        Transport::Internal
+    }
+
+    fn start(&self, _device_id: &str, request: &CredentialRequest) -> AuthenticatorStates {
+        let request = request.clone();
+        let store = self.store.clone();
+        let keys = self.keys.clone();
+        let verifier = self.verifier.clone();
+        let (tx, mut rx) = mpsc::channel(32);
+        tokio::task::spawn_blocking(move || {
+            PlatformAuthenticator::process(&store, &keys, &verifier, tx, request)
+        });
+        Box::pin(stream! {
+            while let Some(state) = rx.recv().await {
+                yield state
+            }
+        })
+    }
+}
+
+fn make_credential(
+    store: &PlatformCredentialStore,
+    keys: &PlatformKeys,
+    verifier: &UserVerifier,
+    tx: &Sender<AuthenticatorState>,
+    request: &MakeCredentialRequestInternal,
+) -> Result<MakeCredentialResponse, DeviceError> {
+    let algorithms = &request.algorithms;
+    let request = &request.ctap;
+    let user_verified = verify_user(verifier, tx, request.user_verification)?;
+    let rp_id = &request.relying_party.id;
+    let excluded: Vec<&[u8]> = request
+        .exclude
+        .iter()
+        .flatten()
+        .map(|descriptor| descriptor.id.as_slice())
+        .collect();
+    if !excluded.is_empty()
+        && !store
+            .find(rp_id, &excluded)
+            .map_err(store_error)?
+            .is_empty()
+    {
+        return Err(DeviceError::Ctap(CtapError::CredentialExcluded));
+    }
+    let algorithm = keys
+        .negotiate(algorithms)
+        .ok_or(DeviceError::Ctap(CtapError::UnsupportedAlgorithm))?;
+
+    let (key_backend, private_key, public_key) = keys.generate(algorithm).map_err(|err| {
+        DeviceError::Transport(format!("Failed to generate credential key: {err}"))
+    })?;
+    let mut id = vec![0; CREDENTIAL_ID_LEN];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| DeviceError::Transport("Failed to generate credential ID".to_string()))?;
+    let credential = PlatformCredential {
+        id: id.clone(),
+        rp_id: rp_id.clone(),
+        user_id: request.user.id.to_vec(),
+        user_name: request.user.name.clone(),
+        user_display_name: request.user.display_name.clone(),
+        algorithm,
+        key_backend,
+        private_key,
+        sign_count: 0,
+        backup_eligible: false,
+        backed_up: false,
+    };
+    let credential_flags = flags(&credential, user_verified);
+    store.add(credential).map_err(store_error)?;
+
+    let cred_props = request
+        .extensions
+        .as_ref()
+        .and_then(|extensions| extensions.cred_props)
+        .filter(|requested| *requested)
+        .map(|_| CredentialPropsExtension { rk: Some(true) });
+    Ok(MakeCredentialResponse {
+        format: "none".to_string(),
+        authenticator_data: AuthenticatorData {
+            rp_id_hash: rp_id_hash(rp_id),
+            flags: credential_flags | AuthenticatorDataFlags::ATTESTED_CREDENTIALS,
+            signature_count: 0,
+            attested_credential: Some(AttestedCredentialData {
+                aaguid: AAGUID,
+                credential_id: id,
+                credential_public_key: public_key,
+            }),
+            extensions: None,
+        },
+        attestation_statement: Ctap2AttestationStatement::None(BTreeMap::new()),
+        enterprise_attestation: None,
+        large_blob_key: None,
+        unsigned_extensions_output: MakeCredentialsResponseUnsignedExtensions {
+            cred_props,
+            ..Default::default()
+        },
+    })
+}
+
+fn get_assertion(
+    store: &PlatformCredentialStore,
+    keys: &PlatformKeys,
+    verifier: &UserVerifier,
+    tx: &Sender<AuthenticatorState>,
+    request: &GetAssertionRequest,
+) -> Result<Assertion, DeviceError> {
+    let user_verified = verify_user(verifier, tx, request.user_verification)?;
+    let allowed: Vec<&[u8]> = request
+        .allow
+        .iter()
+        .map(|descriptor| descriptor.id.as_slice())
+        .collect();
+    let mut credentials = store
+        .find(&request.relying_party_id, &allowed)
+        .map_err(store_error)?;
+    let credential = match credentials.len() {
+        0 => return Err(DeviceError::Ctap(CtapError::NoCredentials)),
+        1 => credentials.remove(0),
+        _ => select_credential(tx, credentials)?,
+    };
+
+    let signature_count = store
+        .increment_sign_count(&credential)
+        .map_err(store_error)?;
+    let authenticator_data = AuthenticatorData {
+        rp_id_hash: rp_id_hash(&credential.rp_id),
+        flags: flags(&credential, user_verified),
+        signature_count,
+        attested_credential: None,
+        extensions: None,
+    };
+    let mut signed_data = webauthn::encode_authenticator_data(&authenticator_data)
+        .map_err(|err| DeviceError::InvalidResponse(err.to_string()))?;
+    signed_data.extend(&request.hash);
+    let signature = keys
+        .sign(
+            credential.key_backend,
+            credential.algorithm,
+            &credential.private_key,
+            &signed_data,
+        )
+        .map_err(|err| DeviceError::Transport(format!("Failed to sign assertion: {err}")))?;
+    Ok(assertion(&credential, authenticator_data, signature))
+}
+
+/// Lets the user choose which of the relying party's credentials to use.
+///
+/// Credentials are chosen from assertions, as they are for security keys,
+/// but these are not signed: only the chosen credential is used.
+fn select_credential(
+    tx: &Sender<AuthenticatorState>,
+    credentials: Vec<PlatformCredential>,
+) -> Result<PlatformCredential, DeviceError> {
+    let response = GetAssertionResponse {
+        assertions: credentials
+            .iter()
+            .map(|credential| {
+                let authenticator_data = AuthenticatorData {
+                    rp_id_hash: rp_id_hash(&credential.rp_id),
+                    flags: flags(credential, false),
+                    signature_count: credential.sign_count,
+                    attested_credential: None,
+                    extensions: None,
+                };
+                assertion(credential, authenticator_data, Vec::new())
+            })
+            .collect(),
+    };
+    let (cred_tx, mut cred_rx) = mpsc::channel(1);
+    send_state(
+        tx,
+        AuthenticatorState::SelectCredential {
+            response: response.clone(),
+            cred_tx,
+        },
+    )?;
+    let cred_id = cred_rx.blocking_recv().ok_or(DeviceError::Cancelled)?;
+    let selected = find_selected_assertion(&response, &cred_id)
+        .and_then(|assertion| assertion.credential_id)
+        .ok_or(DeviceError::Ctap(CtapError::NoCredentials))?;
+    credentials
+        .into_iter()
+        .find(|credential| credential.id == *selected.id)
+        .ok_or(DeviceError::Ctap(CtapError::NoCredentials))
+}
+
+fn assertion(
+    credential: &PlatformCredential,
+    authenticator_data: AuthenticatorData<GetAssertionResponseExtensions>,
+    signature: Vec<u8>,
+) -> Assertion {
+    Assertion {
+        credential_id: Some(Ctap2PublicKeyCredentialDescriptor {
+            id: credential.id.clone().into(),
+            r#type: Ctap2PublicKeyCredentialType::PublicKey,
+            transports: None,
+        }),
+        authenticator_data,
+        signature,
+        user: Some(Ctap2PublicKeyCredentialUserEntity {
+            id: credential.user_id.clone().into(),
+            name: credential.user_name.clone(),
+            display_name: credential.user_display_name.clone(),
+        }),
+        credentials_count: None,
+        user_selected: None,
+        large_blob_key: None,
+        unsigned_extensions_output: None,
+        enterprise_attestation: None,
+        attestation_statement: None,
+    }
+}
+
+/// Verifies the user unless the relying party discourages it, returning
+/// whether they were verified.
+///
+/// Requests that require user verification are refused when the user cannot
+/// be verified, as security keys without PIN or built-in user verification
+/// do. Otherwise they go on without it.
+fn verify_user(
+    verifier: &UserVerifier,
+    tx: &Sender<AuthenticatorState>,
+    requirement: UserVerificationRequirement,
+) -> Result<bool, DeviceError> {
+    if matches!(requirement, UserVerificationRequirement::Discouraged) {
+        return Ok(false);
+    }
+    let verified = verifier.verify(tx)?;
+    if !verified && requirement.is_required() {
+        tracing::info!("Platform authenticator cannot verify the user, refusing request");
+        return Err(DeviceError::Ctap(CtapError::UnsupportedOption));
+    }
+    Ok(verified)
+}
+
+fn flags(credential: &PlatformCredential, user_verified: bool) -> AuthenticatorDataFlags {
+    let mut flags = AuthenticatorDataFlags::USER_PRESENT;
+    flags.set(AuthenticatorDataFlags::USER_VERIFIED, user_verified);
+    flags.set(BACKUP_ELIGIBLE, credential.backup_eligible);
+    flags.set(BACKED_UP, credential.backed_up);
+    flags
+}
+
+/// Fails the request when credentials cannot be read or written, e.g. because
+/// the user did not unlock their keyring.
+fn store_error(err: io::Error) -> DeviceError {
+    DeviceError::Transport(format!("Failed to access platform credentials: {err}"))
+}
+
+fn rp_id_hash(rp_id: &str) -> [u8; 32] {
+    digest::digest(&digest::SHA256, rp_id.as_bytes())
+        .as_ref()
+        .try_into()
+        .expect("SHA-256 digests to be 32 bytes")
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use libwebauthn::{
+        fido::AuthenticatorDataFlags,
+        ops::webauthn::{GetAssertionRequest, MakeCredentialRequest, UserVerificationRequirement},
+        proto::{
+            ctap2::{Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialType},
+            CtapError,
+        },
+    };
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+    use tokio::sync::mpsc;
+
+    use credentialsd_common::model::MakeCredentialRequestInternal;
+
+    use super::{
+        get_assertion, make_credential,
+        store::test::file_store,
+        user_verification::test::{answer_prompts, password_verifier},
+        PlatformKeys, UserVerifier, BACKED_UP, BACKUP_ELIGIBLE,
+    };
+    use crate::{
+        credential_service::{ctap::DeviceError, encrypted_file::test::TempDir},
+        webauthn,
+    };
+
+    fn make_credential_request() -> MakeCredentialRequestInternal {
+        MakeCredentialRequestInternal::new(MakeCredentialRequest::dummy(), vec![-7])
+    }
+
+    fn get_assertion_request(rp_id: &str) -> GetAssertionRequest {
+        GetAssertionRequest {
+            relying_party_id: rp_id.to_string(),
+            hash: vec![1; 32],
+            allow: Vec::new(),
+            extensions: None,
+            user_verification: UserVerificationRequirement::Preferred,
+            timeout: std::time::Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn test_created_credential_signs_assertions() {
+        let dir = TempDir::new();
+        let store = Arc::new(file_store(&dir));
+        let keys = PlatformKeys::software();
+        let verifier = UserVerifier::default();
+        let (tx, _rx) = mpsc::channel(1);
+        let request = make_credential_request();
+        let response = make_credential(&store, &keys, &verifier, &tx, &request).unwrap();
+        assert_eq!("none", response.format);
+        let auth_data = &response.authenticator_data;
+        assert!(auth_data.flags.contains(
+            AuthenticatorDataFlags::USER_PRESENT | AuthenticatorDataFlags::ATTESTED_CREDENTIALS
+        ));
+        assert!(!auth_data
+            .flags
+            .intersects(AuthenticatorDataFlags::USER_VERIFIED | BACKUP_ELIGIBLE | BACKED_UP));
+        let attested = auth_data.attested_credential.as_ref().unwrap();
+        let cosey::PublicKey::P256Key(public_key) = &attested.credential_public_key else {
+            panic!("Expected an ES256 key");
+        };
+
+        let request = get_assertion_request(&request.ctap.relying_party.id);
+        let assertion = get_assertion(&store, &keys, &verifier, &tx, &request).unwrap();
+        assert_eq!(
+            attested.credential_id,
+            assertion.credential_id.unwrap().id.into_vec()
+        );
+        assert_eq!(1, assertion.authenticator_data.signature_count);
+        let mut signed_data =
+            webauthn::encode_authenticator_data(&assertion.authenticator_data).unwrap();
+        signed_data.extend(&request.hash);
+        let mut point = vec![0x04];
+        point.extend_from_slice(&public_key.x);
+        point.extend_from_slice(&public_key.y);
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, point)
+            .verify(&signed_data, &assertion.signature)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_excluded_credentials_are_not_created_again() {
+        let dir = TempDir::new();
+        let store = file_store(&dir);
+        let keys = PlatformKeys::software();
+        let verifier = UserVerifier::default();
+        let (tx, _rx) = mpsc::channel(1);
+        let mut request = make_credential_request();
+        let response = make_credential(&store, &keys, &verifier, &tx, &request).unwrap();
+        let credential_id = response
+            .authenticator_data
+            .attested_credential
+            .unwrap()
+            .credential_id;
+        request.ctap.exclude = Some(vec![Ctap2PublicKeyCredentialDescriptor {
+            id: credential_id.into(),
+            r#type: Ctap2PublicKeyCredentialType::PublicKey,
+            transports: None,
+        }]);
+        assert!(matches!(
+            make_credential(&store, &keys, &verifier, &tx, &request),
+            Err(DeviceError::Ctap(CtapError::CredentialExcluded))
+        ));
+    }
+
+    #[test]
+    fn test_requests_requiring_user_verification_are_refused() {
+        let dir = TempDir::new();
+        let store = file_store(&dir);
+        let keys = PlatformKeys::software();
+        let verifier = UserVerifier::default();
+        let (tx, _rx) = mpsc::channel(1);
+        let mut request = make_credential_request();
+        request.ctap.user_verification = UserVerificationRequirement::Required;
+        assert!(matches!(
+            make_credential(&store, &keys, &verifier, &tx, &request),
+            Err(DeviceError::Ctap(CtapError::UnsupportedOption))
+        ));
+        let mut request = get_assertion_request("example.org");
+        request.user_verification = UserVerificationRequirement::Required;
+        assert!(matches!(
+            get_assertion(&store, &keys, &verifier, &tx, &request),
+            Err(DeviceError::Ctap(CtapError::UnsupportedOption))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_verified_user_is_flagged() {
+        let dir = TempDir::new();
+        let store = file_store(&dir);
+        let keys = PlatformKeys::software();
+        let verifier = password_verifier("hunter2");
+        let mut request = make_credential_request();
+        request.ctap.user_verification = UserVerificationRequirement::Required;
+        let (tx, rx) = mpsc::channel(8);
+        let response = tokio::task::spawn_blocking(move || {
+            make_credential(&store, &keys, &verifier, &tx, &request)
+        });
+        let states = answer_prompts(rx, &["hunter2"]).await;
+        let response = response.await.unwrap().unwrap();
+        assert_eq!(vec!["password Some(3)"], states);
+        assert!(response
+            .authenticator_data
+            .flags
+            .contains(AuthenticatorDataFlags::USER_VERIFIED));
+    }
+}
@@ -0,0 +1,366 @@
+//! Checks the user's login password with PAM, through `libpam`.
+//!
+//! The library is loaded at runtime, as pcsc-lite is for NFC, so that
+//! credentialsd still runs on systems without PAM when password verification
+//! is disabled. Passwords are checked by the `credentialsd` PAM service, see
+//! `pam/credentialsd`, always for the user running the daemon.
+
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    fmt::Debug,
+    mem, ptr,
+};
+
+use libloading::Library;
+
+use super::user_verification::PasswordCheck;
+
+const LIBRARY_NAME: &str = "libpam.so.0";
+const SERVICE_NAME: &CStr = c"credentialsd";
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_AUTH_ERR: c_int = 7;
+const PAM_MAXTRIES: c_int = 11;
+const PAM_CONV_ERR: c_int = 19;
+
+const PAM_SILENT: c_int = 0x8000;
+const PAM_DISALLOW_NULL_AUTHTOK: c_int = 0x0001;
+
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_ERROR_MSG: c_int = 3;
+const PAM_TEXT_INFO: c_int = 4;
+
+#[repr(C)]
+struct PamHandle {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct Message {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct Response {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+type ConversationFn =
+    unsafe extern "C" fn(c_int, *mut *const Message, *mut *mut Response, *mut c_void) -> c_int;
+
+#[repr(C)]
+struct Conversation {
+    conv: ConversationFn,
+    appdata_ptr: *mut c_void,
+}
+
+type StartFn = unsafe extern "C" fn(
+    *const c_char,
+    *const c_char,
+    *const Conversation,
+    *mut *mut PamHandle,
+) -> c_int;
+type AuthenticateFn = unsafe extern "C" fn(*mut PamHandle, c_int) -> c_int;
+type AcctMgmtFn = unsafe extern "C" fn(*mut PamHandle, c_int) -> c_int;
+type EndFn = unsafe extern "C" fn(*mut PamHandle, c_int) -> c_int;
+type StrErrorFn = unsafe extern "C" fn(*mut PamHandle, c_int) -> *const c_char;
+
+struct Api {
+    start: StartFn,
+    authenticate: AuthenticateFn,
+    acct_mgmt: AcctMgmtFn,
+    end: EndFn,
+    strerror: StrErrorFn,
+    // The function pointers above are only valid while the library is loaded.
+    _library: Library,
+}
+
+impl Api {
+    fn load() -> Result<Self, String> {
+        // SAFETY: libpam does not run any initialisation code on load.
+        let library = unsafe { Library::new(LIBRARY_NAME) }
+            .map_err(|err| format!("Failed to load {LIBRARY_NAME}: {err}"))?;
+        // SAFETY: the signatures match those in Linux-PAM's headers.
+        unsafe {
+            Ok(Self {
+                start: *symbol(&library, b"pam_start\0")?,
+                authenticate: *symbol(&library, b"pam_authenticate\0")?,
+                acct_mgmt: *symbol(&library, b"pam_acct_mgmt\0")?,
+                end: *symbol(&library, b"pam_end\0")?,
+                strerror: *symbol(&library, b"pam_strerror\0")?,
+                _library: library,
+            })
+        }
+    }
+}
+
+unsafe fn symbol<'lib, T>(
+    library: &'lib Library,
+    name: &[u8],
+) -> Result<libloading::Symbol<'lib, T>, String> {
+    library.get(name).map_err(|err| {
+        format!(
+            "{LIBRARY_NAME} is missing {}: {err}",
+            String::from_utf8_lossy(&name[..name.len() - 1])
+        )
+    })
+}
+
+/// Checks passwords against the login password of the current user.
+pub(super) struct Pam {
+    api: Api,
+    user: CString,
+}
+
+impl Debug for Pam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pam").field("user", &self.user).finish()
+    }
+}
+
+impl Pam {
+    pub(super) fn load() -> Result<Self, String> {
+        Ok(Self {
+            api: Api::load()?,
+            user: current_user()?,
+        })
+    }
+
+    fn error_message(&self, handle: *mut PamHandle, rv: c_int) -> String {
+        // SAFETY: pam_strerror returns a static string for any error code.
+        let message = unsafe { (self.api.strerror)(handle, rv) };
+        if message.is_null() {
+            return format!("error {rv}");
+        }
+        // SAFETY: checked for null above.
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+impl PasswordCheck for Pam {
+    fn check(&self, password: &str) -> Result<bool, String> {
+        let Ok(password) = CString::new(password) else {
+            return Ok(false);
+        };
+        let mut state = ConversationState {
+            password: &password,
+            answered: false,
+        };
+        let conversation = Conversation {
+            conv: conversation,
+            appdata_ptr: (&mut state as *mut ConversationState).cast(),
+        };
+        let mut handle = ptr::null_mut();
+        // SAFETY: the strings and the conversation outlive the PAM handle,
+        // which is ended below.
+        let rv = unsafe {
+            (self.api.start)(
+                SERVICE_NAME.as_ptr(),
+                self.user.as_ptr(),
+                &conversation,
+                &mut handle,
+            )
+        };
+        if rv != PAM_SUCCESS {
+            return Err(format!(
+                "pam_start failed: {}",
+                self.error_message(handle, rv)
+            ));
+        }
+        // SAFETY: the handle was started successfully.
+        let mut rv =
+            unsafe { (self.api.authenticate)(handle, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK) };
+        if rv == PAM_SUCCESS {
+            // SAFETY: as above.
+            rv = unsafe { (self.api.acct_mgmt)(handle, PAM_SILENT) };
+        }
+        let message = self.error_message(handle, rv);
+        // SAFETY: as above, the handle is not used afterwards.
+        unsafe { (self.api.end)(handle, rv) };
+        match rv {
+            PAM_SUCCESS => Ok(true),
+            PAM_AUTH_ERR | PAM_MAXTRIES => Ok(false),
+            _ => Err(format!("PAM authentication failed: {message}")),
+        }
+    }
+}
+
+/// Data for [`conversation`], behind its `appdata_ptr`.
+struct ConversationState<'a> {
+    password: &'a CStr,
+    /// The password is given once: a second prompt would be for something
+    /// else, e.g. a one-time password.
+    answered: bool,
+}
+
+/// Answers PAM's password prompt with the password the user entered, and
+/// refuses any other prompt.
+unsafe extern "C" fn conversation(
+    num_msg: c_int,
+    msg: *mut *const Message,
+    resp: *mut *mut Response,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    if num_msg <= 0 || msg.is_null() || resp.is_null() || appdata_ptr.is_null() {
+        return PAM_CONV_ERR;
+    }
+    let state = &mut *appdata_ptr.cast::<ConversationState>();
+    let count = num_msg as usize;
+    // PAM frees the responses, so they are allocated with its allocator.
+    let responses: *mut Response = libc::calloc(count, mem::size_of::<Response>()).cast();
+    if responses.is_null() {
+        return PAM_BUF_ERR;
+    }
+    for i in 0..count {
+        // Linux-PAM passes an array of pointers to messages.
+        let message = &**msg.add(i);
+        let text = if message.msg.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(message.msg).to_string_lossy().into_owned()
+        };
+        match message.msg_style {
+            PAM_PROMPT_ECHO_OFF if !state.answered => {
+                let answer = libc::strdup(state.password.as_ptr());
+                if answer.is_null() {
+                    free_responses(responses, count);
+                    return PAM_BUF_ERR;
+                }
+                (*responses.add(i)).resp = answer;
+                state.answered = true;
+            }
+            PAM_ERROR_MSG => tracing::info!("PAM error: {text}"),
+            PAM_TEXT_INFO => tracing::debug!("PAM info: {text}"),
+            style => {
+                tracing::info!("Refusing PAM prompt of style {style}: {text}");
+                free_responses(responses, count);
+                return PAM_CONV_ERR;
+            }
+        }
+    }
+    *resp = responses;
+    PAM_SUCCESS
+}
+
+/// Frees responses, wiping the password from memory.
+unsafe fn free_responses(responses: *mut Response, count: usize) {
+    for i in 0..count {
+        let answer = (*responses.add(i)).resp;
+        if !answer.is_null() {
+            ptr::write_bytes(answer, 0, libc::strlen(answer));
+            libc::free(answer.cast());
+        }
+    }
+    libc::free(responses.cast());
+}
+
+/// Looks up the name of the user running the daemon. The environment is not
+/// trusted for this, as it would let any account's password verify the user.
+fn current_user() -> Result<CString, String> {
+    // SAFETY: getuid cannot fail.
+    let uid = unsafe { libc::getuid() };
+    let mut buf: Vec<c_char> = vec![0; 1024];
+    loop {
+        // SAFETY: passwd only holds pointers into buf, which outlives it.
+        let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        let rv =
+            unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+        match rv {
+            0 if !result.is_null() => {
+                // SAFETY: getpwuid_r filled in the entry.
+                return Ok(unsafe { CStr::from_ptr(passwd.pw_name) }.to_owned());
+            }
+            libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            _ => return Err(format!("Failed to look up the name of user {uid}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        ffi::{c_int, CStr, CString},
+        ptr,
+    };
+
+    use super::{
+        conversation, free_responses, ConversationState, Message, Response, PAM_CONV_ERR,
+        PAM_PROMPT_ECHO_OFF, PAM_SUCCESS, PAM_TEXT_INFO,
+    };
+
+    const PAM_PROMPT_ECHO_ON: c_int = 2;
+
+    /// Runs the conversation like a PAM module would, returning its result
+    /// and the answers to the messages.
+    fn converse(
+        state: &mut ConversationState,
+        messages: &[(c_int, &CStr)],
+    ) -> (c_int, Vec<Option<CString>>) {
+        let messages: Vec<Message> = messages
+            .iter()
+            .map(|(style, text)| Message {
+                msg_style: *style,
+                msg: text.as_ptr(),
+            })
+            .collect();
+        let mut pointers: Vec<*const Message> = messages.iter().map(|m| m as *const _).collect();
+        let mut responses: *mut Response = ptr::null_mut();
+        let rv = unsafe {
+            conversation(
+                pointers.len() as c_int,
+                pointers.as_mut_ptr(),
+                &mut responses,
+                (state as *mut ConversationState).cast(),
+            )
+        };
+        if rv != PAM_SUCCESS {
+            return (rv, Vec::new());
+        }
+        let answers = (0..messages.len())
+            .map(|i| unsafe {
+                let answer = (*responses.add(i)).resp;
+                (!answer.is_null()).then(|| CStr::from_ptr(answer).to_owned())
+            })
+            .collect();
+        unsafe { free_responses(responses, messages.len()) };
+        (rv, answers)
+    }
+
+    #[test]
+    fn test_password_prompt_is_answered_once() {
+        let password = c"hunter2";
+        let mut state = ConversationState {
+            password,
+            answered: false,
+        };
+        let (rv, answers) = converse(
+            &mut state,
+            &[
+                (PAM_TEXT_INFO, c"Checking your password"),
+                (PAM_PROMPT_ECHO_OFF, c"Password: "),
+            ],
+        );
+        assert_eq!(PAM_SUCCESS, rv);
+        assert_eq!(vec![None, Some(password.to_owned())], answers);
+
+        let (rv, _) = converse(&mut state, &[(PAM_PROMPT_ECHO_OFF, c"Verification code: ")]);
+        assert_eq!(PAM_CONV_ERR, rv);
+    }
+
+    #[test]
+    fn test_other_prompts_are_refused() {
+        let mut state = ConversationState {
+            password: c"hunter2",
+            answered: false,
+        };
+        let (rv, _) = converse(&mut state, &[(PAM_PROMPT_ECHO_ON, c"login: ")]);
+        assert_eq!(PAM_CONV_ERR, rv);
+        assert!(!state.answered);
+    }
+}
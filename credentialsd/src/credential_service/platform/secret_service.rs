@@ -0,0 +1,705 @@
+//! Platform credentials stored in the user's keyring through the Secret
+//! Service API (`org.freedesktop.secrets`), so that they are unlocked along
+//! with it and can be seen in keyring tools.
+//!
+//! Each credential is an item of the default collection, with the attributes
+//! of [`SCHEMA`]:
+//!
+//! - `xdg:schema`: `xyz.iinuwa.credentialsd.PlatformCredential`
+//! - `rp-id`: the relying party ID
+//! - `user-id`: the user handle, base64url-encoded without padding
+//!
+//! The secret is the JSON-encoded credential record, including its private
+//! key. Nothing is cached, so locking the keyring locks the credentials too.
+//! When the collection is locked, the user is prompted to unlock it.
+//!
+//! The keys of the daemon's encrypted files are kept in the same collection,
+//! see [`file_key`], with the attributes:
+//!
+//! - `xdg:schema`: `xyz.iinuwa.credentialsd.FileKey`
+//! - `file`: the name of the file
+//!
+//! See <https://specifications.freedesktop.org/secret-service-spec/latest/>.
+
+use std::{collections::HashMap, fmt::Display, io};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures_lite::StreamExt;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+use zbus::{
+    proxy::CacheProperties,
+    zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Type, Value},
+    Connection,
+};
+
+use super::store::{CredentialStorage, PlatformCredential};
+use crate::credential_service::encrypted_file::KEY_LEN;
+
+const SCHEMA: &str = "xyz.iinuwa.credentialsd.PlatformCredential";
+const FILE_KEY_SCHEMA: &str = "xyz.iinuwa.credentialsd.FileKey";
+const DEFAULT_COLLECTION: &str = "default";
+const ITEM_LABEL: &str = "org.freedesktop.Secret.Item.Label";
+const ITEM_ATTRIBUTES: &str = "org.freedesktop.Secret.Item.Attributes";
+const COLLECTION_LABEL: &str = "org.freedesktop.Secret.Collection.Label";
+const CONTENT_TYPE: &str = "application/json";
+const FILE_KEY_CONTENT_TYPE: &str = "application/octet-stream";
+/// The path that stands for no object, e.g. no prompt being needed.
+const NO_OBJECT: &str = "/";
+
+#[zbus::proxy(
+    gen_blocking = false,
+    interface = "org.freedesktop.Secret.Service",
+    default_service = "org.freedesktop.secrets",
+    default_path = "/org/freedesktop/secrets"
+)]
+trait Service {
+    fn open_session(
+        &self,
+        algorithm: &str,
+        input: &Value<'_>,
+    ) -> zbus::Result<(OwnedValue, OwnedObjectPath)>;
+    fn create_collection(
+        &self,
+        properties: HashMap<&str, Value<'_>>,
+        alias: &str,
+    ) -> zbus::Result<(OwnedObjectPath, OwnedObjectPath)>;
+    fn read_alias(&self, name: &str) -> zbus::Result<OwnedObjectPath>;
+    fn unlock(
+        &self,
+        objects: &[ObjectPath<'_>],
+    ) -> zbus::Result<(Vec<OwnedObjectPath>, OwnedObjectPath)>;
+    fn get_secrets(
+        &self,
+        items: &[OwnedObjectPath],
+        session: &ObjectPath<'_>,
+    ) -> zbus::Result<HashMap<OwnedObjectPath, Secret>>;
+}
+
+#[zbus::proxy(
+    gen_blocking = false,
+    interface = "org.freedesktop.Secret.Collection",
+    default_service = "org.freedesktop.secrets"
+)]
+trait Collection {
+    fn search_items(&self, attributes: HashMap<&str, &str>) -> zbus::Result<Vec<OwnedObjectPath>>;
+    fn create_item(
+        &self,
+        properties: HashMap<&str, Value<'_>>,
+        secret: &Secret,
+        replace: bool,
+    ) -> zbus::Result<(OwnedObjectPath, OwnedObjectPath)>;
+
+    #[zbus(property)]
+    fn locked(&self) -> zbus::Result<bool>;
+}
+
+#[zbus::proxy(
+    gen_blocking = false,
+    interface = "org.freedesktop.Secret.Prompt",
+    default_service = "org.freedesktop.secrets"
+)]
+trait Prompt {
+    fn prompt(&self, window_id: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn completed(&self, dismissed: bool, result: Value<'_>) -> zbus::Result<()>;
+}
+
+/// A secret as transferred in a session.
+#[derive(Debug, Deserialize, Serialize, Type)]
+pub(super) struct Secret {
+    session: OwnedObjectPath,
+    parameters: Vec<u8>,
+    value: Vec<u8>,
+    content_type: String,
+}
+
+#[derive(Debug)]
+enum SecretServiceError {
+    Dbus(zbus::Error),
+    /// The user dismissed a prompt, e.g. to unlock the collection.
+    Dismissed,
+    InvalidResponse(&'static str),
+}
+
+impl Display for SecretServiceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Dbus(err) => write!(f, "Secret Service request failed: {err}"),
+            Self::Dismissed => f.write_str("The keyring was not unlocked"),
+            Self::InvalidResponse(msg) => write!(f, "Invalid Secret Service response: {msg}"),
+        }
+    }
+}
+
+impl From<zbus::Error> for SecretServiceError {
+    fn from(value: zbus::Error) -> Self {
+        Self::Dbus(value)
+    }
+}
+
+impl From<SecretServiceError> for io::Error {
+    fn from(value: SecretServiceError) -> Self {
+        let kind = match value {
+            SecretServiceError::Dismissed => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, value.to_string())
+    }
+}
+
+/// Credentials stored in the default collection of the Secret Service.
+///
+/// Secrets are transferred in a `plain` session: they only travel over the
+/// user's session bus, to a service that holds them in memory anyway.
+#[derive(Debug)]
+pub(super) struct SecretServiceStorage {
+    conn: Connection,
+    session: OwnedObjectPath,
+    /// Runs the D-Bus calls of the blocking [`CredentialStorage`] methods.
+    runtime: Handle,
+}
+
+impl SecretServiceStorage {
+    pub(super) async fn connect(conn: Connection) -> io::Result<Self> {
+        let service = ServiceProxy::new(&conn)
+            .await
+            .map_err(SecretServiceError::from)?;
+        let (_, session) = service
+            .open_session("plain", &Value::from(""))
+            .await
+            .map_err(SecretServiceError::from)?;
+        Ok(Self {
+            conn,
+            session,
+            runtime: Handle::current(),
+        })
+    }
+
+    /// Returns the default collection, creating it if there is none, and
+    /// unlocking it if it is locked.
+    async fn collection(&self) -> Result<CollectionProxy<'_>, SecretServiceError> {
+        let service = ServiceProxy::new(&self.conn).await?;
+        let mut path = service.read_alias(DEFAULT_COLLECTION).await?;
+        if path.as_str() == NO_OBJECT {
+            tracing::info!("No default keyring, asking the Secret Service to create one");
+            let (created, prompt) = service
+                .create_collection(
+                    HashMap::from([(COLLECTION_LABEL, Value::from("Login"))]),
+                    DEFAULT_COLLECTION,
+                )
+                .await?;
+            path = if prompt.as_str() == NO_OBJECT {
+                created
+            } else {
+                OwnedObjectPath::try_from(self.prompt(prompt).await?)
+                    .map_err(|_| SecretServiceError::InvalidResponse("Expected a collection"))?
+            };
+        }
+        let collection = CollectionProxy::builder(&self.conn)
+            .path(path)?
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        if collection.locked().await? {
+            tracing::debug!("Keyring is locked, asking the user to unlock it");
+            let (_, prompt) = service.unlock(&[collection.inner().path().clone()]).await?;
+            if prompt.as_str() != NO_OBJECT {
+                self.prompt(prompt).await?;
+            }
+        }
+        Ok(collection)
+    }
+
+    /// Shows a prompt to the user, returning its result once they complete it.
+    async fn prompt(&self, path: OwnedObjectPath) -> Result<OwnedValue, SecretServiceError> {
+        let prompt = PromptProxy::builder(&self.conn).path(path)?.build().await?;
+        // Subscribe first, so that the signal cannot be missed.
+        let mut completed = prompt.receive_completed().await?;
+        prompt.prompt("").await?;
+        let signal = completed
+            .next()
+            .await
+            .ok_or(SecretServiceError::InvalidResponse(
+                "Prompt never completed",
+            ))?;
+        let args = signal.args()?;
+        if args.dismissed {
+            return Err(SecretServiceError::Dismissed);
+        }
+        Ok(args.result.try_to_owned().map_err(zbus::Error::from)?)
+    }
+
+    /// Looks up the secrets of the items with the given attributes.
+    async fn search(
+        &self,
+        collection: &CollectionProxy<'_>,
+        attributes: HashMap<&str, &str>,
+    ) -> Result<HashMap<OwnedObjectPath, Secret>, SecretServiceError> {
+        let items = collection.search_items(attributes).await?;
+        if items.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let service = ServiceProxy::new(&self.conn).await?;
+        Ok(service.get_secrets(&items, &self.session).await?)
+    }
+
+    /// Stores a secret as an item of the collection, replacing any item with
+    /// the same attributes.
+    async fn create_item(
+        &self,
+        collection: &CollectionProxy<'_>,
+        label: String,
+        attributes: HashMap<&str, &str>,
+        value: Vec<u8>,
+        content_type: &str,
+    ) -> Result<(), SecretServiceError> {
+        let secret = Secret {
+            session: self.session.clone(),
+            parameters: Vec::new(),
+            value,
+            content_type: content_type.to_string(),
+        };
+        let (_, prompt) = collection
+            .create_item(
+                HashMap::from([
+                    (ITEM_LABEL, Value::from(label)),
+                    (ITEM_ATTRIBUTES, Value::from(attributes)),
+                ]),
+                &secret,
+                true,
+            )
+            .await?;
+        if prompt.as_str() != NO_OBJECT {
+            self.prompt(prompt).await?;
+        }
+        Ok(())
+    }
+
+    async fn file_key_async(&self, name: &str) -> Result<[u8; KEY_LEN], SecretServiceError> {
+        let collection = self.collection().await?;
+        let attributes = HashMap::from([("xdg:schema", FILE_KEY_SCHEMA), ("file", name)]);
+        let secrets = self.search(&collection, attributes.clone()).await?;
+        if let Some(secret) = secrets.into_values().next() {
+            return secret
+                .value
+                .try_into()
+                .map_err(|_| SecretServiceError::InvalidResponse("Invalid file key"));
+        }
+        tracing::info!("Creating a key for {name} in the keyring");
+        let mut key = [0; KEY_LEN];
+        SystemRandom::new()
+            .fill(&mut key)
+            .map_err(|_| SecretServiceError::InvalidResponse("Failed to generate a key"))?;
+        self.create_item(
+            &collection,
+            format!("credentialsd key for {name}"),
+            attributes,
+            key.to_vec(),
+            FILE_KEY_CONTENT_TYPE,
+        )
+        .await?;
+        Ok(key)
+    }
+
+    async fn list_async(
+        &self,
+        rp_id: Option<&str>,
+    ) -> Result<Vec<PlatformCredential>, SecretServiceError> {
+        let collection = self.collection().await?;
+        let mut attributes = HashMap::from([("xdg:schema", SCHEMA)]);
+        if let Some(rp_id) = rp_id {
+            attributes.insert("rp-id", rp_id);
+        }
+        let secrets = self.search(&collection, attributes).await?;
+        Ok(secrets
+            .into_iter()
+            .filter_map(|(path, secret)| {
+                serde_json::from_slice(&secret.value)
+                    .inspect_err(|err| {
+                        tracing::warn!("Ignoring unreadable platform credential {path}: {err}")
+                    })
+                    .ok()
+            })
+            .collect())
+    }
+
+    async fn put_async(&self, credential: &PlatformCredential) -> Result<(), SecretServiceError> {
+        let collection = self.collection().await?;
+        let user_id = URL_SAFE_NO_PAD.encode(&credential.user_id);
+        let attributes = HashMap::from([
+            ("xdg:schema", SCHEMA),
+            ("rp-id", credential.rp_id.as_str()),
+            ("user-id", user_id.as_str()),
+        ]);
+        let user = credential
+            .user_name
+            .as_deref()
+            .or(credential.user_display_name.as_deref())
+            .unwrap_or(&user_id);
+        let label = format!("Passkey for {user} on {}", credential.rp_id);
+        let value = serde_json::to_vec(credential)
+            .map_err(|_| SecretServiceError::InvalidResponse("Unserializable credential"))?;
+        // Replacing items with the same attributes replaces the credential of
+        // the same user.
+        self.create_item(&collection, label, attributes, value, CONTENT_TYPE)
+            .await
+    }
+}
+
+/// Returns the key of the encrypted file `name` from the user's keyring,
+/// creating a random one if there is none yet.
+///
+/// The user is prompted to unlock the keyring if it is locked.
+pub(crate) async fn file_key(conn: &Connection, name: &str) -> io::Result<[u8; KEY_LEN]> {
+    let storage = SecretServiceStorage::connect(conn.clone()).await?;
+    Ok(storage.file_key_async(name).await?)
+}
+
+impl CredentialStorage for SecretServiceStorage {
+    fn list(&self, rp_id: Option<&str>) -> io::Result<Vec<PlatformCredential>> {
+        Ok(self.runtime.block_on(self.list_async(rp_id))?)
+    }
+
+    fn put(&self, credential: &PlatformCredential) -> io::Result<()> {
+        Ok(self.runtime.block_on(self.put_async(credential))?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use zbus::{
+        connection::Builder,
+        interface,
+        object_server::SignalEmitter,
+        zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value},
+        Connection, Guid,
+    };
+
+    use super::{file_key, Secret, SecretServiceStorage, FILE_KEY_SCHEMA, ITEM_ATTRIBUTES, SCHEMA};
+    use crate::credential_service::platform::store::{test::credential, PlatformCredentialStore};
+
+    const COLLECTION_PATH: &str = "/org/freedesktop/secrets/collection/login";
+    const PROMPT_PATH: &str = "/org/freedesktop/secrets/prompt/p1";
+    const SESSION_PATH: &str = "/org/freedesktop/secrets/session/s1";
+
+    #[derive(Default)]
+    struct MockState {
+        has_default: bool,
+        locked: bool,
+        /// Whether the user dismisses prompts.
+        dismiss: bool,
+        prompts: usize,
+        items: Vec<(OwnedObjectPath, HashMap<String, String>, Vec<u8>)>,
+    }
+
+    type State = Arc<Mutex<MockState>>;
+
+    struct MockService {
+        state: State,
+    }
+
+    #[interface(name = "org.freedesktop.Secret.Service")]
+    impl MockService {
+        fn open_session(
+            &self,
+            algorithm: &str,
+            _input: Value<'_>,
+        ) -> (OwnedValue, OwnedObjectPath) {
+            assert_eq!("plain", algorithm);
+            (
+                Value::from("").try_to_owned().unwrap(),
+                ObjectPath::from_static_str_unchecked(SESSION_PATH).into(),
+            )
+        }
+
+        fn create_collection(
+            &self,
+            _properties: HashMap<String, OwnedValue>,
+            alias: &str,
+        ) -> (OwnedObjectPath, OwnedObjectPath) {
+            assert_eq!("default", alias);
+            (
+                ObjectPath::from_static_str_unchecked("/").into(),
+                ObjectPath::from_static_str_unchecked(PROMPT_PATH).into(),
+            )
+        }
+
+        fn read_alias(&self, name: &str) -> OwnedObjectPath {
+            assert_eq!("default", name);
+            let path = if self.state.lock().unwrap().has_default {
+                COLLECTION_PATH
+            } else {
+                "/"
+            };
+            ObjectPath::from_static_str_unchecked(path).into()
+        }
+
+        fn unlock(&self, objects: Vec<OwnedObjectPath>) -> (Vec<OwnedObjectPath>, OwnedObjectPath) {
+            if self.state.lock().unwrap().locked {
+                (
+                    Vec::new(),
+                    ObjectPath::from_static_str_unchecked(PROMPT_PATH).into(),
+                )
+            } else {
+                (objects, ObjectPath::from_static_str_unchecked("/").into())
+            }
+        }
+
+        fn get_secrets(
+            &self,
+            items: Vec<OwnedObjectPath>,
+            session: OwnedObjectPath,
+        ) -> HashMap<OwnedObjectPath, Secret> {
+            let state = self.state.lock().unwrap();
+            assert!(!state.locked);
+            state
+                .items
+                .iter()
+                .filter(|(path, _, _)| items.contains(path))
+                .map(|(path, _, value)| {
+                    let secret = Secret {
+                        session: session.clone(),
+                        parameters: Vec::new(),
+                        value: value.clone(),
+                        content_type: "application/json".to_string(),
+                    };
+                    (path.clone(), secret)
+                })
+                .collect()
+        }
+    }
+
+    struct MockCollection {
+        state: State,
+    }
+
+    #[interface(name = "org.freedesktop.Secret.Collection")]
+    impl MockCollection {
+        fn search_items(&self, attributes: HashMap<String, String>) -> Vec<OwnedObjectPath> {
+            let state = self.state.lock().unwrap();
+            state
+                .items
+                .iter()
+                .filter(|(_, item, _)| attributes.iter().all(|(k, v)| item.get(k) == Some(v)))
+                .map(|(path, _, _)| path.clone())
+                .collect()
+        }
+
+        fn create_item(
+            &self,
+            properties: HashMap<String, OwnedValue>,
+            secret: Secret,
+            replace: bool,
+        ) -> (OwnedObjectPath, OwnedObjectPath) {
+            let mut state = self.state.lock().unwrap();
+            assert!(!state.locked);
+            let attributes: HashMap<String, String> = properties[ITEM_ATTRIBUTES]
+                .try_clone()
+                .unwrap()
+                .try_into()
+                .unwrap();
+            let existing = state
+                .items
+                .iter()
+                .position(|(_, item, _)| replace && *item == attributes);
+            let path = match existing {
+                Some(index) => state.items.remove(index).0,
+                None => {
+                    ObjectPath::try_from(format!("{COLLECTION_PATH}/{}", state.items.len() + 1))
+                        .unwrap()
+                        .into()
+                }
+            };
+            state.items.push((path.clone(), attributes, secret.value));
+            (path, ObjectPath::from_static_str_unchecked("/").into())
+        }
+
+        #[zbus(property)]
+        fn locked(&self) -> bool {
+            self.state.lock().unwrap().locked
+        }
+    }
+
+    /// Completes when called, unlocking or creating the default collection
+    /// unless the user dismisses it.
+    struct MockPrompt {
+        state: State,
+    }
+
+    #[interface(name = "org.freedesktop.Secret.Prompt")]
+    impl MockPrompt {
+        async fn prompt(
+            &self,
+            _window_id: &str,
+            #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+        ) {
+            let dismissed = {
+                let mut state = self.state.lock().unwrap();
+                state.prompts += 1;
+                if !state.dismiss {
+                    state.locked = false;
+                    state.has_default = true;
+                }
+                state.dismiss
+            };
+            let result = ObjectPath::from_static_str_unchecked(COLLECTION_PATH);
+            Self::completed(&emitter, dismissed, Value::from(result))
+                .await
+                .unwrap();
+        }
+
+        #[zbus(signal)]
+        async fn completed(
+            emitter: &SignalEmitter<'_>,
+            dismissed: bool,
+            result: Value<'_>,
+        ) -> zbus::Result<()>;
+    }
+
+    /// Serves a mock Secret Service, returning the server and client ends of
+    /// the connection.
+    async fn mock_secret_service(state: &State) -> (Connection, Connection) {
+        let (server, client) = tokio::net::UnixStream::pair().unwrap();
+        let server = Builder::unix_stream(server)
+            .server(Guid::generate())
+            .unwrap()
+            .p2p()
+            .serve_at(
+                "/org/freedesktop/secrets",
+                MockService {
+                    state: state.clone(),
+                },
+            )
+            .unwrap()
+            .serve_at(
+                COLLECTION_PATH,
+                MockCollection {
+                    state: state.clone(),
+                },
+            )
+            .unwrap()
+            .serve_at(
+                PROMPT_PATH,
+                MockPrompt {
+                    state: state.clone(),
+                },
+            )
+            .unwrap();
+        let (server, client) =
+            futures_lite::future::zip(server.build(), Builder::unix_stream(client).p2p().build())
+                .await;
+        (server.unwrap(), client.unwrap())
+    }
+
+    async fn store(conn: Connection) -> Arc<PlatformCredentialStore> {
+        let storage = SecretServiceStorage::connect(conn).await.unwrap();
+        Arc::new(PlatformCredentialStore::new(storage))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_credentials_are_stored_as_items() {
+        let state = State::default();
+        let (_server, conn) = mock_secret_service(&state).await;
+        let store = store(conn).await;
+
+        let credentials = tokio::task::spawn_blocking(move || {
+            store.add(credential(1, b"alice")).unwrap();
+            store.add(credential(2, b"bob")).unwrap();
+            store.add(credential(3, b"alice")).unwrap();
+            store.increment_sign_count(&credential(2, b"bob")).unwrap();
+            store.find("example.com", &[]).unwrap()
+        })
+        .await
+        .unwrap();
+
+        let mut ids: Vec<(Vec<u8>, u32)> = credentials
+            .into_iter()
+            .map(|cred| (cred.id, cred.sign_count))
+            .collect();
+        ids.sort();
+        assert_eq!(vec![(vec![2; 16], 1), (vec![3; 16], 0)], ids);
+        let state = state.lock().unwrap();
+        assert_eq!(2, state.items.len());
+        let mut user_ids: Vec<&str> = state
+            .items
+            .iter()
+            .map(|(_, attributes, _)| {
+                assert_eq!(SCHEMA, attributes["xdg:schema"]);
+                assert_eq!("example.com", attributes["rp-id"]);
+                attributes["user-id"].as_str()
+            })
+            .collect();
+        user_ids.sort();
+        assert_eq!(vec!["YWxpY2U", "Ym9i"], user_ids);
+        // Creating the default collection.
+        assert_eq!(1, state.prompts);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_locked_keyring_is_unlocked_with_a_prompt() {
+        let state = State::default();
+        {
+            let mut state = state.lock().unwrap();
+            state.has_default = true;
+            state.locked = true;
+        }
+        let (_server, conn) = mock_secret_service(&state).await;
+        let store = store(conn).await;
+
+        tokio::task::spawn_blocking(move || {
+            store.add(credential(1, b"alice")).unwrap();
+            assert_eq!(1, store.find("example.com", &[]).unwrap().len());
+        })
+        .await
+        .unwrap();
+
+        let state = state.lock().unwrap();
+        assert_eq!(1, state.prompts);
+        assert!(!state.locked);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dismissed_unlock_prompt_fails() {
+        let state = State::default();
+        {
+            let mut state = state.lock().unwrap();
+            state.has_default = true;
+            state.locked = true;
+            state.dismiss = true;
+        }
+        let (_server, conn) = mock_secret_service(&state).await;
+        let store = store(conn).await;
+
+        let err = tokio::task::spawn_blocking(move || store.find("example.com", &[]).unwrap_err())
+            .await
+            .unwrap();
+        assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+    }
+
+    #[tokio::test]
+    async fn test_file_keys_are_created_once() {
+        let state = State::default();
+        state.lock().unwrap().has_default = true;
+        let (_server, conn) = mock_secret_service(&state).await;
+
+        let key = file_key(&conn, "linked-devices").await.unwrap();
+        assert_eq!(key, file_key(&conn, "linked-devices").await.unwrap());
+        assert_ne!(key, file_key(&conn, "platform-credentials").await.unwrap());
+
+        let state = state.lock().unwrap();
+        assert_eq!(2, state.items.len());
+        let (_, attributes, value) = &state.items[0];
+        assert_eq!(FILE_KEY_SCHEMA, attributes["xdg:schema"]);
+        assert_eq!("linked-devices", attributes["file"]);
+        assert_eq!(key.to_vec(), *value);
+    }
+}
@@ -0,0 +1,263 @@
+//! Discoverable credentials of the platform authenticator, persisted per user.
+//!
+//! Credentials are kept either in a file only readable by the user, encrypted
+//! with a key from their keyring, or in the keyring itself through the Secret
+//! Service API, see [`SecretServiceStorage`].
+
+use std::{fmt::Debug, io, path::PathBuf, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use zbus::Connection;
+
+use super::{
+    keys::{Algorithm, KeyBackend},
+    secret_service::{self, SecretServiceStorage},
+};
+use crate::credential_service::encrypted_file::{self, EncryptedFile, KEY_LEN};
+
+const CREDENTIALS_FILE: &str = "platform-credentials";
+/// Binds the ciphertext to its purpose and format version.
+const AAD: &[u8] = b"credentialsd platform credentials v1";
+
+/// A credential created by the platform authenticator.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(super) struct PlatformCredential {
+    pub(super) id: Vec<u8>,
+    pub(super) rp_id: String,
+    pub(super) user_id: Vec<u8>,
+    pub(super) user_name: Option<String>,
+    pub(super) user_display_name: Option<String>,
+    pub(super) algorithm: Algorithm,
+    pub(super) key_backend: KeyBackend,
+    /// PKCS#8 document of a software private key, or the private key wrapped
+    /// by the TPM.
+    pub(super) private_key: Vec<u8>,
+    pub(super) sign_count: u32,
+    pub(super) backup_eligible: bool,
+    pub(super) backed_up: bool,
+}
+
+/// Where credentials are persisted.
+///
+/// Calls may block, e.g. on the user unlocking their keyring, so they must be
+/// made from blocking threads.
+pub(super) trait CredentialStorage: Debug + Send + Sync {
+    /// Lists the credentials for the relying party, or all of them.
+    fn list(&self, rp_id: Option<&str>) -> io::Result<Vec<PlatformCredential>>;
+
+    /// Stores a credential. It replaces any credential of the same user for
+    /// the relying party, including an earlier version of itself.
+    fn put(&self, credential: &PlatformCredential) -> io::Result<()>;
+}
+
+/// Platform credentials of the current user.
+#[derive(Debug)]
+pub struct PlatformCredentialStore {
+    storage: Box<dyn CredentialStorage>,
+    /// Held while a credential is read, modified and written back.
+    lock: Mutex<()>,
+}
+
+impl PlatformCredentialStore {
+    /// Opens the store in `$XDG_DATA_HOME/credentialsd`, with its key from
+    /// the user's keyring on the session bus at `conn`.
+    pub async fn open(conn: &Connection) -> io::Result<Self> {
+        let key = secret_service::file_key(conn, CREDENTIALS_FILE).await?;
+        Ok(Self::new(FileStorage::open_at(
+            encrypted_file::data_dir()?,
+            &key,
+        )?))
+    }
+
+    /// Opens the store in the default collection of the user's Secret
+    /// Service on the session bus at `conn`, usually their login keyring.
+    pub async fn open_secret_service(conn: &Connection) -> io::Result<Self> {
+        Ok(Self::new(
+            SecretServiceStorage::connect(conn.clone()).await?,
+        ))
+    }
+
+    pub(super) fn new(storage: impl CredentialStorage + 'static) -> Self {
+        Self {
+            storage: Box::new(storage),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Lists the credentials for the relying party. If `ids` is not empty,
+    /// only credentials with one of these IDs are returned.
+    pub(super) fn find(&self, rp_id: &str, ids: &[&[u8]]) -> io::Result<Vec<PlatformCredential>> {
+        let mut credentials = self.storage.list(Some(rp_id))?;
+        credentials.retain(|cred| ids.is_empty() || ids.contains(&cred.id.as_slice()));
+        Ok(credentials)
+    }
+
+    /// Lists the credentials of all relying parties.
+    pub(super) fn all(&self) -> io::Result<Vec<PlatformCredential>> {
+        self.storage.list(None)
+    }
+
+    /// Stores a new credential. It replaces any credential of the same user
+    /// for the relying party, as discoverable credentials do on security keys.
+    pub(super) fn add(&self, credential: PlatformCredential) -> io::Result<()> {
+        let _lock = self.lock.lock().unwrap();
+        self.storage.put(&credential)
+    }
+
+    /// Increments the signature counter of the credential, returning the new
+    /// value.
+    pub(super) fn increment_sign_count(&self, credential: &PlatformCredential) -> io::Result<u32> {
+        let _lock = self.lock.lock().unwrap();
+        let mut credential = self
+            .storage
+            .list(Some(&credential.rp_id))?
+            .into_iter()
+            .find(|cred| cred.id == credential.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Unknown credential"))?;
+        credential.sign_count = credential.sign_count.wrapping_add(1);
+        self.storage.put(&credential)?;
+        Ok(credential.sign_count)
+    }
+}
+
+/// Credentials stored in a file encrypted with a key from the user's keyring.
+#[derive(Debug)]
+struct FileStorage {
+    file: EncryptedFile,
+    credentials: Mutex<Vec<PlatformCredential>>,
+}
+
+impl FileStorage {
+    /// Opens the file in `dir`.
+    ///
+    /// Unlike linked devices, credentials that cannot be read are not dropped:
+    /// the user would lose access to their accounts, so the error is returned
+    /// and the file is left alone.
+    fn open_at(dir: PathBuf, key: &[u8; KEY_LEN]) -> io::Result<Self> {
+        let file = EncryptedFile::new(dir, CREDENTIALS_FILE, AAD, key);
+        let credentials = match file.load() {
+            Ok(credentials) => credentials,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            file,
+            credentials: Mutex::new(credentials),
+        })
+    }
+}
+
+impl CredentialStorage for FileStorage {
+    fn list(&self, rp_id: Option<&str>) -> io::Result<Vec<PlatformCredential>> {
+        let credentials = self.credentials.lock().unwrap();
+        Ok(credentials
+            .iter()
+            .filter(|cred| rp_id.is_none_or(|rp_id| cred.rp_id == rp_id))
+            .cloned()
+            .collect())
+    }
+
+    fn put(&self, credential: &PlatformCredential) -> io::Result<()> {
+        let mut credentials = self.credentials.lock().unwrap();
+        let mut updated = credentials.clone();
+        updated
+            .retain(|cred| !(cred.rp_id == credential.rp_id && cred.user_id == credential.user_id));
+        updated.push(credential.clone());
+        self.file.save(&updated)?;
+        *credentials = updated;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use std::fs;
+
+    use super::{FileStorage, PlatformCredential, PlatformCredentialStore, CREDENTIALS_FILE};
+    use crate::credential_service::{
+        encrypted_file::test::{TempDir, KEY},
+        platform::keys::{Algorithm, KeyBackend},
+    };
+
+    pub(in crate::credential_service::platform) fn credential(
+        id: u8,
+        user_id: &[u8],
+    ) -> PlatformCredential {
+        PlatformCredential {
+            id: vec![id; 16],
+            rp_id: "example.com".to_string(),
+            user_id: user_id.to_vec(),
+            user_name: Some("alice".to_string()),
+            user_display_name: None,
+            algorithm: Algorithm::Es256,
+            key_backend: KeyBackend::Software,
+            private_key: b"private-key".to_vec(),
+            sign_count: 0,
+            backup_eligible: false,
+            backed_up: false,
+        }
+    }
+
+    pub(in crate::credential_service::platform) fn file_store(
+        dir: &TempDir,
+    ) -> PlatformCredentialStore {
+        PlatformCredentialStore::new(FileStorage::open_at(dir.0.clone(), &KEY).unwrap())
+    }
+
+    #[test]
+    fn test_credentials_are_persisted_encrypted() {
+        let dir = TempDir::new();
+        let store = file_store(&dir);
+        store.add(credential(1, b"alice")).unwrap();
+        assert_eq!(
+            1,
+            store
+                .increment_sign_count(&credential(1, b"alice"))
+                .unwrap()
+        );
+
+        let data = fs::read(dir.0.join(CREDENTIALS_FILE)).unwrap();
+        assert!(!data.windows(11).any(|w| w == b"private-key"));
+
+        let store = file_store(&dir);
+        let credentials = store.find("example.com", &[]).unwrap();
+        assert_eq!(1, credentials.len());
+        assert_eq!(b"private-key".to_vec(), credentials[0].private_key);
+        assert_eq!(1, credentials[0].sign_count);
+        assert!(store.find("example.org", &[]).unwrap().is_empty());
+        assert_eq!(1, store.all().unwrap().len());
+    }
+
+    #[test]
+    fn test_new_credential_replaces_the_one_of_the_same_user() {
+        let dir = TempDir::new();
+        let store = file_store(&dir);
+        store.add(credential(1, b"alice")).unwrap();
+        store.add(credential(2, b"bob")).unwrap();
+        store.add(credential(3, b"alice")).unwrap();
+
+        let ids: Vec<Vec<u8>> = store
+            .find("example.com", &[])
+            .unwrap()
+            .into_iter()
+            .map(|cred| cred.id)
+            .collect();
+        assert_eq!(vec![vec![2; 16], vec![3; 16]], ids);
+        assert_eq!(1, store.find("example.com", &[&[3; 16]]).unwrap().len());
+    }
+
+    #[test]
+    fn test_unreadable_credentials_are_not_dropped() {
+        let dir = TempDir::new();
+        let store = file_store(&dir);
+        store.add(credential(1, b"alice")).unwrap();
+        fs::write(dir.0.join(CREDENTIALS_FILE), b"garbage").unwrap();
+
+        assert!(FileStorage::open_at(dir.0.clone(), &KEY).is_err());
+        assert_eq!(
+            b"garbage".to_vec(),
+            fs::read(dir.0.join(CREDENTIALS_FILE)).unwrap()
+        );
+    }
+}
@@ -0,0 +1,538 @@
+//! Platform credential keys bound to a TPM 2.0.
+//!
+//! Keys are created under the storage root key (SRK), which the TPM derives
+//! from its owner seed with the TCG's standard ECC template. The TPM only
+//! hands out the private key wrapped by the SRK, so we store that blob, and
+//! load it back into the TPM to sign: the key cannot be used on another
+//! machine.
+//!
+//! This talks to the kernel's resource manager at `/dev/tpmrm0`, marshalling
+//! the few commands that we need ourselves. Objects are created with empty
+//! authorization values, using password sessions.
+
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    net::TcpStream,
+    sync::Mutex,
+};
+
+use ring::digest;
+
+const DEVICE_PATH: &str = "/dev/tpmrm0";
+/// Large enough for any response of the commands that we send.
+const MAX_RESPONSE_LEN: usize = 4096;
+const HEADER_LEN: usize = 10;
+
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_ST_SESSIONS: u16 = 0x8002;
+const TPM_ST_HASHCHECK: u16 = 0x8024;
+
+const TPM_CC_CREATE_PRIMARY: u32 = 0x0000_0131;
+const TPM_CC_CREATE: u32 = 0x0000_0153;
+const TPM_CC_LOAD: u32 = 0x0000_0157;
+const TPM_CC_SIGN: u32 = 0x0000_015D;
+const TPM_CC_FLUSH_CONTEXT: u32 = 0x0000_0165;
+
+const TPM_RH_OWNER: u32 = 0x4000_0001;
+const TPM_RH_NULL: u32 = 0x4000_0007;
+const TPM_RS_PW: u32 = 0x4000_0009;
+
+const TPM_ALG_AES: u16 = 0x0006;
+const TPM_ALG_SHA256: u16 = 0x000B;
+const TPM_ALG_NULL: u16 = 0x0010;
+const TPM_ALG_ECDSA: u16 = 0x0018;
+const TPM_ALG_ECC: u16 = 0x0023;
+const TPM_ALG_CFB: u16 = 0x0043;
+const TPM_ECC_NIST_P256: u16 = 0x0003;
+
+const FIXED_TPM: u32 = 1 << 1;
+const FIXED_PARENT: u32 = 1 << 4;
+const SENSITIVE_DATA_ORIGIN: u32 = 1 << 5;
+const USER_WITH_AUTH: u32 = 1 << 6;
+const NO_DA: u32 = 1 << 10;
+const RESTRICTED: u32 = 1 << 16;
+const DECRYPT: u32 = 1 << 17;
+const SIGN: u32 = 1 << 18;
+
+#[derive(Debug)]
+pub(crate) enum TpmError {
+    Io(io::Error),
+    /// The TPM returned an error response code.
+    Response(u32),
+    /// The TPM returned something we could not parse.
+    Malformed(&'static str),
+}
+
+impl Display for TpmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "TPM I/O error: {err}"),
+            Self::Response(rc) => write!(f, "TPM returned error {rc:#x}"),
+            Self::Malformed(msg) => write!(f, "Malformed TPM response: {msg}"),
+        }
+    }
+}
+
+impl From<io::Error> for TpmError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// A channel to a TPM that commands can be sent over.
+trait TpmDevice: Send {
+    fn transmit(&mut self, command: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+impl TpmDevice for File {
+    fn transmit(&mut self, command: &[u8]) -> io::Result<Vec<u8>> {
+        self.write_all(command)?;
+        // The kernel returns the whole response in a single read.
+        let mut response = vec![0; MAX_RESPONSE_LEN];
+        let len = self.read(&mut response)?;
+        response.truncate(len);
+        Ok(response)
+    }
+}
+
+/// `swtpm`'s TCP server, which takes raw commands.
+impl TpmDevice for TcpStream {
+    fn transmit(&mut self, command: &[u8]) -> io::Result<Vec<u8>> {
+        self.write_all(command)?;
+        let mut response = vec![0; HEADER_LEN];
+        self.read_exact(&mut response)?;
+        let len = u32::from_be_bytes(response[2..6].try_into().unwrap()) as usize;
+        if !(HEADER_LEN..=MAX_RESPONSE_LEN).contains(&len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid TPM response size",
+            ));
+        }
+        response.resize(len, 0);
+        self.read_exact(&mut response[HEADER_LEN..])?;
+        Ok(response)
+    }
+}
+
+pub(crate) struct Tpm {
+    device: Mutex<Box<dyn TpmDevice>>,
+}
+
+impl std::fmt::Debug for Tpm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tpm").finish_non_exhaustive()
+    }
+}
+
+impl Tpm {
+    /// Opens the TPM and checks that keys can be created under its SRK.
+    pub(crate) fn open() -> Result<Self, TpmError> {
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(DEVICE_PATH)?;
+        Self::probe(Box::new(device))
+    }
+
+    /// Connects to the TCP server of `swtpm`, for tests.
+    #[cfg(test)]
+    pub(crate) fn connect(address: &str) -> Result<Self, TpmError> {
+        Self::probe(Box::new(TcpStream::connect(address)?))
+    }
+
+    fn probe(device: Box<dyn TpmDevice>) -> Result<Self, TpmError> {
+        let tpm = Self {
+            device: Mutex::new(device),
+        };
+        let srk = tpm.create_srk()?;
+        tpm.flush(srk)?;
+        Ok(tpm)
+    }
+
+    /// Creates an ECDSA P-256 key under the SRK, returning the wrapped key
+    /// and the public key.
+    pub(crate) fn create_key(&self) -> Result<(Vec<u8>, cosey::PublicKey), TpmError> {
+        let srk = self.create_srk()?;
+        let result = self.create(srk);
+        self.flush(srk)?;
+        let (private, public) = result?;
+        let (x, y) = parse_ecc_point(&public)?;
+        let public_key = cosey::P256PublicKey {
+            x: cosey::Bytes::from_slice(&x).map_err(|_| TpmError::Malformed("x coordinate"))?,
+            y: cosey::Bytes::from_slice(&y).map_err(|_| TpmError::Malformed("y coordinate"))?,
+        };
+        let mut wrapped_key = private;
+        wrapped_key.extend(public);
+        Ok((wrapped_key, public_key.into()))
+    }
+
+    /// Signs the SHA-256 digest of `data` with a key returned by
+    /// [`Tpm::create_key`], returning a DER-encoded ECDSA signature.
+    pub(crate) fn sign(&self, wrapped_key: &[u8], data: &[u8]) -> Result<Vec<u8>, TpmError> {
+        let srk = self.create_srk()?;
+        let key = self.load(srk, wrapped_key);
+        self.flush(srk)?;
+        let key = key?;
+        let digest = digest::digest(&digest::SHA256, data);
+        let signature = self.sign_digest(key, digest.as_ref());
+        self.flush(key)?;
+        let (r, s) = signature?;
+        Ok(der_ecdsa_signature(&r, &s))
+    }
+
+    fn create_srk(&self) -> Result<u32, TpmError> {
+        let mut params = empty_sensitive_create();
+        params.extend(tpm2b(&srk_template()));
+        params.extend(tpm2b(&[])); // outsideInfo
+        params.extend(0u32.to_be_bytes()); // creationPCR
+        let response = self.execute(TPM_CC_CREATE_PRIMARY, &[TPM_RH_OWNER], &params)?;
+        let (handles, _) = parse_response(&response, 1)?;
+        Ok(handles[0])
+    }
+
+    /// Returns the TPM2B_PRIVATE and TPM2B_PUBLIC of a new signing key.
+    fn create(&self, parent: u32) -> Result<(Vec<u8>, Vec<u8>), TpmError> {
+        let mut params = empty_sensitive_create();
+        params.extend(tpm2b(&signing_key_template()));
+        params.extend(tpm2b(&[])); // outsideInfo
+        params.extend(0u32.to_be_bytes()); // creationPCR
+        let response = self.execute(TPM_CC_CREATE, &[parent], &params)?;
+        let (_, params) = parse_response(&response, 0)?;
+        let mut reader = Reader(params);
+        let private = reader.tpm2b_raw()?.to_vec();
+        let public = reader.tpm2b_raw()?.to_vec();
+        Ok((private, public))
+    }
+
+    fn load(&self, parent: u32, wrapped_key: &[u8]) -> Result<u32, TpmError> {
+        // Check that the blob holds a private and a public part.
+        let mut reader = Reader(wrapped_key);
+        reader.tpm2b_raw()?;
+        reader.tpm2b_raw()?;
+        if !reader.0.is_empty() {
+            return Err(TpmError::Malformed("trailing data in wrapped key"));
+        }
+        let response = self.execute(TPM_CC_LOAD, &[parent], wrapped_key)?;
+        let (handles, _) = parse_response(&response, 1)?;
+        Ok(handles[0])
+    }
+
+    fn sign_digest(&self, key: u32, digest: &[u8]) -> Result<(Vec<u8>, Vec<u8>), TpmError> {
+        let mut params = tpm2b(digest);
+        params.extend(TPM_ALG_ECDSA.to_be_bytes());
+        params.extend(TPM_ALG_SHA256.to_be_bytes());
+        // Null ticket: the key is not restricted, so it can sign any digest.
+        params.extend(TPM_ST_HASHCHECK.to_be_bytes());
+        params.extend(TPM_RH_NULL.to_be_bytes());
+        params.extend(tpm2b(&[]));
+        let response = self.execute(TPM_CC_SIGN, &[key], &params)?;
+        let (_, params) = parse_response(&response, 0)?;
+        let mut reader = Reader(params);
+        if reader.u16()? != TPM_ALG_ECDSA {
+            return Err(TpmError::Malformed("unexpected signature algorithm"));
+        }
+        reader.u16()?; // hash
+        let r = reader.tpm2b()?.to_vec();
+        let s = reader.tpm2b()?.to_vec();
+        Ok((r, s))
+    }
+
+    fn flush(&self, handle: u32) -> Result<(), TpmError> {
+        let mut device = self.device.lock().unwrap();
+        let command = command(TPM_CC_FLUSH_CONTEXT, &[], false, &handle.to_be_bytes());
+        let response = device.transmit(&command)?;
+        parse_response(&response, 0)?;
+        Ok(())
+    }
+
+    /// Runs a command that authorizes its handles with empty passwords.
+    fn execute(&self, code: u32, handles: &[u32], params: &[u8]) -> Result<Vec<u8>, TpmError> {
+        let mut device = self.device.lock().unwrap();
+        Ok(device.transmit(&command(code, handles, true, params))?)
+    }
+}
+
+fn command(code: u32, handles: &[u32], sessions: bool, params: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for handle in handles {
+        body.extend(handle.to_be_bytes());
+    }
+    if sessions {
+        // One password session per handle, with an empty password.
+        let mut auth = Vec::new();
+        for _ in handles {
+            auth.extend(TPM_RS_PW.to_be_bytes());
+            auth.extend(tpm2b(&[])); // nonce
+            auth.push(0); // sessionAttributes
+            auth.extend(tpm2b(&[])); // hmac
+        }
+        body.extend((auth.len() as u32).to_be_bytes());
+        body.extend(auth);
+    }
+    body.extend(params);
+
+    let tag = if sessions {
+        TPM_ST_SESSIONS
+    } else {
+        TPM_ST_NO_SESSIONS
+    };
+    let mut command = Vec::with_capacity(HEADER_LEN + body.len());
+    command.extend(tag.to_be_bytes());
+    command.extend(((HEADER_LEN + body.len()) as u32).to_be_bytes());
+    command.extend(code.to_be_bytes());
+    command.extend(body);
+    command
+}
+
+/// Checks the response code, and returns the response handles and parameters.
+fn parse_response(response: &[u8], handles: usize) -> Result<(Vec<u32>, &[u8]), TpmError> {
+    let mut reader = Reader(response);
+    let tag = reader.u16()?;
+    let size = reader.u32()?;
+    let code = reader.u32()?;
+    if code != 0 {
+        return Err(TpmError::Response(code));
+    }
+    if size as usize != response.len() {
+        return Err(TpmError::Malformed("size does not match"));
+    }
+    let handles = (0..handles)
+        .map(|_| reader.u32())
+        .collect::<Result<Vec<_>, _>>()?;
+    let params = match tag {
+        TPM_ST_SESSIONS => {
+            let len = reader.u32()?;
+            reader.take(len as usize)?
+        }
+        TPM_ST_NO_SESSIONS => reader.0,
+        _ => return Err(TpmError::Malformed("unknown tag")),
+    };
+    Ok((handles, params))
+}
+
+/// TPM2B_SENSITIVE_CREATE with an empty authorization value and no data.
+fn empty_sensitive_create() -> Vec<u8> {
+    let mut sensitive = tpm2b(&[]); // userAuth
+    sensitive.extend(tpm2b(&[])); // data
+    tpm2b(&sensitive)
+}
+
+/// The TCG's ECC P-256 SRK template, from the "TCG TPM v2.0 Provisioning
+/// Guidance", so that the SRK is the same as the one other software uses.
+fn srk_template() -> Vec<u8> {
+    let mut public = Vec::new();
+    public.extend(TPM_ALG_ECC.to_be_bytes());
+    public.extend(TPM_ALG_SHA256.to_be_bytes());
+    public.extend(
+        (FIXED_TPM
+            | FIXED_PARENT
+            | SENSITIVE_DATA_ORIGIN
+            | USER_WITH_AUTH
+            | NO_DA
+            | RESTRICTED
+            | DECRYPT)
+            .to_be_bytes(),
+    );
+    public.extend(tpm2b(&[])); // authPolicy
+    public.extend(TPM_ALG_AES.to_be_bytes());
+    public.extend(128u16.to_be_bytes());
+    public.extend(TPM_ALG_CFB.to_be_bytes());
+    public.extend(TPM_ALG_NULL.to_be_bytes()); // scheme
+    public.extend(TPM_ECC_NIST_P256.to_be_bytes());
+    public.extend(TPM_ALG_NULL.to_be_bytes()); // kdf
+    public.extend(tpm2b(&[0; 32])); // unique.x
+    public.extend(tpm2b(&[0; 32])); // unique.y
+    public
+}
+
+/// An ECDSA P-256 key that can only be used in this TPM, under its parent.
+fn signing_key_template() -> Vec<u8> {
+    let mut public = Vec::new();
+    public.extend(TPM_ALG_ECC.to_be_bytes());
+    public.extend(TPM_ALG_SHA256.to_be_bytes());
+    public.extend(
+        (FIXED_TPM | FIXED_PARENT | SENSITIVE_DATA_ORIGIN | USER_WITH_AUTH | NO_DA | SIGN)
+            .to_be_bytes(),
+    );
+    public.extend(tpm2b(&[])); // authPolicy
+    public.extend(TPM_ALG_NULL.to_be_bytes()); // symmetric
+    public.extend(TPM_ALG_ECDSA.to_be_bytes());
+    public.extend(TPM_ALG_SHA256.to_be_bytes());
+    public.extend(TPM_ECC_NIST_P256.to_be_bytes());
+    public.extend(TPM_ALG_NULL.to_be_bytes()); // kdf
+    public.extend(tpm2b(&[])); // unique.x
+    public.extend(tpm2b(&[])); // unique.y
+    public
+}
+
+/// Returns the coordinates of the public key in a TPM2B_PUBLIC of an ECC key.
+fn parse_ecc_point(public: &[u8]) -> Result<(Vec<u8>, Vec<u8>), TpmError> {
+    let mut reader = Reader(Reader(public).tpm2b()?);
+    if reader.u16()? != TPM_ALG_ECC {
+        return Err(TpmError::Malformed("not an ECC key"));
+    }
+    reader.u16()?; // nameAlg
+    reader.u32()?; // objectAttributes
+    reader.tpm2b()?; // authPolicy
+    if reader.u16()? != TPM_ALG_NULL {
+        reader.take(4)?; // keyBits and mode
+    }
+    if reader.u16()? != TPM_ALG_NULL {
+        reader.u16()?; // hashAlg
+    }
+    if reader.u16()? != TPM_ECC_NIST_P256 {
+        return Err(TpmError::Malformed("not a P-256 key"));
+    }
+    if reader.u16()? != TPM_ALG_NULL {
+        reader.u16()?; // hashAlg
+    }
+    let x = reader.tpm2b()?.to_vec();
+    let y = reader.tpm2b()?.to_vec();
+    Ok((x, y))
+}
+
+/// Encodes an ECDSA signature as the DER `Ecdsa-Sig-Value` that WebAuthn
+/// expects.
+fn der_ecdsa_signature(r: &[u8], s: &[u8]) -> Vec<u8> {
+    fn integer(value: &[u8]) -> Vec<u8> {
+        let start = value.iter().position(|b| *b != 0).unwrap_or(value.len());
+        let mut value = value[start..].to_vec();
+        // Positive integers need a leading zero if their top bit is set.
+        if value.first().is_none_or(|b| b & 0x80 != 0) {
+            value.insert(0, 0);
+        }
+        let mut der = vec![0x02, value.len() as u8];
+        der.extend(value);
+        der
+    }
+    let mut body = integer(r);
+    body.extend(integer(s));
+    let mut der = vec![0x30, body.len() as u8];
+    der.extend(body);
+    der
+}
+
+fn tpm2b(data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(2 + data.len());
+    buf.extend((data.len() as u16).to_be_bytes());
+    buf.extend(data);
+    buf
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], TpmError> {
+        if self.0.len() < len {
+            return Err(TpmError::Malformed("truncated"));
+        }
+        let (data, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(data)
+    }
+
+    fn u16(&mut self) -> Result<u16, TpmError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, TpmError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// Reads a sized buffer, returning its contents.
+    fn tpm2b(&mut self) -> Result<&'a [u8], TpmError> {
+        let len = self.u16()?;
+        self.take(len as usize)
+    }
+
+    /// Reads a sized buffer, returning it with its size.
+    fn tpm2b_raw(&mut self) -> Result<&'a [u8], TpmError> {
+        let data = self.0;
+        let len = self.tpm2b()?.len();
+        Ok(&data[..2 + len])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+
+    use super::{
+        command, der_ecdsa_signature, parse_ecc_point, parse_response, signing_key_template, tpm2b,
+        Tpm, TpmError, TPM_CC_FLUSH_CONTEXT,
+    };
+
+    /// Address of a `swtpm` TCP server to run the TPM tests against, e.g.
+    /// started with `swtpm socket --tpm2 --server type=tcp,port=2321
+    /// --ctrl type=tcp,port=2322 --tpmstate dir=/tmp/swtpm --flags
+    /// not-need-init,startup-clear`.
+    const SWTPM_ENV: &str = "CREDENTIALSD_TEST_SWTPM";
+
+    #[test]
+    fn test_commands_are_marshalled() {
+        let command = command(
+            TPM_CC_FLUSH_CONTEXT,
+            &[],
+            false,
+            &0x8000_0001u32.to_be_bytes(),
+        );
+        assert_eq!(
+            b"\x80\x01\x00\x00\x00\x0e\x00\x00\x01\x65\x80\x00\x00\x01".to_vec(),
+            command
+        );
+    }
+
+    #[test]
+    fn test_error_responses_are_returned() {
+        // TPM_RC_AUTH_FAIL for the first session
+        let response = b"\x80\x01\x00\x00\x00\x0a\x00\x00\x09\x8e";
+        assert!(matches!(
+            parse_response(response, 0),
+            Err(TpmError::Response(0x98e))
+        ));
+    }
+
+    #[test]
+    fn test_public_key_is_parsed() {
+        let mut public = signing_key_template();
+        // Replace the empty unique field with a point.
+        public.truncate(public.len() - 4);
+        public.extend(tpm2b(&[1; 32]));
+        public.extend(tpm2b(&[2; 32]));
+        let (x, y) = parse_ecc_point(&tpm2b(&public)).unwrap();
+        assert_eq!(vec![1; 32], x);
+        assert_eq!(vec![2; 32], y);
+    }
+
+    #[test]
+    fn test_signatures_are_der_encoded() {
+        let mut r = vec![0; 32];
+        r[1] = 0x80;
+        let s = vec![0x7f; 32];
+        let der = der_ecdsa_signature(&r, &s);
+        assert_eq!(b"\x30\x44\x02\x20\x00\x80", &der[..6]);
+        assert_eq!(b"\x02\x20\x7f", &der[36..39]);
+        assert_eq!(70, der.len());
+    }
+
+    #[test]
+    #[ignore = "needs a swtpm server at the address in CREDENTIALSD_TEST_SWTPM, see BUILDING.md"]
+    fn test_swtpm_keys_sign() {
+        let address = std::env::var(SWTPM_ENV).expect("CREDENTIALSD_TEST_SWTPM to be set");
+        let tpm = Tpm::connect(&address).unwrap();
+        let (wrapped_key, public_key) = tpm.create_key().unwrap();
+        let signature = tpm.sign(&wrapped_key, b"data").unwrap();
+
+        let cosey::PublicKey::P256Key(public_key) = public_key else {
+            panic!("Expected a P-256 key");
+        };
+        let mut point = vec![0x04];
+        point.extend_from_slice(&public_key.x);
+        point.extend_from_slice(&public_key.y);
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, point)
+            .verify(b"data", &signature)
+            .unwrap();
+    }
+}
@@ -0,0 +1,264 @@
+//! Moving platform passkeys between credential managers in CXF archives, see
+//! [`cxf`](super::cxf).
+//!
+//! Clients request transfers, but only the user can complete them: the
+//! passphrase of the archive is entered in the trusted UI, which is how the
+//! user confirms the transfer. Plain archives of other managers have no
+//! passphrase, and the user confirms their import in the UI instead. Until
+//! then, the passkeys to export or the archive to import are kept in a
+//! [`PendingTransfer`].
+
+use std::{io, sync::Arc};
+
+use credentialsd_common::model::Error as CredentialServiceError;
+
+use super::{
+    cxf::{self, Archive},
+    keys::KeyBackend,
+    store::{PlatformCredential, PlatformCredentialStore},
+};
+
+/// Passphrases of exported archives must be at least this many characters.
+const MIN_PASSPHRASE_LEN: usize = 8;
+
+#[derive(Debug)]
+pub enum TransferRequest {
+    /// Exports the passkeys of the given relying parties, or all of them if
+    /// there are none.
+    Export { rp_ids: Vec<String> },
+    /// Imports the passkeys of an archive.
+    Import { archive: Vec<u8> },
+}
+
+#[derive(Debug)]
+pub enum TransferResponse {
+    Exported { archive: Vec<u8>, count: u32 },
+    Imported { count: u32 },
+}
+
+impl TransferResponse {
+    /// The number of passkeys that were transferred.
+    pub fn count(&self) -> u32 {
+        match self {
+            Self::Exported { count, .. } | Self::Imported { count } => *count,
+        }
+    }
+}
+
+/// A transfer waiting for the user's confirmation.
+#[derive(Debug)]
+pub struct PendingTransfer {
+    store: Arc<PlatformCredentialStore>,
+    kind: PendingKind,
+}
+
+#[derive(Debug)]
+enum PendingKind {
+    Export(Vec<PlatformCredential>),
+    Import(Archive),
+}
+
+impl PendingTransfer {
+    /// Finds the passkeys to export, or reads the archive to import. Reading
+    /// the store may block, so this must be called from a blocking thread.
+    ///
+    /// Plain archives without passkeys are refused right away.
+    ///
+    /// Passkeys bound to the TPM cannot leave it, and are not exported.
+    pub fn new(
+        store: Arc<PlatformCredentialStore>,
+        request: TransferRequest,
+    ) -> Result<Self, CredentialServiceError> {
+        let kind = match request {
+            TransferRequest::Export { rp_ids } => {
+                let mut credentials = store.all().map_err(store_error)?;
+                credentials.retain(|cred| rp_ids.is_empty() || rp_ids.contains(&cred.rp_id));
+                let count = credentials.len();
+                credentials.retain(|cred| cred.key_backend == KeyBackend::Software);
+                if credentials.len() < count {
+                    tracing::info!(
+                        "Not exporting {} passkeys bound to the TPM",
+                        count - credentials.len()
+                    );
+                }
+                if credentials.is_empty() {
+                    return Err(CredentialServiceError::NoCredentials);
+                }
+                PendingKind::Export(credentials)
+            }
+            TransferRequest::Import { archive } => {
+                let archive = Archive::parse(&archive)
+                    .map_err(|err| CredentialServiceError::Internal(err.to_string()))?;
+                // Plain archives can be checked before bothering the user.
+                if !archive.is_encrypted() && archive.rp_ids().is_empty() {
+                    return Err(CredentialServiceError::NoCredentials);
+                }
+                PendingKind::Import(archive)
+            }
+        };
+        Ok(Self { store, kind })
+    }
+
+    /// The relying parties of the passkeys to transfer, to show to the user.
+    /// Empty for imports of encrypted archives, which can't be read yet.
+    pub fn rp_ids(&self) -> Vec<String> {
+        let credentials = match &self.kind {
+            PendingKind::Export(credentials) => credentials,
+            PendingKind::Import(archive) => return archive.rp_ids(),
+        };
+        let mut rp_ids: Vec<String> = credentials.iter().map(|cred| cred.rp_id.clone()).collect();
+        rp_ids.sort();
+        rp_ids.dedup();
+        rp_ids
+    }
+
+    /// Encrypts the passkeys to export, or decrypts and stores the imported
+    /// ones, with the passphrase entered by the user. Imports of plain
+    /// archives ignore the passphrase. The store may block, so this must be
+    /// called from a blocking thread.
+    ///
+    /// Imported passkeys replace those of the same users, like new ones do.
+    pub fn complete(&self, passphrase: &str) -> Result<TransferResponse, CredentialServiceError> {
+        match &self.kind {
+            PendingKind::Export(credentials) => {
+                if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+                    return Err(CredentialServiceError::Internal(format!(
+                        "The passphrase must have at least {MIN_PASSPHRASE_LEN} characters"
+                    )));
+                }
+                let archive = cxf::export(credentials, passphrase)
+                    .map_err(|err| CredentialServiceError::Internal(err.to_string()))?;
+                Ok(TransferResponse::Exported {
+                    archive,
+                    count: credentials.len() as u32,
+                })
+            }
+            PendingKind::Import(archive) => {
+                let credentials = archive
+                    .open(passphrase)
+                    .map_err(|err| CredentialServiceError::Internal(err.to_string()))?;
+                if credentials.is_empty() {
+                    return Err(CredentialServiceError::NoCredentials);
+                }
+                let count = credentials.len() as u32;
+                for credential in credentials {
+                    self.store.add(credential).map_err(store_error)?;
+                }
+                Ok(TransferResponse::Imported { count })
+            }
+        }
+    }
+}
+
+fn store_error(err: io::Error) -> CredentialServiceError {
+    CredentialServiceError::Internal(format!("Failed to access platform credentials: {err}"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use credentialsd_common::model::Error as CredentialServiceError;
+
+    use super::{PendingTransfer, TransferRequest, TransferResponse};
+    use crate::credential_service::{
+        encrypted_file::test::TempDir,
+        platform::{
+            keys::{Algorithm, KeyBackend, PlatformKeys},
+            store::test::{credential, file_store},
+        },
+    };
+
+    #[test]
+    fn test_passkeys_are_moved_to_another_store() {
+        let keys = PlatformKeys::software();
+        let source_dir = TempDir::new();
+        let source = Arc::new(file_store(&source_dir));
+        for (id, user_id, rp_id) in [
+            (1, b"alice", "example.com"),
+            (2, b"bob00", "example.com"),
+            (3, b"alice", "example.org"),
+        ] {
+            let (_, private_key, _) = keys.generate(Algorithm::Es256).unwrap();
+            let mut credential = credential(id, user_id);
+            credential.rp_id = rp_id.to_string();
+            credential.private_key = private_key;
+            source.add(credential).unwrap();
+        }
+        let mut bound = credential(4, b"carol");
+        bound.key_backend = KeyBackend::Tpm;
+        source.add(bound).unwrap();
+
+        let export = PendingTransfer::new(
+            source.clone(),
+            TransferRequest::Export {
+                rp_ids: vec!["example.com".to_string()],
+            },
+        )
+        .unwrap();
+        assert_eq!(vec!["example.com"], export.rp_ids());
+        assert!(export.complete("short").is_err());
+        let TransferResponse::Exported { archive, count } = export.complete("passphrase").unwrap()
+        else {
+            panic!("Expected an exported archive");
+        };
+        assert_eq!(2, count);
+
+        let target_dir = TempDir::new();
+        let target = Arc::new(file_store(&target_dir));
+        let import =
+            PendingTransfer::new(target.clone(), TransferRequest::Import { archive }).unwrap();
+        assert!(import.rp_ids().is_empty());
+        assert!(import.complete("wrong passphrase").is_err());
+        assert_eq!(2, import.complete("passphrase").unwrap().count());
+        let mut ids: Vec<Vec<u8>> = target
+            .find("example.com", &[])
+            .unwrap()
+            .into_iter()
+            .map(|cred| cred.id)
+            .collect();
+        ids.sort();
+        assert_eq!(vec![vec![1; 16], vec![2; 16]], ids);
+        assert!(target.find("example.org", &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_plain_archive_is_imported_without_passphrase() {
+        let dir = TempDir::new();
+        let store = Arc::new(file_store(&dir));
+        let import = PendingTransfer::new(
+            store.clone(),
+            TransferRequest::Import {
+                archive: include_bytes!("testdata/plain.cxf.json").to_vec(),
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["example.edu", "example.net", "example.org"],
+            import.rp_ids()
+        );
+        assert_eq!(2, import.complete("").unwrap().count());
+        assert_eq!(1, store.find("example.net", &[]).unwrap().len());
+        assert_eq!(1, store.find("example.org", &[]).unwrap().len());
+
+        let empty = br#"{"version": {"major": 1, "minor": 0}, "accounts": []}"#;
+        let result = PendingTransfer::new(
+            store,
+            TransferRequest::Import {
+                archive: empty.to_vec(),
+            },
+        );
+        assert!(matches!(result, Err(CredentialServiceError::NoCredentials)));
+    }
+
+    #[test]
+    fn test_nothing_to_export_fails() {
+        let dir = TempDir::new();
+        let store = Arc::new(file_store(&dir));
+        let mut bound = credential(1, b"alice");
+        bound.key_backend = KeyBackend::Tpm;
+        store.add(bound).unwrap();
+        let result = PendingTransfer::new(store, TransferRequest::Export { rp_ids: Vec::new() });
+        assert!(matches!(result, Err(CredentialServiceError::NoCredentials)));
+    }
+}
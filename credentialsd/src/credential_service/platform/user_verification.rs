@@ -0,0 +1,366 @@
+//! User verification for the platform authenticator, with the user's
+//! fingerprint through fprintd, or their login password through PAM.
+//!
+//! Methods are tried in the configured order, each with a few attempts. While
+//! the fingerprint reader is waiting, the user may enter their password
+//! instead if password verification comes later in the order. Methods that
+//! cannot be used right now, e.g. because there is no reader or no enrolled
+//! finger, are skipped.
+
+use std::fmt::Debug;
+
+use futures_lite::future;
+use libwebauthn::proto::CtapError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use super::{
+    fprintd::{FingerprintOutcome, Fprintd},
+    pam::Pam,
+};
+use crate::{
+    config::UserVerificationMethod,
+    credential_service::{
+        ctap::{send_state, DeviceError},
+        transport::AuthenticatorState,
+    },
+};
+
+const MAX_PASSWORD_ATTEMPTS: u32 = 3;
+
+/// Checks the user's login password.
+pub(super) trait PasswordCheck: Debug + Send + Sync {
+    /// Returns whether `password` is the user's login password.
+    ///
+    /// This blocks, and may take a few seconds for wrong passwords.
+    fn check(&self, password: &str) -> Result<bool, String>;
+}
+
+#[derive(Debug)]
+enum Method {
+    Fingerprint(Fprintd),
+    Password(Box<dyn PasswordCheck>),
+}
+
+/// Passwords entered by the user. The sender is handed to the UI with each
+/// prompt.
+pub(super) struct PasswordChannel {
+    pub(super) tx: Sender<String>,
+    pub(super) rx: Receiver<String>,
+}
+
+impl PasswordChannel {
+    /// Waits for the user to enter a password, unless the request is
+    /// cancelled.
+    fn receive(&mut self, tx: &Sender<AuthenticatorState>) -> Result<String, DeviceError> {
+        future::block_on(future::or(
+            async { self.rx.recv().await.ok_or(DeviceError::Cancelled) },
+            async {
+                tx.closed().await;
+                Err(DeviceError::Cancelled)
+            },
+        ))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct UserVerifier {
+    methods: Vec<Method>,
+}
+
+impl UserVerifier {
+    /// Sets up the configured methods. Methods whose service is missing are
+    /// left out.
+    pub async fn new(methods: &[UserVerificationMethod]) -> Self {
+        let mut verifier = Self::default();
+        for method in methods {
+            match method {
+                UserVerificationMethod::Fingerprint => match Fprintd::connect().await {
+                    Ok(fprintd) => verifier.methods.push(Method::Fingerprint(fprintd)),
+                    Err(err) => tracing::info!("Fingerprint verification unavailable: {err}"),
+                },
+                UserVerificationMethod::Password => match Pam::load() {
+                    Ok(pam) => verifier.methods.push(Method::Password(Box::new(pam))),
+                    Err(err) => tracing::info!("Password verification unavailable: {err}"),
+                },
+            }
+        }
+        verifier
+    }
+
+    /// Whether the user can be verified at all.
+    pub fn is_available(&self) -> bool {
+        !self.methods.is_empty()
+    }
+
+    /// Verifies the user, prompting them through `tx`.
+    ///
+    /// Returns `false` without prompting if none of the methods can be used
+    /// right now, and fails with `UvBlocked` if the user could not be
+    /// verified with any of them.
+    pub(super) fn verify(&self, tx: &Sender<AuthenticatorState>) -> Result<bool, DeviceError> {
+        let (password_tx, password_rx) = mpsc::channel(1);
+        let mut passwords = PasswordChannel {
+            tx: password_tx,
+            rx: password_rx,
+        };
+        let mut entered_password = None;
+        let mut attempted = false;
+        for (i, method) in self.methods.iter().enumerate() {
+            match method {
+                Method::Fingerprint(fprintd) => {
+                    let password_later = self.methods[i + 1..]
+                        .iter()
+                        .any(|method| matches!(method, Method::Password(_)));
+                    let passwords = password_later.then_some(&mut passwords);
+                    match fprintd.verify(tx, passwords)? {
+                        FingerprintOutcome::Matched => return Ok(true),
+                        FingerprintOutcome::NoMatch => attempted = true,
+                        FingerprintOutcome::Password(password) => {
+                            attempted = true;
+                            entered_password = Some(password);
+                        }
+                        FingerprintOutcome::Unavailable => {}
+                    }
+                }
+                Method::Password(check) => {
+                    attempted = true;
+                    let password = entered_password.take();
+                    if verify_password(check.as_ref(), tx, &mut passwords, password)? {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+        if attempted {
+            Err(DeviceError::Ctap(CtapError::UvBlocked))
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+/// Asks for the user's password until it is right or the attempts run out.
+/// `entered` is a password the user already entered, which counts as the
+/// first attempt.
+fn verify_password(
+    check: &dyn PasswordCheck,
+    tx: &Sender<AuthenticatorState>,
+    passwords: &mut PasswordChannel,
+    mut entered: Option<String>,
+) -> Result<bool, DeviceError> {
+    for attempt in 0..MAX_PASSWORD_ATTEMPTS {
+        let password = match entered.take() {
+            Some(password) => password,
+            None => {
+                send_state(
+                    tx,
+                    AuthenticatorState::NeedsPassword {
+                        attempts_left: Some(MAX_PASSWORD_ATTEMPTS - attempt),
+                        password_tx: passwords.tx.clone(),
+                    },
+                )?;
+                passwords.receive(tx)?
+            }
+        };
+        match check.check(&password) {
+            Ok(true) => return Ok(true),
+            Ok(false) => tracing::info!("Wrong password entered for the platform authenticator"),
+            Err(err) => return Err(DeviceError::Transport(err)),
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use std::{
+        fmt::Debug,
+        sync::{Arc, Mutex},
+    };
+
+    use libwebauthn::proto::CtapError;
+    use tokio::sync::mpsc::{self, Receiver};
+
+    use super::{Method, PasswordCheck, UserVerifier};
+    use crate::credential_service::{
+        ctap::DeviceError,
+        platform::fprintd::{test::mock_fprintd, Fprintd},
+        transport::AuthenticatorState,
+    };
+
+    /// Accepts a single password, recording the passwords checked.
+    #[derive(Debug)]
+    struct MockPassword {
+        password: &'static str,
+        checked: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl PasswordCheck for MockPassword {
+        fn check(&self, password: &str) -> Result<bool, String> {
+            self.checked.lock().unwrap().push(password.to_string());
+            Ok(password == self.password)
+        }
+    }
+
+    pub(in crate::credential_service::platform) fn password_verifier(
+        password: &'static str,
+    ) -> UserVerifier {
+        UserVerifier {
+            methods: vec![Method::Password(Box::new(MockPassword {
+                password,
+                checked: Arc::default(),
+            }))],
+        }
+    }
+
+    /// Plays the user: enters the given passwords when asked, and records the
+    /// states shown in a compact form.
+    pub(in crate::credential_service::platform) async fn answer_prompts(
+        mut rx: Receiver<AuthenticatorState>,
+        passwords: &[&str],
+    ) -> Vec<String> {
+        let mut passwords = passwords.iter();
+        let mut states = Vec::new();
+        while let Some(state) = rx.recv().await {
+            match state {
+                AuthenticatorState::NeedsFingerprint {
+                    attempts_left,
+                    password_tx,
+                } => {
+                    states.push(format!("fingerprint {attempts_left:?}"));
+                    // An empty password leaves the fingerprint prompt alone.
+                    if let (Some(password_tx), Some(password)) = (password_tx, passwords.next()) {
+                        if !password.is_empty() {
+                            password_tx.send(password.to_string()).await.unwrap();
+                        }
+                    }
+                }
+                AuthenticatorState::NeedsPassword {
+                    attempts_left,
+                    password_tx,
+                } => {
+                    states.push(format!("password {attempts_left:?}"));
+                    match passwords.next() {
+                        Some(password) => password_tx.send(password.to_string()).await.unwrap(),
+                        // Cancels the request.
+                        None => break,
+                    }
+                }
+                state => panic!("Unexpected state: {state:?}"),
+            }
+        }
+        states
+    }
+
+    async fn verify(
+        verifier: UserVerifier,
+        passwords: &[&str],
+    ) -> (Result<bool, DeviceError>, Vec<String>) {
+        let (tx, rx) = mpsc::channel(8);
+        let result = tokio::task::spawn_blocking(move || verifier.verify(&tx));
+        let states = answer_prompts(rx, passwords).await;
+        (result.await.unwrap(), states)
+    }
+
+    fn fingerprint_then_password(fprintd: Fprintd, password: &MockPassword) -> UserVerifier {
+        UserVerifier {
+            methods: vec![
+                Method::Fingerprint(fprintd),
+                Method::Password(Box::new(MockPassword {
+                    password: password.password,
+                    checked: password.checked.clone(),
+                })),
+            ],
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_matching_fingerprint_verifies_the_user() {
+        let (fprintd, _server) = mock_fprintd(&["verify-retry-scan", "verify-match"]).await;
+        let verifier = UserVerifier {
+            methods: vec![Method::Fingerprint(fprintd)],
+        };
+        let (result, states) = verify(verifier, &[]).await;
+        assert!(result.unwrap());
+        assert_eq!(vec!["fingerprint Some(3)"], states);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_password_is_asked_after_fingerprint_attempts_run_out() {
+        let script = ["verify-no-match", "verify-no-match", "verify-no-match"];
+        let (fprintd, server) = mock_fprintd(&script).await;
+        let password = MockPassword {
+            password: "hunter2",
+            checked: Arc::default(),
+        };
+        let verifier = fingerprint_then_password(fprintd, &password);
+        // Passwords are only entered once the reader gave up.
+        let (result, states) = verify(verifier, &["", "", "", "hunter1", "hunter2"]).await;
+        assert!(result.unwrap());
+        assert_eq!(
+            vec![
+                "fingerprint Some(3)",
+                "fingerprint Some(2)",
+                "fingerprint Some(1)",
+                "password Some(3)",
+                "password Some(2)",
+            ],
+            states
+        );
+        assert_eq!(
+            vec!["hunter1", "hunter2"],
+            *password.checked.lock().unwrap()
+        );
+        assert!(server.reader().released);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_password_can_be_entered_instead_of_fingerprint() {
+        // The reader waits for a finger that never comes.
+        let (fprintd, server) = mock_fprintd(&[]).await;
+        let password = MockPassword {
+            password: "hunter2",
+            checked: Arc::default(),
+        };
+        let verifier = fingerprint_then_password(fprintd, &password);
+        let (result, states) = verify(verifier, &["hunter2"]).await;
+        assert!(result.unwrap());
+        assert_eq!(vec!["fingerprint Some(3)"], states);
+        let reader = server.reader();
+        assert!(reader.stopped && reader.released);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_user_is_blocked_after_wrong_passwords() {
+        let verifier = password_verifier("hunter2");
+        let (result, states) = verify(verifier, &["a", "b", "c"]).await;
+        assert!(matches!(
+            result,
+            Err(DeviceError::Ctap(CtapError::UvBlocked))
+        ));
+        assert_eq!(
+            vec!["password Some(3)", "password Some(2)", "password Some(1)"],
+            states
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dismissed_password_prompt_cancels_the_request() {
+        let verifier = password_verifier("hunter2");
+        let (result, _) = verify(verifier, &[]).await;
+        assert!(matches!(result, Err(DeviceError::Cancelled)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unusable_methods_are_skipped() {
+        let (fprintd, server) = mock_fprintd(&[]).await;
+        server.reader().fingers.clear();
+        let verifier = UserVerifier {
+            methods: vec![Method::Fingerprint(fprintd)],
+        };
+        let (result, states) = verify(verifier, &[]).await;
+        assert!(!result.unwrap());
+        assert!(states.is_empty());
+        assert!(!server.reader().claimed);
+    }
+}
@@ -0,0 +1,231 @@
+//! Descriptors of credential providers, through which providers register with
+//! credentialsd.
+//!
+//! Like D-Bus services, providers install a key file named after their ID,
+//! e.g. `org.keepassxc.KeePassXC.provider`, into the `credentialsd/providers`
+//! subdirectory of a data directory:
+//!
+//! ```ini
+//! [Credential Provider]
+//! Name=KeePassXC
+//! BusName=org.keepassxc.KeePassXC
+//! ObjectPath=/org/keepassxc/KeePassXC/CredentialProvider
+//! Version=1
+//! ```
+//!
+//! Data directories are searched in the order of the XDG Base Directory
+//! specification, starting with `$XDG_DATA_HOME`, and the first descriptor
+//! found for an ID is used.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use zbus::{names::OwnedWellKnownName, zvariant::OwnedObjectPath};
+
+const GROUP: &str = "Credential Provider";
+const EXTENSION: &str = "provider";
+const PROVIDERS_DIR: &str = "credentialsd/providers";
+const DEFAULT_DATA_DIRS: &str = "/usr/local/share:/usr/share";
+
+/// The version of the `CredentialProvider1` interface that is implemented.
+const INTERFACE_VERSION: &str = "1";
+
+/// A credential provider, as registered by its descriptor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProviderDescriptor {
+    /// The name of the descriptor file, without its extension.
+    pub id: String,
+    /// Name of the provider, shown to the user.
+    pub name: String,
+    pub bus_name: OwnedWellKnownName,
+    pub object_path: OwnedObjectPath,
+}
+
+impl ProviderDescriptor {
+    fn parse(id: &str, contents: &str) -> Result<Self, String> {
+        let mut group = None;
+        let mut entries = BTreeMap::new();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                group = Some(name);
+                continue;
+            }
+            if group != Some(GROUP) {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid line: {line}"))?;
+            // Localised keys, e.g. `Name[de]`, are not supported yet.
+            entries.insert(key.trim(), value.trim());
+        }
+        let entry = |key: &str| {
+            entries
+                .get(key)
+                .copied()
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| format!("Missing {key} in the [{GROUP}] group"))
+        };
+        let version = entry("Version")?;
+        if version != INTERFACE_VERSION {
+            return Err(format!("Unsupported interface version {version}"));
+        }
+        Ok(Self {
+            id: id.to_string(),
+            name: entry("Name")?.to_string(),
+            bus_name: OwnedWellKnownName::try_from(entry("BusName")?)
+                .map_err(|err| format!("Invalid BusName: {err}"))?,
+            object_path: OwnedObjectPath::try_from(entry("ObjectPath")?)
+                .map_err(|err| format!("Invalid ObjectPath: {err}"))?,
+        })
+    }
+}
+
+/// The directories that descriptors are searched in, in order.
+pub(super) fn search_dirs() -> Vec<PathBuf> {
+    let data_dirs = std::env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| DEFAULT_DATA_DIRS.to_string());
+    dirs::data_dir()
+        .into_iter()
+        .chain(std::env::split_paths(&data_dirs))
+        .map(|dir| dir.join(PROVIDERS_DIR))
+        .collect()
+}
+
+/// Reads the descriptors in `dirs`, sorted by name. Invalid descriptors are
+/// skipped.
+pub(super) fn discover(dirs: &[PathBuf]) -> Vec<ProviderDescriptor> {
+    let mut descriptors: BTreeMap<String, ProviderDescriptor> = BTreeMap::new();
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for path in entries.flatten().map(|entry| entry.path()) {
+            let Some(id) = descriptor_id(&path) else {
+                continue;
+            };
+            if descriptors.contains_key(id) {
+                continue;
+            }
+            let descriptor = fs::read_to_string(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|contents| ProviderDescriptor::parse(id, &contents));
+            match descriptor {
+                Ok(descriptor) => {
+                    descriptors.insert(id.to_string(), descriptor);
+                }
+                Err(err) => {
+                    tracing::warn!("Ignoring credential provider {}: {err}", path.display())
+                }
+            }
+        }
+    }
+    let mut descriptors: Vec<_> = descriptors.into_values().collect();
+    descriptors.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+    descriptors
+}
+
+fn descriptor_id(path: &Path) -> Option<&str> {
+    if path.extension()? != EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str().filter(|id| !id.is_empty())
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use std::{fs, path::Path};
+
+    use super::{discover, ProviderDescriptor};
+    use crate::credential_service::encrypted_file::test::TempDir;
+
+    /// Installs a descriptor for a provider in `dir`.
+    pub(in crate::credential_service::provider) fn install(
+        dir: &Path,
+        id: &str,
+        name: &str,
+        bus_name: &str,
+    ) {
+        fs::create_dir_all(dir).unwrap();
+        let descriptor = format!(
+            "[Credential Provider]\n\
+             Name={name}\n\
+             BusName={bus_name}\n\
+             ObjectPath=/xyz/iinuwa/credentialsd/ReferenceProvider\n\
+             Version=1\n"
+        );
+        fs::write(dir.join(format!("{id}.provider")), descriptor).unwrap();
+    }
+
+    #[test]
+    fn test_descriptor_is_parsed() {
+        let descriptor = ProviderDescriptor::parse(
+            "org.keepassxc.KeePassXC",
+            "# Installed by KeePassXC\n\
+             [Desktop Entry]\n\
+             Name=Ignored\n\
+             \n\
+             [Credential Provider]\n\
+             Name = KeePassXC\n\
+             Name[de]=KeePassXC\n\
+             BusName=org.keepassxc.KeePassXC\n\
+             ObjectPath=/org/keepassxc/KeePassXC/CredentialProvider\n\
+             Version=1\n",
+        )
+        .unwrap();
+        assert_eq!("org.keepassxc.KeePassXC", descriptor.id);
+        assert_eq!("KeePassXC", descriptor.name);
+        assert_eq!("org.keepassxc.KeePassXC", descriptor.bus_name.as_str());
+        assert_eq!(
+            "/org/keepassxc/KeePassXC/CredentialProvider",
+            descriptor.object_path.as_str()
+        );
+    }
+
+    #[test]
+    fn test_invalid_descriptors_are_rejected() {
+        let valid = "[Credential Provider]\nName=Provider\nBusName=org.example.Provider\nObjectPath=/org/example/Provider\nVersion=1\n";
+        assert!(ProviderDescriptor::parse("org.example.Provider", valid).is_ok());
+        for (from, to) in [
+            ("Version=1", "Version=2"),
+            ("Name=Provider\n", ""),
+            ("BusName=org.example.Provider", "BusName=:1.42"),
+            ("ObjectPath=/org/example/Provider", "ObjectPath=org/example"),
+            ("[Credential Provider]", "[Desktop Entry]"),
+        ] {
+            let contents = valid.replace(from, to);
+            assert!(
+                ProviderDescriptor::parse("org.example.Provider", &contents).is_err(),
+                "{contents}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_first_descriptor_found_is_used() {
+        let home = TempDir::new();
+        let system = TempDir::new();
+        install(
+            &home.0,
+            "org.example.Vault",
+            "My Vault",
+            "org.example.Vault",
+        );
+        install(&system.0, "org.example.Vault", "Vault", "org.example.Vault");
+        install(&system.0, "org.example.Keys", "Keys", "org.example.Keys");
+        fs::write(system.0.join("org.example.Broken.provider"), "Name=Broken").unwrap();
+        fs::write(system.0.join("README"), "Not a descriptor").unwrap();
+
+        let descriptors = discover(&[home.0.clone(), system.0.clone()]);
+        let names: Vec<_> = descriptors.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(vec!["Keys", "My Vault"], names);
+    }
+}
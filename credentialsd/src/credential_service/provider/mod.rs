@@ -0,0 +1,758 @@
+//! Third-party credential providers, e.g. password managers, that keep
+//! passkeys for the user and use them on their behalf.
+//!
+//! Providers register with a descriptor, see [`descriptor`], and implement the
+//! `xyz.iinuwa.credentialsd.CredentialProvider1` interface on the session bus,
+//! see `doc/api.md`. Each enabled provider is offered to the user as a device,
+//! and choosing it in the trusted UI is how the user consents to the request.
+//! For assertions, the provider's credentials for the RP are listed in the
+//! UI's credential chooser, and the provider is only asked to sign with the
+//! chosen one. Providers may show UI of their own, e.g. to unlock their vault
+//! or to verify the user.
+//!
+//! Providers do not attest to their credentials, so these are created with
+//! `none` attestation.
+
+mod descriptor;
+#[cfg(test)]
+mod reference;
+mod store;
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use async_stream::stream;
+use libwebauthn::{
+    fido::{AuthenticatorData, AuthenticatorDataFlags},
+    ops::webauthn::{
+        Assertion, GetAssertionRequest, GetAssertionResponse, GetAssertionResponseExtensions,
+        MakeCredentialResponse, MakeCredentialsResponseUnsignedExtensions,
+        UserVerificationRequirement,
+    },
+    proto::{
+        ctap2::{
+            Ctap2AttestationStatement, Ctap2PublicKeyCredentialDescriptor,
+            Ctap2PublicKeyCredentialType, Ctap2PublicKeyCredentialUserEntity,
+        },
+        CtapError,
+    },
+};
+use ring::digest;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::mpsc::{self, Sender};
+use zbus::{
+    zvariant::{DeserializeDict, SerializeDict, Type},
+    Connection,
+};
+
+use credentialsd_common::model::{
+    CredentialRequest, CredentialResponse, Error, MakeCredentialRequestInternal, Transport,
+};
+
+pub use self::{descriptor::ProviderDescriptor, store::ProviderStore};
+use super::{
+    ctap::DeviceError,
+    transport::{
+        AbortOnDrop, AuthenticatorState, AuthenticatorStates, AuthenticatorTransport,
+        TransportDevice,
+    },
+    usb::find_selected_assertion,
+};
+
+/// Prefix of the names of errors that providers return.
+const ERROR_PREFIX: &str = "xyz.iinuwa.credentialsd.CredentialProvider1.Error.";
+
+#[zbus::proxy(
+    gen_blocking = false,
+    interface = "xyz.iinuwa.credentialsd.CredentialProvider1"
+)]
+trait CredentialProvider {
+    /// Lists the credentials for the RP, restricted to `allowed` unless it is
+    /// empty.
+    fn get_credentials(
+        &self,
+        rp_id: &str,
+        allowed: &[Vec<u8>],
+    ) -> zbus::Result<Vec<ProviderCredential>>;
+
+    fn make_credential(
+        &self,
+        request: &ProviderMakeCredentialRequest,
+    ) -> zbus::Result<ProviderMakeCredentialResponse>;
+
+    fn get_assertion(
+        &self,
+        request: &ProviderGetAssertionRequest,
+    ) -> zbus::Result<ProviderGetAssertionResponse>;
+}
+
+/// A credential that a provider holds for an RP.
+#[derive(Clone, Debug, Deserialize, Serialize, Type)]
+struct ProviderCredential {
+    id: Vec<u8>,
+    user_id: Vec<u8>,
+    user_name: String,
+    user_display_name: String,
+}
+
+#[derive(Debug, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict", rename_all = "camelCase")]
+struct ProviderMakeCredentialRequest {
+    origin: String,
+    client_data_hash: Vec<u8>,
+    rp_id: String,
+    rp_name: Option<String>,
+    user_id: Vec<u8>,
+    user_name: Option<String>,
+    user_display_name: Option<String>,
+    /// COSE algorithm identifiers, in the order the RP prefers them.
+    algorithms: Vec<i32>,
+    exclude_credentials: Vec<Vec<u8>>,
+    user_verification: String,
+}
+
+#[derive(Debug, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict", rename_all = "camelCase")]
+struct ProviderMakeCredentialResponse {
+    authenticator_data: Vec<u8>,
+}
+
+#[derive(Debug, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict", rename_all = "camelCase")]
+struct ProviderGetAssertionRequest {
+    client_data_hash: Vec<u8>,
+    rp_id: String,
+    credential_id: Vec<u8>,
+    user_verification: String,
+}
+
+#[derive(Debug, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict", rename_all = "camelCase")]
+struct ProviderGetAssertionResponse {
+    authenticator_data: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl From<&MakeCredentialRequestInternal> for ProviderMakeCredentialRequest {
+    fn from(request: &MakeCredentialRequestInternal) -> Self {
+        let algorithms = request
+            .algorithms
+            .iter()
+            .filter_map(|alg| i32::try_from(*alg).ok())
+            .collect();
+        let request = &request.ctap;
+        Self {
+            origin: request.origin.clone(),
+            client_data_hash: request.hash.clone(),
+            rp_id: request.relying_party.id.clone(),
+            rp_name: request.relying_party.name.clone(),
+            user_id: request.user.id.to_vec(),
+            user_name: request.user.name.clone(),
+            user_display_name: request.user.display_name.clone(),
+            algorithms,
+            exclude_credentials: request
+                .exclude
+                .iter()
+                .flatten()
+                .map(|descriptor| descriptor.id.to_vec())
+                .collect(),
+            user_verification: user_verification(request.user_verification).to_string(),
+        }
+    }
+}
+
+/// The installed credential providers, each offered as a device with the ID
+/// of its descriptor.
+#[derive(Debug)]
+pub(crate) struct ProviderTransport {
+    conn: Connection,
+    store: Arc<ProviderStore>,
+}
+
+impl ProviderTransport {
+    /// Providers are reached on the session bus through `conn`.
+    pub(crate) fn new(conn: Connection, store: Arc<ProviderStore>) -> Self {
+        Self { conn, store }
+    }
+}
+
+impl AuthenticatorTransport for ProviderTransport {
+    fn transport(&self) -> Transport {
+        Transport::PasskeyProvider
+    }
+
+    fn devices(&self) -> Vec<TransportDevice> {
+        self.store
+            .list()
+            .into_iter()
+            .map(|provider| TransportDevice {
+                id: provider.descriptor.id,
+                name: Some(provider.descriptor.name),
+                enabled: provider.enabled,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn start(&self, device_id: &str, request: &CredentialRequest) -> AuthenticatorStates {
+        let request = request.clone();
+        let conn = self.conn.clone();
+        let descriptor = self.store.get(device_id);
+        let (tx, mut rx) = mpsc::channel(32);
+        let task = tokio::spawn(async move {
+            match descriptor {
+                Some(descriptor) => process(&conn, &descriptor, tx, request).await,
+                // Removed or turned off since it was listed.
+                None => {
+                    _ = tx
+                        .send(AuthenticatorState::Failed(Error::AuthenticatorError))
+                        .await;
+                }
+            }
+        });
+        let task = AbortOnDrop(task.abort_handle());
+        Box::pin(stream! {
+            let _task = task;
+            while let Some(state) = rx.recv().await {
+                yield state
+            }
+        })
+    }
+
+    fn set_device_enabled(&self, device_id: &str, enabled: bool) -> Result<(), Error> {
+        self.store
+            .set_enabled(device_id, enabled)
+            .map_err(|err| Error::Internal(err.to_string()))
+    }
+}
+
+async fn process(
+    conn: &Connection,
+    descriptor: &ProviderDescriptor,
+    tx: Sender<AuthenticatorState>,
+    request: CredentialRequest,
+) {
+    let result = async {
+        tx.send(AuthenticatorState::Connected)
+            .await
+            .map_err(|_| DeviceError::Cancelled)?;
+        let provider = CredentialProviderProxy::builder(conn)
+            .destination(descriptor.bus_name.clone())
+            .and_then(|builder| builder.path(descriptor.object_path.clone()))
+            .map_err(provider_error)?
+            .build()
+            .await
+            .map_err(provider_error)?;
+        match &request {
+            CredentialRequest::CreatePublicKeyCredentialRequest(request) => {
+                make_credential(&provider, request).await.map(|response| {
+                    CredentialResponse::from_make_credential(&response, &["internal"], "platform")
+                })
+            }
+            CredentialRequest::GetPublicKeyCredentialRequest(request) => {
+                get_assertion(&provider, &tx, request)
+                    .await
+                    .map(|assertion| CredentialResponse::from_get_assertion(&assertion, "platform"))
+            }
+        }
+    }
+    .await;
+    let state = match result {
+        Ok(response) => AuthenticatorState::Completed(response),
+        Err(DeviceError::Cancelled) => {
+            tracing::debug!("Request to credential provider {} cancelled", descriptor.id);
+            return;
+        }
+        Err(err) => {
+            tracing::warn!(
+                "Failed to make/get credential with credential provider {}: {err}",
+                descriptor.id
+            );
+            AuthenticatorState::Failed(err.into())
+        }
+    };
+    _ = tx.send(state).await;
+}
+
+async fn make_credential(
+    provider: &CredentialProviderProxy<'_>,
+    request: &MakeCredentialRequestInternal,
+) -> Result<MakeCredentialResponse, DeviceError> {
+    let response = provider
+        .make_credential(&request.into())
+        .await
+        .map_err(provider_error)?;
+    let request = &request.ctap;
+    let authenticator_data = parse_authenticator_data(&response.authenticator_data)?;
+    check_authenticator_data(
+        &authenticator_data,
+        &request.relying_party.id,
+        request.user_verification,
+    )?;
+    if authenticator_data.attested_credential.is_none() {
+        return Err(DeviceError::InvalidResponse(
+            "Provider did not return the created credential".to_string(),
+        ));
+    }
+    Ok(MakeCredentialResponse {
+        format: "none".to_string(),
+        authenticator_data,
+        attestation_statement: Ctap2AttestationStatement::None(BTreeMap::new()),
+        enterprise_attestation: None,
+        large_blob_key: None,
+        unsigned_extensions_output: MakeCredentialsResponseUnsignedExtensions::default(),
+    })
+}
+
+async fn get_assertion(
+    provider: &CredentialProviderProxy<'_>,
+    tx: &Sender<AuthenticatorState>,
+    request: &GetAssertionRequest,
+) -> Result<Assertion, DeviceError> {
+    let allowed: Vec<Vec<u8>> = request
+        .allow
+        .iter()
+        .map(|descriptor| descriptor.id.to_vec())
+        .collect();
+    let mut credentials = provider
+        .get_credentials(&request.relying_party_id, &allowed)
+        .await
+        .map_err(provider_error)?;
+    // Only offer what the RP asked for, whatever the provider returned.
+    credentials.retain(|credential| allowed.is_empty() || allowed.contains(&credential.id));
+    let credential = match credentials.len() {
+        0 => return Err(DeviceError::Ctap(CtapError::NoCredentials)),
+        1 => credentials.remove(0),
+        _ => select_credential(tx, &request.relying_party_id, credentials).await?,
+    };
+
+    let response = provider
+        .get_assertion(&ProviderGetAssertionRequest {
+            client_data_hash: request.hash.clone(),
+            rp_id: request.relying_party_id.clone(),
+            credential_id: credential.id.clone(),
+            user_verification: user_verification(request.user_verification).to_string(),
+        })
+        .await
+        .map_err(provider_error)?;
+    let authenticator_data = parse_authenticator_data(&response.authenticator_data)?;
+    check_authenticator_data(
+        &authenticator_data,
+        &request.relying_party_id,
+        request.user_verification,
+    )?;
+    Ok(assertion(
+        credential,
+        authenticator_data,
+        response.signature,
+    ))
+}
+
+/// Lets the user choose which of the provider's credentials to use, in the
+/// same way as for the platform authenticator.
+async fn select_credential(
+    tx: &Sender<AuthenticatorState>,
+    rp_id: &str,
+    credentials: Vec<ProviderCredential>,
+) -> Result<ProviderCredential, DeviceError> {
+    let response = GetAssertionResponse {
+        assertions: credentials
+            .iter()
+            .map(|credential| {
+                let authenticator_data = AuthenticatorData {
+                    rp_id_hash: rp_id_hash(rp_id),
+                    flags: AuthenticatorDataFlags::empty(),
+                    signature_count: 0,
+                    attested_credential: None,
+                    extensions: None,
+                };
+                assertion(credential.clone(), authenticator_data, Vec::new())
+            })
+            .collect(),
+    };
+    let (cred_tx, mut cred_rx) = mpsc::channel(1);
+    tx.send(AuthenticatorState::SelectCredential {
+        response: response.clone(),
+        cred_tx,
+    })
+    .await
+    .map_err(|_| DeviceError::Cancelled)?;
+    let cred_id = cred_rx.recv().await.ok_or(DeviceError::Cancelled)?;
+    let selected = find_selected_assertion(&response, &cred_id)
+        .and_then(|assertion| assertion.credential_id)
+        .ok_or(DeviceError::Ctap(CtapError::NoCredentials))?;
+    credentials
+        .into_iter()
+        .find(|credential| credential.id == *selected.id)
+        .ok_or(DeviceError::Ctap(CtapError::NoCredentials))
+}
+
+fn assertion(
+    credential: ProviderCredential,
+    authenticator_data: AuthenticatorData<GetAssertionResponseExtensions>,
+    signature: Vec<u8>,
+) -> Assertion {
+    Assertion {
+        credential_id: Some(Ctap2PublicKeyCredentialDescriptor {
+            id: credential.id.into(),
+            r#type: Ctap2PublicKeyCredentialType::PublicKey,
+            transports: None,
+        }),
+        authenticator_data,
+        signature,
+        user: Some(Ctap2PublicKeyCredentialUserEntity {
+            id: credential.user_id.into(),
+            name: Some(credential.user_name),
+            display_name: Some(credential.user_display_name),
+        }),
+        credentials_count: None,
+        user_selected: None,
+        large_blob_key: None,
+        unsigned_extensions_output: None,
+        enterprise_attestation: None,
+        attestation_statement: None,
+    }
+}
+
+/// Parses authenticator data returned by a provider.
+fn parse_authenticator_data<T: DeserializeOwned>(
+    data: &[u8],
+) -> Result<AuthenticatorData<T>, DeviceError> {
+    let deserializer = serde::de::value::BytesDeserializer::<serde::de::value::Error>::new(data);
+    AuthenticatorData::deserialize(deserializer).map_err(|err| {
+        DeviceError::InvalidResponse(format!("Invalid authenticator data from provider: {err}"))
+    })
+}
+
+/// Checks that the provider answered for the right RP, and verified the user
+/// if the RP requires it.
+fn check_authenticator_data<T>(
+    authenticator_data: &AuthenticatorData<T>,
+    rp_id: &str,
+    requirement: UserVerificationRequirement,
+) -> Result<(), DeviceError> {
+    if authenticator_data.rp_id_hash != rp_id_hash(rp_id) {
+        return Err(DeviceError::InvalidResponse(format!(
+            "Provider answered for another RP than {rp_id}"
+        )));
+    }
+    if requirement.is_required()
+        && !authenticator_data
+            .flags
+            .contains(AuthenticatorDataFlags::USER_VERIFIED)
+    {
+        return Err(DeviceError::Ctap(CtapError::UnsupportedOption));
+    }
+    Ok(())
+}
+
+/// Maps errors returned by providers, see `doc/api.md`.
+fn provider_error(err: zbus::Error) -> DeviceError {
+    if let zbus::Error::MethodError(name, message, _) = &err {
+        match name.as_str().strip_prefix(ERROR_PREFIX) {
+            Some("NoCredentials") => return DeviceError::Ctap(CtapError::NoCredentials),
+            Some("CredentialExcluded") => return DeviceError::Ctap(CtapError::CredentialExcluded),
+            Some("UserVerificationFailed") => return DeviceError::Ctap(CtapError::UvBlocked),
+            Some(_) | None => {
+                return DeviceError::Transport(format!(
+                    "Credential provider failed: {}",
+                    message.as_deref().unwrap_or(name.as_str())
+                ))
+            }
+        }
+    }
+    DeviceError::Transport(format!("Failed to reach credential provider: {err}"))
+}
+
+fn user_verification(requirement: UserVerificationRequirement) -> &'static str {
+    match requirement {
+        UserVerificationRequirement::Required => "required",
+        UserVerificationRequirement::Preferred => "preferred",
+        UserVerificationRequirement::Discouraged => "discouraged",
+    }
+}
+
+fn rp_id_hash(rp_id: &str) -> [u8; 32] {
+    digest::digest(&digest::SHA256, rp_id.as_bytes())
+        .as_ref()
+        .try_into()
+        .expect("SHA-256 digests to be 32 bytes")
+}
+
+#[cfg(test)]
+mod test {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use futures_lite::StreamExt;
+    use libwebauthn::{
+        fido::{AuthenticatorData, AuthenticatorDataFlags},
+        ops::webauthn::{
+            GetAssertionRequest, GetAssertionResponseExtensions, MakeCredentialRequest,
+            UserVerificationRequirement,
+        },
+        proto::ctap2::{Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialType},
+    };
+    use ring::{
+        digest,
+        signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1},
+    };
+
+    use credentialsd_common::model::{
+        CredentialRequest, CredentialResponse, Error, MakeCredentialRequestInternal,
+    };
+
+    use super::{
+        check_authenticator_data,
+        reference::{serve_reference_provider, REFERENCE_PROVIDER_ID},
+        rp_id_hash, ProviderTransport,
+    };
+    use crate::{
+        credential_service::{
+            ctap::DeviceError,
+            transport::{AuthenticatorState, AuthenticatorTransport, TransportDevice},
+        },
+        webauthn,
+    };
+
+    fn make_credential_request(rp_id: &str, user_name: &str) -> CredentialRequest {
+        let mut request = MakeCredentialRequest::dummy();
+        request.relying_party.id = rp_id.to_string();
+        request.user.name = Some(user_name.to_string());
+        CredentialRequest::CreatePublicKeyCredentialRequest(MakeCredentialRequestInternal::new(
+            request,
+            vec![-7],
+        ))
+    }
+
+    fn get_assertion_request(rp_id: &str) -> GetAssertionRequest {
+        GetAssertionRequest {
+            relying_party_id: rp_id.to_string(),
+            hash: vec![1; 32],
+            allow: Vec::new(),
+            extensions: None,
+            user_verification: UserVerificationRequirement::Preferred,
+            timeout: std::time::Duration::from_secs(30),
+        }
+    }
+
+    /// Runs a request with the provider, choosing the credential of
+    /// `user_name` if the user is asked to. Returns the names offered to the
+    /// user and the result.
+    async fn run(
+        transport: &ProviderTransport,
+        request: CredentialRequest,
+        user_name: &str,
+    ) -> (Vec<String>, Result<CredentialResponse, Error>) {
+        let mut states = transport.start(REFERENCE_PROVIDER_ID, &request);
+        let mut offered = Vec::new();
+        while let Some(state) = states.next().await {
+            match state {
+                AuthenticatorState::Connected => {}
+                AuthenticatorState::SelectCredential { response, cred_tx } => {
+                    let mut chosen = None;
+                    for assertion in response.assertions {
+                        let user = assertion.user.unwrap().name.unwrap();
+                        if user == user_name {
+                            let id = assertion.credential_id.unwrap().id;
+                            chosen =
+                                Some(URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, &id)));
+                        }
+                        offered.push(user);
+                    }
+                    cred_tx.send(chosen.unwrap()).await.unwrap();
+                }
+                AuthenticatorState::Completed(response) => return (offered, Ok(response)),
+                AuthenticatorState::Failed(err) => return (offered, Err(err)),
+                state => panic!("Unexpected state: {state:?}"),
+            }
+        }
+        panic!("Provider stopped without completing");
+    }
+
+    #[tokio::test]
+    async fn test_providers_turned_off_are_not_started() {
+        let (transport, _served) = serve_reference_provider().await;
+        assert_eq!(
+            vec![TransportDevice {
+                id: REFERENCE_PROVIDER_ID.to_string(),
+                name: Some("Reference Provider".to_string()),
+                ..Default::default()
+            }],
+            transport.devices()
+        );
+        transport
+            .set_device_enabled(REFERENCE_PROVIDER_ID, false)
+            .unwrap();
+        assert!(!transport.devices()[0].enabled);
+        let (_, response) = run(
+            &transport,
+            make_credential_request("example.org", "alice"),
+            "",
+        )
+        .await;
+        assert!(matches!(response, Err(Error::AuthenticatorError)));
+    }
+
+    #[tokio::test]
+    async fn test_credentials_created_by_provider_sign_assertions() {
+        let (transport, _served) = serve_reference_provider().await;
+        let (_, response) = run(
+            &transport,
+            make_credential_request("example.org", "alice"),
+            "",
+        )
+        .await;
+        let CredentialResponse::CreatePublicKeyCredentialResponse(created) = response.unwrap()
+        else {
+            panic!("Expected a created credential");
+        };
+        assert_eq!("none", created.ctap.format);
+        let attested = created.ctap.authenticator_data.attested_credential.unwrap();
+        let cosey::PublicKey::P256Key(public_key) = &attested.credential_public_key else {
+            panic!("Expected an ES256 key");
+        };
+
+        let request = get_assertion_request("example.org");
+        let (offered, response) = run(
+            &transport,
+            CredentialRequest::GetPublicKeyCredentialRequest(request.clone()),
+            "alice",
+        )
+        .await;
+        // A single credential is used without asking.
+        assert!(offered.is_empty());
+        let CredentialResponse::GetPublicKeyCredentialResponse(asserted) = response.unwrap() else {
+            panic!("Expected an assertion");
+        };
+        let assertion = asserted.ctap;
+        assert_eq!(
+            attested.credential_id,
+            assertion.credential_id.unwrap().id.into_vec()
+        );
+        assert_eq!(1, assertion.authenticator_data.signature_count);
+        assert!(assertion
+            .authenticator_data
+            .flags
+            .contains(AuthenticatorDataFlags::USER_VERIFIED));
+        let mut signed_data =
+            webauthn::encode_authenticator_data(&assertion.authenticator_data).unwrap();
+        signed_data.extend(&request.hash);
+        let mut point = vec![0x04];
+        point.extend_from_slice(&public_key.x);
+        point.extend_from_slice(&public_key.y);
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, point)
+            .verify(&signed_data, &assertion.signature)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_provider_credentials_are_chosen_in_the_ui() {
+        let (transport, served) = serve_reference_provider().await;
+        for (rp_id, user_name) in [
+            ("example.org", "alice"),
+            ("example.org", "bob"),
+            ("example.com", "carol"),
+        ] {
+            let (_, response) =
+                run(&transport, make_credential_request(rp_id, user_name), "").await;
+            response.unwrap();
+        }
+        let request =
+            CredentialRequest::GetPublicKeyCredentialRequest(get_assertion_request("example.org"));
+        let (offered, response) = run(&transport, request, "bob").await;
+        assert_eq!(vec!["alice", "bob"], offered);
+        let CredentialResponse::GetPublicKeyCredentialResponse(asserted) = response.unwrap() else {
+            panic!("Expected an assertion");
+        };
+        assert_eq!(
+            Some("bob"),
+            asserted.ctap.user.as_ref().unwrap().name.as_deref()
+        );
+        let signed: Vec<_> = served
+            .provider
+            .credentials
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|stored| stored.sign_count > 0)
+            .map(|stored| stored.credential.user_name.clone())
+            .collect();
+        assert_eq!(vec!["bob"], signed);
+    }
+
+    #[tokio::test]
+    async fn test_provider_errors_fail_the_request() {
+        let (transport, _served) = serve_reference_provider().await;
+        let (_, response) = run(
+            &transport,
+            make_credential_request("example.org", "alice"),
+            "",
+        )
+        .await;
+        let CredentialResponse::CreatePublicKeyCredentialResponse(created) = response.unwrap()
+        else {
+            panic!("Expected a created credential");
+        };
+        let credential_id = created
+            .ctap
+            .authenticator_data
+            .attested_credential
+            .unwrap()
+            .credential_id;
+
+        let mut request = make_credential_request("example.org", "alice");
+        let CredentialRequest::CreatePublicKeyCredentialRequest(ref mut create) = request else {
+            unreachable!();
+        };
+        create.ctap.exclude = Some(vec![Ctap2PublicKeyCredentialDescriptor {
+            id: credential_id.clone().into(),
+            r#type: Ctap2PublicKeyCredentialType::PublicKey,
+            transports: None,
+        }]);
+        let (_, response) = run(&transport, request, "").await;
+        assert!(matches!(response, Err(Error::CredentialExcluded)));
+
+        // Credentials that the RP did not allow are not offered.
+        let mut request = get_assertion_request("example.org");
+        request.allow = vec![Ctap2PublicKeyCredentialDescriptor {
+            id: vec![1; 16].into(),
+            r#type: Ctap2PublicKeyCredentialType::PublicKey,
+            transports: None,
+        }];
+        let request = CredentialRequest::GetPublicKeyCredentialRequest(request);
+        let (_, response) = run(&transport, request, "").await;
+        assert!(matches!(response, Err(Error::NoCredentials)));
+    }
+
+    #[test]
+    fn test_authenticator_data_is_checked() {
+        let authenticator_data: AuthenticatorData<GetAssertionResponseExtensions> =
+            AuthenticatorData {
+                rp_id_hash: rp_id_hash("example.org"),
+                flags: AuthenticatorDataFlags::USER_PRESENT,
+                signature_count: 0,
+                attested_credential: None,
+                extensions: None,
+            };
+        assert!(check_authenticator_data(
+            &authenticator_data,
+            "example.org",
+            UserVerificationRequirement::Preferred
+        )
+        .is_ok());
+        assert!(matches!(
+            check_authenticator_data(
+                &authenticator_data,
+                "evil.example",
+                UserVerificationRequirement::Preferred
+            ),
+            Err(DeviceError::InvalidResponse(_))
+        ));
+        assert!(matches!(
+            check_authenticator_data(
+                &authenticator_data,
+                "example.org",
+                UserVerificationRequirement::Required
+            ),
+            Err(DeviceError::Ctap(_))
+        ));
+    }
+}
@@ -0,0 +1,201 @@
+//! A reference credential provider, which keeps ES256 passkeys in memory.
+//!
+//! It shows what providers implement, and is served over a peer-to-peer
+//! connection in tests, where the bus name in the descriptor is not used.
+
+use std::sync::{Arc, Mutex};
+
+use libwebauthn::{
+    fido::{AttestedCredentialData, AuthenticatorData, AuthenticatorDataFlags},
+    ops::webauthn::GetAssertionResponseExtensions,
+};
+use ring::{
+    rand::{SecureRandom, SystemRandom},
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+};
+use zbus::{connection::Builder, interface, Connection, Guid};
+
+use super::{
+    rp_id_hash, store::ProviderStore, ProviderCredential, ProviderGetAssertionRequest,
+    ProviderGetAssertionResponse, ProviderMakeCredentialRequest, ProviderMakeCredentialResponse,
+    ProviderTransport,
+};
+use crate::{
+    credential_service::{encrypted_file::test::TempDir, provider::descriptor::test::install},
+    webauthn,
+};
+
+const OBJECT_PATH: &str = "/xyz/iinuwa/credentialsd/ReferenceProvider";
+pub(super) const REFERENCE_PROVIDER_ID: &str = "xyz.iinuwa.credentialsd.ReferenceProvider";
+const ES256: i32 = -7;
+
+#[derive(Debug, zbus::DBusError)]
+#[zbus(prefix = "xyz.iinuwa.credentialsd.CredentialProvider1.Error")]
+pub(super) enum ProviderError {
+    #[zbus(error)]
+    ZBus(zbus::Error),
+    NoCredentials(String),
+    CredentialExcluded(String),
+    NotSupported(String),
+}
+
+pub(super) struct StoredCredential {
+    pub(super) credential: ProviderCredential,
+    rp_id: String,
+    private_key: Vec<u8>,
+    pub(super) sign_count: u32,
+}
+
+#[derive(Clone, Default)]
+pub(super) struct ReferenceProvider {
+    pub(super) credentials: Arc<Mutex<Vec<StoredCredential>>>,
+}
+
+#[interface(name = "xyz.iinuwa.credentialsd.CredentialProvider1")]
+impl ReferenceProvider {
+    fn get_credentials(&self, rp_id: &str, allowed: Vec<Vec<u8>>) -> Vec<ProviderCredential> {
+        self.credentials
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|stored| stored.rp_id == rp_id)
+            .filter(|stored| allowed.is_empty() || allowed.contains(&stored.credential.id))
+            .map(|stored| stored.credential.clone())
+            .collect()
+    }
+
+    fn make_credential(
+        &self,
+        request: ProviderMakeCredentialRequest,
+    ) -> Result<ProviderMakeCredentialResponse, ProviderError> {
+        let mut credentials = self.credentials.lock().unwrap();
+        if credentials.iter().any(|stored| {
+            stored.rp_id == request.rp_id
+                && request.exclude_credentials.contains(&stored.credential.id)
+        }) {
+            return Err(ProviderError::CredentialExcluded(
+                "Already registered".to_string(),
+            ));
+        }
+        if !request.algorithms.contains(&ES256) {
+            return Err(ProviderError::NotSupported(
+                "Only ES256 is supported".to_string(),
+            ));
+        }
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+                .unwrap();
+        let point = key_pair.public_key().as_ref();
+        let public_key = cosey::P256PublicKey {
+            x: cosey::Bytes::from_slice(&point[1..33]).unwrap(),
+            y: cosey::Bytes::from_slice(&point[33..65]).unwrap(),
+        };
+        let mut id = vec![0; 16];
+        rng.fill(&mut id).unwrap();
+
+        let authenticator_data: AuthenticatorData<GetAssertionResponseExtensions> =
+            AuthenticatorData {
+                rp_id_hash: rp_id_hash(&request.rp_id),
+                flags: AuthenticatorDataFlags::USER_PRESENT
+                    | AuthenticatorDataFlags::USER_VERIFIED
+                    | AuthenticatorDataFlags::ATTESTED_CREDENTIALS,
+                signature_count: 0,
+                attested_credential: Some(AttestedCredentialData {
+                    aaguid: [0; 16],
+                    credential_id: id.clone(),
+                    credential_public_key: public_key.into(),
+                }),
+                extensions: None,
+            };
+        credentials.push(StoredCredential {
+            credential: ProviderCredential {
+                id,
+                user_id: request.user_id,
+                user_name: request.user_name.unwrap_or_default(),
+                user_display_name: request.user_display_name.unwrap_or_default(),
+            },
+            rp_id: request.rp_id,
+            private_key: pkcs8.as_ref().to_vec(),
+            sign_count: 0,
+        });
+        Ok(ProviderMakeCredentialResponse {
+            authenticator_data: webauthn::encode_authenticator_data(&authenticator_data).unwrap(),
+        })
+    }
+
+    fn get_assertion(
+        &self,
+        request: ProviderGetAssertionRequest,
+    ) -> Result<ProviderGetAssertionResponse, ProviderError> {
+        let mut credentials = self.credentials.lock().unwrap();
+        let stored = credentials
+            .iter_mut()
+            .find(|stored| {
+                stored.rp_id == request.rp_id && stored.credential.id == request.credential_id
+            })
+            .ok_or_else(|| ProviderError::NoCredentials("Unknown credential".to_string()))?;
+        stored.sign_count += 1;
+        let authenticator_data: AuthenticatorData<GetAssertionResponseExtensions> =
+            AuthenticatorData {
+                rp_id_hash: rp_id_hash(&request.rp_id),
+                flags: AuthenticatorDataFlags::USER_PRESENT | AuthenticatorDataFlags::USER_VERIFIED,
+                signature_count: stored.sign_count,
+                attested_credential: None,
+                extensions: None,
+            };
+        let authenticator_data = webauthn::encode_authenticator_data(&authenticator_data).unwrap();
+        let mut signed_data = authenticator_data.clone();
+        signed_data.extend(&request.client_data_hash);
+        let rng = SystemRandom::new();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &stored.private_key, &rng)
+                .unwrap();
+        let signature = key_pair.sign(&rng, &signed_data).unwrap();
+        Ok(ProviderGetAssertionResponse {
+            authenticator_data,
+            signature: signature.as_ref().to_vec(),
+        })
+    }
+}
+
+/// The reference provider, served for as long as this lives.
+pub(super) struct ServedProvider {
+    pub(super) provider: ReferenceProvider,
+    _conn: Connection,
+    /// Where the descriptor is installed, and the provider turned off.
+    _dirs: [TempDir; 2],
+}
+
+/// Serves the reference provider, returning a transport for it.
+pub(super) async fn serve_reference_provider() -> (ProviderTransport, ServedProvider) {
+    let provider = ReferenceProvider::default();
+    let (server, client) = tokio::net::UnixStream::pair().unwrap();
+    let server = Builder::unix_stream(server)
+        .server(Guid::generate())
+        .unwrap()
+        .p2p()
+        .serve_at(OBJECT_PATH, provider.clone())
+        .unwrap();
+    let (server, client) =
+        futures_lite::future::zip(server.build(), Builder::unix_stream(client).p2p().build()).await;
+    let providers = TempDir::new();
+    let data = TempDir::new();
+    install(
+        &providers.0,
+        REFERENCE_PROVIDER_ID,
+        "Reference Provider",
+        REFERENCE_PROVIDER_ID,
+    );
+    let store = ProviderStore::open_at(vec![providers.0.clone()], data.0.clone());
+    let served = ServedProvider {
+        provider,
+        _conn: server.unwrap(),
+        _dirs: [providers, data],
+    };
+    (
+        ProviderTransport::new(client.unwrap(), Arc::new(store)),
+        served,
+    )
+}
@@ -0,0 +1,156 @@
+//! The credential providers installed for the user, and which of them the
+//! user turned off.
+//!
+//! Providers are enabled once they are installed, like GNOME Shell search
+//! providers. The IDs of those turned off are kept in the user's data
+//! directory, so that they stay off when the provider is updated.
+
+use std::{
+    collections::BTreeSet,
+    fs::{self, DirBuilder},
+    io,
+    os::unix::fs::DirBuilderExt,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use super::descriptor::{self, ProviderDescriptor};
+use crate::credential_service::encrypted_file;
+
+const DISABLED_FILE: &str = "disabled-providers";
+
+/// An installed credential provider.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Provider {
+    pub descriptor: ProviderDescriptor,
+    pub enabled: bool,
+}
+
+#[derive(Debug)]
+pub struct ProviderStore {
+    /// Where descriptors are searched. They are read again every time, as
+    /// providers can be installed and removed while the daemon runs.
+    search_dirs: Vec<PathBuf>,
+    data_dir: PathBuf,
+    disabled: Mutex<BTreeSet<String>>,
+}
+
+impl ProviderStore {
+    /// Opens the store in `$XDG_DATA_HOME/credentialsd`.
+    pub fn open() -> io::Result<Self> {
+        Ok(Self::open_at(
+            descriptor::search_dirs(),
+            encrypted_file::data_dir()?,
+        ))
+    }
+
+    pub(super) fn open_at(search_dirs: Vec<PathBuf>, data_dir: PathBuf) -> Self {
+        let disabled = match fs::read(data_dir.join(DISABLED_FILE)) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+                tracing::warn!("Failed to read disabled credential providers: {err}");
+                BTreeSet::new()
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(err) => {
+                tracing::warn!("Failed to read disabled credential providers: {err}");
+                BTreeSet::new()
+            }
+        };
+        Self {
+            search_dirs,
+            data_dir,
+            disabled: Mutex::new(disabled),
+        }
+    }
+
+    /// Lists the installed providers, sorted by name.
+    pub fn list(&self) -> Vec<Provider> {
+        let disabled = self.disabled.lock().unwrap();
+        descriptor::discover(&self.search_dirs)
+            .into_iter()
+            .map(|descriptor| Provider {
+                enabled: !disabled.contains(&descriptor.id),
+                descriptor,
+            })
+            .collect()
+    }
+
+    /// Finds an enabled provider.
+    pub fn get(&self, id: &str) -> Option<ProviderDescriptor> {
+        self.list()
+            .into_iter()
+            .find(|provider| provider.enabled && provider.descriptor.id == id)
+            .map(|provider| provider.descriptor)
+    }
+
+    /// Turns a provider on or off. Only installed providers can be turned
+    /// off, but any provider can be turned back on, e.g. after it was
+    /// removed.
+    pub fn set_enabled(&self, id: &str, enabled: bool) -> io::Result<()> {
+        if !enabled && !self.list().iter().any(|p| p.descriptor.id == id) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Unknown credential provider: {id}"),
+            ));
+        }
+        let mut disabled = self.disabled.lock().unwrap();
+        let changed = if enabled {
+            disabled.remove(id)
+        } else {
+            disabled.insert(id.to_string())
+        };
+        if !changed {
+            return Ok(());
+        }
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.data_dir)?;
+        fs::write(
+            self.data_dir.join(DISABLED_FILE),
+            serde_json::to_vec(&*disabled)?,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ProviderStore;
+    use crate::credential_service::{
+        encrypted_file::test::TempDir, provider::descriptor::test::install,
+    };
+
+    #[test]
+    fn test_disabled_providers_are_remembered() {
+        let providers = TempDir::new();
+        let data = TempDir::new();
+        install(
+            &providers.0,
+            "org.example.Vault",
+            "Vault",
+            "org.example.Vault",
+        );
+        install(&providers.0, "org.example.Keys", "Keys", "org.example.Keys");
+        let store = ProviderStore::open_at(vec![providers.0.clone()], data.0.clone());
+        assert!(store.list().iter().all(|provider| provider.enabled));
+        store.set_enabled("org.example.Vault", false).unwrap();
+        assert!(store.get("org.example.Vault").is_none());
+        assert!(store.set_enabled("org.example.Unknown", false).is_err());
+
+        let store = ProviderStore::open_at(vec![providers.0.clone()], data.0.clone());
+        let enabled: Vec<_> = store
+            .list()
+            .into_iter()
+            .map(|provider| (provider.descriptor.id, provider.enabled))
+            .collect();
+        assert_eq!(
+            vec![
+                ("org.example.Keys".to_string(), true),
+                ("org.example.Vault".to_string(), false)
+            ],
+            enabled
+        );
+        store.set_enabled("org.example.Vault", true).unwrap();
+        assert_eq!("Vault", store.get("org.example.Vault").unwrap().name);
+    }
+}
@@ -0,0 +1,453 @@
+//! Transports that the credential service can reach authenticators over, and
+//! the registry of transports enabled for this session.
+
+use std::{fmt::Debug, pin::Pin, sync::Arc};
+
+use futures_lite::Stream;
+use libwebauthn::ops::webauthn::GetAssertionResponse;
+use tokio::{sync::mpsc, task::AbortHandle};
+
+use credentialsd_common::model::{
+    CredentialRequest, CredentialResponse, Device, Error, FingerprintSampleStatus,
+    SecurityKeyChange, Transport,
+};
+
+use super::{
+    management::{ManagementRequest, ManagementResponse},
+    usb::list_credentials,
+};
+
+/// A way of reaching authenticators, e.g. USB or a hybrid QR code.
+///
+/// Transports are registered with a [`TransportRegistry`] at startup, and
+/// offer the UI one or more devices to choose from.
+pub(crate) trait AuthenticatorTransport: Debug + Send + Sync {
+    /// The kind of transport that this is shown to the user as.
+    fn transport(&self) -> Transport;
+
+    /// Lists the devices offered over this transport, including those that
+    /// the user turned off.
+    ///
+    /// By default, the transport is offered as a single device, which
+    /// reaches whichever authenticator is found first.
+    fn devices(&self) -> Vec<TransportDevice> {
+        vec![TransportDevice::default()]
+    }
+
+    /// Whether a device can be started: by default, if it is listed and
+    /// turned on.
+    fn is_available(&self, device_id: &str) -> bool {
+        self.devices()
+            .iter()
+            .any(|device| device.id == device_id && device.enabled)
+    }
+
+    /// Starts looking for the authenticator of one of the devices to fulfil
+    /// the request. The stream ends after the device reaches a terminal
+    /// state, and dropping it cancels the transport.
+    fn start(&self, device_id: &str, request: &CredentialRequest) -> AuthenticatorStates;
+
+    /// Whether the devices of this transport can handle the management
+    /// request.
+    fn can_manage(&self, _request: &ManagementRequest) -> bool {
+        false
+    }
+
+    /// Starts looking for the authenticator of one of the devices to handle
+    /// the management request, like [`Self::start`] does for credential
+    /// requests. The stream ends with [`AuthenticatorState::Managed`] once the
+    /// request is handled.
+    fn manage(
+        &self,
+        _device_id: &str,
+        _request: &ManagementRequest,
+    ) -> Result<AuthenticatorStates, Error> {
+        Err(Error::Internal(format!(
+            "{} devices cannot be managed",
+            self.transport().as_str()
+        )))
+    }
+
+    /// Sets the name shown for a device.
+    fn rename_device(&self, _device_id: &str, _name: &str) -> Result<(), Error> {
+        Err(Error::Internal(format!(
+            "{} devices cannot be renamed",
+            self.transport().as_str()
+        )))
+    }
+
+    /// Removes a device, so that it is no longer listed.
+    fn forget_device(&self, _device_id: &str) -> Result<(), Error> {
+        Err(Error::Internal(format!(
+            "{} devices cannot be forgotten",
+            self.transport().as_str()
+        )))
+    }
+
+    /// Turns a device on or off. Devices that are off are not offered.
+    fn set_device_enabled(&self, _device_id: &str, _enabled: bool) -> Result<(), Error> {
+        Err(Error::Internal(format!(
+            "{} devices cannot be turned off",
+            self.transport().as_str()
+        )))
+    }
+}
+
+/// A device offered over a transport.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TransportDevice {
+    /// ID of the device within its transport.
+    pub id: String,
+    /// Name to show to the user, if the device has one.
+    pub name: Option<String>,
+    /// HID manufacturer name of a security key.
+    pub manufacturer: Option<String>,
+    /// HID product name of a security key.
+    pub product: Option<String>,
+    pub enabled: bool,
+}
+
+impl Default for TransportDevice {
+    /// The single device of transports that reach whichever authenticator is
+    /// found first.
+    fn default() -> Self {
+        Self {
+            id: String::new(),
+            name: None,
+            manufacturer: None,
+            product: None,
+            enabled: true,
+        }
+    }
+}
+
+pub(crate) type AuthenticatorStates =
+    Pin<Box<dyn Stream<Item = AuthenticatorState> + Send + 'static>>;
+
+/// States that devices of all transports go through, each using the ones that
+/// apply to it, with the channels that the user answers them through.
+#[derive(Clone, Debug)]
+pub enum AuthenticatorState {
+    /// Not looking for an authenticator.
+    Idle,
+
+    /// Awaiting an authenticator, e.g. a USB device to be plugged in or a
+    /// card to be tapped on a reader.
+    Waiting,
+
+    /// When we encounter multiple devices, we let all of them blink and
+    /// continue with the one that was tapped.
+    SelectingDevice,
+
+    /// Authenticator found, running the ceremony.
+    Connected,
+
+    /// The device needs the PIN to be entered.
+    NeedsPin {
+        attempts_left: Option<u32>,
+        pin_tx: mpsc::Sender<String>,
+    },
+
+    /// The device needs on-device user verification.
+    NeedsUserVerification { attempts_left: Option<u32> },
+
+    /// The device needs evidence of user presence (e.g. touch) to release the credential.
+    NeedsUserPresence,
+
+    /// Multiple credentials have been found and the user has to select which to use
+    SelectCredential {
+        response: GetAssertionResponse,
+        cred_tx: mpsc::Sender<String>,
+    },
+
+    /// Received credential
+    Completed(CredentialResponse),
+
+    /// A management request of a security key completed.
+    Managed(Box<ManagementResponse>),
+
+    /// There was an error while interacting with the authenticator.
+    Failed(Error),
+
+    /// The device was unplugged before the ceremony completed.
+    Disconnected,
+
+    /// The platform authenticator waits for the user's fingerprint. If set,
+    /// `password_tx` takes the user's login password instead.
+    NeedsFingerprint {
+        attempts_left: Option<u32>,
+        password_tx: Option<mpsc::Sender<String>>,
+    },
+
+    /// The platform authenticator needs the user's login password.
+    NeedsPassword {
+        attempts_left: Option<u32>,
+        password_tx: mpsc::Sender<String>,
+    },
+
+    /// The user has to choose a new PIN for the security key.
+    NeedsNewPin {
+        min_length: u32,
+        must_change: bool,
+        pin_tx: mpsc::Sender<String>,
+    },
+
+    /// The security key waits for a sample of a fingerprint to enroll.
+    NeedsFingerprintSample {
+        remaining_samples: Option<u32>,
+        last_sample: Option<FingerprintSampleStatus>,
+    },
+
+    /// The user has to confirm a change to the security key.
+    NeedsConfirmation {
+        change: SecurityKeyChange,
+        confirm_tx: mpsc::Sender<()>,
+    },
+
+    /// The user has to unplug the security key and plug it back in.
+    NeedsReplug,
+
+    /// The user has to scan this hybrid QR code with their phone.
+    NeedsQrCodeScan(String),
+
+    /// The phone was found, and a tunnel to it is being established.
+    Connecting,
+}
+
+impl From<&AuthenticatorState> for credentialsd_common::model::AuthenticatorState {
+    fn from(value: &AuthenticatorState) -> Self {
+        match value {
+            AuthenticatorState::Idle => Self::Idle,
+            AuthenticatorState::Waiting => Self::Waiting,
+            AuthenticatorState::SelectingDevice => Self::SelectingDevice,
+            AuthenticatorState::Connected => Self::Connected,
+            AuthenticatorState::NeedsPin { attempts_left, .. } => Self::NeedsPin {
+                attempts_left: *attempts_left,
+            },
+            AuthenticatorState::NeedsUserVerification { attempts_left } => {
+                Self::NeedsUserVerification {
+                    attempts_left: *attempts_left,
+                }
+            }
+            AuthenticatorState::NeedsUserPresence => Self::NeedsUserPresence,
+            AuthenticatorState::SelectCredential { response, .. } => Self::SelectCredential {
+                creds: list_credentials(response),
+            },
+            AuthenticatorState::Completed(_) | AuthenticatorState::Managed(_) => Self::Completed,
+            AuthenticatorState::Failed(err) => Self::Failed(err.to_owned()),
+            AuthenticatorState::Disconnected => Self::Disconnected,
+            AuthenticatorState::NeedsFingerprint {
+                attempts_left,
+                password_tx,
+            } => Self::NeedsFingerprint {
+                attempts_left: *attempts_left,
+                password_fallback: password_tx.is_some(),
+            },
+            AuthenticatorState::NeedsPassword { attempts_left, .. } => Self::NeedsPassword {
+                attempts_left: *attempts_left,
+            },
+            AuthenticatorState::NeedsNewPin {
+                min_length,
+                must_change,
+                ..
+            } => Self::NeedsNewPin {
+                min_length: *min_length,
+                must_change: *must_change,
+            },
+            AuthenticatorState::NeedsFingerprintSample {
+                remaining_samples,
+                last_sample,
+            } => Self::NeedsFingerprintSample {
+                remaining_samples: *remaining_samples,
+                last_sample: *last_sample,
+            },
+            AuthenticatorState::NeedsConfirmation { change, .. } => {
+                Self::NeedsConfirmation(change.clone())
+            }
+            AuthenticatorState::NeedsReplug => Self::NeedsReplug,
+            AuthenticatorState::NeedsQrCodeScan(qr_code) => Self::NeedsQrCodeScan(qr_code.clone()),
+            AuthenticatorState::Connecting => Self::Connecting,
+        }
+    }
+}
+
+/// Aborts a task when dropped.
+///
+/// Transports move this into their event stream, so that the work they spawned
+/// is cancelled when the user selects another device or the request ends.
+#[derive(Debug)]
+pub(crate) struct AbortOnDrop(pub AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// The transports enabled for this session.
+///
+/// Devices are identified by the position of their transport, followed by
+/// their ID within the transport, if it has several, e.g. `2-` and the ID of
+/// a linked phone.
+#[derive(Debug, Default)]
+pub struct TransportRegistry {
+    transports: Vec<Arc<dyn AuthenticatorTransport>>,
+}
+
+impl TransportRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a transport. Its devices are listed after those of the
+    /// transports registered before it. They are listed from the transport
+    /// every time, as e.g. phones can be linked and credential providers
+    /// installed while the daemon runs.
+    pub(crate) fn register(&mut self, transport: impl AuthenticatorTransport + 'static) {
+        self.transports.push(Arc::new(transport));
+    }
+
+    /// Lists the devices that are turned on, for the UI to select from.
+    pub fn devices(&self) -> Vec<Device> {
+        self.list(|_| true)
+            .filter(|(_, enabled)| *enabled)
+            .map(|(device, _)| device)
+            .collect()
+    }
+
+    /// Lists the devices that are turned on and can handle the management
+    /// request.
+    pub(crate) fn management_devices(&self, request: &ManagementRequest) -> Vec<Device> {
+        self.list(|transport| transport.can_manage(request))
+            .filter(|(_, enabled)| *enabled)
+            .map(|(device, _)| device)
+            .collect()
+    }
+
+    /// Lists the devices of a kind of transport, including those turned off,
+    /// along with whether they are turned on.
+    pub(crate) fn transport_devices(&self, transport: Transport) -> Vec<(Device, bool)> {
+        self.list(|candidate| candidate.transport() == transport)
+            .collect()
+    }
+
+    /// Finds the transport of a device that can be started, along with the
+    /// ID of the device within the transport.
+    pub(crate) fn get(&self, device_id: &str) -> Option<(Arc<dyn AuthenticatorTransport>, String)> {
+        let (transport, id) = self.resolve(device_id)?;
+        transport
+            .is_available(id)
+            .then(|| (transport.clone(), id.to_string()))
+    }
+
+    /// Sets the name shown for a device.
+    pub(crate) fn rename_device(&self, device_id: &str, name: &str) -> Result<(), Error> {
+        let (transport, id) = self.resolve_known(device_id)?;
+        transport.rename_device(id, name)
+    }
+
+    /// Removes a device, so that it is no longer listed.
+    pub(crate) fn forget_device(&self, device_id: &str) -> Result<(), Error> {
+        let (transport, id) = self.resolve_known(device_id)?;
+        transport.forget_device(id)
+    }
+
+    /// Turns a device on or off.
+    pub(crate) fn set_device_enabled(&self, device_id: &str, enabled: bool) -> Result<(), Error> {
+        let (transport, id) = self.resolve_known(device_id)?;
+        transport.set_device_enabled(id, enabled)
+    }
+
+    fn list<'a>(
+        &'a self,
+        filter: impl Fn(&dyn AuthenticatorTransport) -> bool + 'a,
+    ) -> impl Iterator<Item = (Device, bool)> + 'a {
+        self.transports
+            .iter()
+            .enumerate()
+            .filter(move |(_, transport)| filter(transport.as_ref()))
+            .flat_map(|(index, transport)| {
+                transport.devices().into_iter().map(move |device| {
+                    let enabled = device.enabled;
+                    let id = if device.id.is_empty() {
+                        index.to_string()
+                    } else {
+                        format!("{index}-{}", device.id)
+                    };
+                    let device = Device {
+                        id,
+                        transport: transport.transport(),
+                        name: device.name,
+                        manufacturer: device.manufacturer,
+                        product: device.product,
+                    };
+                    (device, enabled)
+                })
+            })
+    }
+
+    fn resolve<'a>(
+        &self,
+        device_id: &'a str,
+    ) -> Option<(&Arc<dyn AuthenticatorTransport>, &'a str)> {
+        let (index, id) = device_id.split_once('-').unwrap_or((device_id, ""));
+        let transport = self.transports.get(index.parse::<usize>().ok()?)?;
+        Some((transport, id))
+    }
+
+    fn resolve_known<'a>(
+        &self,
+        device_id: &'a str,
+    ) -> Result<(&Arc<dyn AuthenticatorTransport>, &'a str), Error> {
+        self.resolve(device_id)
+            .ok_or_else(|| Error::Internal(format!("Unknown device: {device_id}")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use credentialsd_common::model::Transport;
+
+    use super::TransportRegistry;
+    use crate::credential_service::{
+        hybrid::test::DummyHybridHandler, management::ManagementRequest, nfc::NfcHandler,
+        usb::InProcessUsbHandler,
+    };
+
+    #[test]
+    fn test_registered_transports_are_listed_in_order() {
+        let mut registry = TransportRegistry::new();
+        registry.register(InProcessUsbHandler {});
+        registry.register(DummyHybridHandler::default());
+        let devices = registry.devices();
+        let transports: Vec<_> = devices.iter().map(|device| &device.transport).collect();
+        assert_eq!(vec![&Transport::Usb, &Transport::HybridQr], transports);
+        assert_eq!("1", devices[1].id);
+        let (transport, id) = registry.get(&devices[1].id).unwrap();
+        assert_eq!(Transport::HybridQr, transport.transport());
+        assert_eq!("", id);
+        assert!(registry.get("1-unknown").is_none());
+        assert!(registry.get("unknown").is_none());
+        assert!(registry.rename_device(&devices[0].id, "Key").is_err());
+    }
+
+    #[test]
+    fn test_management_devices_are_listed_by_request() {
+        let mut registry = TransportRegistry::new();
+        registry.register(InProcessUsbHandler {});
+        registry.register(DummyHybridHandler::default());
+        registry.register(NfcHandler::default());
+        let transports = |request| {
+            registry
+                .management_devices(&request)
+                .into_iter()
+                .map(|device| device.transport)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![Transport::Usb, Transport::Nfc],
+            transports(ManagementRequest::GetInfo)
+        );
+        assert_eq!(vec![Transport::Usb], transports(ManagementRequest::SetPin));
+    }
+}
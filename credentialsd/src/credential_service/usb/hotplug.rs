@@ -0,0 +1,277 @@
+//! Watches for hidraw devices being added and removed, so that USB
+//! authenticators are only enumerated when something changed.
+//!
+//! Events are read from the netlink group that udev broadcasts to once it has
+//! processed a device, so the device node has its final permissions by then.
+//! Only privileged processes can send to netlink groups.
+
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use tokio::io::unix::AsyncFd;
+
+/// Netlink multicast group of the events sent by udev, as opposed to the raw
+/// kernel events in group 1.
+const UDEV_MONITOR_GROUP: u32 = 2;
+
+/// Messages from udev start with this prefix, followed by a header.
+const LIBUDEV_PREFIX: &[u8] = b"libudev\0";
+const LIBUDEV_MAGIC: u32 = 0xfeedcafe;
+
+/// Large enough for the properties of any hidraw device.
+const MAX_MESSAGE_LEN: usize = 8192;
+
+#[derive(Debug, PartialEq)]
+enum HotplugEvent {
+    /// A hidraw device was added. Contains its name, e.g. `hidraw3`.
+    Added(String),
+    /// A hidraw device was removed. Contains its name.
+    Removed(String),
+}
+
+#[derive(Debug)]
+pub(super) struct HotplugMonitor {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl HotplugMonitor {
+    /// Subscribes to the events sent by udev.
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: socket() has no memory safety requirements.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd is a newly created socket that nothing else owns.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: sockaddr_nl is plain data, for which all zeroes is valid.
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = UDEV_MONITOR_GROUP;
+        // SAFETY: addr is a valid sockaddr_nl, and its size is passed along.
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Self::from_fd(fd)
+    }
+
+    /// Reads events from a non-blocking datagram socket.
+    fn from_fd(fd: OwnedFd) -> io::Result<Self> {
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+
+    /// Waits for a hidraw device to be added.
+    pub async fn added(&mut self) -> io::Result<()> {
+        loop {
+            if let HotplugEvent::Added(_) = self.next().await? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Waits for the hidraw device with the given name to be removed.
+    pub async fn removed(&mut self, name: &str) -> io::Result<()> {
+        loop {
+            if matches!(self.next().await?, HotplugEvent::Removed(removed) if removed == name) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Waits for the next hidraw device to be added or removed.
+    async fn next(&mut self) -> io::Result<HotplugEvent> {
+        let mut buf = vec![0; MAX_MESSAGE_LEN];
+        loop {
+            let len = self.recv(&mut buf).await?;
+            if let Some(event) = parse_uevent(&buf[..len]) {
+                return Ok(event);
+            }
+        }
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let result = guard.try_io(|fd| {
+                // SAFETY: buf is valid for writes of buf.len() bytes.
+                let len = unsafe {
+                    libc::recv(
+                        fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if len < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(len as usize)
+                }
+            });
+            match result {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// Parses a uevent, returning it if it is about a hidraw device being added
+/// or removed.
+///
+/// Both the messages sent by udev and the raw ones sent by the kernel are
+/// understood: they carry the same `KEY=value` properties.
+fn parse_uevent(message: &[u8]) -> Option<HotplugEvent> {
+    let properties = if let Some(header) = message.strip_prefix(LIBUDEV_PREFIX) {
+        // magic (big endian), header size, properties offset and length.
+        let field = |n: usize| -> Option<[u8; 4]> { header.get(n * 4..n * 4 + 4)?.try_into().ok() };
+        if u32::from_be_bytes(field(0)?) != LIBUDEV_MAGIC {
+            return None;
+        }
+        let offset = u32::from_ne_bytes(field(2)?) as usize;
+        let len = u32::from_ne_bytes(field(3)?) as usize;
+        message.get(offset..offset.checked_add(len)?)?
+    } else {
+        // Kernel messages start with "ACTION@DEVPATH".
+        let start = message.iter().position(|&b| b == 0)? + 1;
+        &message[start..]
+    };
+
+    let (mut action, mut subsystem, mut devname) = (None, None, None);
+    for property in properties.split(|&b| b == 0) {
+        let Ok(property) = std::str::from_utf8(property) else {
+            continue;
+        };
+        match property.split_once('=') {
+            Some(("ACTION", value)) => action = Some(value),
+            Some(("SUBSYSTEM", value)) => subsystem = Some(value),
+            Some(("DEVNAME", value)) => devname = Some(value),
+            _ => {}
+        }
+    }
+    if subsystem? != "hidraw" {
+        return None;
+    }
+    // udev sends the path of the device node, and the kernel its name.
+    let name = devname?.rsplit('/').next()?.to_string();
+    match action? {
+        "add" => Some(HotplugEvent::Added(name)),
+        "remove" => Some(HotplugEvent::Removed(name)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        os::{fd::OwnedFd, unix::net::UnixDatagram},
+        time::Duration,
+    };
+
+    use super::{parse_uevent, HotplugEvent, HotplugMonitor, LIBUDEV_MAGIC, LIBUDEV_PREFIX};
+
+    /// Builds a message like the ones udev sends.
+    fn udev_message(action: &str, subsystem: &str, devname: &str) -> Vec<u8> {
+        let properties = format!(
+            "ACTION={action}\0DEVPATH=/devices/usb1/1-2/{devname}\0SUBSYSTEM={subsystem}\0DEVNAME=/dev/{devname}\0SEQNUM=4242\0"
+        );
+        let header_len: u32 = 40;
+        let mut message = LIBUDEV_PREFIX.to_vec();
+        message.extend(LIBUDEV_MAGIC.to_be_bytes());
+        message.extend(header_len.to_ne_bytes());
+        message.extend(header_len.to_ne_bytes());
+        message.extend((properties.len() as u32).to_ne_bytes());
+        // Filter hashes and tag bloom filter.
+        message.extend([0; 16]);
+        message.extend(properties.as_bytes());
+        message
+    }
+
+    /// Returns a monitor reading from a socket pair, and the other end to
+    /// send synthetic uevents to.
+    fn monitor_pair() -> (HotplugMonitor, UnixDatagram) {
+        let (rx, tx) = UnixDatagram::pair().unwrap();
+        rx.set_nonblocking(true).unwrap();
+        (HotplugMonitor::from_fd(OwnedFd::from(rx)).unwrap(), tx)
+    }
+
+    #[test]
+    fn test_parse_udev_message() {
+        assert_eq!(
+            Some(HotplugEvent::Added("hidraw3".to_string())),
+            parse_uevent(&udev_message("add", "hidraw", "hidraw3"))
+        );
+        assert_eq!(
+            Some(HotplugEvent::Removed("hidraw3".to_string())),
+            parse_uevent(&udev_message("remove", "hidraw", "hidraw3"))
+        );
+    }
+
+    #[test]
+    fn test_parse_kernel_message() {
+        let message = b"add@/devices/usb1/1-2/hidraw/hidraw0\0ACTION=add\0DEVPATH=/devices/usb1/1-2/hidraw/hidraw0\0SUBSYSTEM=hidraw\0DEVNAME=hidraw0\0SEQNUM=1234\0";
+        assert_eq!(
+            Some(HotplugEvent::Added("hidraw0".to_string())),
+            parse_uevent(message)
+        );
+    }
+
+    #[test]
+    fn test_other_events_are_ignored() {
+        assert_eq!(None, parse_uevent(&udev_message("add", "input", "event4")));
+        assert_eq!(
+            None,
+            parse_uevent(&udev_message("bind", "hidraw", "hidraw3"))
+        );
+        let mut message = udev_message("add", "hidraw", "hidraw3");
+        message[8] ^= 0xff;
+        assert_eq!(None, parse_uevent(&message));
+    }
+
+    #[tokio::test]
+    async fn test_monitor_skips_unrelated_events() {
+        let (mut monitor, tx) = monitor_pair();
+        tx.send(&udev_message("add", "input", "event4")).unwrap();
+        tx.send(&udev_message("add", "hidraw", "hidraw3")).unwrap();
+        assert_eq!(
+            HotplugEvent::Added("hidraw3".to_string()),
+            monitor.next().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_removal_of_other_devices_is_ignored() {
+        let (mut monitor, tx) = monitor_pair();
+        tx.send(&udev_message("remove", "hidraw", "hidraw2"))
+            .unwrap();
+        tx.send(&udev_message("add", "hidraw", "hidraw4")).unwrap();
+        let timeout = Duration::from_millis(100);
+        assert!(tokio::time::timeout(timeout, monitor.removed("hidraw3"))
+            .await
+            .is_err());
+        tx.send(&udev_message("remove", "hidraw", "hidraw3"))
+            .unwrap();
+        tokio::time::timeout(timeout, monitor.removed("hidraw3"))
+            .await
+            .expect("removal to be noticed")
+            .unwrap();
+    }
+}
@@ -0,0 +1,827 @@
+//! Management requests on USB security keys.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use libwebauthn::{
+    management::{AuthenticatorConfig, BioEnrollment, CredentialManagement},
+    pin::PinManagement,
+    proto::{
+        ctap2::{
+            cbor::CborResponse, Ctap2, Ctap2COSEAlgorithmIdentifier, Ctap2ClientPinRequest,
+            Ctap2CredentialData, Ctap2GetInfoResponse, Ctap2LastEnrollmentSampleStatus,
+            Ctap2PinUvAuthProtocol, Ctap2PublicKeyCredentialDescriptor,
+            Ctap2PublicKeyCredentialRpEntity, Ctap2PublicKeyCredentialType,
+            Ctap2PublicKeyCredentialUserEntity,
+        },
+        CtapError,
+    },
+    transport::{
+        hid::{
+            channel::HidChannel,
+            framing::{HidCommand, HidMessage},
+            HidDevice,
+        },
+        Channel, Device,
+    },
+    webauthn::{Error as WebAuthnError, PlatformError},
+    UvUpdate,
+};
+use ring::digest;
+use tokio::sync::{broadcast, mpsc, mpsc::Sender};
+use tracing::{debug, warn};
+
+use credentialsd_common::model::{
+    AuthenticatorInfo, Error, Fingerprint, FingerprintSampleStatus, Operation, PinInfo,
+    SecurityKeyChange, SecurityKeyCredential,
+};
+
+use super::{handle_usb_updates, webauthn_error, CancelOnDrop, UsbUvMessage};
+use crate::credential_service::management::{
+    check_new_pin, ManagementRequest, ManagementResponse, DEFAULT_MIN_PIN_LENGTH, MAX_PIN_BYTES,
+};
+
+/// Timeout of commands that don't wait for the user: PINs are entered before
+/// they are sent to the key.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Timeout of capturing a fingerprint sample, which waits for the user to
+/// touch the sensor.
+const SAMPLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Timeout of a reset, which waits for the user to touch the key.
+const RESET_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `authenticatorReset`, which libwebauthn has no request for.
+const AUTHENTICATOR_RESET: u8 = 0x07;
+
+/// `confirmed` is shared by the attempts on every key that the user plugs in,
+/// so that a change is only confirmed once.
+pub(super) async fn handle_management(
+    request: &ManagementRequest,
+    confirmed: &AtomicBool,
+    mut device: HidDevice,
+    signal_tx: &Sender<Result<UsbUvMessage, Error>>,
+) {
+    let response = match device.channel().await {
+        Err(err) => {
+            tracing::error!("Failed to open channel to USB authenticator: {:?}", err);
+            Err(Error::AuthenticatorError)
+        }
+        Ok(mut channel) => {
+            // Dropped before the channel, if the task is aborted.
+            let _cancel_guard = CancelOnDrop(vec![channel.get_handle()]);
+            match request {
+                ManagementRequest::GetInfo => authenticator_info(&mut channel)
+                    .await
+                    .map(ManagementResponse::Info),
+                ManagementRequest::GetPinInfo => pin_info(&mut channel)
+                    .await
+                    .map(ManagementResponse::PinInfo),
+                ManagementRequest::SetPin => set_pin(&mut channel, signal_tx)
+                    .await
+                    .map(|()| ManagementResponse::PinSet),
+                ManagementRequest::Reset => reset(&channel, confirmed, signal_tx)
+                    .await
+                    .map(|()| ManagementResponse::ResetDone),
+                ManagementRequest::ListCredentials
+                | ManagementRequest::DeleteCredential { .. }
+                | ManagementRequest::UpdateUserDisplayName { .. }
+                | ManagementRequest::ListFingerprints
+                | ManagementRequest::EnrollFingerprint { .. }
+                | ManagementRequest::RenameFingerprint { .. }
+                | ManagementRequest::RemoveFingerprint { .. }
+                | ManagementRequest::SetAlwaysUv { .. }
+                | ManagementRequest::SetMinPinLength { .. }
+                | ManagementRequest::EnableEnterpriseAttestation => {
+                    // The PIN or on-device verification is asked for like in
+                    // ceremonies, once per channel.
+                    let updates_tx = signal_tx.clone().downgrade();
+                    let ux_updates_rx = channel.get_ux_update_receiver();
+                    tokio::spawn(async move {
+                        handle_usb_updates(&updates_tx, ux_updates_rx).await;
+                    });
+                    manage_verified(request, &mut channel, confirmed, signal_tx).await
+                }
+            }
+        }
+    };
+    let response = response.map(|response| UsbUvMessage::Managed(Box::new(response)));
+    if let Err(err) = signal_tx.send(response).await {
+        tracing::error!(
+            "Failed to notify that the management request completed: {:?}",
+            err
+        );
+    }
+}
+
+async fn authenticator_info(channel: &mut HidChannel<'_>) -> Result<AuthenticatorInfo, Error> {
+    let info = channel.ctap2_get_info().await.map_err(webauthn_error)?;
+    let pin_retries = if info.option_enabled("clientPin") {
+        pin_retries(channel, &info).await
+    } else {
+        None
+    };
+    let uv_retries = if info.option_enabled("uv") {
+        uv_retries(channel).await
+    } else {
+        None
+    };
+    Ok(AuthenticatorInfo {
+        versions: info.versions,
+        aaguid: info.aaguid.into_vec(),
+        options: info.options.unwrap_or_default(),
+        // libwebauthn drops the identifiers of algorithms that it doesn't
+        // know.
+        algorithms: info
+            .algorithms
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|alg| alg.algorithm != Ctap2COSEAlgorithmIdentifier::Unknown)
+            .map(|alg| alg.algorithm as i32)
+            .collect(),
+        extensions: info.extensions.unwrap_or_default(),
+        pin_retries,
+        uv_retries,
+        firmware_version: info.firmware_version,
+        transports: info.transports.unwrap_or_default(),
+    })
+}
+
+async fn pin_info(channel: &mut HidChannel<'_>) -> Result<PinInfo, Error> {
+    let info = channel.ctap2_get_info().await.map_err(webauthn_error)?;
+    let pin_set = has_pin(&info)?;
+    let pin_retries = if pin_set {
+        pin_retries(channel, &info).await
+    } else {
+        None
+    };
+    Ok(PinInfo {
+        pin_set,
+        pin_retries,
+        min_pin_length: info.min_pin_length.unwrap_or(DEFAULT_MIN_PIN_LENGTH),
+        force_pin_change: info.force_pin_change.unwrap_or(false),
+    })
+}
+
+/// Asks the user for the current PIN, if the key has one, and then for the
+/// new one, until the key accepts them.
+///
+/// Keys that require their PIN to be changed reject the current one as the
+/// new PIN, so the user is asked for another one right away.
+async fn set_pin(
+    channel: &mut HidChannel<'_>,
+    signal_tx: &Sender<Result<UsbUvMessage, Error>>,
+) -> Result<(), Error> {
+    let mut ux_updates = channel.get_ux_update_receiver();
+    loop {
+        let info = channel.ctap2_get_info().await.map_err(webauthn_error)?;
+        let current_pin = if has_pin(&info)? {
+            let attempts_left = pin_retries(channel, &info).await;
+            let pin = prompt_pin(signal_tx, |pin_tx| UsbUvMessage::NeedsPin {
+                attempts_left,
+                pin_tx,
+            })
+            .await?;
+            Some(pin)
+        } else {
+            None
+        };
+        let min_length = info.min_pin_length.unwrap_or(DEFAULT_MIN_PIN_LENGTH);
+        let must_change = info.force_pin_change == Some(true);
+        let new_pin = loop {
+            let pin = prompt_pin(signal_tx, |pin_tx| UsbUvMessage::NeedsNewPin {
+                min_length,
+                must_change,
+                pin_tx,
+            })
+            .await?;
+            let current_pin = current_pin.as_deref().filter(|_| must_change);
+            match check_new_pin(&pin, min_length, current_pin) {
+                Ok(()) => break pin,
+                Err(err) => debug!("Rejected new PIN: {err:?}"),
+            }
+        };
+        // libwebauthn asks for the current PIN itself, after it was entered.
+        let result = tokio::select! {
+            result = channel.change_pin(new_pin, TIMEOUT) => result,
+            () = answer_pin_requests(&mut ux_updates, current_pin.as_deref()) => {
+                return Err(Error::Internal("USB update channel closed".to_string()));
+            }
+        };
+        match result {
+            Ok(()) => return Ok(()),
+            Err(WebAuthnError::Ctap(CtapError::PINInvalid)) => {
+                debug!("Wrong PIN entered, asking again");
+            }
+            // Rejected by a policy of the key that we don't know about.
+            Err(WebAuthnError::Ctap(CtapError::PINPolicyViolation)) => {
+                debug!("Security key rejected the new PIN, asking again");
+            }
+            Err(err) => {
+                warn!("Failed to set the PIN of the USB authenticator: {:?}", err);
+                return Err(webauthn_error(err));
+            }
+        }
+    }
+}
+
+/// Runs the requests that need a PIN/UV auth token from the key.
+async fn manage_verified(
+    request: &ManagementRequest,
+    channel: &mut HidChannel<'_>,
+    confirmed: &AtomicBool,
+    signal_tx: &Sender<Result<UsbUvMessage, Error>>,
+) -> Result<ManagementResponse, Error> {
+    let info = channel.ctap2_get_info().await.map_err(webauthn_error)?;
+    let supported = match request.operation() {
+        Operation::ManageFingerprints => info.supports_bio_enrollment(),
+        Operation::ConfigureSecurityKey => info.option_enabled("authnrCfg"),
+        _ => info.supports_credential_management(),
+    };
+    if !supported {
+        warn!(
+            "USB authenticator does not support {:?}",
+            request.operation()
+        );
+        return Err(Error::AuthenticatorError);
+    }
+    if let Operation::ConfigureSecurityKey = request.operation() {
+        let Some(change) = config_change(request, &info)? else {
+            debug!("USB authenticator is configured as requested already");
+            return Ok(ManagementResponse::Configured);
+        };
+        confirm(signal_tx, confirmed, change).await?;
+    }
+    loop {
+        let result = match request {
+            ManagementRequest::ListCredentials => {
+                list_credentials(channel)
+                    .await
+                    .map(|(credentials, remaining)| ManagementResponse::Credentials {
+                        credentials,
+                        remaining,
+                    })
+            }
+            ManagementRequest::DeleteCredential { credential_id } => channel
+                .delete_credential(&descriptor(credential_id), TIMEOUT)
+                .await
+                .map(|()| ManagementResponse::CredentialDeleted),
+            ManagementRequest::UpdateUserDisplayName {
+                credential_id,
+                display_name,
+            } => update_user_display_name(channel, credential_id, display_name)
+                .await
+                .map(|()| ManagementResponse::UserUpdated),
+            ManagementRequest::ListFingerprints => list_fingerprints(channel)
+                .await
+                .map(ManagementResponse::Fingerprints),
+            ManagementRequest::EnrollFingerprint { name } => {
+                enroll_fingerprint(channel, signal_tx, name.as_deref())
+                    .await
+                    .map(|template_id| ManagementResponse::FingerprintEnrolled { template_id })
+            }
+            ManagementRequest::RenameFingerprint { template_id, name } => channel
+                .rename_bio_enrollment(template_id, name, TIMEOUT)
+                .await
+                .map(|()| ManagementResponse::FingerprintRenamed),
+            ManagementRequest::RemoveFingerprint { template_id } => channel
+                .remove_bio_enrollment(template_id, TIMEOUT)
+                .await
+                .map(|()| ManagementResponse::FingerprintRemoved),
+            ManagementRequest::SetAlwaysUv { .. } => channel
+                .toggle_always_uv(TIMEOUT)
+                .await
+                .map(|()| ManagementResponse::Configured),
+            ManagementRequest::SetMinPinLength { min_length, rp_ids } => {
+                set_min_pin_length(channel, &info, *min_length, rp_ids)
+                    .await
+                    .map(|()| ManagementResponse::Configured)
+            }
+            ManagementRequest::EnableEnterpriseAttestation => channel
+                .enable_enterprise_attestation(TIMEOUT)
+                .await
+                .map(|()| ManagementResponse::Configured),
+            ManagementRequest::GetInfo
+            | ManagementRequest::GetPinInfo
+            | ManagementRequest::SetPin
+            | ManagementRequest::Reset => {
+                unreachable!("Info and PIN requests, and resets, don't need an auth token")
+            }
+        };
+        match result {
+            Ok(response) => return Ok(response),
+            Err(WebAuthnError::Ctap(ctap_error)) if ctap_error.is_retryable_user_error() => {
+                warn!("Retrying management request");
+            }
+            Err(err) => {
+                warn!("Failed to manage the USB authenticator: {:?}", err);
+                return Err(webauthn_error(err));
+            }
+        }
+    }
+}
+
+/// The change that a configuration request makes to the key, to be confirmed
+/// by the user, or `None` if the key is configured as requested already.
+///
+/// Fails if the key doesn't support the change.
+fn config_change(
+    request: &ManagementRequest,
+    info: &Ctap2GetInfoResponse,
+) -> Result<Option<SecurityKeyChange>, Error> {
+    let option = |name: &str| info.options.as_ref().and_then(|o| o.get(name)).copied();
+    let change = match request {
+        ManagementRequest::SetAlwaysUv { enable } => match option("alwaysUv") {
+            Some(current) if current == *enable => None,
+            Some(_) => Some(SecurityKeyChange::SetAlwaysUv(*enable)),
+            None => {
+                warn!("USB authenticator does not support alwaysUv");
+                return Err(Error::AuthenticatorError);
+            }
+        },
+        ManagementRequest::EnableEnterpriseAttestation => match option("ep") {
+            Some(true) => None,
+            Some(false) => Some(SecurityKeyChange::EnableEnterpriseAttestation),
+            None => {
+                warn!("USB authenticator does not support enterprise attestation");
+                return Err(Error::AuthenticatorError);
+            }
+        },
+        ManagementRequest::SetMinPinLength { min_length, rp_ids } => {
+            if option("setMinPINLength") != Some(true) {
+                warn!("USB authenticator does not support setting the minimum PIN length");
+                return Err(Error::AuthenticatorError);
+            }
+            let current = info.min_pin_length.unwrap_or(DEFAULT_MIN_PIN_LENGTH);
+            // Keys only ever raise the minimum length, up to what fits in a
+            // PIN.
+            if *min_length < current || *min_length as usize > MAX_PIN_BYTES {
+                warn!("Rejected minimum PIN length {min_length}, the current one is {current}");
+                return Err(Error::AuthenticatorError);
+            }
+            let max_rp_ids = info.max_rpids_for_setminpinlength.unwrap_or(0);
+            if rp_ids.len() > max_rp_ids as usize {
+                warn!("USB authenticator accepts only {max_rp_ids} relying parties to read the minimum PIN length");
+                return Err(Error::AuthenticatorError);
+            }
+            if *min_length == current && rp_ids.is_empty() {
+                None
+            } else {
+                Some(SecurityKeyChange::SetMinPinLength {
+                    min_length: *min_length,
+                    rp_ids: rp_ids.clone(),
+                })
+            }
+        }
+        _ => unreachable!("not a configuration request"),
+    };
+    Ok(change)
+}
+
+async fn set_min_pin_length(
+    channel: &mut HidChannel<'_>,
+    info: &Ctap2GetInfoResponse,
+    min_length: u32,
+    rp_ids: &[String],
+) -> Result<(), WebAuthnError> {
+    if min_length > info.min_pin_length.unwrap_or(DEFAULT_MIN_PIN_LENGTH) {
+        channel
+            .set_min_pin_length(u64::from(min_length), TIMEOUT)
+            .await?;
+    }
+    if !rp_ids.is_empty() {
+        channel
+            .set_min_pin_length_rpids(rp_ids.to_vec(), TIMEOUT)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Resets the key after the user confirmed it.
+///
+/// Keys only accept a reset within seconds of being plugged in, and after the
+/// user touched them. If the key refuses, the user is asked to plug it back
+/// in, which starts the request again on it.
+async fn reset(
+    channel: &HidChannel<'_>,
+    confirmed: &AtomicBool,
+    signal_tx: &Sender<Result<UsbUvMessage, Error>>,
+) -> Result<(), Error> {
+    confirm(signal_tx, confirmed, SecurityKeyChange::Reset).await?;
+    send_message(signal_tx, UsbUvMessage::NeedsUserPresence).await?;
+    match send_reset(channel).await {
+        Ok(()) => {
+            debug!("Reset USB authenticator");
+            Ok(())
+        }
+        Err(WebAuthnError::Ctap(CtapError::NotAllowed | CtapError::UserActionTimeout)) => {
+            debug!("USB authenticator refused to be reset, asking to plug it back in");
+            send_message(signal_tx, UsbUvMessage::NeedsReplug).await?;
+            // This task is aborted when the key is unplugged.
+            std::future::pending().await
+        }
+        Err(err) => {
+            warn!("Failed to reset the USB authenticator: {:?}", err);
+            Err(webauthn_error(err))
+        }
+    }
+}
+
+/// Sends `authenticatorReset` on a CTAPHID channel of our own, since the one
+/// of libwebauthn is private.
+async fn send_reset(channel: &HidChannel<'_>) -> Result<(), WebAuthnError> {
+    let invalid_response = || WebAuthnError::Platform(PlatformError::InvalidDeviceResponse);
+    let nonce: [u8; 8] = rand::random();
+    channel
+        .hid_send(&HidMessage::broadcast(HidCommand::Init, &nonce))
+        .await?;
+    let cid = loop {
+        let response = channel.hid_recv(TIMEOUT).await?;
+        // Other clients may allocate channels at the same time.
+        if response.cmd != HidCommand::Init || response.payload.get(..8) != Some(&nonce[..]) {
+            continue;
+        }
+        let cid = response.payload.get(8..12).ok_or_else(invalid_response)?;
+        break u32::from_be_bytes(cid.try_into().expect("four bytes"));
+    };
+    channel
+        .hid_send(&HidMessage::new(
+            cid,
+            HidCommand::Cbor,
+            &[AUTHENTICATOR_RESET],
+        ))
+        .await?;
+    let response = channel.hid_recv(RESET_TIMEOUT).await?;
+    if response.cmd != HidCommand::Cbor {
+        return Err(invalid_response());
+    }
+    let response = CborResponse::try_from(&response.payload).map_err(|_| invalid_response())?;
+    match response.status_code {
+        CtapError::Ok => Ok(()),
+        err => Err(WebAuthnError::Ctap(err)),
+    }
+}
+
+/// Asks the user to confirm a change in the trusted UI, unless they did so
+/// before plugging the key back in.
+async fn confirm(
+    signal_tx: &Sender<Result<UsbUvMessage, Error>>,
+    confirmed: &AtomicBool,
+    change: SecurityKeyChange,
+) -> Result<(), Error> {
+    if confirmed.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    let (confirm_tx, mut confirm_rx) = mpsc::channel(1);
+    send_message(
+        signal_tx,
+        UsbUvMessage::NeedsConfirmation { change, confirm_tx },
+    )
+    .await?;
+    confirm_rx.recv().await.ok_or_else(|| {
+        Error::Internal("Confirmation channel closed before the change was confirmed".to_string())
+    })?;
+    confirmed.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+async fn send_message(
+    signal_tx: &Sender<Result<UsbUvMessage, Error>>,
+    message: UsbUvMessage,
+) -> Result<(), Error> {
+    signal_tx
+        .send(Ok(message))
+        .await
+        .map_err(|_| Error::Internal("USB state channel closed".to_string()))
+}
+
+/// Lists the discoverable credentials of all relying parties on the key, and
+/// how many more it can store.
+async fn list_credentials(
+    channel: &mut HidChannel<'_>,
+) -> Result<(Vec<SecurityKeyCredential>, u32), WebAuthnError> {
+    let metadata = channel.get_credential_metadata(TIMEOUT).await?;
+    let remaining = u32::try_from(metadata.max_possible_remaining_resident_credentials_count)
+        .unwrap_or(u32::MAX);
+    let mut credentials = Vec::new();
+    // Keys without credentials fail to enumerate relying parties.
+    if metadata.existing_resident_credentials_count == 0 {
+        return Ok((credentials, remaining));
+    }
+    // Any other command ends the enumeration, so relying parties are all
+    // read before their credentials.
+    let (first, total_rps) = channel.enumerate_rps_begin(TIMEOUT).await?;
+    let mut rps = vec![first.rp];
+    for _ in 1..total_rps {
+        rps.push(channel.enumerate_rps_next_rp(TIMEOUT).await?.rp);
+    }
+    for rp in rps {
+        // The hash returned with the relying party is still CBOR-encoded.
+        let rp_id_hash = digest::digest(&digest::SHA256, rp.id.as_bytes());
+        let (first, total) = channel
+            .enumerate_credentials_begin(rp_id_hash.as_ref(), TIMEOUT)
+            .await?;
+        credentials.push(security_key_credential(&rp, first));
+        for _ in 1..total {
+            let credential = channel.enumerate_credentials_next(TIMEOUT).await?;
+            credentials.push(security_key_credential(&rp, credential));
+        }
+    }
+    Ok((credentials, remaining))
+}
+
+/// Keys replace the whole user entity, so the current one is looked up first.
+async fn update_user_display_name(
+    channel: &mut HidChannel<'_>,
+    credential_id: &[u8],
+    display_name: &str,
+) -> Result<(), WebAuthnError> {
+    let (credentials, _) = list_credentials(channel).await?;
+    let Some(credential) = credentials
+        .into_iter()
+        .find(|c| c.credential_id == credential_id)
+    else {
+        return Err(WebAuthnError::Ctap(CtapError::NoCredentials));
+    };
+    let user = Ctap2PublicKeyCredentialUserEntity {
+        id: credential.user_id.into(),
+        name: credential.user_name,
+        display_name: Some(display_name.to_string()),
+    };
+    channel
+        .update_user_info(&descriptor(credential_id), &user, TIMEOUT)
+        .await
+}
+
+fn descriptor(credential_id: &[u8]) -> Ctap2PublicKeyCredentialDescriptor {
+    Ctap2PublicKeyCredentialDescriptor {
+        id: credential_id.to_vec().into(),
+        r#type: Ctap2PublicKeyCredentialType::PublicKey,
+        transports: None,
+    }
+}
+
+fn security_key_credential(
+    rp: &Ctap2PublicKeyCredentialRpEntity,
+    credential: Ctap2CredentialData,
+) -> SecurityKeyCredential {
+    SecurityKeyCredential {
+        rp_id: rp.id.clone(),
+        rp_name: rp.name.clone(),
+        credential_id: credential.credential_id.id.into_vec(),
+        user_id: credential.user.id.into_vec(),
+        user_name: credential.user.name,
+        user_display_name: credential.user.display_name,
+    }
+}
+
+async fn list_fingerprints(
+    channel: &mut HidChannel<'_>,
+) -> Result<Vec<Fingerprint>, WebAuthnError> {
+    match channel.get_bio_enrollments(TIMEOUT).await {
+        Ok(templates) => Ok(templates
+            .into_iter()
+            .filter_map(|template| {
+                Some(Fingerprint {
+                    template_id: template.template_id?.into_vec(),
+                    name: template.template_friendly_name,
+                })
+            })
+            .collect()),
+        // Keys without fingerprints report this instead of an empty list.
+        Err(WebAuthnError::Ctap(CtapError::InvalidOption)) => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Enrolls a new fingerprint, telling the user how each sample went until the
+/// key has enough of them.
+async fn enroll_fingerprint(
+    channel: &mut HidChannel<'_>,
+    signal_tx: &Sender<Result<UsbUvMessage, Error>>,
+    name: Option<&str>,
+) -> Result<Vec<u8>, WebAuthnError> {
+    // Gets the auth token first, so that the PIN isn't asked for while the
+    // user is asked to touch the sensor.
+    list_fingerprints(channel).await?;
+    let sensor = channel.get_fingerprint_sensor_info(TIMEOUT).await?;
+    let remaining_samples = sensor
+        .max_capture_samples_required_for_enroll
+        .and_then(|n| u32::try_from(n).ok());
+    request_sample(signal_tx, remaining_samples, None).await;
+    let (template_id, mut status, mut remaining) = channel
+        .start_new_bio_enrollment(None, SAMPLE_TIMEOUT)
+        .await?;
+    let template_id = decode_template_id(&template_id)?;
+    while remaining > 0 {
+        let remaining_samples = u32::try_from(remaining).ok();
+        request_sample(signal_tx, remaining_samples, sample_status(status)).await;
+        (status, remaining) = channel
+            .capture_next_bio_enrollment_sample(&template_id, None, SAMPLE_TIMEOUT)
+            .await?;
+    }
+    debug!("Enrolled fingerprint");
+    if let Some(name) = name {
+        channel
+            .rename_bio_enrollment(&template_id, name, TIMEOUT)
+            .await?;
+    }
+    Ok(template_id)
+}
+
+async fn request_sample(
+    signal_tx: &Sender<Result<UsbUvMessage, Error>>,
+    remaining_samples: Option<u32>,
+    last_sample: Option<FingerprintSampleStatus>,
+) {
+    let message = UsbUvMessage::NeedsFingerprintSample {
+        remaining_samples,
+        last_sample,
+    };
+    if let Err(err) = signal_tx.send(Ok(message)).await {
+        tracing::error!(
+            "Authenticator waits for a fingerprint sample, but we cannot relay the message to the credential service: {:?}",
+            err
+        );
+    }
+}
+
+fn sample_status(status: Ctap2LastEnrollmentSampleStatus) -> Option<FingerprintSampleStatus> {
+    FingerprintSampleStatus::try_from(status as u8).ok()
+}
+
+/// libwebauthn returns the IDs of new templates still CBOR-encoded.
+fn decode_template_id(data: &[u8]) -> Result<Vec<u8>, WebAuthnError> {
+    match serde_cbor_2::from_slice(data) {
+        Ok(serde_cbor_2::Value::Bytes(template_id)) => Ok(template_id),
+        _ => Err(WebAuthnError::Platform(
+            PlatformError::InvalidDeviceResponse,
+        )),
+    }
+}
+
+/// Whether the key has a PIN. Fails for keys that don't support PINs.
+fn has_pin(info: &Ctap2GetInfoResponse) -> Result<bool, Error> {
+    let pin_set = info.options.as_ref().and_then(|o| o.get("clientPin"));
+    pin_set.copied().ok_or_else(|| {
+        warn!("USB authenticator does not support PINs");
+        Error::AuthenticatorError
+    })
+}
+
+async fn pin_retries(channel: &mut HidChannel<'_>, info: &Ctap2GetInfoResponse) -> Option<u32> {
+    // CTAP 2.0 keys need a PIN protocol, and all of them support the first.
+    let protocol = (!info.supports_fido_2_1()).then_some(Ctap2PinUvAuthProtocol::One);
+    let request = Ctap2ClientPinRequest::new_get_pin_retries(protocol);
+    match channel.ctap2_client_pin(&request, TIMEOUT).await {
+        Ok(response) => response.pin_retries,
+        Err(err) => {
+            warn!(
+                "Failed to get the PIN retries of the USB authenticator: {:?}",
+                err
+            );
+            None
+        }
+    }
+}
+
+async fn uv_retries(channel: &mut HidChannel<'_>) -> Option<u32> {
+    let request = Ctap2ClientPinRequest::new_get_uv_retries();
+    match channel.ctap2_client_pin(&request, TIMEOUT).await {
+        Ok(response) => response.uv_retries,
+        Err(err) => {
+            warn!(
+                "Failed to get the UV retries of the USB authenticator: {:?}",
+                err
+            );
+            None
+        }
+    }
+}
+
+/// Asks the user for a PIN in the trusted UI.
+async fn prompt_pin(
+    signal_tx: &Sender<Result<UsbUvMessage, Error>>,
+    message: impl FnOnce(Sender<String>) -> UsbUvMessage,
+) -> Result<String, Error> {
+    let (pin_tx, mut pin_rx) = mpsc::channel(1);
+    signal_tx
+        .send(Ok(message(pin_tx)))
+        .await
+        .map_err(|_| Error::Internal("USB state channel closed".to_string()))?;
+    pin_rx
+        .recv()
+        .await
+        .ok_or_else(|| Error::Internal("PIN channel closed before receiving a PIN".to_string()))
+}
+
+/// Answers the PIN requests of libwebauthn with the PIN that the user
+/// entered, or cancels them if there is none. Returns when the channel closes.
+async fn answer_pin_requests(updates: &mut broadcast::Receiver<UvUpdate>, pin: Option<&str>) {
+    while let Ok(update) = updates.recv().await {
+        if let UvUpdate::PinRequired(update) = update {
+            match pin {
+                Some(pin) => {
+                    if let Err(err) = update.send_pin(pin) {
+                        tracing::error!("Error sending pin to device: {:?}", err);
+                    }
+                }
+                None => update.cancel(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use credentialsd_common::model::SecurityKeyChange;
+    use libwebauthn::proto::ctap2::Ctap2GetInfoResponse;
+    use serde_cbor_2::Value;
+
+    use super::{config_change, decode_template_id};
+    use crate::credential_service::management::ManagementRequest;
+
+    fn get_info(options: &[(&str, bool)]) -> Ctap2GetInfoResponse {
+        let options = options
+            .iter()
+            .map(|(name, value)| (Value::Text(name.to_string()), Value::Bool(*value)))
+            .collect();
+        let info = [
+            (
+                0x01,
+                Value::Array(vec![Value::Text("FIDO_2_1".to_string())]),
+            ),
+            (0x03, Value::Bytes(vec![0; 16])),
+            (0x04, Value::Map(options)),
+            // minPINLength
+            (0x0D, Value::Integer(6)),
+            // maxRPIDsForSetMinPINLength
+            (0x10, Value::Integer(1)),
+        ]
+        .into_iter()
+        .map(|(key, value)| (Value::Integer(key), value))
+        .collect();
+        serde_cbor_2::value::from_value(Value::Map(info)).unwrap()
+    }
+
+    #[test]
+    fn test_config_changes_are_only_confirmed_if_needed() {
+        let info = get_info(&[("alwaysUv", false), ("ep", true), ("setMinPINLength", true)]);
+        assert_eq!(
+            Some(SecurityKeyChange::SetAlwaysUv(true)),
+            config_change(&ManagementRequest::SetAlwaysUv { enable: true }, &info).unwrap()
+        );
+        assert_eq!(
+            None,
+            config_change(&ManagementRequest::SetAlwaysUv { enable: false }, &info).unwrap()
+        );
+        assert_eq!(
+            None,
+            config_change(&ManagementRequest::EnableEnterpriseAttestation, &info).unwrap()
+        );
+        let set_min_pin_length = |min_length, rp_ids: &[&str]| {
+            let request = ManagementRequest::SetMinPinLength {
+                min_length,
+                rp_ids: rp_ids.iter().map(|rp_id| rp_id.to_string()).collect(),
+            };
+            config_change(&request, &info)
+        };
+        assert_eq!(None, set_min_pin_length(6, &[]).unwrap());
+        assert_eq!(
+            Some(SecurityKeyChange::SetMinPinLength {
+                min_length: 6,
+                rp_ids: vec!["example.com".to_string()],
+            }),
+            set_min_pin_length(6, &["example.com"]).unwrap()
+        );
+        // The minimum length is never lowered.
+        assert!(set_min_pin_length(4, &[]).is_err());
+        assert!(set_min_pin_length(64, &[]).is_err());
+        assert!(set_min_pin_length(8, &["example.com", "example.org"]).is_err());
+    }
+
+    #[test]
+    fn test_unsupported_config_changes_fail() {
+        let info = get_info(&[]);
+        assert!(config_change(&ManagementRequest::SetAlwaysUv { enable: true }, &info).is_err());
+        assert!(config_change(&ManagementRequest::EnableEnterpriseAttestation, &info).is_err());
+        let request = ManagementRequest::SetMinPinLength {
+            min_length: 8,
+            rp_ids: Vec::new(),
+        };
+        assert!(config_change(&request, &info).is_err());
+    }
+
+    #[test]
+    fn test_decode_template_id() {
+        assert_eq!(
+            vec![0x01, 0x02],
+            decode_template_id(&[0x42, 0x01, 0x02]).unwrap()
+        );
+        // A text string is not a template ID.
+        assert!(decode_template_id(&[0x62, 0x01, 0x02]).is_err());
+    }
+}
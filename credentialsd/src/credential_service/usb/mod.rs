@@ -0,0 +1,914 @@
+mod hotplug;
+mod management;
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use async_stream::stream;
+use base64::{self, engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use libwebauthn::{
+    ops::webauthn::{Assertion, GetAssertionResponse},
+    proto::CtapError,
+    transport::{
+        hid::{channel::HidChannelHandle, device::HidBackendDevice, HidDevice},
+        Channel, Device,
+    },
+    webauthn::{Error as WebAuthnError, WebAuthn},
+    UvUpdate,
+};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{self, Receiver, Sender, WeakSender};
+use tracing::{debug, warn};
+
+use credentialsd_common::model::{
+    Credential, CredentialRequest, Error, FingerprintSampleStatus, GetAssertionResponseInternal,
+    SecurityKeyChange, Transport,
+};
+
+use self::hotplug::HotplugMonitor;
+use super::{
+    libwebauthn_request,
+    management::{ManagementRequest, ManagementResponse},
+    transport::{
+        AbortOnDrop, AuthenticatorState, AuthenticatorStates, AuthenticatorTransport,
+        TransportDevice,
+    },
+    AuthenticatorResponse, CredentialResponse,
+};
+
+/// How often to list USB authenticators when hotplug events are unavailable.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+pub struct InProcessUsbHandler {}
+
+/// What to do with the security key that the user picks.
+#[derive(Clone, Debug)]
+enum UsbOperation {
+    Ceremony(Box<CredentialRequest>),
+    Manage {
+        request: ManagementRequest,
+        /// Whether the user confirmed the change already, so that they are
+        /// not asked again after plugging the key back in.
+        confirmed: Arc<AtomicBool>,
+    },
+}
+
+impl InProcessUsbHandler {
+    /// Looks for plugged-in security keys, only considering `key` if the user
+    /// picked one.
+    async fn process_idle_waiting(
+        failures: &mut usize,
+        prev_usb_state: &UsbStateInternal,
+        watcher: &mut DeviceWatcher,
+        key: Option<&str>,
+    ) -> Result<UsbStateInternal, Error> {
+        if let UsbStateInternal::Waiting = prev_usb_state {
+            watcher.device_added().await;
+        }
+        match libwebauthn::transport::hid::list_devices().await {
+            Ok(mut hid_devices) => {
+                if let Some(key) = key {
+                    hid_devices.retain(|device| hidraw_name(device).as_deref() == Some(key));
+                }
+                if hid_devices.is_empty() {
+                    let state = UsbStateInternal::Waiting;
+                    Ok(state)
+                } else if hid_devices.len() == 1 {
+                    Ok(UsbStateInternal::Connected(hid_devices.swap_remove(0)))
+                } else {
+                    Ok(UsbStateInternal::SelectingDevice(hid_devices))
+                }
+            }
+            Err(err) => {
+                *failures += 1;
+                if *failures == 5 {
+                    Err(Error::Internal(format!(
+                        "Failed to list USB authenticators: {:?}. Cancelling USB state updates.",
+                        err
+                    )))
+                } else {
+                    tracing::warn!(
+                        "Failed to list USB authenticators: {:?}. Throttling USB state updates",
+                        err
+                    );
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    Ok(prev_usb_state.clone())
+                }
+            }
+        }
+    }
+
+    async fn process_selecting_device(
+        hid_devices: Vec<HidDevice>,
+    ) -> Result<UsbStateInternal, Error> {
+        let expected_answers = hid_devices.len();
+        let (blinking_tx, mut blinking_rx) =
+            tokio::sync::mpsc::channel::<Option<usize>>(expected_answers);
+        let mut channel_map = HashMap::new();
+        let (setup_tx, mut setup_rx) =
+            tokio::sync::mpsc::channel::<(usize, HidDevice, HidChannelHandle)>(expected_answers);
+        for (idx, mut device) in hid_devices.into_iter().enumerate() {
+            let stx = setup_tx.clone();
+            let tx = blinking_tx.clone();
+            tokio::spawn(async move {
+                let dev = device.clone();
+
+                let res = match device.channel().await {
+                    Ok(ref mut channel) => {
+                        let cancel_handle = channel.get_handle();
+                        stx.send((idx, dev, cancel_handle)).await.unwrap();
+                        drop(stx);
+
+                        let was_selected = channel
+                            .blink_and_wait_for_user_presence(Duration::from_secs(300))
+                            .await;
+                        match was_selected {
+                            Ok(true) => Ok(Some(idx)),
+                            Ok(false) => Ok(None),
+                            Err(err) => Err(format!(
+                                "Failed to send wink request to authenticator: {:?}",
+                                err
+                            )),
+                        }
+                    }
+                    Err(err) => Err(format!(
+                        "Failed to create channel for USB authenticator: {:?}",
+                        err
+                    )),
+                }
+                .inspect_err(|err| tracing::warn!(err))
+                .unwrap_or_default(); // In case of error, we also send `None`
+                if let Err(err) = tx.send(res).await {
+                    tracing::error!("Failed to send notification of wink response: {:?}", err,);
+                }
+            });
+        }
+        drop(setup_tx);
+        // Receiving all cancel handles
+        while let Some((idx, device, handle)) = setup_rx.recv().await {
+            channel_map.insert(idx, (device, handle));
+        }
+        // Stop the devices from blinking if another transport wins meanwhile.
+        let mut cancel_guard = CancelOnDrop(
+            channel_map
+                .values()
+                .map(|(_, handle)| handle.clone())
+                .collect(),
+        );
+
+        tracing::info!("Waiting for user interaction");
+        drop(blinking_tx);
+        let mut state = UsbStateInternal::Idle;
+        while let Some(msg) = blinking_rx.recv().await {
+            match msg {
+                Some(idx) => {
+                    cancel_guard.0.clear();
+                    let (device, _handle) = channel_map.remove(&idx).unwrap();
+                    tracing::info!("User selected device {device:?}.");
+                    for (_key, (device, handle)) in channel_map.into_iter() {
+                        tracing::info!("Cancelling device {device:?}.");
+                        handle.cancel_ongoing_operation().await;
+                    }
+                    state = UsbStateInternal::Connected(device);
+                    break;
+                }
+                None => {
+                    continue;
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    async fn process_select_credential(
+        response: GetAssertionResponse,
+        cred_rx: &mut Receiver<String>,
+    ) -> Result<UsbStateInternal, Error> {
+        match cred_rx.recv().await {
+            Some(cred_id) => {
+                let assertion = find_selected_assertion(&response, &cred_id);
+                match assertion {
+                    Some(assertion) => Ok(UsbStateInternal::Completed(
+                        CredentialResponse::GetPublicKeyCredentialResponse(Box::new(
+                            GetAssertionResponseInternal::new(
+                                assertion,
+                                "cross-platform".to_string(),
+                            ),
+                        )),
+                    )),
+                    None => Err(Error::NoCredentials),
+                }
+            }
+            None => {
+                tracing::debug!("cred channel closed before receiving cred from client.");
+                Err(Error::Internal(
+                    "Cred channel disconnected prematurely".to_string(),
+                ))
+            }
+        }
+    }
+
+    async fn process_user_interaction(
+        signal_rx: &mut Receiver<Result<UsbUvMessage, Error>>,
+        cred_tx: &Sender<String>,
+        watcher: &mut DeviceWatcher,
+        device: Option<&HidDevice>,
+    ) -> Result<UsbStateInternal, Error> {
+        let msg = tokio::select! {
+            // A vanished device also fails to open, so report the removal instead.
+            biased;
+            () = watcher.device_removed(device) => {
+                tracing::info!("USB authenticator was unplugged during the ceremony");
+                return Ok(UsbStateInternal::Disconnected);
+            }
+            msg = signal_rx.recv() => msg,
+        };
+        match msg {
+            Some(msg) => match msg {
+                Ok(UsbUvMessage::NeedsPin {
+                    attempts_left,
+                    pin_tx,
+                }) => Ok(UsbStateInternal::NeedsPin {
+                    attempts_left,
+                    pin_tx,
+                }),
+                Ok(UsbUvMessage::NeedsUserVerification { attempts_left }) => {
+                    Ok(UsbStateInternal::NeedsUserVerification { attempts_left })
+                }
+                Ok(UsbUvMessage::NeedsUserPresence) => Ok(UsbStateInternal::NeedsUserPresence),
+                Ok(UsbUvMessage::NeedsNewPin {
+                    min_length,
+                    must_change,
+                    pin_tx,
+                }) => Ok(UsbStateInternal::NeedsNewPin {
+                    min_length,
+                    must_change,
+                    pin_tx,
+                }),
+                Ok(UsbUvMessage::NeedsFingerprintSample {
+                    remaining_samples,
+                    last_sample,
+                }) => Ok(UsbStateInternal::NeedsFingerprintSample {
+                    remaining_samples,
+                    last_sample,
+                }),
+                Ok(UsbUvMessage::NeedsConfirmation { change, confirm_tx }) => {
+                    Ok(UsbStateInternal::NeedsConfirmation { change, confirm_tx })
+                }
+                Ok(UsbUvMessage::NeedsReplug) => Ok(UsbStateInternal::NeedsReplug),
+                Ok(UsbUvMessage::Managed(response)) => Ok(UsbStateInternal::Managed(*response)),
+                Ok(UsbUvMessage::ReceivedCredentials(response)) => match *response {
+                    AuthenticatorResponse::CredentialCreated(make_credential_response) => Ok(
+                        UsbStateInternal::Completed(CredentialResponse::from_make_credential(
+                            &make_credential_response,
+                            &["usb"],
+                            "cross-platform",
+                        )),
+                    ),
+                    AuthenticatorResponse::CredentialsAsserted(get_assertion_response) => {
+                        if get_assertion_response.assertions.len() == 1 {
+                            Ok(UsbStateInternal::Completed(
+                                CredentialResponse::from_get_assertion(
+                                    &get_assertion_response.assertions[0],
+                                    "cross-platform",
+                                ),
+                            ))
+                        } else {
+                            Ok(UsbStateInternal::SelectCredential {
+                                response: get_assertion_response,
+                                cred_tx: cred_tx.clone(),
+                            })
+                        }
+                    }
+                },
+                Err(err) => Err(err),
+            },
+            None => Err(Error::Internal("USB UV handler channel closed".to_string())),
+        }
+    }
+
+    async fn process(
+        tx: Sender<UsbStateInternal>,
+        operation: UsbOperation,
+        key: Option<String>,
+    ) -> Result<(), Error> {
+        let mut state = UsbStateInternal::Idle;
+        // Replaced for each device that a ceremony is started on.
+        let (_, mut signal_rx) = mpsc::channel(256);
+        let (cred_tx, mut cred_rx) = mpsc::channel(1);
+        let mut watcher = DeviceWatcher::new();
+        debug!("polling for USB status");
+        let mut failures = 0;
+        // The authenticator operation runs in its own task; it must not outlive this one.
+        let mut _events_task = None;
+        // The device that the ceremony is running on, to notice it being unplugged.
+        let mut connected = None;
+        // act on current USB USB state, send state changes to the stream, and
+        // loop until a credential or error is returned.
+        loop {
+            tracing::debug!("current usb state: {:?}", state);
+            let prev_usb_state = state;
+            let next_usb_state = match prev_usb_state {
+                UsbStateInternal::Idle
+                | UsbStateInternal::Waiting
+                | UsbStateInternal::Disconnected => {
+                    Self::process_idle_waiting(
+                        &mut failures,
+                        &prev_usb_state,
+                        &mut watcher,
+                        key.as_deref(),
+                    )
+                    .await
+                }
+                UsbStateInternal::SelectingDevice(hid_devices) => {
+                    Self::process_selecting_device(hid_devices).await
+                }
+                UsbStateInternal::Connected(device) => {
+                    // Don't pick up messages of a ceremony on a device that was unplugged.
+                    let signal_tx;
+                    (signal_tx, signal_rx) = mpsc::channel(256);
+                    connected = Some(device.clone());
+                    let operation = operation.clone();
+                    let task = tokio::spawn(async move {
+                        match operation {
+                            UsbOperation::Ceremony(cred_request) => {
+                                handle_events(&cred_request, device, &signal_tx).await
+                            }
+                            UsbOperation::Manage { request, confirmed } => {
+                                management::handle_management(
+                                    &request, &confirmed, device, &signal_tx,
+                                )
+                                .await
+                            }
+                        }
+                    });
+                    _events_task = Some(AbortOnDrop(task.abort_handle()));
+                    Self::process_user_interaction(
+                        &mut signal_rx,
+                        &cred_tx,
+                        &mut watcher,
+                        connected.as_ref(),
+                    )
+                    .await
+                }
+                UsbStateInternal::NeedsPin { .. }
+                | UsbStateInternal::NeedsNewPin { .. }
+                | UsbStateInternal::NeedsFingerprintSample { .. }
+                | UsbStateInternal::NeedsConfirmation { .. }
+                | UsbStateInternal::NeedsReplug
+                | UsbStateInternal::NeedsUserVerification { .. }
+                | UsbStateInternal::NeedsUserPresence => {
+                    Self::process_user_interaction(
+                        &mut signal_rx,
+                        &cred_tx,
+                        &mut watcher,
+                        connected.as_ref(),
+                    )
+                    .await
+                }
+                UsbStateInternal::SelectCredential {
+                    response,
+                    cred_tx: _,
+                } => Self::process_select_credential(response, &mut cred_rx).await,
+                UsbStateInternal::Completed(_) | UsbStateInternal::Managed(_) => break Ok(()),
+                // Let the user try again by plugging the authenticator back
+                // in, or by plugging in another one.
+                UsbStateInternal::Failed(_) if connected.is_some() => {
+                    watcher.device_removed(connected.as_ref()).await;
+                    Ok(UsbStateInternal::Disconnected)
+                }
+                UsbStateInternal::Failed(err) => break Err(err),
+            };
+            state = next_usb_state.unwrap_or_else(UsbStateInternal::Failed);
+            if let UsbStateInternal::Disconnected = state {
+                _events_task = None;
+                connected = None;
+            }
+            tx.send(state.clone()).await.map_err(|_| {
+                Error::Internal("USB state channel receiver closed prematurely".to_string())
+            })?;
+        }
+    }
+}
+
+/// Waits for USB authenticators to be plugged in or out.
+///
+/// Uses udev events when they are available, and falls back to polling.
+struct DeviceWatcher {
+    monitor: Option<HotplugMonitor>,
+}
+
+impl DeviceWatcher {
+    fn new() -> Self {
+        let monitor = HotplugMonitor::new()
+            .inspect_err(|err| {
+                warn!("Failed to watch for USB authenticators, polling instead: {err}");
+            })
+            .ok();
+        Self { monitor }
+    }
+
+    /// Returns when a device may have been plugged in.
+    async fn device_added(&mut self) {
+        if let Some(monitor) = &mut self.monitor {
+            match monitor.added().await {
+                Ok(()) => return,
+                Err(err) => {
+                    warn!("Failed to watch for USB authenticators, polling instead: {err}");
+                    self.monitor = None;
+                }
+            }
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    /// Returns when the device is unplugged, or is already gone. Never
+    /// returns for devices without a hidraw node.
+    async fn device_removed(&mut self, device: Option<&HidDevice>) {
+        let Some(name) = device.and_then(hidraw_name) else {
+            return std::future::pending().await;
+        };
+        if let Some(monitor) = &mut self.monitor {
+            match monitor.removed(&name).await {
+                Ok(()) => return,
+                Err(err) => {
+                    warn!("Failed to watch for USB authenticators, polling instead: {err}");
+                    self.monitor = None;
+                }
+            }
+        }
+        loop {
+            if let Ok(devices) = libwebauthn::transport::hid::list_devices().await {
+                if !devices
+                    .iter()
+                    .any(|device| hidraw_name(device).as_ref() == Some(&name))
+                {
+                    return;
+                }
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Cancels the ongoing operations on HID channels when dropped, e.g. when the
+/// request was fulfilled by another transport, so that the authenticators stop
+/// waiting for the user.
+struct CancelOnDrop(Vec<HidChannelHandle>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        for handle in self.0.drain(..) {
+            // This only queues the cancellation for the channel.
+            futures_lite::future::block_on(handle.cancel_ongoing_operation());
+        }
+    }
+}
+
+/// Offers a plugged-in security key as a device of its own, identified by
+/// its hidraw node and named after its HID manufacturer and product names.
+fn key_device(device: &HidDevice) -> Option<TransportDevice> {
+    match &device.backend {
+        HidBackendDevice::HidApiDevice(info) => Some(TransportDevice {
+            id: hidraw_name(device)?,
+            manufacturer: info.manufacturer_string().map(str::to_string),
+            product: info.product_string().map(str::to_string),
+            ..Default::default()
+        }),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }
+}
+
+/// Returns the name of the hidraw node of a device, e.g. `hidraw3`.
+fn hidraw_name(device: &HidDevice) -> Option<String> {
+    match &device.backend {
+        HidBackendDevice::HidApiDevice(info) => {
+            let path = info.path().to_str().ok()?;
+            Some(Path::new(path).file_name()?.to_str()?.to_string())
+        }
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }
+}
+
+async fn handle_events(
+    cred_request: &CredentialRequest,
+    mut device: HidDevice,
+    signal_tx: &Sender<Result<UsbUvMessage, Error>>,
+) {
+    let device_debug = device.to_string();
+    match device.channel().await {
+        Err(err) => {
+            tracing::error!("Failed to open channel to USB authenticator, cannot receive user verification events: {:?}", err);
+            if let Err(err) = signal_tx.send(Err(Error::AuthenticatorError)).await {
+                tracing::error!(
+                    "Failed to notify that the channel failed to open: {:?}",
+                    err
+                );
+            }
+        }
+        Ok(mut channel) => {
+            // Dropped before the channel, if the task is aborted.
+            let _cancel_guard = CancelOnDrop(vec![channel.get_handle()]);
+            let signal_tx2 = signal_tx.clone().downgrade();
+            let ux_updates_rx = channel.get_ux_update_receiver();
+            tokio::spawn(async move {
+                handle_usb_updates(&signal_tx2, ux_updates_rx).await;
+                debug!("Reached end of USB update task");
+            });
+            tracing::debug!(
+                "Polling for credential from USB authenticator {}",
+                &device_debug
+            );
+            let response: Result<UsbUvMessage, Error> = loop {
+                let response = match cred_request {
+                    CredentialRequest::CreatePublicKeyCredentialRequest(make_cred_request) => {
+                        let Some(make_cred_request) = libwebauthn_request(make_cred_request) else {
+                            break Err(WebAuthnError::Ctap(CtapError::UnsupportedAlgorithm));
+                        };
+                        channel
+                            .webauthn_make_credential(&make_cred_request)
+                            .await
+                            .map(|response| {
+                                UsbUvMessage::ReceivedCredentials(Box::new(response.into()))
+                            })
+                    }
+                    CredentialRequest::GetPublicKeyCredentialRequest(get_cred_request) => channel
+                        .webauthn_get_assertion(get_cred_request)
+                        .await
+                        .map(|response| {
+                            UsbUvMessage::ReceivedCredentials(Box::new(response.into()))
+                        }),
+                };
+                match response {
+                    Ok(response) => {
+                        tracing::debug!("Received credential from USB authenticator");
+                        break Ok(response);
+                    }
+                    Err(WebAuthnError::Ctap(ctap_error))
+                        if ctap_error.is_retryable_user_error() =>
+                    {
+                        warn!("Retrying WebAuthn credential operation");
+                        continue;
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            "Failed to make/get credential with USB authenticator: {:?}",
+                            err
+                        );
+                        break Err(err);
+                    }
+                }
+            }
+            .map_err(webauthn_error);
+            if let Err(err) = signal_tx.send(response).await {
+                tracing::error!("Failed to notify that ceremony completed: {:?}", err);
+            }
+        }
+    }
+}
+
+/// Maps errors of libwebauthn to those shown to the user.
+fn webauthn_error(err: WebAuthnError) -> Error {
+    match err {
+        WebAuthnError::Ctap(CtapError::PINAuthBlocked) => Error::PinAttemptsExhausted,
+        WebAuthnError::Ctap(CtapError::PINPolicyViolation) => Error::PinChangeRequired,
+        WebAuthnError::Ctap(CtapError::NoCredentials) => Error::NoCredentials,
+        WebAuthnError::Ctap(CtapError::CredentialExcluded) => Error::CredentialExcluded,
+        _ => Error::AuthenticatorError,
+    }
+}
+
+impl InProcessUsbHandler {
+    /// Runs the operation on the security key of the device, or on the one
+    /// that the user picks if the device stands for any key, streaming its
+    /// states until it completes.
+    fn run(operation: UsbOperation, device_id: &str) -> AuthenticatorStates {
+        let key = (!device_id.is_empty()).then(|| device_id.to_string());
+        let (tx, mut rx) = mpsc::channel(32);
+        let task = tokio::spawn(async move {
+            // TODO: instead of logging error here, push the errors into the
+            // stream so credential service can handle/forward them to the UI
+            if let Err(err) = InProcessUsbHandler::process(tx, operation, key).await {
+                tracing::error!("Error getting credential from USB: {:?}", err);
+            }
+        });
+        let task = AbortOnDrop(task.abort_handle());
+        Box::pin(stream! {
+            let _task = task;
+            while let Some(state) = rx.recv().await {
+                yield state.into()
+            }
+        })
+    }
+}
+
+impl AuthenticatorTransport for InProcessUsbHandler {
+    fn transport(&self) -> Transport {
+        Transport::Usb
+    }
+
+    /// Lists each plugged-in security key, so that the user can tell them
+    /// apart. If there is none, a single device waits for any key to be
+    /// plugged in.
+    fn devices(&self) -> Vec<TransportDevice> {
+        // hidapi enumerates devices right away, without waiting on them.
+        let keys = futures_lite::future::block_on(libwebauthn::transport::hid::list_devices())
+            .inspect_err(|err| warn!("Failed to list USB authenticators: {err:?}"))
+            .unwrap_or_default();
+        let devices: Vec<_> = keys.iter().filter_map(key_device).collect();
+        if devices.is_empty() {
+            vec![TransportDevice::default()]
+        } else {
+            devices
+        }
+    }
+
+    /// Keys may be unplugged after they were listed, and plugged in again
+    /// before they are started, so any of them can be started and is waited
+    /// for.
+    fn is_available(&self, device_id: &str) -> bool {
+        device_id.is_empty() || device_id.starts_with("hidraw")
+    }
+
+    fn start(&self, device_id: &str, request: &CredentialRequest) -> AuthenticatorStates {
+        Self::run(UsbOperation::Ceremony(Box::new(request.clone())), device_id)
+    }
+
+    fn can_manage(&self, _request: &ManagementRequest) -> bool {
+        true
+    }
+
+    fn manage(
+        &self,
+        device_id: &str,
+        request: &ManagementRequest,
+    ) -> Result<AuthenticatorStates, Error> {
+        let operation = UsbOperation::Manage {
+            request: request.clone(),
+            confirmed: Arc::new(AtomicBool::new(false)),
+        };
+        Ok(Self::run(operation, device_id))
+    }
+}
+
+/// Used to share internal state between handler and credential service
+#[derive(Clone, Debug, Default)]
+pub(super) enum UsbStateInternal {
+    /// Not polling for FIDO USB device.
+    #[default]
+    Idle,
+
+    /// Awaiting FIDO USB device to be plugged in.
+    Waiting,
+
+    /// When we encounter multiple devices, we let all of them blink and continue
+    /// with the one that was tapped.
+    SelectingDevice(Vec<HidDevice>),
+
+    /// USB device connected, prompt user to tap
+    Connected(HidDevice),
+
+    /// The device needs the PIN to be entered.
+    NeedsPin {
+        attempts_left: Option<u32>,
+        pin_tx: mpsc::Sender<String>,
+    },
+
+    /// The user has to choose a new PIN for the device.
+    NeedsNewPin {
+        min_length: u32,
+        must_change: bool,
+        pin_tx: mpsc::Sender<String>,
+    },
+
+    /// The device waits for a sample of a fingerprint to enroll.
+    NeedsFingerprintSample {
+        remaining_samples: Option<u32>,
+        last_sample: Option<FingerprintSampleStatus>,
+    },
+
+    /// The user has to confirm a change to the device.
+    NeedsConfirmation {
+        change: SecurityKeyChange,
+        confirm_tx: mpsc::Sender<()>,
+    },
+
+    /// The user has to unplug the device and plug it back in.
+    NeedsReplug,
+
+    /// The device needs on-device user verification.
+    NeedsUserVerification { attempts_left: Option<u32> },
+
+    /// The device needs evidence of user presence (e.g. touch) to release the credential.
+    NeedsUserPresence,
+
+    /// Multiple credentials have been found and the user has to select which to use
+    SelectCredential {
+        response: GetAssertionResponse,
+        cred_tx: mpsc::Sender<String>,
+    },
+
+    /// USB tapped, received credential
+    Completed(CredentialResponse),
+
+    /// A management request completed.
+    Managed(ManagementResponse),
+
+    /// There was an error while interacting with the authenticator.
+    Failed(Error),
+
+    /// The device was unplugged before the ceremony completed.
+    Disconnected,
+    // TODO: implement cancellation
+    // This isn't actually sent from the server.
+    //UserCancelled,
+}
+
+impl From<UsbStateInternal> for AuthenticatorState {
+    fn from(value: UsbStateInternal) -> Self {
+        match value {
+            UsbStateInternal::Idle => AuthenticatorState::Idle,
+            UsbStateInternal::Waiting => AuthenticatorState::Waiting,
+            UsbStateInternal::Connected(_) => AuthenticatorState::Connected,
+            UsbStateInternal::NeedsPin {
+                attempts_left,
+                pin_tx,
+            } => AuthenticatorState::NeedsPin {
+                attempts_left,
+                pin_tx,
+            },
+            UsbStateInternal::NeedsUserVerification { attempts_left } => {
+                AuthenticatorState::NeedsUserVerification { attempts_left }
+            }
+            UsbStateInternal::NeedsNewPin {
+                min_length,
+                must_change,
+                pin_tx,
+            } => AuthenticatorState::NeedsNewPin {
+                min_length,
+                must_change,
+                pin_tx,
+            },
+            UsbStateInternal::NeedsFingerprintSample {
+                remaining_samples,
+                last_sample,
+            } => AuthenticatorState::NeedsFingerprintSample {
+                remaining_samples,
+                last_sample,
+            },
+            UsbStateInternal::NeedsConfirmation { change, confirm_tx } => {
+                AuthenticatorState::NeedsConfirmation { change, confirm_tx }
+            }
+            UsbStateInternal::NeedsReplug => AuthenticatorState::NeedsReplug,
+            UsbStateInternal::NeedsUserPresence => AuthenticatorState::NeedsUserPresence,
+            UsbStateInternal::Completed(response) => AuthenticatorState::Completed(response),
+            UsbStateInternal::Managed(response) => AuthenticatorState::Managed(Box::new(response)),
+            // UsbStateInternal::UserCancelled => AuthenticatorState:://UserCancelled,
+            UsbStateInternal::SelectingDevice(_) => AuthenticatorState::SelectingDevice,
+            UsbStateInternal::SelectCredential { response, cred_tx } => {
+                AuthenticatorState::SelectCredential { response, cred_tx }
+            }
+            UsbStateInternal::Failed(err) => AuthenticatorState::Failed(err),
+            UsbStateInternal::Disconnected => AuthenticatorState::Disconnected,
+        }
+    }
+}
+
+/// Lists the credentials of a response for the user to select from.
+pub(super) fn list_credentials(response: &GetAssertionResponse) -> Vec<Credential> {
+    response
+        .assertions
+        .iter()
+        .map(|x| Credential {
+            id: x
+                .credential_id
+                .as_ref()
+                .map(|i| {
+                    // In order to not expose the credential ID to the untrusted UI components,
+                    // we hash and then encode it into a String.
+                    URL_SAFE_NO_PAD.encode(ring::digest::digest(&ring::digest::SHA256, &i.id))
+                })
+                .unwrap(),
+
+            name: x
+                .user
+                .as_ref()
+                .and_then(|u| u.name.clone())
+                .unwrap_or_else(|| String::from("<unknown>")),
+            username: x
+                .user
+                .as_ref()
+                .map(|u| u.display_name.clone())
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Finds the assertion for a credential selected from [`list_credentials`].
+pub(super) fn find_selected_assertion(
+    response: &GetAssertionResponse,
+    cred_id: &str,
+) -> Option<Assertion> {
+    response
+        .assertions
+        .iter()
+        .find(|c| {
+            c.credential_id
+                .as_ref()
+                .map(|c| {
+                    // In order to not expose the credential ID to the untrusted UI component,
+                    // we hashed it, before sending it. So we have to re-hash all our credential
+                    // IDs to identify the selected one.
+                    URL_SAFE_NO_PAD.encode(ring::digest::digest(&ring::digest::SHA256, &c.id))
+                        == cred_id
+                })
+                .unwrap_or_default()
+        })
+        .cloned()
+}
+
+async fn handle_usb_updates(
+    signal_tx: &WeakSender<Result<UsbUvMessage, Error>>,
+    mut state_rx: broadcast::Receiver<UvUpdate>,
+) {
+    while let Ok(msg) = state_rx.recv().await {
+        let signal_tx = match signal_tx.upgrade() {
+            Some(tx) => tx,
+            None => break,
+        };
+        match msg {
+            UvUpdate::UvRetry { attempts_left } => {
+                if let Err(err) = signal_tx
+                    .send(Ok(UsbUvMessage::NeedsUserVerification { attempts_left }))
+                    .await
+                {
+                    tracing::error!("Authenticator requested user verficiation, but we cannot relay the message to credential service: {:?}", err);
+                }
+            }
+            UvUpdate::PinRequired(pin_update) => {
+                let (pin_tx, mut pin_rx) = mpsc::channel(1);
+                if let Err(err) = signal_tx
+                    .send(Ok(UsbUvMessage::NeedsPin {
+                        pin_tx,
+                        attempts_left: pin_update.attempts_left,
+                    }))
+                    .await
+                {
+                    tracing::error!("Authenticator requested a PIN from the user, but we cannot relay the message to the credential service: {:?}", err);
+                }
+                match pin_rx.recv().await {
+                    Some(pin) => match pin_update.send_pin(&pin) {
+                        Ok(()) => {}
+                        Err(err) => tracing::error!("Error sending pin to device: {:?}", err),
+                    },
+                    None => tracing::debug!("Pin channel closed before receiving pin from client."),
+                }
+            }
+            UvUpdate::PresenceRequired => {
+                if let Err(err) = signal_tx.send(Ok(UsbUvMessage::NeedsUserPresence)).await {
+                    tracing::error!("Authenticator requested user presence, but we cannot relay the message to the credential service: {:?}", err);
+                }
+            }
+        }
+    }
+    debug!("USB update channel closed.");
+}
+
+/// Messages sent between USB authenticator and handler for UV
+enum UsbUvMessage {
+    NeedsPin {
+        attempts_left: Option<u32>,
+        pin_tx: mpsc::Sender<String>,
+    },
+    NeedsUserVerification {
+        attempts_left: Option<u32>,
+    },
+    NeedsUserPresence,
+    ReceivedCredentials(Box<AuthenticatorResponse>),
+    /// The user has to choose a new PIN.
+    NeedsNewPin {
+        min_length: u32,
+        must_change: bool,
+        pin_tx: mpsc::Sender<String>,
+    },
+    /// The key waits for the next sample of a fingerprint to enroll.
+    NeedsFingerprintSample {
+        remaining_samples: Option<u32>,
+        last_sample: Option<FingerprintSampleStatus>,
+    },
+    /// The user has to confirm a change to the key.
+    NeedsConfirmation {
+        change: SecurityKeyChange,
+        confirm_tx: mpsc::Sender<()>,
+    },
+    /// The key only accepts the request after it has been plugged back in.
+    NeedsReplug,
+    Managed(Box<ManagementResponse>),
+}
@@ -2,7 +2,11 @@
 //! the credential request through the trusted UI.
 
 use std::future::Future;
-use std::{collections::VecDeque, fmt::Debug, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    sync::Arc,
+};
 
 use credentialsd_common::model::{
     BackgroundEvent, CredentialRequest, CredentialResponse, Error as CredentialServiceError,
@@ -26,25 +30,23 @@ use zbus::{
 };
 
 use crate::credential_service::{
-    hybrid::{HybridHandler, HybridState},
-    usb::UsbHandler,
-    CredentialService, UiController, UsbState,
+    management::{ManagementRequest, ManagementResponse},
+    platform::{TransferRequest, TransferResponse},
+    AuthenticatorState, CredentialService, UiController,
 };
 pub const SERVICE_PATH: &str = "/xyz/iinuwa/credentialsd/FlowControl";
 pub const SERVICE_NAME: &str = "xyz.iinuwa.credentialsd.FlowControl";
 
-pub async fn start_flow_control_service<
-    H: HybridHandler + Debug + Send + Sync + 'static,
-    U: UsbHandler + Debug + Send + Sync + 'static,
-    UC: UiController + Debug + Send + Sync + 'static,
->(
-    credential_service: CredentialService<H, U, UC>,
+pub async fn start_flow_control_service<UC: UiController + Debug + Send + Sync + 'static>(
+    credential_service: CredentialService<UC>,
 ) -> zbus::Result<(
     Connection,
     Sender<(
         CredentialRequest,
         oneshot::Sender<Result<CredentialResponse, CredentialServiceError>>,
     )>,
+    TransferInitiator,
+    ManagementInitiator,
 )> {
     let svc = Arc::new(AsyncMutex::new(credential_service));
     let svc2 = svc.clone();
@@ -55,31 +57,162 @@ pub async fn start_flow_control_service<
             FlowControlService {
                 signal_state: Arc::new(AsyncMutex::new(SignalState::Idle)),
                 svc,
-                usb_pin_tx: Arc::new(AsyncMutex::new(None)),
-                usb_cred_tx: Arc::new(AsyncMutex::new(None)),
-                usb_event_forwarder_task: Arc::new(AsyncMutex::new(None)),
-                hybrid_event_forwarder_task: Arc::new(AsyncMutex::new(None)),
+                pin_tx: Arc::new(AsyncMutex::new(HashMap::new())),
+                cred_tx: Arc::new(AsyncMutex::new(HashMap::new())),
+                password_tx: Arc::new(AsyncMutex::new(None)),
+                confirm_tx: Arc::new(AsyncMutex::new(None)),
+                device_event_forwarder_task: Arc::new(AsyncMutex::new(None)),
             },
         )?
         .build()
         .await?;
     let (initiator_tx, mut initiator_rx) = mpsc::channel(2);
+    let svc3 = svc2.clone();
+    let svc4 = svc2.clone();
     tokio::spawn(async move {
         let svc = svc2;
         while let Some((msg, tx)) = initiator_rx.recv().await {
             svc.lock().await.init_request(&msg, tx).await;
         }
     });
-    Ok((conn, initiator_tx))
+    let (transfer_tx, mut transfer_rx) = mpsc::channel(2);
+    tokio::spawn(async move {
+        let svc = svc3;
+        while let Some((request, tx)) = transfer_rx.recv().await {
+            svc.lock().await.init_transfer(request, tx).await;
+        }
+    });
+    let (management_tx, mut management_rx) = mpsc::channel(2);
+    tokio::spawn(async move {
+        let svc = svc4;
+        while let Some((request, tx)) = management_rx.recv().await {
+            svc.lock().await.init_management(request, tx).await;
+        }
+    });
+    Ok((conn, initiator_tx, transfer_tx, management_tx))
 }
 
-struct FlowControlService<H: HybridHandler, U: UsbHandler, UC: UiController> {
+/// Starts transfers of platform passkeys.
+pub type TransferInitiator = Sender<(
+    TransferRequest,
+    oneshot::Sender<Result<TransferResponse, CredentialServiceError>>,
+)>;
+
+/// Starts management requests of security keys.
+pub type ManagementInitiator = Sender<(
+    ManagementRequest,
+    oneshot::Sender<Result<ManagementResponse, CredentialServiceError>>,
+)>;
+
+struct FlowControlService<UC: UiController> {
     signal_state: Arc<AsyncMutex<SignalState>>,
-    svc: Arc<AsyncMutex<CredentialService<H, U, UC>>>,
-    usb_pin_tx: Arc<AsyncMutex<Option<Sender<String>>>>,
-    usb_cred_tx: Arc<AsyncMutex<Option<Sender<String>>>>,
-    usb_event_forwarder_task: Arc<AsyncMutex<Option<AbortHandle>>>,
-    hybrid_event_forwarder_task: Arc<AsyncMutex<Option<AbortHandle>>>,
+    svc: Arc<AsyncMutex<CredentialService<UC>>>,
+    /// Senders for the PIN of each device waiting for one, by device ID.
+    pin_tx: Arc<AsyncMutex<HashMap<String, Sender<String>>>>,
+    /// Senders for the credential chosen on each device waiting for one, by
+    /// device ID.
+    cred_tx: Arc<AsyncMutex<HashMap<String, Sender<String>>>>,
+    /// Only the platform authenticator asks for a password, and it is never
+    /// raced with other devices.
+    password_tx: Arc<AsyncMutex<Option<Sender<String>>>>,
+    /// Only security keys being managed ask for a confirmation, and a single
+    /// one is managed at a time.
+    confirm_tx: Arc<AsyncMutex<Option<Sender<()>>>>,
+    device_event_forwarder_task: Arc<AsyncMutex<Option<AbortHandle>>>,
+}
+
+impl<UC> FlowControlService<UC>
+where
+    UC: UiController + Debug + Send + Sync + 'static,
+{
+    /// Starts the transports for the given devices together, and forwards
+    /// their state updates to the UI.
+    ///
+    /// The previously selected devices are cancelled first: the forwarder task
+    /// owns the transports' event streams, so aborting it stops the transports.
+    async fn start_devices(
+        &self,
+        device_ids: &[String],
+        object_server: &ObjectServer,
+    ) -> fdo::Result<()> {
+        let mut forwarder_task = self.device_event_forwarder_task.lock().await;
+        if let Some(prev_task) = forwarder_task.take() {
+            prev_task.abort();
+        }
+        self.pin_tx.lock().await.clear();
+        self.cred_tx.lock().await.clear();
+        self.password_tx.lock().await.take();
+        self.confirm_tx.lock().await.take();
+        let mut stream = self
+            .svc
+            .lock()
+            .await
+            .start_devices(device_ids)
+            .map_err(|err| fdo::Error::Failed(err.to_string()))?;
+        let pin_tx_by_device = self.pin_tx.clone();
+        let cred_tx_by_device = self.cred_tx.clone();
+        let password_tx = self.password_tx.clone();
+        let confirm_tx = self.confirm_tx.clone();
+        let signal_state = self.signal_state.clone();
+        let object_server = object_server.clone();
+        let task = tokio::spawn(async move {
+            let interface: zbus::Result<InterfaceRef<FlowControlService<UC>>> =
+                object_server.interface(SERVICE_PATH).await;
+
+            let emitter = match interface {
+                Ok(ref i) => i.signal_emitter(),
+                Err(err) => {
+                    tracing::error!("Failed to get connection to D-Bus to send signals: {err}");
+                    return;
+                }
+            };
+            // Ends when a device completes, or all of them gave up.
+            while let Some((device_id, state)) = stream.next().await {
+                let event = BackgroundEvent::DeviceStateChanged {
+                    device_id: device_id.clone(),
+                    state: (&state).into(),
+                };
+                if let Err(err) = send_state_update(emitter, &signal_state, event).await {
+                    tracing::error!("Failed to send state update to UI: {err}");
+                    break;
+                }
+                match state.state {
+                    AuthenticatorState::NeedsPin { pin_tx, .. }
+                    | AuthenticatorState::NeedsNewPin { pin_tx, .. } => {
+                        pin_tx_by_device.lock().await.insert(device_id, pin_tx);
+                    }
+                    AuthenticatorState::SelectCredential { cred_tx, .. } => {
+                        cred_tx_by_device.lock().await.insert(device_id, cred_tx);
+                    }
+                    AuthenticatorState::NeedsFingerprint {
+                        password_tx: Some(tx),
+                        ..
+                    }
+                    | AuthenticatorState::NeedsPassword {
+                        password_tx: tx, ..
+                    } => {
+                        let mut password_tx = password_tx.lock().await;
+                        let _ = password_tx.insert(tx);
+                    }
+                    AuthenticatorState::NeedsConfirmation { confirm_tx: tx, .. } => {
+                        let mut confirm_tx = confirm_tx.lock().await;
+                        let _ = confirm_tx.insert(tx);
+                    }
+                    // The device no longer listens for what it asked for.
+                    AuthenticatorState::Waiting
+                    | AuthenticatorState::Failed(_)
+                    | AuthenticatorState::Disconnected => {
+                        pin_tx_by_device.lock().await.remove(&device_id);
+                        cred_tx_by_device.lock().await.remove(&device_id);
+                    }
+                    _ => {}
+                };
+            }
+        })
+        .abort_handle();
+        *forwarder_task = Some(task);
+        Ok(())
+    }
 }
 
 /// The following methods are for communication between the [trusted]
@@ -93,10 +226,8 @@ struct FlowControlService<H: HybridHandler, U: UsbHandler, UC: UiController> {
         default_service = "xyz.iinuwa.credentialsd.FlowControl",
     )
 )]
-impl<H, U, UC> FlowControlService<H, U, UC>
+impl<UC> FlowControlService<UC>
 where
-    H: HybridHandler + Debug + Send + Sync + 'static,
-    U: UsbHandler + Debug + Send + Sync + 'static,
     UC: UiController + Debug + Send + Sync + 'static,
 {
     async fn subscribe(
@@ -130,131 +261,113 @@ where
         Ok(dbus_devices)
     }
 
-    async fn get_hybrid_credential(
+    async fn select_device(
         &self,
+        device_id: String,
         #[zbus(object_server)] object_server: &ObjectServer,
     ) -> fdo::Result<()> {
-        let svc = self.svc.lock().await;
-        let mut stream = svc.get_hybrid_credential();
-        let signal_state = self.signal_state.clone();
-        let object_server = object_server.clone();
-        let task = tokio::spawn(async move {
-            let interface: zbus::Result<InterfaceRef<FlowControlService<H, U, UC>>> =
-                object_server.interface(SERVICE_PATH).await;
-
-            let emitter = match interface {
-                Ok(ref i) => i.signal_emitter(),
-                Err(err) => {
-                    tracing::error!("Failed to get connection to D-Bus to send signals: {err}");
-                    return;
-                }
-            };
-            while let Some(state) = stream.next().await {
-                let event = credentialsd_common::model::BackgroundEvent::HybridQrStateChanged(
-                    state.clone().into(),
-                )
-                .try_into();
-                match event {
-                    Err(err) => {
-                        tracing::error!("Failed to serialize state update: {err}");
-                        break;
-                    }
-                    Ok(event) => match send_state_update(emitter, &signal_state, event).await {
-                        Ok(_) => {}
-                        Err(err) => {
-                            tracing::error!("Failed to send state update to UI: {err}");
-                            break;
-                        }
-                    },
-                }
-                match state {
-                    HybridState::Completed | HybridState::Failed => {
-                        break;
-                    }
-                    _ => {}
-                };
-            }
-        })
-        .abort_handle();
-        if let Some(prev_task) = self.hybrid_event_forwarder_task.lock().await.replace(task) {
-            prev_task.abort();
-        }
-        Ok(())
+        self.start_devices(&[device_id], object_server).await
     }
 
-    async fn get_usb_credential(
+    async fn select_devices(
         &self,
+        device_ids: Vec<String>,
         #[zbus(object_server)] object_server: &ObjectServer,
     ) -> fdo::Result<()> {
-        let mut stream = self.svc.lock().await.get_usb_credential();
-        let usb_pin_tx = self.usb_pin_tx.clone();
-        let usb_cred_tx = self.usb_cred_tx.clone();
-        let signal_state = self.signal_state.clone();
-        let object_server = object_server.clone();
-        let task = tokio::spawn(async move {
-            let interface: zbus::Result<InterfaceRef<FlowControlService<H, U, UC>>> =
-                object_server.interface(SERVICE_PATH).await;
+        self.start_devices(&device_ids, object_server).await
+    }
 
-            let emitter = match interface {
-                Ok(ref i) => i.signal_emitter(),
-                Err(err) => {
-                    tracing::error!("Failed to get connection to D-Bus to send signals: {err}");
-                    return;
-                }
-            };
-            while let Some(state) = stream.next().await {
-                match credentialsd_common::model::BackgroundEvent::UsbStateChanged((&state).into())
-                    .try_into()
-                {
-                    Err(err) => {
-                        tracing::error!("Failed to serialize state update: {err}");
-                        break;
-                    }
-                    Ok(event) => match send_state_update(emitter, &signal_state, event).await {
-                        Ok(_) => {}
-                        Err(err) => {
-                            tracing::error!("Failed to send state update to UI: {err}");
-                            break;
-                        }
-                    },
-                };
-                match state {
-                    UsbState::NeedsPin { pin_tx, .. } => {
-                        let mut usb_pin_tx = usb_pin_tx.lock().await;
-                        let _ = usb_pin_tx.insert(pin_tx);
-                    }
-                    UsbState::SelectCredential { cred_tx, .. } => {
-                        let mut usb_cred_tx = usb_cred_tx.lock().await;
-                        let _ = usb_cred_tx.insert(cred_tx);
-                    }
-                    UsbState::Completed | UsbState::Failed(_) => {
-                        break;
-                    }
-                    _ => {}
-                };
-            }
-        })
-        .abort_handle();
-        if let Some(prev_task) = self.usb_event_forwarder_task.lock().await.replace(task) {
-            prev_task.abort();
+    async fn enter_client_pin(&self, device_id: String, pin: String) -> fdo::Result<()> {
+        if let Some(pin_tx) = self.pin_tx.lock().await.remove(&device_id) {
+            // The security key may have been unplugged in the meantime.
+            let _ = pin_tx.send(pin).await;
+        }
+        Ok(())
+    }
+
+    async fn enter_password(&self, password: String) -> fdo::Result<()> {
+        if let Some(password_tx) = self.password_tx.lock().await.take() {
+            // The platform authenticator may have given up on the password
+            // already, e.g. because the fingerprint matched in the meantime.
+            let _ = password_tx.send(password).await;
         }
         Ok(())
     }
 
-    async fn enter_client_pin(&self, pin: String) -> fdo::Result<()> {
-        if let Some(pin_tx) = self.usb_pin_tx.lock().await.take() {
-            pin_tx.send(pin).await.unwrap();
+    async fn select_credential(&self, device_id: String, credential_id: String) -> fdo::Result<()> {
+        if let Some(cred_tx) = self.cred_tx.lock().await.remove(&device_id) {
+            // The device may have given up in the meantime.
+            let _ = cred_tx.send(credential_id).await;
         }
         Ok(())
     }
 
-    async fn select_credential(&self, credential_id: String) -> fdo::Result<()> {
-        if let Some(cred_tx) = self.usb_cred_tx.lock().await.take() {
-            cred_tx.send(credential_id).await.unwrap();
+    async fn confirm_change(&self) -> fdo::Result<()> {
+        if let Some(confirm_tx) = self.confirm_tx.lock().await.take() {
+            // The security key may have been unplugged in the meantime.
+            let _ = confirm_tx.send(()).await;
         }
         Ok(())
     }
 
+    async fn rename_device(&self, device_id: String, name: String) -> fdo::Result<()> {
+        self.svc
+            .lock()
+            .await
+            .rename_device(&device_id, &name)
+            .map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+
+    async fn forget_device(&self, device_id: String) -> fdo::Result<()> {
+        self.svc
+            .lock()
+            .await
+            .forget_device(&device_id)
+            .map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+
+    /// Lists the installed credential providers as (device ID, name, enabled).
+    async fn get_credential_providers(&self) -> fdo::Result<Vec<(String, String, bool)>> {
+        let providers = self.svc.lock().await.credential_providers();
+        Ok(providers
+            .into_iter()
+            .map(|(device, enabled)| (device.id, device.name.unwrap_or_default(), enabled))
+            .collect())
+    }
+
+    async fn set_credential_provider_enabled(
+        &self,
+        device_id: String,
+        enabled: bool,
+    ) -> fdo::Result<()> {
+        self.svc
+            .lock()
+            .await
+            .set_credential_provider_enabled(&device_id, enabled)
+            .map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+
+    /// Lists the relying parties whose passkeys the current transfer exports,
+    /// or imports from a plain archive.
+    async fn get_transfer_summary(&self) -> fdo::Result<Vec<String>> {
+        self.svc
+            .lock()
+            .await
+            .transfer_summary()
+            .map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+
+    /// Completes the current transfer with the passphrase of the archive,
+    /// returning the number of passkeys transferred.
+    async fn confirm_transfer(&self, passphrase: String) -> fdo::Result<u32> {
+        self.svc
+            .lock()
+            .await
+            .confirm_transfer(passphrase)
+            .await
+            .map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+
     async fn cancel_request(&self, request_id: RequestId) -> fdo::Result<()> {
         self.svc.lock().await.cancel_request(request_id).await;
         Ok(())
@@ -302,6 +415,16 @@ pub trait CredentialRequestController {
         &self,
         request: CredentialRequest,
     ) -> impl Future<Output = Result<CredentialResponse, WebAuthnError>> + Send;
+
+    fn request_transfer(
+        &self,
+        request: TransferRequest,
+    ) -> impl Future<Output = Result<TransferResponse, WebAuthnError>> + Send;
+
+    fn request_management(
+        &self,
+        request: ManagementRequest,
+    ) -> impl Future<Output = Result<ManagementResponse, WebAuthnError>> + Send;
 }
 
 pub struct CredentialRequestControllerClient {
@@ -309,6 +432,8 @@ pub struct CredentialRequestControllerClient {
         CredentialRequest,
         oneshot::Sender<Result<CredentialResponse, CredentialServiceError>>,
     )>,
+    pub transfers: TransferInitiator,
+    pub management: ManagementInitiator,
 }
 
 impl CredentialRequestController for CredentialRequestControllerClient {
@@ -333,11 +458,46 @@ impl CredentialRequestController for CredentialRequestControllerClient {
         // For now, just squashing.
         response.map_err(|_| WebAuthnError::NotAllowedError)
     }
+
+    async fn request_transfer(
+        &self,
+        request: TransferRequest,
+    ) -> Result<TransferResponse, WebAuthnError> {
+        let (tx, rx) = oneshot::channel();
+        self.transfers.send((request, tx)).await.unwrap();
+        let response = rx.await.map_err(|_| {
+            tracing::error!("Transfer response channel closed prematurely");
+            WebAuthnError::NotAllowedError
+        })?;
+        // Like credential requests, the user cancelling and every failure
+        // look the same to the client.
+        response.map_err(|err| {
+            tracing::info!("Transfer of platform passkeys failed: {err}");
+            WebAuthnError::NotAllowedError
+        })
+    }
+
+    async fn request_management(
+        &self,
+        request: ManagementRequest,
+    ) -> Result<ManagementResponse, WebAuthnError> {
+        let (tx, rx) = oneshot::channel();
+        self.management.send((request, tx)).await.unwrap();
+        let response = rx.await.map_err(|_| {
+            tracing::error!("Management response channel closed prematurely");
+            WebAuthnError::NotAllowedError
+        })?;
+        response.map_err(|err| {
+            tracing::info!("Management request failed: {err}");
+            WebAuthnError::NotAllowedError
+        })
+    }
 }
 
 #[cfg(test)]
 pub mod test {
     use std::{
+        collections::HashMap,
         error::Error,
         fmt::Debug,
         pin::Pin,
@@ -352,20 +512,15 @@ pub mod test {
     use futures_lite::{Stream, StreamExt};
     use tokio::sync::{mpsc, oneshot, Mutex as AsyncMutex};
 
-    use crate::credential_service::{
-        hybrid::{HybridHandler, HybridState},
-        usb::UsbHandler,
-        CredentialService, UiController, UsbState,
-    };
+    use crate::credential_service::{AuthenticatorState, CredentialService, UiController};
 
     #[allow(clippy::enum_variant_names)]
     #[derive(Debug)]
     pub enum DummyFlowRequest {
-        EnterClientPin(String),
+        EnterClientPin(String, String),
         GetDevices,
-        GetHybridCredential,
-        GetUsbCredential,
         InitStream,
+        SelectDevice(String),
     }
 
     // Clippy complains that these variant names have the same prefix, but that's
@@ -374,9 +529,8 @@ pub mod test {
     pub enum DummyFlowResponse {
         EnterClientPin(Result<(), ()>),
         GetDevices(Vec<Device>),
-        GetHybridCredential,
-        GetUsbCredential,
         InitStream(Result<Pin<Box<dyn Stream<Item = BackgroundEvent> + Send + 'static>>, ()>),
+        SelectDevice(Result<(), ()>),
     }
 
     impl Debug for DummyFlowResponse {
@@ -384,12 +538,11 @@ pub mod test {
             match self {
                 Self::EnterClientPin(arg0) => f.debug_tuple("EnterClientPin").field(arg0).finish(),
                 Self::GetDevices(arg0) => f.debug_tuple("GetDevices").field(arg0).finish(),
-                Self::GetHybridCredential => f.debug_tuple("GetHybridCredential").finish(),
-                Self::GetUsbCredential => f.debug_tuple("GetUsbCredential").finish(),
                 Self::InitStream(_) => f
                     .debug_tuple("InitStream")
                     .field(&String::from("<BackgroundEventStream>"))
                     .finish(),
+                Self::SelectDevice(arg0) => f.debug_tuple("SelectDevice").field(arg0).finish(),
             }
         }
     }
@@ -413,6 +566,7 @@ pub mod test {
         }
     }
 
+    /// Requests that the dummy server does not handle fail.
     impl FlowController for DummyFlowClient {
         async fn get_available_public_key_devices(&self) -> Result<Vec<Device>, ()> {
             let response = self.send(DummyFlowRequest::GetDevices).await.unwrap();
@@ -423,9 +577,9 @@ pub mod test {
             }
         }
 
-        async fn get_hybrid_credential(&mut self) -> Result<(), ()> {
-            if let Ok(DummyFlowResponse::GetHybridCredential) =
-                self.send(DummyFlowRequest::GetHybridCredential).await
+        async fn select_device(&mut self, device_id: String) -> Result<(), ()> {
+            if let Ok(DummyFlowResponse::SelectDevice(Ok(()))) =
+                self.send(DummyFlowRequest::SelectDevice(device_id)).await
             {
                 Ok(())
             } else {
@@ -433,15 +587,6 @@ pub mod test {
             }
         }
 
-        async fn get_usb_credential(&mut self) -> Result<(), ()> {
-            let response = self.send(DummyFlowRequest::GetUsbCredential).await.unwrap();
-            if let DummyFlowResponse::GetUsbCredential = response {
-                Ok(())
-            } else {
-                Err(())
-            }
-        }
-
         async fn subscribe(
             &mut self,
         ) -> Result<Pin<Box<dyn Stream<Item = BackgroundEvent> + Send + 'static>>, ()> {
@@ -454,9 +599,10 @@ pub mod test {
             }
         }
 
-        async fn enter_client_pin(&mut self, pin: String) -> Result<(), ()> {
-            if let Ok(DummyFlowResponse::EnterClientPin(Ok(()))) =
-                self.send(DummyFlowRequest::EnterClientPin(pin)).await
+        async fn enter_client_pin(&mut self, device_id: String, pin: String) -> Result<(), ()> {
+            if let Ok(DummyFlowResponse::EnterClientPin(Ok(()))) = self
+                .send(DummyFlowRequest::EnterClientPin(device_id, pin))
+                .await
             {
                 Ok(())
             } else {
@@ -464,36 +610,60 @@ pub mod test {
             }
         }
 
-        async fn select_credential(&self, _credential_id: String) -> Result<(), ()> {
+        async fn enter_password(&mut self, _password: String) -> Result<(), ()> {
+            Err(())
+        }
+
+        async fn select_credential(
+            &self,
+            _device_id: String,
+            _credential_id: String,
+        ) -> Result<(), ()> {
             todo!();
         }
 
+        async fn select_devices(&mut self, _device_ids: Vec<String>) -> Result<(), ()> {
+            Err(())
+        }
+
+        async fn rename_device(&self, _device_id: String, _name: String) -> Result<(), ()> {
+            Err(())
+        }
+
+        async fn forget_device(&self, _device_id: String) -> Result<(), ()> {
+            Err(())
+        }
+
+        async fn get_transfer_summary(&self) -> Result<Vec<String>, ()> {
+            Err(())
+        }
+
+        async fn confirm_transfer(&mut self, _passphrase: String) -> Result<u32, ()> {
+            Err(())
+        }
+
+        async fn confirm_change(&mut self) -> Result<(), ()> {
+            Err(())
+        }
+
         async fn cancel_request(&self, _request_id: RequestId) -> Result<(), ()> {
             todo!()
         }
     }
 
     #[derive(Debug)]
-    pub struct DummyFlowServer<H, U, UC>
+    pub struct DummyFlowServer<UC>
     where
-        H: HybridHandler + Debug + Send + Sync,
-        U: UsbHandler + Debug + Send + Sync,
         UC: UiController + Debug + Send + Sync,
     {
         rx: mpsc::Receiver<(DummyFlowRequest, oneshot::Sender<DummyFlowResponse>)>,
-        svc: Arc<AsyncMutex<CredentialService<H, U, UC>>>,
+        svc: Arc<AsyncMutex<CredentialService<UC>>>,
         bg_event_tx: Option<mpsc::Sender<BackgroundEvent>>,
-        usb_pin_tx: Arc<AsyncMutex<Option<tokio::sync::mpsc::Sender<String>>>>,
-        usb_event_forwarder_task: Arc<Mutex<Option<tokio::task::AbortHandle>>>,
-        hybrid_event_forwarder_task: Arc<Mutex<Option<tokio::task::AbortHandle>>>,
+        pin_tx: Arc<AsyncMutex<HashMap<String, tokio::sync::mpsc::Sender<String>>>>,
+        device_event_forwarder_task: Arc<Mutex<Option<tokio::task::AbortHandle>>>,
     }
 
-    impl<
-            H: HybridHandler + Debug + Send + Sync,
-            U: UsbHandler + Debug + Send + Sync,
-            UC: UiController + Debug + Send + Sync,
-        > DummyFlowServer<H, U, UC>
-    {
+    impl<UC: UiController + Debug + Send + Sync> DummyFlowServer<UC> {
         /*
         async fn send(&self, request: ManagementRequest) -> Result<ManagementResponse, ()> {
             let (response_tx, response_rx) = oneshot::channel();
@@ -514,15 +684,14 @@ pub mod test {
             }
         }
         */
-        pub fn new(svc: Arc<AsyncMutex<CredentialService<H, U, UC>>>) -> (Self, DummyFlowClient) {
+        pub fn new(svc: Arc<AsyncMutex<CredentialService<UC>>>) -> (Self, DummyFlowClient) {
             let (request_tx, request_rx) = mpsc::channel(32);
             let server = Self {
                 rx: request_rx,
                 svc,
                 bg_event_tx: None,
-                usb_pin_tx: Arc::new(AsyncMutex::new(None)),
-                usb_event_forwarder_task: Arc::new(Mutex::new(None)),
-                hybrid_event_forwarder_task: Arc::new(Mutex::new(None)),
+                pin_tx: Arc::new(AsyncMutex::new(HashMap::new())),
+                device_event_forwarder_task: Arc::new(Mutex::new(None)),
             };
             let client = DummyFlowClient { tx: request_tx };
             (server, client)
@@ -532,27 +701,22 @@ pub mod test {
             while let Some((request, tx)) = self.rx.recv().await {
                 tracing::debug!(target: "DummyFlowServer", "Received message: {request:?}");
                 let response = match request {
-                    DummyFlowRequest::EnterClientPin(pin) => {
-                        let rsp = self.enter_client_pin(pin).await;
+                    DummyFlowRequest::EnterClientPin(device_id, pin) => {
+                        let rsp = self.enter_client_pin(device_id, pin).await;
                         DummyFlowResponse::EnterClientPin(rsp)
                     }
                     DummyFlowRequest::GetDevices => {
                         let rsp = self.get_available_public_key_devices().await.unwrap();
                         DummyFlowResponse::GetDevices(rsp)
                     }
-                    DummyFlowRequest::GetHybridCredential => {
-                        self.get_hybrid_credential().await.unwrap();
-                        DummyFlowResponse::GetHybridCredential
-                    }
-
-                    DummyFlowRequest::GetUsbCredential => {
-                        self.get_usb_credential().await.unwrap();
-                        DummyFlowResponse::GetUsbCredential
-                    }
                     DummyFlowRequest::InitStream => {
                         let rsp = self.subscribe().await;
                         DummyFlowResponse::InitStream(rsp)
                     }
+                    DummyFlowRequest::SelectDevice(device_id) => {
+                        let rsp = self.select_device(device_id).await;
+                        DummyFlowResponse::SelectDevice(rsp)
+                    }
                 };
                 tx.send(response).unwrap()
             }
@@ -570,84 +734,45 @@ pub mod test {
             Ok(devices)
         }
 
-        async fn get_hybrid_credential(&mut self) -> Result<(), ()> {
-            let svc = self.svc.lock().await;
-            let mut stream = svc.get_hybrid_credential();
-            tracing::debug!(target: "DummyFlowServer", "Subscribing to hybrid credential state changes");
-            if let Some(tx_weak) = self.bg_event_tx.as_ref().map(|t| t.clone().downgrade()) {
-                let task = tokio::spawn(async move {
-                    while let Some(hybrid_state) = stream.next().await {
-                        tracing::debug!(target: "DummyFlowServer", "Received hybrid state change: {hybrid_state:?}");
-                        if let Some(tx) = tx_weak.upgrade() {
-                            match hybrid_state {
-                                HybridState::Completed | HybridState::Failed => {
-                                    tx.send(BackgroundEvent::HybridQrStateChanged(
-                                        hybrid_state.into(),
-                                    ))
-                                    .await
-                                    .unwrap();
-                                    break;
-                                }
-                                _ => tx
-                                    .send(BackgroundEvent::HybridQrStateChanged(
-                                        hybrid_state.into(),
-                                    ))
-                                    .await
-                                    .unwrap(),
-                            };
-                        }
-                    }
-                })
-                .abort_handle();
-                if let Some(prev_task) = self
-                    .hybrid_event_forwarder_task
-                    .lock()
-                    .unwrap()
-                    .replace(task)
-                {
-                    prev_task.abort();
-                }
-            } else {
-                tracing::warn!(target: "DummyFlowServer", "Output stream not initialized before setting up hybrid state stream; some messages may be missed.");
+        async fn select_device(&mut self, device_id: String) -> Result<(), ()> {
+            if let Some(prev_task) = self.device_event_forwarder_task.lock().unwrap().take() {
+                prev_task.abort();
             }
-            Ok(())
-        }
-
-        async fn get_usb_credential(&mut self) -> Result<(), ()> {
-            let mut stream = self.svc.lock().await.get_usb_credential();
+            self.pin_tx.lock().await.clear();
+            let svc = self.svc.lock().await;
+            let mut stream = svc.start_device(&device_id).map_err(|_| ())?;
+            tracing::debug!(target: "DummyFlowServer", "Subscribing to device {device_id} state changes");
             if let Some(tx_weak) = self.bg_event_tx.as_ref().map(|t| t.clone().downgrade()) {
-                let usb_pin_tx = self.usb_pin_tx.clone();
+                let pin_tx_by_device = self.pin_tx.clone();
                 let task = tokio::spawn(async move {
                     while let Some(state) = stream.next().await {
+                        tracing::debug!(target: "DummyFlowServer", "Received device state change: {state:?}");
                         if let Some(tx) = tx_weak.upgrade() {
-                            if tx
-                                .send(BackgroundEvent::UsbStateChanged(state.clone().into()))
-                                .await
-                                .is_err()
-                            {
-                                tracing::debug!("Closing USB background event forwarder");
+                            let event = BackgroundEvent::DeviceStateChanged {
+                                device_id: device_id.clone(),
+                                state: (&state).into(),
+                            };
+                            if tx.send(event).await.is_err() {
+                                tracing::debug!("Closing device background event forwarder");
+                                break;
+                            }
+                            let is_terminal = state.is_terminal();
+                            if let AuthenticatorState::NeedsPin { pin_tx, .. } = state.state {
+                                pin_tx_by_device
+                                    .lock()
+                                    .await
+                                    .insert(device_id.clone(), pin_tx);
+                            }
+                            if is_terminal {
                                 break;
                             }
-                            match state {
-                                UsbState::NeedsPin { pin_tx, .. } => {
-                                    let mut usb_pin_tx = usb_pin_tx.lock().await;
-                                    let _ = usb_pin_tx.insert(pin_tx);
-                                }
-                                UsbState::Completed | UsbState::Failed(_) => {
-                                    break;
-                                }
-                                _ => {}
-                            };
                         }
                     }
                 })
                 .abort_handle();
-                if let Some(prev_task) = self.usb_event_forwarder_task.lock().unwrap().replace(task)
-                {
-                    prev_task.abort();
-                }
+                *self.device_event_forwarder_task.lock().unwrap() = Some(task);
             } else {
-                tracing::warn!(target: "DummyFlowServer", "Output stream not initialized before setting up USB state stream; some messages may be missed.");
+                tracing::warn!(target: "DummyFlowServer", "Output stream not initialized before setting up device state stream; some messages may be missed.");
             }
             Ok(())
         }
@@ -667,14 +792,19 @@ pub mod test {
             }))
         }
 
-        async fn enter_client_pin(&mut self, pin: String) -> Result<(), ()> {
-            if let Some(pin_tx) = self.usb_pin_tx.lock().await.take() {
-                pin_tx.send(pin).await.unwrap();
+        async fn enter_client_pin(&mut self, device_id: String, pin: String) -> Result<(), ()> {
+            if let Some(pin_tx) = self.pin_tx.lock().await.remove(&device_id) {
+                // The security key may have been unplugged in the meantime.
+                let _ = pin_tx.send(pin).await;
             }
             Ok(())
         }
 
-        async fn select_credential(&self, _credential_id: String) -> Result<(), ()> {
+        async fn select_credential(
+            &self,
+            _device_id: String,
+            _credential_id: String,
+        ) -> Result<(), ()> {
             todo!();
         }
 
@@ -683,18 +813,9 @@ pub mod test {
         }
     }
 
-    impl<
-            H: HybridHandler + Debug + Send + Sync,
-            U: UsbHandler + Debug + Send + Sync,
-            UC: UiController + Debug + Send + Sync,
-        > Drop for DummyFlowServer<H, U, UC>
-    {
+    impl<UC: UiController + Debug + Send + Sync> Drop for DummyFlowServer<UC> {
         fn drop(&mut self) {
-            if let Some(task) = self.usb_event_forwarder_task.lock().unwrap().take() {
-                task.abort();
-            }
-
-            if let Some(task) = self.hybrid_event_forwarder_task.lock().unwrap().take() {
+            if let Some(task) = self.device_event_forwarder_task.lock().unwrap().take() {
                 task.abort();
             }
         }
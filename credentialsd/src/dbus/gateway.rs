@@ -13,18 +13,29 @@ use credentialsd_common::{
 use tokio::sync::Mutex as AsyncMutex;
 use zbus::{fdo, interface, Connection, DBusError};
 
-use crate::dbus::{
-    create_credential_request_try_into_ctap2, create_credential_response_try_from_ctap2,
-    get_credential_request_try_into_ctap2, get_credential_response_try_from_ctap2,
-    CredentialRequestController,
+use crate::{
+    credential_service::platform::{
+        KeyBackend, PlatformCapabilities, TransferRequest, TransferResponse,
+    },
+    dbus::{
+        create_credential_request_try_into_ctap2, create_credential_response_try_from_ctap2,
+        get_credential_request_try_into_ctap2, get_credential_response_try_from_ctap2,
+        management::{self, SecurityKeyManager},
+        CredentialRequestController,
+    },
 };
 
 pub const SERVICE_NAME: &str = "xyz.iinuwa.credentialsd.Credentials";
 pub const SERVICE_PATH: &str = "/xyz/iinuwa/credentialsd/Credentials";
 
+/// Starts the public service, along with the management of security keys.
+/// `platform_authenticator` is what the platform authenticator can do, if it
+/// is offered to users, which clients can check for.
 pub async fn start_gateway<C: CredentialRequestController + Send + Sync + 'static>(
     controller: C,
+    platform_authenticator: Option<PlatformCapabilities>,
 ) -> Result<Connection, zbus::Error> {
+    let controller = Arc::new(AsyncMutex::new(controller));
     zbus::connection::Builder::session()
         .inspect_err(|err| {
             tracing::error!("Failed to connect to D-Bus session: {err}");
@@ -33,15 +44,18 @@ pub async fn start_gateway<C: CredentialRequestController + Send + Sync + 'stati
         .serve_at(
             SERVICE_PATH,
             CredentialGateway {
-                controller: Arc::new(AsyncMutex::new(controller)),
+                controller: controller.clone(),
+                platform_authenticator,
             },
         )?
+        .serve_at(management::SERVICE_PATH, SecurityKeyManager { controller })?
         .build()
         .await
 }
 
 struct CredentialGateway<C: CredentialRequestController> {
     controller: Arc<AsyncMutex<C>>,
+    platform_authenticator: Option<PlatformCapabilities>,
 }
 
 /// These are public methods that can be called by arbitrary clients to begin a credential flow.
@@ -72,7 +86,7 @@ impl<C: CredentialRequestController + Send + Sync + 'static> CredentialGateway<C
                     e
                 })?;
             if make_cred_request.algorithms.is_empty() {
-                tracing::info!("No supported algorithms given in request. Rejecting request.");
+                tracing::info!("No public key algorithms were requested. Rejecting request.");
                 return Err(Error::NotSupportedError);
             }
             let cred_request =
@@ -174,14 +188,63 @@ impl<C: CredentialRequestController + Send + Sync + 'static> CredentialGateway<C
             conditional_create: false,
             conditional_get: false,
             hybrid_transport: true,
-            passkey_platform_authenticator: false,
-            user_verifying_platform_authenticator: false,
+            passkey_platform_authenticator: self.platform_authenticator.is_some(),
+            user_verifying_platform_authenticator: self
+                .platform_authenticator
+                .is_some_and(|platform| platform.user_verification),
             related_origins: false,
             signal_all_accepted_credentials: false,
             signal_current_user_details: false,
             signal_unknown_credential: false,
+            tpm_bound_platform_authenticator: self
+                .platform_authenticator
+                .is_some_and(|platform| platform.key_backend == KeyBackend::Tpm),
         })
     }
+
+    /// Exports the platform passkeys of the given relying parties, or all of
+    /// them, to an encrypted CXF archive, once the user confirms it in the
+    /// trusted UI by choosing a passphrase. Passkeys bound to the TPM are not
+    /// exported.
+    async fn export_platform_credentials(&self, rp_ids: Vec<String>) -> Result<Vec<u8>, Error> {
+        if self.platform_authenticator.is_none() {
+            return Err(Error::NotSupportedError);
+        }
+        let response = self
+            .controller
+            .lock()
+            .await
+            .request_transfer(TransferRequest::Export { rp_ids })
+            .await?;
+        if let TransferResponse::Exported { archive, .. } = response {
+            Ok(archive)
+        } else {
+            tracing::error!("Did not receive expected export response.");
+            Err(WebAuthnError::NotAllowedError.into())
+        }
+    }
+
+    /// Imports the passkeys of a CXF archive, either encrypted by credentialsd
+    /// or a plain header exported by another credential manager, once the
+    /// user confirms it in the trusted UI. Returns the number of passkeys
+    /// imported.
+    async fn import_platform_credentials(&self, archive: Vec<u8>) -> Result<u32, Error> {
+        if self.platform_authenticator.is_none() {
+            return Err(Error::NotSupportedError);
+        }
+        let response = self
+            .controller
+            .lock()
+            .await
+            .request_transfer(TransferRequest::Import { archive })
+            .await?;
+        if let TransferResponse::Imported { count } = response {
+            Ok(count)
+        } else {
+            tracing::error!("Did not receive expected import response.");
+            Err(WebAuthnError::NotAllowedError.into())
+        }
+    }
 }
 
 async fn check_origin(
@@ -215,7 +278,7 @@ async fn check_origin(
 #[allow(clippy::enum_variant_names)]
 #[derive(DBusError, Debug)]
 #[zbus(prefix = "xyz.iinuwa.credentialsd")]
-enum Error {
+pub(super) enum Error {
     #[zbus(error)]
     ZBus(zbus::Error),
 
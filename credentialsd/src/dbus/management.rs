@@ -0,0 +1,241 @@
+//! Implements the service that public clients can connect to to manage
+//! security keys, e.g. to set their PIN, delete the passkeys stored on them,
+//! enroll fingerprints or reset them.
+//!
+//! Secrets are never passed through this interface: the user picks the
+//! security key and enters its PIN in the trusted UI. Changes to the settings
+//! of the key are confirmed there too.
+
+use std::sync::Arc;
+
+use credentialsd_common::model::{
+    AuthenticatorInfo, Fingerprint, PinInfo, SecurityKeyCredential, WebAuthnError,
+};
+use tokio::sync::Mutex as AsyncMutex;
+use zbus::interface;
+
+use super::{gateway::Error, validation::prepare_name};
+use crate::{
+    credential_service::management::{ManagementRequest, ManagementResponse},
+    dbus::CredentialRequestController,
+};
+
+pub const SERVICE_PATH: &str = "/xyz/iinuwa/credentialsd/SecurityKeys";
+
+pub(super) struct SecurityKeyManager<C: CredentialRequestController> {
+    pub(super) controller: Arc<AsyncMutex<C>>,
+}
+
+impl<C: CredentialRequestController> SecurityKeyManager<C> {
+    async fn request(&self, request: ManagementRequest) -> Result<ManagementResponse, Error> {
+        Ok(self
+            .controller
+            .lock()
+            .await
+            .request_management(request)
+            .await?)
+    }
+
+    async fn configure(&self, request: ManagementRequest) -> Result<(), Error> {
+        match self.request(request).await? {
+            ManagementResponse::Configured => Ok(()),
+            _ => {
+                tracing::error!("Did not receive expected configuration response.");
+                Err(WebAuthnError::NotAllowedError.into())
+            }
+        }
+    }
+}
+
+/// These are public methods that can be called by arbitrary clients to manage
+/// a security key, which the user picks in the trusted UI.
+#[interface(name = "xyz.iinuwa.credentialsd.SecurityKeys1")]
+impl<C: CredentialRequestController + Send + Sync + 'static> SecurityKeyManager<C> {
+    /// Reads what the security key reports about itself in
+    /// `authenticatorGetInfo`, with its PIN and UV retries. Unlike the other
+    /// methods, this works with NFC and BLE security keys too.
+    async fn get_info(&self) -> Result<AuthenticatorInfo, Error> {
+        match self.request(ManagementRequest::GetInfo).await? {
+            ManagementResponse::Info(info) => Ok(info),
+            _ => {
+                tracing::error!("Did not receive expected authenticator info response.");
+                Err(WebAuthnError::NotAllowedError.into())
+            }
+        }
+    }
+
+    /// Reads whether the security key has a PIN, how many attempts are left,
+    /// the minimum length of new PINs, and whether the key requires its PIN
+    /// to be changed.
+    async fn get_pin_info(&self) -> Result<PinInfo, Error> {
+        match self.request(ManagementRequest::GetPinInfo).await? {
+            ManagementResponse::PinInfo(info) => Ok(info),
+            _ => {
+                tracing::error!("Did not receive expected PIN info response.");
+                Err(WebAuthnError::NotAllowedError.into())
+            }
+        }
+    }
+
+    /// Sets the PIN of a security key that has none, or changes its current
+    /// PIN. Both are entered by the user in the trusted UI.
+    async fn set_pin(&self) -> Result<(), Error> {
+        match self.request(ManagementRequest::SetPin).await? {
+            ManagementResponse::PinSet => Ok(()),
+            _ => {
+                tracing::error!("Did not receive expected PIN response.");
+                Err(WebAuthnError::NotAllowedError.into())
+            }
+        }
+    }
+
+    /// Lists the discoverable credentials on a security key, with how many
+    /// more it can store.
+    #[zbus(out_args("credentials", "remaining"))]
+    async fn get_credentials(&self) -> Result<(Vec<SecurityKeyCredential>, u32), Error> {
+        match self.request(ManagementRequest::ListCredentials).await? {
+            ManagementResponse::Credentials {
+                credentials,
+                remaining,
+            } => Ok((credentials, remaining)),
+            _ => {
+                tracing::error!("Did not receive expected credentials response.");
+                Err(WebAuthnError::NotAllowedError.into())
+            }
+        }
+    }
+
+    async fn delete_credential(&self, credential_id: Vec<u8>) -> Result<(), Error> {
+        if credential_id.is_empty() {
+            return Err(WebAuthnError::TypeError.into());
+        }
+        match self
+            .request(ManagementRequest::DeleteCredential { credential_id })
+            .await?
+        {
+            ManagementResponse::CredentialDeleted => Ok(()),
+            _ => {
+                tracing::error!("Did not receive expected deletion response.");
+                Err(WebAuthnError::NotAllowedError.into())
+            }
+        }
+    }
+
+    /// Replaces the display name of the user of a credential. The name is
+    /// prepared like `user.displayName` in creation requests.
+    async fn update_user_display_name(
+        &self,
+        credential_id: Vec<u8>,
+        display_name: String,
+    ) -> Result<(), Error> {
+        if credential_id.is_empty() {
+            return Err(WebAuthnError::TypeError.into());
+        }
+        let request = ManagementRequest::UpdateUserDisplayName {
+            credential_id,
+            display_name: prepare_name(&display_name),
+        };
+        match self.request(request).await? {
+            ManagementResponse::UserUpdated => Ok(()),
+            _ => {
+                tracing::error!("Did not receive expected user update response.");
+                Err(WebAuthnError::NotAllowedError.into())
+            }
+        }
+    }
+
+    async fn get_fingerprints(&self) -> Result<Vec<Fingerprint>, Error> {
+        match self.request(ManagementRequest::ListFingerprints).await? {
+            ManagementResponse::Fingerprints(fingerprints) => Ok(fingerprints),
+            _ => {
+                tracing::error!("Did not receive expected fingerprints response.");
+                Err(WebAuthnError::NotAllowedError.into())
+            }
+        }
+    }
+
+    /// Enrolls a new fingerprint, and returns its template ID. The user is
+    /// guided through the samples in the trusted UI. An empty name leaves the
+    /// fingerprint unnamed.
+    async fn enroll_fingerprint(&self, name: String) -> Result<Vec<u8>, Error> {
+        let name = Some(prepare_name(&name)).filter(|name| !name.is_empty());
+        match self
+            .request(ManagementRequest::EnrollFingerprint { name })
+            .await?
+        {
+            ManagementResponse::FingerprintEnrolled { template_id } => Ok(template_id),
+            _ => {
+                tracing::error!("Did not receive expected enrollment response.");
+                Err(WebAuthnError::NotAllowedError.into())
+            }
+        }
+    }
+
+    async fn rename_fingerprint(&self, template_id: Vec<u8>, name: String) -> Result<(), Error> {
+        let name = prepare_name(&name);
+        if template_id.is_empty() || name.is_empty() {
+            return Err(WebAuthnError::TypeError.into());
+        }
+        match self
+            .request(ManagementRequest::RenameFingerprint { template_id, name })
+            .await?
+        {
+            ManagementResponse::FingerprintRenamed => Ok(()),
+            _ => {
+                tracing::error!("Did not receive expected rename response.");
+                Err(WebAuthnError::NotAllowedError.into())
+            }
+        }
+    }
+
+    async fn remove_fingerprint(&self, template_id: Vec<u8>) -> Result<(), Error> {
+        if template_id.is_empty() {
+            return Err(WebAuthnError::TypeError.into());
+        }
+        match self
+            .request(ManagementRequest::RemoveFingerprint { template_id })
+            .await?
+        {
+            ManagementResponse::FingerprintRemoved => Ok(()),
+            _ => {
+                tracing::error!("Did not receive expected removal response.");
+                Err(WebAuthnError::NotAllowedError.into())
+            }
+        }
+    }
+
+    /// Requires user verification for every use of the security key, or
+    /// stops requiring it.
+    async fn set_always_uv(&self, enable: bool) -> Result<(), Error> {
+        self.configure(ManagementRequest::SetAlwaysUv { enable })
+            .await
+    }
+
+    /// Raises the minimum length of PINs of the security key, and lets the
+    /// given relying parties read it. Keys never lower the minimum length.
+    async fn set_min_pin_length(&self, min_length: u32, rp_ids: Vec<String>) -> Result<(), Error> {
+        if rp_ids.iter().any(|rp_id| rp_id.is_empty()) {
+            return Err(WebAuthnError::TypeError.into());
+        }
+        self.configure(ManagementRequest::SetMinPinLength { min_length, rp_ids })
+            .await
+    }
+
+    async fn enable_enterprise_attestation(&self) -> Result<(), Error> {
+        self.configure(ManagementRequest::EnableEnterpriseAttestation)
+            .await
+    }
+
+    /// Deletes all passkeys, fingerprints and the PIN of the security key.
+    /// The user is asked to plug the key back in if it was plugged in too
+    /// long ago to be reset.
+    async fn reset(&self) -> Result<(), Error> {
+        match self.request(ManagementRequest::Reset).await? {
+            ManagementResponse::ResetDone => Ok(()),
+            _ => {
+                tracing::error!("Did not receive expected reset response.");
+                Err(WebAuthnError::NotAllowedError.into())
+            }
+        }
+    }
+}
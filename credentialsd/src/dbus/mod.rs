@@ -3,7 +3,8 @@
 //! There are two services that run in this process: the gateway and the flow
 //! controller.
 //!
-//! The gateway is accessed by public clients and initiates new requests.
+//! The gateway is accessed by public clients and initiates new requests. Next
+//! to it, public clients can manage security keys.
 //!
 //! The flow controller launches a UI and receives user interaction events.
 //!
@@ -11,8 +12,10 @@
 
 mod flow_control;
 mod gateway;
+mod management;
 mod model;
 mod ui_control;
+mod validation;
 
 use self::model::{
     create_credential_request_try_into_ctap2, create_credential_response_try_from_ctap2,
@@ -29,6 +32,6 @@ pub use self::{
 
 #[cfg(test)]
 pub mod test {
-    pub use super::flow_control::test::DummyFlowServer;
+    pub use super::flow_control::test::{DummyFlowClient, DummyFlowServer};
     pub use super::ui_control::test::DummyUiServer;
 }
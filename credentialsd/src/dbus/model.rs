@@ -6,32 +6,45 @@ use std::{collections::HashMap, time::Duration};
 
 use base64::{self, engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
 
+use libwebauthn::{
+    ops::webauthn::{MakeCredentialLargeBlobExtension, PRFValue},
+    proto::ctap2::{Ctap2PublicKeyCredentialType, Ctap2Transport},
+};
+
 use credentialsd_common::{
     model::{
-        GetAssertionResponseInternal, MakeCredentialResponseInternal, Operation, WebAuthnError,
+        GetAssertionResponseInternal, MakeCredentialRequestInternal,
+        MakeCredentialResponseInternal, Operation, WebAuthnError,
     },
     server::{
         CreateCredentialRequest, CreatePublicKeyCredentialResponse, GetCredentialRequest,
         GetPublicKeyCredentialResponse,
     },
+    webauthn::{
+        AuthenticationExtensionsPRFValuesJSON, PublicKeyCredentialCreationOptionsJSON,
+        PublicKeyCredentialDescriptorJSON, PublicKeyCredentialParameters,
+        PublicKeyCredentialRequestOptionsJSON, PublicKeyCredentialType,
+    },
 };
 
+use super::validation;
 use crate::{
-    cose::CoseKeyAlgorithmIdentifier,
+    cbor,
+    cose::{CoseKey, CoseKeyAlgorithmIdentifier},
     webauthn::{
         self, CredentialProtectionExtension, Ctap2PublicKeyCredentialDescriptor,
         Ctap2PublicKeyCredentialRpEntity, Ctap2PublicKeyCredentialUserEntity,
         GetAssertionHmacOrPrfInput, GetAssertionLargeBlobExtension, GetAssertionRequest,
         GetAssertionRequestExtensions, GetPublicKeyCredentialUnsignedExtensionsResponse,
         MakeCredentialHmacOrPrfInput, MakeCredentialRequest, MakeCredentialsRequestExtensions,
-        PublicKeyCredentialParameters, ResidentKeyRequirement, UserVerificationRequirement,
+        ResidentKeyRequirement, UserVerificationRequirement,
     },
 };
 
 // Helper functions for translating D-Bus types into internal types
 pub(super) fn create_credential_request_try_into_ctap2(
     request: &CreateCredentialRequest,
-) -> std::result::Result<(MakeCredentialRequest, String), WebAuthnError> {
+) -> std::result::Result<(MakeCredentialRequestInternal, String), WebAuthnError> {
     if request.public_key.is_none() {
         return Err(WebAuthnError::NotSupportedError);
     }
@@ -40,98 +53,65 @@ pub(super) fn create_credential_request_try_into_ctap2(
         WebAuthnError::TypeError
     })?;
 
-    let request_value =
-        serde_json::from_str::<serde_json::Value>(&options.request_json).map_err(|err| {
+    let mut options: PublicKeyCredentialCreationOptionsJSON =
+        serde_json::from_str(&options.request_json).map_err(|err| {
             tracing::info!("Invalid request JSON: {err}");
             WebAuthnError::TypeError
         })?;
-    let json = request_value.as_object().ok_or_else(|| {
-        tracing::info!("Invalid request JSON: not an object");
-        WebAuthnError::TypeError
-    })?;
-    let challenge = json
-        .get("challenge")
-        .and_then(|c| c.as_str())
-        .ok_or_else(|| {
-            tracing::info!("JSON missing `challenge` field.");
-            WebAuthnError::TypeError
-        })?
-        .to_owned();
-    let rp = json
-        .get("rp")
-        .and_then(|val| {
-            serde_json::from_str::<Ctap2PublicKeyCredentialRpEntity>(&val.to_string()).ok()
-        })
-        .ok_or_else(|| {
-            tracing::info!("JSON missing `rp` field");
-            WebAuthnError::TypeError
-        })?;
-    let user =
-        json.get("user")
-            .ok_or_else(|| {
-                tracing::info!("JSON missing `user` field.");
-                WebAuthnError::TypeError
-            })
-            .and_then(|val| {
-                serde_json::from_str::<Ctap2PublicKeyCredentialUserEntity>(&val.to_string())
-                    .map_err(|e| {
-                        tracing::info!("JSON missing `user` field: {e}");
-                        WebAuthnError::TypeError
-                    })
-            })?;
-    let other_options =
-        serde_json::from_str::<webauthn::MakeCredentialOptions>(&request_value.to_string())
-            .map_err(|e| {
-                tracing::info!("Received invalid request JSON: {e}");
-                WebAuthnError::TypeError
-            })?;
-    let (resident_key, user_verification) =
-        if let Some(authenticator_selection) = other_options.authenticator_selection {
-            let resident_key = match authenticator_selection.resident_key.as_deref() {
-                Some("required") => Some(ResidentKeyRequirement::Required),
-                Some("preferred") => Some(ResidentKeyRequirement::Preferred),
-                Some("discouraged") => Some(ResidentKeyRequirement::Discouraged),
-                Some(_) => None,
-                // legacy webauthn-1 member
-                None if authenticator_selection.require_resident_key == Some(true) => {
-                    Some(ResidentKeyRequirement::Required)
-                }
-                None => None,
-            };
+    validation::validate_creation_options(&mut options)?;
+    let (origin, is_cross_origin) = match (request.origin.as_ref(), request.is_same_origin.as_ref())
+    {
+        (Some(origin), Some(is_same_origin)) => (origin.to_string(), !is_same_origin),
+        (Some(origin), None) => (origin.to_string(), true),
+        // origin should always be set on request either by client or D-Bus service,
+        // so this shouldn't be called
+        (None, _) => {
+            tracing::info!("Error reading origin from request.");
+            return Err(WebAuthnError::TypeError);
+        }
+    };
 
-            let user_verification = authenticator_selection
+    let rp = Ctap2PublicKeyCredentialRpEntity {
+        id: options
+            .rp
+            .id
+            .unwrap_or_else(|| effective_domain(&origin).to_string()),
+        name: Some(options.rp.name),
+    };
+    let user = Ctap2PublicKeyCredentialUserEntity {
+        id: options.user.id.into(),
+        name: Some(options.user.name),
+        display_name: Some(options.user.display_name),
+    };
+    let (resident_key, user_verification) = match options.authenticator_selection {
+        Some(authenticator_selection) => (
+            authenticator_selection
+                .resident_key_requirement()
+                .map(ResidentKeyRequirement::from),
+            authenticator_selection
                 .user_verification
-                .map(|uv| match uv.as_ref() {
-                    "required" => UserVerificationRequirement::Required,
-                    "preferred" => UserVerificationRequirement::Preferred,
-                    "discouraged" => UserVerificationRequirement::Discouraged,
-                    _ => todo!("This should be fixed in the future"),
-                })
-                .unwrap_or(UserVerificationRequirement::Preferred);
-
-            (resident_key, user_verification)
-        } else {
-            (None, UserVerificationRequirement::Preferred)
-        };
-    let extensions = if let Some(incoming_extensions) = other_options.extensions {
-        let extensions = MakeCredentialsRequestExtensions {
+                .unwrap_or_default()
+                .into(),
+        ),
+        None => (None, UserVerificationRequirement::Preferred),
+    };
+    let extensions = options.extensions.map(|incoming_extensions| {
+        MakeCredentialsRequestExtensions {
             cred_props: incoming_extensions.cred_props,
-            cred_blob: incoming_extensions
-                .cred_blob
-                .and_then(|x| URL_SAFE_NO_PAD.decode(x).ok()),
+            cred_blob: incoming_extensions.cred_blob,
             min_pin_length: incoming_extensions.min_pin_length,
-            cred_protect: match incoming_extensions.credential_protection_policy {
-                Some(cred_prot_policy) => Some(CredentialProtectionExtension {
-                    policy: cred_prot_policy,
+            cred_protect: incoming_extensions.credential_protection_policy.map(
+                |cred_prot_policy| CredentialProtectionExtension {
+                    policy: cred_prot_policy.into(),
                     enforce_policy: incoming_extensions
                         .enforce_credential_protection_policy
                         .unwrap_or_default(),
-                }),
-                None => None,
-            },
+                },
+            ),
             large_blob: incoming_extensions
                 .large_blob
-                .map(|x| x.support.unwrap_or_default())
+                .and_then(|x| x.support)
+                .map(MakeCredentialLargeBlobExtension::from)
                 .unwrap_or_default(),
             hmac_or_prf: if incoming_extensions.prf.is_some() {
                 // CTAP currently doesn't support PRF queries at credentials.create()
@@ -142,67 +122,63 @@ pub(super) fn create_credential_request_try_into_ctap2(
                 // MakeCredentialHmacOrPrfInput::Hmac is not used directly by webauthn
                 MakeCredentialHmacOrPrfInput::None
             },
-        };
-        Some(extensions)
-    } else {
-        None
-    };
+        }
+    });
 
-    let credential_parameters = match request_value.clone().get("pubKeyCredParams") {
+    let credential_parameters = if options.pub_key_cred_params.is_empty() {
         // https://www.w3.org/TR/webauthn-3/#sctn-createCredential Section 5.1.3.10
         // Default to ES256 and RS256 if no params are given.
-        None => Ok(vec![
+        vec![
             PublicKeyCredentialParameters {
+                cred_type: Some(PublicKeyCredentialType::PublicKey),
                 alg: CoseKeyAlgorithmIdentifier::ES256.into(),
             },
             PublicKeyCredentialParameters {
+                cred_type: Some(PublicKeyCredentialType::PublicKey),
                 alg: CoseKeyAlgorithmIdentifier::RS256.into(),
             },
-        ]),
-        Some(val) => serde_json::from_str::<Vec<PublicKeyCredentialParameters>>(&val.to_string())
-            .map_err(|e| {
-                tracing::info!("Request JSON missing or invalid `pubKeyCredParams` key: {e}.");
-                WebAuthnError::TypeError
-            }),
-    }?;
+        ]
+    } else {
+        options.pub_key_cred_params
+    };
+    // Keep the RP's order of preference: the authenticator picks the first
+    // algorithm it supports.
     let algorithms = credential_parameters
         .iter()
-        .filter_map(|p| p.try_into().ok())
+        .filter(|p| p.cred_type == Some(PublicKeyCredentialType::PublicKey))
+        .map(|p| p.alg)
+        .collect::<Vec<_>>();
+    let exclude: Vec<Ctap2PublicKeyCredentialDescriptor> = options
+        .exclude_credentials
+        .iter()
+        .filter_map(credential_descriptor_try_into_ctap2)
         .collect();
-    let exclude = other_options.excluded_credentials.map(|v| {
-        v.iter()
-            .map(|e| e.try_into())
-            .filter_map(|e| e.ok())
-            .collect()
-    });
-    let (origin, is_cross_origin) = match (request.origin.as_ref(), request.is_same_origin.as_ref())
-    {
-        (Some(origin), Some(is_same_origin)) => (origin.to_string(), !is_same_origin),
-        (Some(origin), None) => (origin.to_string(), true),
-        // origin should always be set on request either by client or D-Bus service,
-        // so this shouldn't be called
-        (None, _) => {
-            tracing::info!("Error reading origin from request.");
-            return Err(WebAuthnError::TypeError);
-        }
+    let exclude = if exclude.is_empty() {
+        None
+    } else {
+        Some(exclude)
     };
+    let challenge = URL_SAFE_NO_PAD.encode(&options.challenge);
     let client_data_json =
         webauthn::format_client_data_json(Operation::Create, &challenge, &origin, is_cross_origin);
     let client_data_hash = webauthn::create_client_data_hash(&client_data_json);
     Ok((
-        MakeCredentialRequest {
-            hash: client_data_hash,
-            origin,
+        MakeCredentialRequestInternal::new(
+            MakeCredentialRequest {
+                hash: client_data_hash,
+                origin,
 
-            relying_party: rp,
-            user,
-            resident_key,
-            user_verification,
+                relying_party: rp,
+                user,
+                resident_key,
+                user_verification,
+                algorithms: Vec::new(),
+                exclude,
+                extensions,
+                timeout: timeout_from_ms(options.timeout),
+            },
             algorithms,
-            exclude,
-            extensions,
-            timeout: other_options.timeout.unwrap_or(Duration::from_secs(300)),
-        },
+        ),
         client_data_json,
     ))
 }
@@ -220,22 +196,38 @@ pub(super) fn create_credential_response_try_from_ctap2(
     let unsigned_extensions = serde_json::to_string(&response.ctap.unsigned_extensions_output)
         .map_err(|err| format!("failed to serialized unsigned extensions output: {err}"))
         .unwrap();
-    let authenticator_data_blob = auth_data
-        .to_response_bytes()
-        .map_err(|err| format!("failed to serialize authenticator data into bytes: {err}"))?;
-    let attestation_statement = (&response.ctap.attestation_statement)
-        .try_into()
-        .map_err(|_| "Could not serialize attestation statement".to_string())?;
+    let attestation_statement = webauthn::AttestationStatement::try_from_ctap2(
+        &response.ctap.format,
+        &response.ctap.attestation_statement,
+    )
+    .map_err(|_| {
+        format!(
+            "Could not serialize {} attestation statement",
+            response.ctap.format
+        )
+    })?;
+    let authenticator_data_blob = webauthn::encode_attested_authenticator_data(
+        auth_data,
+        &response.ctap.format,
+        &attestation_statement,
+    )
+    .map_err(|err| format!("failed to serialize authenticator data into bytes: {err}"))?;
     let attestation_object = webauthn::create_attestation_object(
         &authenticator_data_blob,
         &attestation_statement,
         response.ctap.enterprise_attestation.unwrap_or(false),
     )
-    .map_err(|_| "Failed to create attestation object".to_string())?;
+    .map_err(|err| format!("Failed to create attestation object: {err}"))?;
+    let public_key = cbor::Value::from_serialize(&attested_credential.credential_public_key)
+        .map_err(|err| format!("Failed to encode credential public key: {err}"))?;
+    let public_key = CoseKey::try_from(&public_key)
+        .map_err(|err| format!("Invalid credential public key: {err:?}"))?;
     // TODO: do we need to check that the client_data_hash is the same?
     let registration_response_json = webauthn::CreatePublicKeyCredentialResponse::new(
         attested_credential.credential_id.clone(),
         attestation_object,
+        authenticator_data_blob,
+        &public_key,
         client_data_json,
         Some(response.transport.clone()),
         unsigned_extensions,
@@ -254,7 +246,7 @@ pub(super) fn get_credential_request_try_into_ctap2(
     if request.public_key.is_none() {
         return Err(WebAuthnError::NotSupportedError);
     }
-    let options: webauthn::GetCredentialOptions = request
+    let options: PublicKeyCredentialRequestOptionsJSON = request
         .public_key
         .as_ref()
         .ok_or_else(|| {
@@ -267,16 +259,11 @@ pub(super) fn get_credential_request_try_into_ctap2(
                 WebAuthnError::TypeError
             })
         })?;
+    validation::validate_request_options(&options)?;
     let mut allow: Vec<Ctap2PublicKeyCredentialDescriptor> = options
         .allow_credentials
         .iter()
-        .filter_map(|cred| {
-            if cred.cred_type == "public-key" {
-                cred.try_into().ok()
-            } else {
-                None
-            }
-        })
+        .filter_map(credential_descriptor_try_into_ctap2)
         .collect();
     // TODO: The allow is returning an empty list instead of either None or a list of transports.
     // This should be investigated, but this is just a UI hint and isn't necessary to pass to the authenticator.
@@ -296,46 +283,29 @@ pub(super) fn get_credential_request_try_into_ctap2(
         }
     };
 
-    let client_data_json = webauthn::format_client_data_json(
-        Operation::Get,
-        &options.challenge,
-        &origin,
-        is_cross_origin,
-    );
+    let challenge = URL_SAFE_NO_PAD.encode(&options.challenge);
+    let client_data_json =
+        webauthn::format_client_data_json(Operation::Get, &challenge, &origin, is_cross_origin);
     let client_data_hash = webauthn::create_client_data_hash(&client_data_json);
+    let user_verification = options.user_verification.unwrap_or_default().into();
     // TODO: actually calculate correct effective domain, and use fallback to related origin requests to fill this in. For now, just default to origin.
-    let user_verification = match options
-        .user_verification
-        .unwrap_or_else(|| String::from("preferred"))
-        .as_ref()
-    {
-        "required" => UserVerificationRequirement::Required,
-        "preferred" => UserVerificationRequirement::Preferred,
-        "discouraged" => UserVerificationRequirement::Discouraged,
-        _ => {
-            tracing::info!("Invalid user verification requirement specified by client.");
-            return Err(WebAuthnError::TypeError);
-        }
-    };
-    let relying_party_id = options.rp_id.unwrap_or_else(|| {
-        // TODO: We're assuming that the origin is `<scheme>://data`, which is
-        // currently checked by the caller, but we should encode this in a type.
-        let (_, effective_domain) = origin.rsplit_once('/').unwrap();
-        effective_domain.to_string()
-    });
+    let relying_party_id = options
+        .rp_id
+        .unwrap_or_else(|| effective_domain(&origin).to_string());
 
-    let extensions = if let Some(incoming_extensions) = options.extensions {
-        let extensions = GetAssertionRequestExtensions {
+    let extensions = options
+        .extensions
+        .map(|incoming_extensions| GetAssertionRequestExtensions {
             cred_blob: incoming_extensions.get_cred_blob,
             hmac_or_prf: incoming_extensions
                 .prf
                 .and_then(|x| {
                     x.eval.map(|eval| {
-                        let eval = Some(eval.decode());
+                        let eval = Some(prf_value_into_ctap2(&eval));
                         let mut eval_by_credential = HashMap::new();
                         if let Some(incoming_eval) = x.eval_by_credential {
                             for (key, val) in incoming_eval.iter() {
-                                eval_by_credential.insert(key.clone(), val.decode());
+                                eval_by_credential.insert(key.clone(), prf_value_into_ctap2(val));
                             }
                         }
                         GetAssertionHmacOrPrfInput::Prf {
@@ -351,11 +321,7 @@ pub(super) fn get_credential_request_try_into_ctap2(
                 .filter(|x| x.read == Some(true))
                 .map(|_| GetAssertionLargeBlobExtension::Read)
                 .unwrap_or(GetAssertionLargeBlobExtension::None),
-        };
-        Some(extensions)
-    } else {
-        None
-    };
+        });
 
     Ok((
         GetAssertionRequest {
@@ -364,7 +330,7 @@ pub(super) fn get_credential_request_try_into_ctap2(
             user_verification,
             allow,
             extensions,
-            timeout: options.timeout.unwrap_or(Duration::from_secs(300)),
+            timeout: timeout_from_ms(options.timeout),
         },
         client_data_json,
     ))
@@ -374,11 +340,9 @@ pub(super) fn get_credential_response_try_from_ctap2(
     response: &GetAssertionResponseInternal,
     client_data_json: String,
 ) -> std::result::Result<GetPublicKeyCredentialResponse, String> {
-    let authenticator_data_blob = response
-        .ctap
-        .authenticator_data
-        .to_response_bytes()
-        .map_err(|err| format!("Failed to parse authenticator data: {err}"))?;
+    let authenticator_data_blob =
+        webauthn::encode_authenticator_data(&response.ctap.authenticator_data)
+            .map_err(|err| format!("Failed to serialize authenticator data: {err}"))?;
 
     // We can't just do this here, because we need encode all byte arrays for the JS-communication:
     // let unsigned_extensions = response
@@ -412,3 +376,56 @@ pub(super) fn get_credential_response_try_from_ctap2(
     };
     Ok(response)
 }
+
+/// Returns the host part of an origin.
+fn effective_domain(origin: &str) -> &str {
+    // TODO: We're assuming that the origin is `<scheme>://data`, which is
+    // currently checked by the caller, but we should encode this in a type.
+    origin
+        .rsplit_once('/')
+        .map(|(_, domain)| domain)
+        .unwrap_or(origin)
+}
+
+fn timeout_from_ms(timeout: Option<u32>) -> Duration {
+    timeout
+        .map(|ms| Duration::from_millis(ms as u64))
+        .unwrap_or(Duration::from_secs(300))
+}
+
+/// Returns `None` for descriptors of credential types we don't know about,
+/// which must be ignored.
+fn credential_descriptor_try_into_ctap2(
+    descriptor: &PublicKeyCredentialDescriptorJSON,
+) -> Option<Ctap2PublicKeyCredentialDescriptor> {
+    if descriptor.cred_type != Some(PublicKeyCredentialType::PublicKey) {
+        return None;
+    }
+    let transports: Vec<Ctap2Transport> = descriptor
+        .transports
+        .iter()
+        .filter_map(|t| (*t).try_into().ok())
+        .collect();
+    Some(Ctap2PublicKeyCredentialDescriptor {
+        r#type: Ctap2PublicKeyCredentialType::PublicKey,
+        id: descriptor.id.clone().into(),
+        transports: if transports.is_empty() {
+            None
+        } else {
+            Some(transports)
+        },
+    })
+}
+
+fn prf_value_into_ctap2(value: &AuthenticationExtensionsPRFValuesJSON) -> PRFValue {
+    let mut res = PRFValue::default();
+    let len_to_copy = std::cmp::min(value.first.len(), 32); // Determine how many bytes to copy
+    res.first[..len_to_copy].copy_from_slice(&value.first[..len_to_copy]);
+    if let Some(second) = value.second.as_ref() {
+        let len_to_copy = std::cmp::min(second.len(), 32); // Determine how many bytes to copy
+        let mut res_second = [0u8; 32];
+        res_second[..len_to_copy].copy_from_slice(&second[..len_to_copy]);
+        res.second = Some(res_second);
+    }
+    res
+}
@@ -54,7 +54,9 @@ pub mod test {
     };
 
     use credentialsd_common::{
-        client::FlowController, model::BackgroundEvent, server::ViewRequest,
+        client::FlowController,
+        model::{BackgroundEvent, Transport},
+        server::ViewRequest,
     };
     use futures_lite::StreamExt;
     use tokio::sync::{
@@ -135,14 +137,7 @@ pub mod test {
                     break;
                 }
             }
-            self.svc
-                .lock()
-                .await
-                .as_mut()
-                .unwrap()
-                .get_hybrid_credential()
-                .await
-                .unwrap()
+            self.select_transport(Transport::HybridQr).await
         }
 
         pub async fn request_usb_credential(&self) {
@@ -157,17 +152,23 @@ pub mod test {
                     break;
                 }
             }
-            self.svc
-                .lock()
-                .await
-                .as_mut()
-                .unwrap()
-                .get_usb_credential()
+            self.select_transport(Transport::Usb).await
+        }
+
+        async fn select_transport(&self, transport: Transport) {
+            let mut svc = self.svc.lock().await;
+            let svc = svc.as_mut().unwrap();
+            let device = svc
+                .get_available_public_key_devices()
                 .await
                 .unwrap()
+                .into_iter()
+                .find(|d| d.transport == transport)
+                .unwrap();
+            svc.select_device(device.id).await.unwrap()
         }
 
-        pub async fn enter_client_pin(&self, pin: String) {
+        pub async fn enter_client_pin(&self, device_id: String, pin: String) {
             tracing::debug!(
                 target: "DummyUiServer",
                 "Received enter_client_pin() request"
@@ -177,12 +178,12 @@ pub mod test {
                 .await
                 .as_mut()
                 .unwrap()
-                .enter_client_pin(pin)
+                .enter_client_pin(device_id, pin)
                 .await
                 .unwrap();
         }
 
-        pub async fn select_credential(&self, _cred_id: String) {
+        pub async fn select_credential(&self, _device_id: String, _cred_id: String) {
             tracing::debug!(
                 target: "DummyUiServer",
                 "Received select_credential() request"
@@ -0,0 +1,150 @@
+//! Validation and normalization of WebAuthn request options received from
+//! clients, before they are translated into CTAP requests.
+
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use credentialsd_common::{
+    model::WebAuthnError,
+    webauthn::{PublicKeyCredentialCreationOptionsJSON, PublicKeyCredentialRequestOptionsJSON},
+};
+
+/// Minimum challenge length, in bytes.
+///
+/// https://www.w3.org/TR/webauthn-3/#sctn-cryptographic-challenges
+const MIN_CHALLENGE_LENGTH: usize = 16;
+
+/// Maximum user handle length, in bytes.
+///
+/// https://www.w3.org/TR/webauthn-3/#user-handle
+const MAX_USER_ID_LENGTH: usize = 64;
+
+/// Length, in bytes, that names are truncated to before being sent to the
+/// authenticator. Authenticators must store at least this many bytes.
+///
+/// https://www.w3.org/TR/webauthn-3/#sctn-strings-truncation
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Checks the creation options against the constraints in the spec, and
+/// prepares the RP and user names for the authenticator.
+pub(super) fn validate_creation_options(
+    options: &mut PublicKeyCredentialCreationOptionsJSON,
+) -> Result<(), WebAuthnError> {
+    validate_challenge(&options.challenge)?;
+    if options.user.id.is_empty() || options.user.id.len() > MAX_USER_ID_LENGTH {
+        tracing::info!(
+            "Invalid request: user.id must be between 1 and {MAX_USER_ID_LENGTH} bytes, got {}",
+            options.user.id.len()
+        );
+        return Err(WebAuthnError::TypeError);
+    }
+    options.rp.name = prepare_name(&options.rp.name);
+    options.user.name = prepare_name(&options.user.name);
+    options.user.display_name = prepare_name(&options.user.display_name);
+    Ok(())
+}
+
+/// Checks the request options against the constraints in the spec.
+pub(super) fn validate_request_options(
+    options: &PublicKeyCredentialRequestOptionsJSON,
+) -> Result<(), WebAuthnError> {
+    validate_challenge(&options.challenge)
+}
+
+fn validate_challenge(challenge: &[u8]) -> Result<(), WebAuthnError> {
+    if challenge.len() < MIN_CHALLENGE_LENGTH {
+        tracing::info!(
+            "Invalid request: challenge must be at least {MIN_CHALLENGE_LENGTH} bytes, got {}",
+            challenge.len()
+        );
+        return Err(WebAuthnError::TypeError);
+    }
+    Ok(())
+}
+
+/// Applies the enforcement rules of the PRECIS Nickname profile ([RFC 8266]
+/// section 2.2), which WebAuthn uses for `name` and `displayName` members,
+/// then truncates the result to [`MAX_NAME_LENGTH`] bytes without splitting a
+/// grapheme cluster.
+///
+/// Rather than rejecting the whole request, control characters (which the
+/// FreeformClass disallows) are dropped.
+///
+/// [RFC 8266]: https://www.rfc-editor.org/rfc/rfc8266#section-2.2
+pub(super) fn prepare_name(name: &str) -> String {
+    // Additional mapping rule: map non-ASCII spaces to ASCII space, strip
+    // leading and trailing spaces, and collapse runs of spaces.
+    let mapped: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if is_space_separator(c) { ' ' } else { c })
+        .collect();
+    let mapped = mapped
+        .split(' ')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    // Normalization rule
+    let normalized: String = mapped.nfkc().collect();
+
+    truncate_graphemes(&normalized, MAX_NAME_LENGTH)
+}
+
+/// Unicode general category Zs
+fn is_space_separator(c: char) -> bool {
+    matches!(
+        c,
+        '\u{0020}' | '\u{00A0}' | '\u{1680}' | '\u{202F}' | '\u{205F}' | '\u{3000}'
+    ) || ('\u{2000}'..='\u{200A}').contains(&c)
+}
+
+fn truncate_graphemes(s: &str, max_len: usize) -> String {
+    let mut len = 0;
+    s.graphemes(true)
+        .take_while(|g| {
+            len += g.len();
+            len <= max_len
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{prepare_name, truncate_graphemes};
+
+    #[test]
+    fn test_prepare_name_maps_spaces() {
+        assert_eq!(
+            "Alice Example",
+            prepare_name("\u{3000} Alice\u{00A0}\u{00A0} Example\t ")
+        );
+    }
+
+    #[test]
+    fn test_prepare_name_normalizes_nfkc() {
+        // FULLWIDTH LATIN CAPITAL LETTER A, LATIN SMALL LETTER E + COMBINING ACUTE ACCENT
+        assert_eq!("A\u{00E9}", prepare_name("\u{FF21}e\u{0301}"));
+        // LATIN SMALL LIGATURE FI
+        assert_eq!("fi", prepare_name("\u{FB01}"));
+    }
+
+    #[test]
+    fn test_truncate_keeps_short_names() {
+        assert_eq!("Example", prepare_name("Example"));
+    }
+
+    #[test]
+    fn test_truncate_respects_grapheme_boundaries() {
+        // Family emoji: 4 code points joined with ZWJs, 25 bytes in one grapheme
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}\u{200D}\u{1F466}";
+        assert_eq!(25, family.len());
+        let name = format!("{}{family}", "a".repeat(50));
+        assert_eq!("a".repeat(50), truncate_graphemes(&name, 64));
+
+        let name = "\u{00E9}".repeat(40);
+        let truncated = prepare_name(&name);
+        assert_eq!(64, truncated.len());
+        assert_eq!("\u{00E9}".repeat(32), truncated);
+    }
+}
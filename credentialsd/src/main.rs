@@ -1,15 +1,26 @@
 mod cbor;
+mod config;
 mod cose;
 mod credential_service;
 mod dbus;
-mod serde;
 mod webauthn;
 
 use std::{error::Error, sync::Arc};
 
 use crate::{
+    config::{Config, PlatformStorage, TransportKind},
     credential_service::{
-        hybrid::InternalHybridHandler, usb::InProcessUsbHandler, CredentialService,
+        ble::BleHandler,
+        hybrid::{InternalHybridHandler, LinkedHybridHandler},
+        linked_devices::LinkedDeviceStore,
+        nfc::NfcHandler,
+        platform::{
+            store::PlatformCredentialStore, PlatformAuthenticator, PlatformKeys, UserVerifier,
+        },
+        provider::{ProviderStore, ProviderTransport},
+        transport::TransportRegistry,
+        usb::InProcessUsbHandler,
+        CredentialService,
     },
     dbus::{CredentialRequestControllerClient, UiControlServiceClient},
 };
@@ -27,24 +38,103 @@ async fn main() {
 }
 
 async fn run() -> Result<(), Box<dyn Error>> {
+    let config = Config::load()?;
+
     print!("Connecting to D-Bus as client...\t");
     let dbus_client_conn = zbus::connection::Builder::session()?.build().await?;
     println!(" ✅");
 
     print!("Starting D-Bus UI -> Credential control service...");
-    let ui_controller = UiControlServiceClient::new(dbus_client_conn);
-    let credential_service = CredentialService::new(
-        InternalHybridHandler::new(),
-        InProcessUsbHandler {},
-        Arc::new(ui_controller),
-    );
-    let (_flow_control_conn, initiator) =
+    let ui_controller = UiControlServiceClient::new(dbus_client_conn.clone());
+    let linked_devices = if config.transports.contains(&TransportKind::HybridLinked) {
+        // Phones can still be used with a QR code without the store.
+        match LinkedDeviceStore::open(&dbus_client_conn).await {
+            Ok(store) => Some(Arc::new(store)),
+            Err(err) => {
+                tracing::error!("Failed to open linked devices, running without them: {err}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut platform_authenticator = None;
+    let mut platform_credentials = None;
+    let mut transports = TransportRegistry::new();
+    for kind in config.transports {
+        match kind {
+            TransportKind::Usb => {
+                transports.register(InProcessUsbHandler {});
+            }
+            TransportKind::HybridLinked => {
+                if let Some(ref store) = linked_devices {
+                    transports.register(LinkedHybridHandler::new(store.clone()));
+                }
+            }
+            TransportKind::HybridQr => {
+                transports.register(InternalHybridHandler::new(linked_devices.clone()));
+            }
+            TransportKind::Providers => {
+                transports.register(ProviderTransport::new(
+                    dbus_client_conn.clone(),
+                    Arc::new(ProviderStore::open()?),
+                ));
+            }
+            TransportKind::Nfc => {
+                transports.register(NfcHandler {});
+            }
+            TransportKind::Ble => {
+                transports.register(BleHandler::default());
+            }
+            TransportKind::Internal => {
+                let keys = match PlatformKeys::open(config.platform_keys) {
+                    Ok(keys) => keys,
+                    Err(err) => {
+                        tracing::error!("{err}, not offering the platform authenticator");
+                        continue;
+                    }
+                };
+                let store = match config.platform_storage {
+                    PlatformStorage::File => PlatformCredentialStore::open(&dbus_client_conn).await,
+                    PlatformStorage::SecretService => {
+                        PlatformCredentialStore::open_secret_service(&dbus_client_conn).await
+                    }
+                };
+                match store {
+                    Ok(store) => {
+                        let store = Arc::new(store);
+                        let verifier = UserVerifier::new(&config.user_verification).await;
+                        let authenticator = PlatformAuthenticator::new(
+                            store.clone(),
+                            Arc::new(keys),
+                            Arc::new(verifier),
+                        );
+                        platform_authenticator = Some(authenticator.capabilities());
+                        platform_credentials = Some(store);
+                        transports.register(authenticator);
+                    }
+                    // Keep the daemon usable with other devices, and leave the
+                    // credentials alone so that they can be recovered.
+                    Err(err) => {
+                        tracing::error!("Failed to open platform credentials, not offering the platform authenticator: {err}");
+                    }
+                }
+            }
+        };
+    }
+    let credential_service =
+        CredentialService::new(transports, platform_credentials, Arc::new(ui_controller));
+    let (_flow_control_conn, initiator, transfers, management) =
         dbus::start_flow_control_service(credential_service).await?;
     println!(" ✅");
 
     print!("Starting D-Bus public client service...");
-    let initiator = CredentialRequestControllerClient { initiator };
-    let _gateway_conn = dbus::start_gateway(initiator).await?;
+    let initiator = CredentialRequestControllerClient {
+        initiator,
+        transfers,
+        management,
+    };
+    let _gateway_conn = dbus::start_gateway(initiator, platform_authenticator).await?;
     println!(" ✅");
 
     println!("Waiting for messages...");
@@ -1,21 +1,19 @@
-use std::{collections::HashMap, time::Duration};
-
 use base64::{self, engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use libwebauthn::{
-    ops::webauthn::{CredentialProtectionPolicy, MakeCredentialLargeBlobExtension},
-    proto::ctap2::{
-        Ctap2AttestationStatement, Ctap2CredentialType, Ctap2PublicKeyCredentialType,
-        Ctap2Transport,
-    },
+    fido::{AuthenticatorData, AuthenticatorDataFlags},
+    proto::ctap2::Ctap2AttestationStatement,
 };
 use ring::digest;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use serde_json::json;
 use tracing::debug;
 
-use credentialsd_common::model::Operation;
+use credentialsd_common::{model::Operation, webauthn::PublicKeyCredentialParameters};
 
-use crate::cose::{CoseKeyAlgorithmIdentifier, CoseKeyType};
+use crate::{
+    cbor,
+    cose::{CoseKey, CoseKeyAlgorithmIdentifier, CoseKeyType},
+};
 
 pub use libwebauthn::ops::webauthn::{
     CredentialProtectionExtension, GetAssertionHmacOrPrfInput, GetAssertionLargeBlobExtension,
@@ -30,12 +28,77 @@ pub use libwebauthn::proto::ctap2::{
 
 #[derive(Debug)]
 pub enum Error {
-    Unknown,
-    NotSupported,
-    InvalidState,
-    NotAllowed,
-    Constraint,
-    Internal(String),
+    Cbor(cbor::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Cbor(err) => err.fmt(f),
+        }
+    }
+}
+
+impl From<cbor::Error> for Error {
+    fn from(err: cbor::Error) -> Self {
+        Error::Cbor(err)
+    }
+}
+
+/// Encodes authenticator data parsed by libwebauthn.
+///
+/// libwebauthn doesn't keep the bytes returned by the authenticator, so the
+/// credential public key and extension outputs have to be encoded again.
+/// Authenticators return these in CTAP2 canonical form, so encoding them
+/// canonically reproduces the bytes that the authenticator signed.
+pub(crate) fn encode_authenticator_data<T: Serialize>(
+    authenticator_data: &AuthenticatorData<T>,
+) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::with_capacity(37);
+    data.extend(authenticator_data.rp_id_hash);
+    data.push(authenticator_data.flags.bits());
+    data.extend(authenticator_data.signature_count.to_be_bytes());
+    if let Some(attested_credential) = &authenticator_data.attested_credential {
+        let credential_id_len = u16::try_from(attested_credential.credential_id.len())
+            .map_err(|_| cbor::Error::Unrepresentable("credential ID too long"))?;
+        data.extend(attested_credential.aaguid);
+        data.extend(credential_id_len.to_be_bytes());
+        data.extend(&attested_credential.credential_id);
+        let public_key = cbor::Value::from_serialize(&attested_credential.credential_public_key)?;
+        data.extend(cbor::to_vec(&public_key)?);
+    }
+    if authenticator_data
+        .flags
+        .contains(AuthenticatorDataFlags::EXTENSION_DATA)
+    {
+        let extensions = match &authenticator_data.extensions {
+            Some(extensions) => cbor::Value::from_serialize(extensions)?,
+            None => cbor::Value::Map(Vec::new()),
+        };
+        data.extend(cbor::to_vec(&extensions)?);
+    }
+    Ok(data)
+}
+
+/// Encodes the authenticator data of a new credential, to be returned with
+/// `attestation_statement`.
+///
+/// If the statement that the authenticator sent in `format` was replaced with
+/// `none` attestation, nothing vouches for the AAGUID anymore, so it is
+/// zeroed like when clients replace attestation with `none` themselves.
+pub(crate) fn encode_attested_authenticator_data<T: Clone + Serialize>(
+    authenticator_data: &AuthenticatorData<T>,
+    format: &str,
+    attestation_statement: &AttestationStatement,
+) -> Result<Vec<u8>, Error> {
+    if format == "none" || *attestation_statement != AttestationStatement::None {
+        return encode_authenticator_data(authenticator_data);
+    }
+    let mut authenticator_data = authenticator_data.clone();
+    if let Some(attested_credential) = authenticator_data.attested_credential.as_mut() {
+        attested_credential.aaguid = [0; 16];
+    }
+    encode_authenticator_data(&authenticator_data)
 }
 
 pub(crate) fn create_attestation_object(
@@ -43,286 +106,77 @@ pub(crate) fn create_attestation_object(
     attestation_statement: &AttestationStatement,
     _enterprise_attestation_possible: bool,
 ) -> Result<Vec<u8>, Error> {
-    let mut attestation_object = Vec::new();
-    let mut cbor_writer = crate::cbor::CborWriter::new(&mut attestation_object);
-    cbor_writer.write_map_start(3).unwrap();
-    cbor_writer.write_text("fmt").unwrap();
-    match attestation_statement {
+    let att_stmt = match attestation_statement {
         AttestationStatement::Packed {
             algorithm,
             signature,
             certificates,
         } => {
-            cbor_writer.write_text("packed").unwrap();
-            cbor_writer.write_text("attStmt").unwrap();
-            let len = if certificates.is_empty() { 2 } else { 3 };
-            cbor_writer.write_map_start(len).unwrap();
-            cbor_writer.write_text("alg").unwrap();
-            cbor_writer.write_number((*algorithm).into()).unwrap();
-            cbor_writer.write_text("sig").unwrap();
-            cbor_writer.write_bytes(signature).unwrap();
+            let mut att_stmt = vec![
+                ("alg".into(), i64::from(*algorithm).into()),
+                ("sig".into(), signature.as_slice().into()),
+            ];
             if !certificates.is_empty() {
-                cbor_writer.write_text("x5c").unwrap();
-                cbor_writer.write_array_start(certificates.len()).unwrap();
-                for cert in certificates.iter() {
-                    cbor_writer.write_bytes(cert).unwrap();
-                }
+                att_stmt.push(("x5c".into(), certificates_value(certificates)));
             }
+            cbor::Value::Map(att_stmt)
+        }
+        AttestationStatement::Tpm {
+            version,
+            algorithm,
+            signature,
+            certificates,
+            certificate_info,
+            public_area,
+        } => cbor::Value::Map(vec![
+            ("ver".into(), version.as_str().into()),
+            ("alg".into(), i64::from(*algorithm).into()),
+            ("x5c".into(), certificates_value(certificates)),
+            ("sig".into(), signature.as_slice().into()),
+            ("certInfo".into(), certificate_info.as_slice().into()),
+            ("pubArea".into(), public_area.as_slice().into()),
+        ]),
+        AttestationStatement::AndroidKey {
+            algorithm,
+            signature,
+            certificates,
+        } => cbor::Value::Map(vec![
+            ("alg".into(), i64::from(*algorithm).into()),
+            ("sig".into(), signature.as_slice().into()),
+            ("x5c".into(), certificates_value(certificates)),
+        ]),
+        AttestationStatement::Apple { certificates } => {
+            cbor::Value::Map(vec![("x5c".into(), certificates_value(certificates))])
         }
         AttestationStatement::U2F {
             signature,
             certificate,
-        } => {
-            cbor_writer.write_text("fido-u2f").unwrap();
-            cbor_writer.write_text("attStmt").unwrap();
-            cbor_writer.write_map_start(2).unwrap();
-            cbor_writer.write_text("x5c").unwrap();
-            cbor_writer.write_array_start(1).unwrap();
-            cbor_writer.write_bytes(certificate).unwrap();
-            cbor_writer.write_text("sig").unwrap();
-            cbor_writer.write_bytes(signature).unwrap();
-        }
-        AttestationStatement::None => {
-            cbor_writer.write_text("none").unwrap();
-            cbor_writer.write_text("attStmt").unwrap();
-            cbor_writer.write_map_start(0).unwrap();
-        }
+        } => cbor::Value::Map(vec![
+            ("sig".into(), signature.as_slice().into()),
+            (
+                "x5c".into(),
+                certificates_value(std::slice::from_ref(certificate)),
+            ),
+        ]),
+        AttestationStatement::None => cbor::Value::Map(Vec::new()),
+        AttestationStatement::Other { statement, .. } => statement.clone(),
     };
 
-    cbor_writer.write_text("authData").unwrap();
-    cbor_writer.write_bytes(authenticator_data).unwrap();
-
-    Ok(attestation_object)
-}
-
-#[derive(Debug, Deserialize)]
-pub(crate) struct MakeCredentialOptions {
-    /// Timeout in milliseconds
-    #[serde(deserialize_with = "crate::serde::duration::from_opt_ms")]
-    #[serde(default)]
-    pub timeout: Option<Duration>,
-    #[serde(rename = "excludeCredentials")]
-    pub excluded_credentials: Option<Vec<CredentialDescriptor>>,
-    #[serde(rename = "authenticatorSelection")]
-    pub authenticator_selection: Option<AuthenticatorSelectionCriteria>,
-    /// https://www.w3.org/TR/webauthn-3/#enum-attestation-convey
-    #[allow(dead_code)]
-    pub attestation: Option<String>,
-    /// extensions input as a JSON object
-    pub extensions: Option<MakeCredentialExtensions>,
-}
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub(crate) struct MakeCredentialExtensions {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub cred_blob: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub cred_props: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub min_pin_length: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub credential_protection_policy: Option<CredentialProtectionPolicy>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub enforce_credential_protection_policy: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub large_blob: Option<LargeBlobExtension>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub prf: Option<Prf>,
-}
-
-#[derive(Debug, Default, Deserialize)]
-pub(crate) struct LargeBlobExtension {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub support: Option<MakeCredentialLargeBlobExtension>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub read: Option<bool>,
-    #[allow(dead_code)] // TODO: Not currently used, but we should eventually implement
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub write: Option<String>,
-}
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub(crate) struct Prf {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) eval: Option<PRFValue>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) eval_by_credential: Option<HashMap<String, PRFValue>>,
-}
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct PRFValue {
-    // base64 encoded data
-    pub first: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub second: Option<String>,
-}
-
-impl PRFValue {
-    pub(crate) fn decode(&self) -> libwebauthn::ops::webauthn::PRFValue {
-        let mut res = libwebauthn::ops::webauthn::PRFValue::default();
-        let first = URL_SAFE_NO_PAD.decode(&self.first).unwrap();
-        let len_to_copy = std::cmp::min(first.len(), 32); // Determine how many bytes to copy
-        res.first[..len_to_copy].copy_from_slice(&first[..len_to_copy]);
-        if let Some(second) = self
-            .second
-            .as_ref()
-            .map(|second| URL_SAFE_NO_PAD.decode(second).unwrap())
-        {
-            let len_to_copy = std::cmp::min(second.len(), 32); // Determine how many bytes to copy
-            let mut res_second = [0u8; 32];
-            res_second[..len_to_copy].copy_from_slice(&second[..len_to_copy]);
-            res.second = Some(res_second);
-        }
-        res
-    }
-}
-
-#[derive(Debug, Deserialize)]
-pub(crate) struct GetCredentialOptions {
-    /// Challenge bytes in base64url-encoding with no padding.
-    pub(crate) challenge: String,
-
-    #[serde(deserialize_with = "crate::serde::duration::from_opt_ms")]
-    #[serde(default)]
-    pub(crate) timeout: Option<Duration>,
-
-    /// Relying Party ID.
-    /// If not set, the request origin's effective domain will be used instead.
-    #[serde(rename = "rpId")]
-    pub(crate) rp_id: Option<String>,
-
-    /// An list of allowed credentials, in descending order of RP preference.
-    /// If empty, then any credential that can fulfill the request is allowed.
-    #[serde(rename = "allowCredentials")]
-    #[serde(default)]
-    pub(crate) allow_credentials: Vec<CredentialDescriptor>,
-
-    /// Defaults to `preferred`
-    #[serde(rename = "userVerification")]
-    pub(crate) user_verification: Option<String>,
-
-    /// Contextual information from the RP to help the client guide the user
-    /// through the authentication ceremony.
-    #[allow(dead_code)] // TODO: Not currently used, but we should eventually implement support for hints.
-    #[serde(default)]
-    pub(crate) hints: Vec<String>,
-
-    pub(crate) extensions: Option<GetCredentialExtensions>,
-}
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub(crate) struct GetCredentialExtensions {
-    // TODO: appid
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub get_cred_blob: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub large_blob: Option<LargeBlobExtension>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub prf: Option<Prf>,
-}
-
-#[derive(Debug, Deserialize)]
-/// https://www.w3.org/TR/webauthn-3/#dictionary-credential-descriptor
-pub(crate) struct CredentialDescriptor {
-    /// Type of the public key credential the caller is referring to.
-    ///
-    /// The value SHOULD be a member of PublicKeyCredentialType but client
-    /// platforms MUST ignore any PublicKeyCredentialDescriptor with an unknown
-    /// type.
-    #[serde(rename = "type")]
-    pub(crate) cred_type: String,
-    /// Credential ID of the public key credential the caller is referring to.
-    #[serde(with = "crate::serde::b64")]
-    pub(crate) id: Vec<u8>,
-    pub(crate) transports: Option<Vec<String>>,
-}
-
-impl TryFrom<&CredentialDescriptor> for Ctap2PublicKeyCredentialDescriptor {
-    type Error = Error;
-    fn try_from(value: &CredentialDescriptor) -> Result<Self, Self::Error> {
-        let transports = value.transports.as_ref().filter(|t| !t.is_empty());
-        let transports = match transports {
-            Some(transports) => {
-                let mut transport_list = transports.iter().map(|t| match t.as_ref() {
-                    "ble" => Some(Ctap2Transport::Ble),
-                    "nfc" => Some(Ctap2Transport::Nfc),
-                    "usb" => Some(Ctap2Transport::Usb),
-                    "internal" => Some(Ctap2Transport::Internal),
-                    _ => None,
-                });
-                if transport_list.any(|t| t.is_none()) {
-                    return Err(Error::Internal(
-                        "Invalid transport type specified".to_owned(),
-                    ));
-                }
-                transport_list.collect()
-            }
-            None => None,
-        };
-        Ok(Self {
-            r#type: Ctap2PublicKeyCredentialType::PublicKey,
-            id: value.id.clone().into(),
-            transports,
-        })
-    }
-}
-impl TryFrom<CredentialDescriptor> for Ctap2PublicKeyCredentialDescriptor {
-    type Error = Error;
-    fn try_from(value: CredentialDescriptor) -> Result<Self, Self::Error> {
-        Ctap2PublicKeyCredentialDescriptor::try_from(&value)
-    }
-}
-
-#[derive(Debug, Deserialize)]
-/// https://www.w3.org/TR/webauthn-3/#dictionary-authenticatorSelection
-pub(crate) struct AuthenticatorSelectionCriteria {
-    // /// https://www.w3.org/TR/webauthn-3/#enum-attachment
-    // #[zvariant(rename = "authenticatorAttachment")]
-    // pub authenticator_attachment: Option<String>,
-    //
-    /// https://www.w3.org/TR/webauthn-3/#enum-residentKeyRequirement
-    #[serde(rename = "residentKey")]
-    pub resident_key: Option<String>,
-
-    // Implied by resident_key == "required", deprecated in webauthn
-    // https://www.w3.org/TR/webauthn-3/#enum-residentKeyRequirement
-    #[serde(rename = "requireResidentKey")]
-    pub require_resident_key: Option<bool>,
-
-    /// https://www.w3.org/TR/webauthn-3/#enumdef-userverificationrequirement
-    #[serde(rename = "userVerification")]
-    pub user_verification: Option<String>,
-}
-
-#[derive(Clone, Deserialize)]
-/// https://www.w3.org/TR/webauthn-3/#dictdef-publickeycredentialparameters
-pub(crate) struct PublicKeyCredentialParameters {
-    pub alg: i64,
+    let attestation_object = cbor::Value::Map(vec![
+        ("fmt".into(), attestation_statement.format().into()),
+        ("attStmt".into(), att_stmt),
+        ("authData".into(), authenticator_data.into()),
+    ]);
+    Ok(cbor::to_vec(&attestation_object)?)
 }
 
-impl TryFrom<&PublicKeyCredentialParameters> for Ctap2CredentialType {
-    type Error = Error;
-
-    fn try_from(value: &PublicKeyCredentialParameters) -> Result<Self, Self::Error> {
-        let algorithm = match value.alg {
-            -7 => libwebauthn::proto::ctap2::Ctap2COSEAlgorithmIdentifier::ES256,
-            -8 => libwebauthn::proto::ctap2::Ctap2COSEAlgorithmIdentifier::EDDSA,
-            // TODO: we should still pass on the raw value to the authenticator and let it decide whether it's supported.
-            _ => {
-                return Err(Error::Internal(
-                    "Invalid algorithm passed for new credential".to_owned(),
-                ))
-            }
-        };
-        Ok(Self {
-            public_key_type: Ctap2PublicKeyCredentialType::PublicKey,
-            algorithm,
-        })
-    }
+fn certificates_value(certificates: &[Vec<u8>]) -> cbor::Value {
+    cbor::Value::Array(
+        certificates
+            .iter()
+            .map(|cert| cert.as_slice().into())
+            .collect(),
+    )
 }
 
 impl TryFrom<&PublicKeyCredentialParameters> for CoseKeyType {
@@ -344,6 +198,10 @@ impl TryFrom<PublicKeyCredentialParameters> for CoseKeyType {
     }
 }
 
+/// Attestation statements for the formats registered in the IANA "WebAuthn
+/// Attestation Statement Format Identifiers" registry.
+///
+/// https://www.w3.org/TR/webauthn-3/#sctn-defined-attestation-formats
 #[derive(Debug, PartialEq)]
 pub(crate) enum AttestationStatement {
     None,
@@ -352,43 +210,126 @@ pub(crate) enum AttestationStatement {
         certificate: Vec<u8>,
     },
     Packed {
+        algorithm: CoseKeyAlgorithmIdentifier,
+        signature: Vec<u8>,
+        /// Empty for self attestation.
+        certificates: Vec<Vec<u8>>,
+    },
+    Tpm {
+        version: String,
         algorithm: CoseKeyAlgorithmIdentifier,
         signature: Vec<u8>,
         certificates: Vec<Vec<u8>>,
+        certificate_info: Vec<u8>,
+        public_area: Vec<u8>,
+    },
+    AndroidKey {
+        algorithm: CoseKeyAlgorithmIdentifier,
+        signature: Vec<u8>,
+        certificates: Vec<Vec<u8>>,
+    },
+    Apple {
+        certificates: Vec<Vec<u8>>,
+    },
+    /// The attStmt map as sent by the authenticator, passed through as is.
+    /// This is used for every format when the raw response is available,
+    /// e.g. `tpm` or `android-safetynet`.
+    Other {
+        format: String,
+        statement: cbor::Value,
     },
 }
 
-impl TryFrom<&Ctap2AttestationStatement> for AttestationStatement {
-    type Error = Error;
-
-    fn try_from(value: &Ctap2AttestationStatement) -> Result<Self, Self::Error> {
-        match value {
-            Ctap2AttestationStatement::None(_) => Ok(AttestationStatement::None),
-            Ctap2AttestationStatement::PackedOrAndroid(att_stmt) => {
-                let alg = att_stmt
-                    .algorithm
-                    .try_into()
-                    .map_err(|_| Error::NotSupported)?;
-                Ok(Self::Packed {
-                    algorithm: alg,
-                    signature: att_stmt.signature.as_ref().to_vec(),
-                    certificates: att_stmt
-                        .certificates
-                        .iter()
-                        .map(|c| c.as_ref().to_vec())
-                        .collect(),
-                })
+impl AttestationStatement {
+    pub(crate) fn format(&self) -> &str {
+        match self {
+            Self::None => "none",
+            Self::U2F { .. } => "fido-u2f",
+            Self::Packed { .. } => "packed",
+            Self::Tpm { .. } => "tpm",
+            Self::AndroidKey { .. } => "android-key",
+            Self::Apple { .. } => "apple",
+            Self::Other { format, .. } => format,
+        }
+    }
+
+    /// libwebauthn deserializes the attestation statement without looking at
+    /// the format, picking the first variant whose fields match, so the
+    /// format the authenticator sent needs to be passed in separately.
+    ///
+    /// Transports that see the raw response replace the parsed statement
+    /// with the attStmt map the authenticator sent, which is passed through
+    /// as is. Otherwise, the statement can only be rebuilt if libwebauthn's
+    /// struct holds all of its members. When it doesn't, e.g. for `tpm`
+    /// statements that were read as `packed` ones, or for algorithms that
+    /// libwebauthn doesn't know, `none` attestation is returned instead, as
+    /// clients may do.
+    pub(crate) fn try_from_ctap2(
+        format: &str,
+        value: &Ctap2AttestationStatement,
+    ) -> Result<Self, Error> {
+        match (format, value) {
+            ("none", _) => Ok(Self::None),
+            (format, Ctap2AttestationStatement::None(map)) => Ok(Self::Other {
+                format: format.to_string(),
+                statement: cbor::Value::from_serialize(map).map_err(|err| {
+                    debug!("Failed to encode {format} attestation statement: {err}");
+                    Error::from(err)
+                })?,
+            }),
+            ("packed", Ctap2AttestationStatement::PackedOrAndroid(att_stmt)) => {
+                match att_stmt.algorithm.try_into() {
+                    Ok(algorithm) => Ok(Self::Packed {
+                        algorithm,
+                        signature: att_stmt.signature.to_vec(),
+                        certificates: att_stmt.certificates.iter().map(|c| c.to_vec()).collect(),
+                    }),
+                    Err(_) => Ok(Self::unrecoverable(format, value)),
+                }
             }
-            Ctap2AttestationStatement::FidoU2F(att_stmt) => Ok(Self::U2F {
-                signature: att_stmt.signature.as_ref().to_vec(),
+            ("android-key", Ctap2AttestationStatement::PackedOrAndroid(att_stmt)) => {
+                match att_stmt.algorithm.try_into() {
+                    Ok(algorithm) => Ok(Self::AndroidKey {
+                        algorithm,
+                        signature: att_stmt.signature.to_vec(),
+                        certificates: att_stmt.certificates.iter().map(|c| c.to_vec()).collect(),
+                    }),
+                    Err(_) => Ok(Self::unrecoverable(format, value)),
+                }
+            }
+            ("tpm", Ctap2AttestationStatement::Tpm(att_stmt)) => {
+                match att_stmt.algorithm.try_into() {
+                    Ok(algorithm) => Ok(Self::Tpm {
+                        version: att_stmt.version.clone(),
+                        algorithm,
+                        signature: att_stmt.signature.to_vec(),
+                        certificates: att_stmt.certificates.iter().map(|c| c.to_vec()).collect(),
+                        certificate_info: att_stmt.certificate_info.to_vec(),
+                        public_area: att_stmt.public_area.to_vec(),
+                    }),
+                    Err(_) => Ok(Self::unrecoverable(format, value)),
+                }
+            }
+            ("fido-u2f", Ctap2AttestationStatement::FidoU2F(att_stmt)) => Ok(Self::U2F {
+                signature: att_stmt.signature.to_vec(),
                 certificate: att_stmt.certificate.to_vec(),
             }),
-            _ => {
-                debug!("Unsupported attestation type: {:?}", value);
-                Err(Error::NotSupported)
-            }
+            ("apple", Ctap2AttestationStatement::AppleAnonymous(att_stmt)) => Ok(Self::Apple {
+                certificates: att_stmt.certificates.iter().map(|c| c.to_vec()).collect(),
+            }),
+            _ => Ok(Self::unrecoverable(format, value)),
         }
     }
+
+    /// libwebauthn has already dropped members of the statement, so it can't
+    /// be passed on to the RP.
+    fn unrecoverable(format: &str, value: &Ctap2AttestationStatement) -> Self {
+        debug!(
+            "Attestation statement for format {format} was not preserved, returning none attestation: {:?}",
+            value
+        );
+        Self::None
+    }
 }
 
 pub struct CreatePublicKeyCredentialResponse {
@@ -467,6 +408,16 @@ pub struct AttestationResponse {
     /// Bytes containing authenticator data and an attestation statement.
     attestation_object: Vec<u8>,
 
+    /// The authenticator data contained in the attestation object.
+    authenticator_data: Vec<u8>,
+
+    /// The credential public key as a DER SubjectPublicKeyInfo, if the
+    /// algorithm has a SPKI representation.
+    public_key: Option<Vec<u8>>,
+
+    /// COSE algorithm identifier of the credential public key.
+    public_key_algorithm: i64,
+
     /// Transports that the authenticator is believed to support, or an
     /// empty sequence if the information is unavailable.
     ///
@@ -481,9 +432,12 @@ pub struct AttestationResponse {
 }
 
 impl CreatePublicKeyCredentialResponse {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: Vec<u8>,
         attestation_object: Vec<u8>,
+        authenticator_data: Vec<u8>,
+        public_key: &CoseKey,
         client_data_json: String,
         transports: Option<Vec<String>>,
         extension_output_json: String,
@@ -494,6 +448,9 @@ impl CreatePublicKeyCredentialResponse {
             response: AttestationResponse {
                 client_data_json,
                 attestation_object,
+                authenticator_data,
+                public_key: public_key.to_spki(),
+                public_key_algorithm: public_key.algorithm.into(),
                 transports: transports.unwrap_or_default(),
             },
             extensions: extension_output_json,
@@ -509,6 +466,9 @@ impl CreatePublicKeyCredentialResponse {
         let response = json!({
             "clientDataJSON": URL_SAFE_NO_PAD.encode(self.response.client_data_json.as_bytes()),
             "attestationObject": URL_SAFE_NO_PAD.encode(&self.response.attestation_object),
+            "authenticatorData": URL_SAFE_NO_PAD.encode(&self.response.authenticator_data),
+            "publicKey": self.response.public_key.as_ref().map(|key| URL_SAFE_NO_PAD.encode(key)),
+            "publicKeyAlgorithm": self.response.public_key_algorithm,
             "transports": self.response.transports,
         });
         let extensions: serde_json::Value = serde_json::from_str(&self.extensions)
@@ -687,7 +647,204 @@ pub fn format_client_data_json(
     let op_str = match op {
         Operation::Create => "webauthn.create",
         Operation::Get => "webauthn.get",
+        Operation::Export
+        | Operation::Import
+        | Operation::ManagePin
+        | Operation::ManageCredentials
+        | Operation::ManageFingerprints
+        | Operation::ConfigureSecurityKey
+        | Operation::InspectSecurityKey
+        | Operation::ResetSecurityKey => {
+            unreachable!("Only WebAuthn ceremonies have client data")
+        }
     };
     let cross_origin_str = if is_cross_origin { "true" } else { "false" };
     format!("{{\"type\":\"{op_str}\",\"challenge\":\"{challenge}\",\"origin\":\"{origin}\",\"crossOrigin\":{cross_origin_str}}}")
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use libwebauthn::{
+        fido::{AttestedCredentialData, AuthenticatorData, AuthenticatorDataFlags},
+        proto::ctap2::{Ctap2AttestationStatement, Ctap2MakeCredentialsResponseExtensions},
+    };
+    use serde_cbor_2::Value;
+
+    use super::{
+        create_attestation_object, encode_attested_authenticator_data, encode_authenticator_data,
+        AttestationStatement,
+    };
+    use crate::{cbor, cose::CoseKeyAlgorithmIdentifier};
+
+    #[test]
+    fn test_encode_authenticator_data_extensions_canonically() {
+        let authenticator_data = AuthenticatorData {
+            rp_id_hash: [0; 32],
+            flags: AuthenticatorDataFlags::USER_PRESENT | AuthenticatorDataFlags::EXTENSION_DATA,
+            signature_count: 1,
+            attested_credential: None,
+            extensions: Some(Ctap2MakeCredentialsResponseExtensions {
+                min_pin_length: Some(4),
+                hmac_secret: Some(true),
+                ..Default::default()
+            }),
+        };
+        let encoded = encode_authenticator_data(&authenticator_data).unwrap();
+        assert_eq!([0; 32], encoded[..32]);
+        assert_eq!([0x81, 0x00, 0x00, 0x00, 0x01], encoded[32..37]);
+        let expected_extensions = [
+            &[0xa2, 0x6b][..],
+            b"hmac-secret",
+            &[0xf5, 0x6c],
+            b"minPinLength",
+            &[0x04],
+        ]
+        .concat();
+        assert_eq!(expected_extensions, encoded[37..]);
+    }
+
+    #[test]
+    fn test_unknown_attestation_format_is_passed_through() {
+        let mut map = BTreeMap::new();
+        map.insert(Value::Text("ver".to_string()), Value::Text("1".to_string()));
+        let value = Ctap2AttestationStatement::None(map);
+        let att_stmt = AttestationStatement::try_from_ctap2("android-safetynet", &value).unwrap();
+        assert_eq!("android-safetynet", att_stmt.format());
+
+        let attestation_object = create_attestation_object(&[0xaa], &att_stmt, false).unwrap();
+        let expected = [
+            &[0xa3, 0x63][..],
+            b"fmt",
+            &[0x71],
+            b"android-safetynet",
+            &[0x67],
+            b"attStmt",
+            &[0xa1, 0x63],
+            b"ver",
+            &[0x61],
+            b"1",
+            &[0x68],
+            b"authData",
+            &[0x41, 0xaa],
+        ]
+        .concat();
+        assert_eq!(expected, attestation_object);
+    }
+
+    #[test]
+    fn test_statements_libwebauthn_cannot_represent_become_none() {
+        // A tpm statement, as read by libwebauthn, which has dropped `ver`,
+        // `certInfo` and `pubArea`.
+        let value: Ctap2AttestationStatement =
+            serde_cbor_2::value::from_value(Value::Map(BTreeMap::from([
+                (Value::Text("alg".to_string()), Value::Integer(-257)),
+                (Value::Text("sig".to_string()), Value::Bytes(vec![0x01])),
+                (Value::Text("x5c".to_string()), Value::Array(Vec::new())),
+            ])))
+            .unwrap();
+        assert!(matches!(
+            value,
+            Ctap2AttestationStatement::PackedOrAndroid(_)
+        ));
+        let att_stmt = AttestationStatement::try_from_ctap2("tpm", &value).unwrap();
+        assert_eq!(AttestationStatement::None, att_stmt);
+        // RS256 is unknown to libwebauthn, so the algorithm is lost.
+        let att_stmt = AttestationStatement::try_from_ctap2("packed", &value).unwrap();
+        assert_eq!(AttestationStatement::None, att_stmt);
+    }
+
+    #[test]
+    fn test_aaguid_is_zeroed_when_attestation_is_dropped() {
+        let authenticator_data = AuthenticatorData {
+            rp_id_hash: [0; 32],
+            flags: AuthenticatorDataFlags::USER_PRESENT
+                | AuthenticatorDataFlags::ATTESTED_CREDENTIALS,
+            signature_count: 0,
+            attested_credential: Some(AttestedCredentialData {
+                aaguid: [0xaa; 16],
+                credential_id: vec![0x01],
+                credential_public_key: cosey::Ed25519PublicKey {
+                    x: cosey::Bytes::from_slice(&[0x02; 32]).unwrap(),
+                }
+                .into(),
+            }),
+            extensions: None::<Ctap2MakeCredentialsResponseExtensions>,
+        };
+        let aaguid = |encoded: Vec<u8>| encoded[37..53].to_vec();
+
+        let dropped = encode_attested_authenticator_data(
+            &authenticator_data,
+            "tpm",
+            &AttestationStatement::None,
+        )
+        .unwrap();
+        assert_eq!(vec![0; 16], aaguid(dropped));
+
+        for (format, att_stmt) in [
+            ("none", AttestationStatement::None),
+            (
+                "apple",
+                AttestationStatement::Apple {
+                    certificates: Vec::new(),
+                },
+            ),
+        ] {
+            let kept =
+                encode_attested_authenticator_data(&authenticator_data, format, &att_stmt).unwrap();
+            assert_eq!(vec![0xaa; 16], aaguid(kept));
+        }
+    }
+
+    #[test]
+    fn test_encode_tpm_attestation() {
+        let att_stmt = AttestationStatement::Tpm {
+            version: "2.0".to_string(),
+            algorithm: CoseKeyAlgorithmIdentifier::RS256,
+            signature: vec![0x01],
+            certificates: vec![vec![0x02]],
+            certificate_info: vec![0x03],
+            public_area: vec![0x04],
+        };
+        let attestation_object = create_attestation_object(&[], &att_stmt, false).unwrap();
+        let cbor::Value::Map(entries) = cbor::from_slice(&attestation_object).unwrap() else {
+            panic!("Expected a map");
+        };
+        assert!(entries.contains(&("fmt".into(), "tpm".into())));
+        let (_, cbor::Value::Map(att_stmt)) = entries
+            .iter()
+            .find(|(key, _)| *key == "attStmt".into())
+            .unwrap()
+        else {
+            panic!("Expected attStmt to be a map");
+        };
+        for member in ["ver", "alg", "sig", "x5c", "certInfo", "pubArea"] {
+            assert!(att_stmt.iter().any(|(key, _)| *key == member.into()));
+        }
+    }
+
+    #[test]
+    fn test_encode_apple_attestation() {
+        let att_stmt = AttestationStatement::Apple {
+            certificates: vec![vec![0x01, 0x02]],
+        };
+        let attestation_object = create_attestation_object(&[], &att_stmt, false).unwrap();
+        let expected = [
+            &[0xa3, 0x63][..],
+            b"fmt",
+            &[0x65],
+            b"apple",
+            &[0x67],
+            b"attStmt",
+            &[0xa1, 0x63],
+            b"x5c",
+            &[0x81, 0x42, 0x01, 0x02],
+            &[0x68],
+            b"authData",
+            &[0x40],
+        ]
+        .concat();
+        assert_eq!(expected, attestation_object);
+    }
+}
@@ -28,6 +28,7 @@ fn test_client_capabilities() {
         ("signalAllAcceptedCredentials", false),
         ("signalCurrentUserDetails", false),
         ("signalUnknownCredential", false),
+        ("tpmBoundPlatformAuthenticator", false),
     ]);
     for (key, expected) in capabilities.iter() {
         let actual = rsp.get(*key).unwrap();
@@ -0,0 +1,140 @@
+#!/usr/bin/env python3
+
+"""Manages USB security keys through credentialsd.
+
+The security key is picked, and its PIN entered, in the credentialsd UI.
+"""
+
+import asyncio
+import sys
+import uuid
+
+from dbus_next.aio import MessageBus
+from dbus_next import DBusError
+
+import util
+
+USAGE = """Usage: security_keys.py <cmd> [args]
+
+Commands:
+    info
+    pin-info
+    set-pin
+    list
+    delete <credential-id>
+    rename <credential-id> <display-name>
+    fingerprints
+    enroll [name]
+    rename-fingerprint <template-id> <name>
+    remove-fingerprint <template-id>
+    always-uv on|off
+    min-pin-length <length> [rp-id...]
+    enterprise-attestation
+    reset
+
+Credential and template IDs are base64url-encoded, as printed by 'list' and
+'fingerprints'."""
+
+
+async def run(cmd, args):
+    bus = await MessageBus().connect()
+
+    # The interface is not in the introspection XML of the gateway yet.
+    introspection = await bus.introspect(
+        "xyz.iinuwa.credentialsd.Credentials",
+        "/xyz/iinuwa/credentialsd/SecurityKeys",
+    )
+    proxy_object = bus.get_proxy_object(
+        "xyz.iinuwa.credentialsd.Credentials",
+        "/xyz/iinuwa/credentialsd/SecurityKeys",
+        introspection,
+    )
+    interface = proxy_object.get_interface("xyz.iinuwa.credentialsd.SecurityKeys1")
+
+    if cmd == "info":
+        info = await interface.call_get_info()
+        for key, value in info.items():
+            value = value.value
+            if key == "aaguid":
+                value = str(uuid.UUID(bytes=bytes(value)))
+            print(f"{key}: {value}")
+    elif cmd == "pin-info":
+        info = await interface.call_get_pin_info()
+        for key, value in info.items():
+            print(f"{key}: {value.value}")
+    elif cmd == "set-pin":
+        await interface.call_set_pin()
+        print("PIN set")
+    elif cmd == "list":
+        [credentials, remaining] = await interface.call_get_credentials()
+        for cred in credentials:
+            rp_id = cred["rpId"].value
+            user = cred.get("userName")
+            user = user.value if user else util.b64_encode(cred["userId"].value)
+            display_name = cred.get("userDisplayName")
+            if display_name:
+                user += f" ({display_name.value})"
+            cred_id = util.b64_encode(cred["credentialId"].value)
+            print(f"{rp_id}\t{user}\t{cred_id}")
+        print(f"{len(credentials)} passkeys, room for {remaining} more")
+    elif cmd == "delete" and len(args) == 1:
+        await interface.call_delete_credential(util.b64_decode(args[0]))
+        print("Passkey deleted")
+    elif cmd == "rename" and len(args) == 2:
+        await interface.call_update_user_display_name(
+            util.b64_decode(args[0]), args[1]
+        )
+        print("Passkey renamed")
+    elif cmd == "fingerprints":
+        fingerprints = await interface.call_get_fingerprints()
+        for fingerprint in fingerprints:
+            name = fingerprint.get("name")
+            template_id = util.b64_encode(fingerprint["templateId"].value)
+            print(f"{template_id}\t{name.value if name else ''}")
+    elif cmd == "enroll" and len(args) <= 1:
+        template_id = await interface.call_enroll_fingerprint(
+            args[0] if args else ""
+        )
+        print(f"Fingerprint enrolled: {util.b64_encode(template_id)}")
+    elif cmd == "rename-fingerprint" and len(args) == 2:
+        await interface.call_rename_fingerprint(util.b64_decode(args[0]), args[1])
+        print("Fingerprint renamed")
+    elif cmd == "remove-fingerprint" and len(args) == 1:
+        await interface.call_remove_fingerprint(util.b64_decode(args[0]))
+        print("Fingerprint removed")
+    elif cmd == "always-uv" and args in (["on"], ["off"]):
+        await interface.call_set_always_uv(args[0] == "on")
+        print(f"alwaysUv turned {args[0]}")
+    elif cmd == "min-pin-length" and args and args[0].isdigit():
+        await interface.call_set_min_pin_length(int(args[0]), args[1:])
+        print("Minimum PIN length set")
+    elif cmd == "enterprise-attestation" and not args:
+        await interface.call_enable_enterprise_attestation()
+        print("Enterprise attestation enabled")
+    elif cmd == "reset" and not args:
+        await interface.call_reset()
+        print("Security key reset")
+    else:
+        print(USAGE, file=sys.stderr)
+        exit(1)
+
+
+def main():
+    args = sys.argv[1:]
+    if not args:
+        print(USAGE, file=sys.stderr)
+        exit(1)
+    loop = asyncio.new_event_loop()
+    asyncio.set_event_loop(loop)
+    try:
+        loop.run_until_complete(run(args[0], args[1:]))
+    except DBusError as e:
+        print(
+            "Received error: " + e.type + (f": {e.text}" if e.text else ""),
+            file=sys.stderr,
+        )
+        exit(1)
+
+
+if __name__ == "__main__":
+    main()